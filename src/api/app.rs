@@ -8,11 +8,13 @@ use std::{sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 use tower_http::cors::{Any, CorsLayer};
 
+use crate::MachineConfig;
+
 pub struct App;
 
 impl App {
-    pub async fn run() {
-        let cpu = Arc::new(Mutex::new(crate::Cpu::new(Vec::new())));
+    pub async fn run(config: MachineConfig) -> Result<(), String> {
+        let cpu = Arc::new(Mutex::new(crate::Cpu::new(config, Vec::new())?));
 
         let cors = CorsLayer::new()
             .allow_origin(Any) // 允许任何来源的请求（开发模式用）
@@ -28,15 +30,121 @@ impl App {
                 "/api/v1/compiler/compile",
                 post(super::external::post_compile),
             )
+            .route(
+                "/api/v1/console/output",
+                post(super::internal::post_console_output),
+            )
+            .route(
+                "/api/v1/console/input",
+                post(super::internal::post_console_input),
+            )
+            .route(
+                "/api/v1/core/backtrace",
+                post(super::internal::post_backtrace),
+            )
+            .route(
+                "/api/v1/core/commit-log",
+                post(super::internal::post_commit_log),
+            )
+            .route(
+                "/api/v1/core/commit-log/start",
+                post(super::internal::post_commit_log_start),
+            )
+            .route(
+                "/api/v1/core/commit-log/stop",
+                post(super::internal::post_commit_log_stop),
+            )
+            .route(
+                "/api/v1/core/lockstep",
+                post(super::internal::post_lockstep),
+            )
+            .route(
+                "/api/v1/core/coverage",
+                post(super::internal::post_coverage),
+            )
+            .route(
+                "/api/v1/core/coverage/start",
+                post(super::internal::post_coverage_start),
+            )
+            .route(
+                "/api/v1/core/coverage/stop",
+                post(super::internal::post_coverage_stop),
+            )
             .route("/api/v1/core/memory", post(super::internal::post_memory))
+            .route(
+                "/api/v1/core/memory-map",
+                post(super::internal::post_memory_map),
+            )
+            .route(
+                "/api/v1/core/memory-usage",
+                post(super::internal::post_memory_usage),
+            )
+            .route("/api/v1/core/profile", post(super::internal::post_profile))
+            .route(
+                "/api/v1/core/profile/start",
+                post(super::internal::post_profile_start),
+            )
+            .route(
+                "/api/v1/core/profile/stop",
+                post(super::internal::post_profile_stop),
+            )
             .route(
                 "/api/v1/core/registers",
                 post(super::internal::post_registers),
             )
+            .route("/api/v1/core/source", post(super::internal::post_source))
+            .route(
+                "/api/v1/core/source/step",
+                post(super::internal::post_source_step),
+            )
+            .route(
+                "/api/v1/core/source/locals",
+                post(super::internal::post_source_locals),
+            )
+            .route(
+                "/api/v1/core/source/backtrace",
+                post(super::internal::post_source_backtrace),
+            )
+            .route("/api/v1/core/machine", post(super::internal::post_machine))
+            .route("/api/v1/core/fdt", post(super::internal::post_fdt))
             .route("/api/v1/core/run", post(super::internal::post_run))
             .route("/api/v1/core/step", post(super::internal::post_step))
+            .route("/api/v1/core/step-n", post(super::internal::post_step_n))
+            .route(
+                "/api/v1/core/step-over",
+                post(super::internal::post_step_over),
+            )
+            .route(
+                "/api/v1/core/step-out",
+                post(super::internal::post_step_out),
+            )
+            .route(
+                "/api/v1/core/run-until",
+                post(super::internal::post_run_until),
+            )
+            .route(
+                "/api/v1/core/step-back",
+                post(super::internal::post_step_back),
+            )
+            .route(
+                "/api/v1/core/reverse-continue",
+                post(super::internal::post_reverse_continue),
+            )
+            .route("/api/v1/core/goto", post(super::internal::post_goto))
+            .route(
+                "/api/v1/core/breakpoints",
+                post(super::internal::post_breakpoints),
+            )
             .route("/api/v1/core/restart", post(super::internal::post_restart))
             .route("/api/v1/core/stop", post(super::internal::post_stop))
+            .route(
+                "/api/v1/core/snapshot/save",
+                post(super::internal::post_snapshot_save),
+            )
+            .route(
+                "/api/v1/core/snapshot/restore",
+                post(super::internal::post_snapshot_restore),
+            )
             .layer(cors)
             .layer(Extension(cpu));
 
@@ -46,6 +154,7 @@ impl App {
             .unwrap();
         println!("listening on {}", listener.local_addr().unwrap());
         axum::serve(listener, app).await.unwrap();
+        Ok(())
     }
 
     async fn handler() -> Html<&'static str> {
@@ -1,14 +1,60 @@
-use std::{fs::File, io::Read, sync::Arc};
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
 
-use axum::{Extension, Json};
+use axum::{http::header, response::IntoResponse, Extension, Json};
 use tokio::sync::Mutex;
 
 use crate::{
-    core::param::DRAM_BASE,
-    model::{MemoryRangePayload, MemoryValueResponse, RegisterValueResponse, StepResponse},
+    core::{
+        fdt,
+        param::{API_FILES_DIR, STEP_BUDGET},
+        MachineConfig, Snapshot, StopReason,
+    },
+    model::{
+        BacktraceResponse, BreakpointsPayload, CommitLogPayload, CommitLogResponse,
+        CommitLogStartPayload, ConsoleInputPayload, ConsoleOutputPayload, ConsoleOutputResponse,
+        CoverageResponse, FrameResponse, FunctionCoverageResponse, GotoPayload, LocalResponse,
+        LockstepPayload, MemoryRangePayload, MemoryRegionResponse, MemoryUsageResponse,
+        MemoryValueResponse, MismatchResponse, ProfilePayload, RegisterValueResponse,
+        RunUntilPayload, SnapshotPayload, SourceFrameResponse, SourceLineResponse,
+        StepCountPayload, StepResponse,
+    },
     Cpu,
 };
 
+/// Where a file named in a request lives: under `API_FILES_DIR`, which is
+/// created on first use. The API is open to any web origin, so absolute
+/// paths and `..` are refused.
+fn api_file(path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+    let plain = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if path.is_empty() || !plain {
+        return Err(format!("{} is not a relative path without ..", path));
+    }
+    fs::create_dir_all(API_FILES_DIR).map_err(|e| format!("{}: {}", API_FILES_DIR, e))?;
+    Ok(Path::new(API_FILES_DIR).join(relative))
+}
+
+/// Move every host path in `config` under `API_FILES_DIR`, as for any
+/// other file named in a request. `.` stands for the directory itself, as
+/// the root of a Linux program.
+fn confine(config: &mut MachineConfig) -> Result<(), String> {
+    for path in config.host_paths_mut() {
+        *path = match path.to_str() {
+            Some(".") => PathBuf::from(API_FILES_DIR),
+            Some(name) => api_file(name)?,
+            None => return Err(format!("{} is not valid UTF-8", path.display())),
+        };
+    }
+    Ok(())
+}
+
 pub async fn post_memory(
     Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
     Json(payload): Json<MemoryRangePayload>,
@@ -18,6 +64,176 @@ pub async fn post_memory(
     Json(cpu.read_memory_range(payload.begin, payload.end))
 }
 
+pub async fn post_memory_usage(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+) -> Json<MemoryUsageResponse> {
+    let cpu = cpu.lock().await;
+
+    Json(MemoryUsageResponse::new(
+        cpu.config.dram_size,
+        cpu.bus.dram().resident_size(),
+    ))
+}
+
+pub async fn post_memory_map(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+) -> Json<Vec<MemoryRegionResponse>> {
+    let cpu = cpu.lock().await;
+
+    Json(
+        cpu.bus
+            .memory_map()
+            .into_iter()
+            .map(|(name, base, size)| MemoryRegionResponse::new(name, base, size))
+            .collect(),
+    )
+}
+
+pub async fn post_console_output(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<ConsoleOutputPayload>,
+) -> Json<ConsoleOutputResponse> {
+    let cpu = cpu.lock().await;
+
+    let (offset, data) = cpu.console.output_since(payload.offset);
+    Json(ConsoleOutputResponse::new(
+        offset,
+        String::from_utf8_lossy(&data).into_owned(),
+    ))
+}
+
+pub async fn post_console_input(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<ConsoleInputPayload>,
+) -> Json<Vec<String>> {
+    let cpu = cpu.lock().await;
+
+    cpu.console.push_input(payload.data.as_bytes());
+    Json(vec![format!("{} byte(s) queued.", payload.data.len())])
+}
+
+pub async fn post_commit_log(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<CommitLogPayload>,
+) -> Json<CommitLogResponse> {
+    let cpu = cpu.lock().await;
+
+    let (offset, lines) = match &cpu.commit_log {
+        Some(log) => log.lines_since(payload.offset),
+        None => (payload.offset, Vec::new()),
+    };
+    Json(CommitLogResponse::new(offset, lines))
+}
+
+pub async fn post_commit_log_start(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<CommitLogStartPayload>,
+) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    let file = match payload.path.as_deref().map(api_file).transpose() {
+        Ok(file) => file,
+        Err(e) => return Json(vec![format!("Failed to start the commit log: {}.", e)]),
+    };
+    match (cpu.start_commit_log(file.as_deref()), &payload.path) {
+        (Ok(()), Some(path)) => Json(vec![format!("Logging commits to {}.", path)]),
+        (Ok(()), None) => Json(vec!["Logging commits.".to_string()]),
+        (Err(e), _) => Json(vec![format!("Failed to start the commit log: {}.", e)]),
+    }
+}
+
+pub async fn post_commit_log_stop(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    match cpu.stop_commit_log() {
+        Ok(()) => Json(vec!["Commit log stopped.".to_string()]),
+        Err(e) => Json(vec![format!("Failed to flush the commit log: {}.", e)]),
+    }
+}
+
+pub async fn post_lockstep(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<LockstepPayload>,
+) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    match api_file(&payload.path).and_then(|file| cpu.start_lockstep(&file)) {
+        Ok(()) => Json(vec![format!("Checking against {}.", payload.path)]),
+        Err(e) => Json(vec![format!("Failed to open the reference: {}.", e)]),
+    }
+}
+
+pub async fn post_profile_start(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    cpu.start_profile();
+    Json(vec!["Profiling started.".into()])
+}
+
+pub async fn post_profile_stop(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    cpu.stop_profile();
+    Json(vec!["Profiling stopped.".into()])
+}
+
+pub async fn post_profile(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<ProfilePayload>,
+) -> Json<Vec<String>> {
+    let cpu = cpu.lock().await;
+
+    let report = match (payload.format.as_str(), &payload.function) {
+        ("flat", _) => cpu.profile_flat(),
+        ("annotate", Some(function)) => cpu.profile_annotate(function),
+        ("annotate", None) => Err("annotate needs a function".to_string()),
+        ("folded", _) => cpu.profile_folded(),
+        (format, _) => Err(format!("unknown profile format {}", format)),
+    };
+    match report {
+        Ok(report) => Json(report.lines().map(String::from).collect()),
+        Err(e) => Json(vec![format!("Failed to export the profile: {}.", e)]),
+    }
+}
+
+pub async fn post_coverage_start(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    cpu.start_coverage();
+    Json(vec!["Coverage started.".into()])
+}
+
+pub async fn post_coverage_stop(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    cpu.stop_coverage();
+    Json(vec!["Coverage stopped.".into()])
+}
+
+pub async fn post_coverage(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+) -> Result<Json<CoverageResponse>, Json<Vec<String>>> {
+    let cpu = cpu.lock().await;
+
+    let failed = |e| Json(vec![format!("Failed to export coverage: {}.", e)]);
+    let lcov = cpu.coverage_lcov().map_err(failed)?;
+    let functions = cpu.coverage_functions().map_err(failed)?;
+    Ok(Json(CoverageResponse::new(
+        lcov,
+        functions
+            .into_iter()
+            .map(|f| FunctionCoverageResponse {
+                name: f.name,
+                file: f.file,
+                line: f.line,
+                calls: f.calls,
+                lines: f.lines,
+                hit: f.hit,
+            })
+            .collect(),
+    )))
+}
+
 pub async fn post_registers(
     Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
 ) -> Json<Vec<RegisterValueResponse>> {
@@ -29,15 +245,8 @@ pub async fn post_registers(
 pub async fn post_step(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<StepResponse> {
     let mut cpu = cpu.lock().await;
 
-    let insn = match cpu.fetch() {
-        Ok(inst) => inst,
-        _ => 0xffffffff,
-    };
-
-    match cpu.execute(insn as u32) {
-        Ok(new_pc) => cpu.pc = new_pc,
-        _ => (),
-    };
+    let insn = cpu.fetch().unwrap_or(0xffffffff);
+    let _ = cpu.step();
 
     cpu.running = false;
 
@@ -48,6 +257,200 @@ pub async fn post_step(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<StepR
     ))
 }
 
+pub async fn post_step_n(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<StepCountPayload>,
+) -> Json<StepResponse> {
+    let mut cpu = cpu.lock().await;
+
+    let reason = cpu.step_n(payload.count.min(STEP_BUDGET));
+    Json(stopped(&mut cpu, reason))
+}
+
+pub async fn post_step_over(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<StepResponse> {
+    let mut cpu = cpu.lock().await;
+
+    let reason = cpu.step_over(STEP_BUDGET);
+    Json(stopped(&mut cpu, reason))
+}
+
+pub async fn post_step_out(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<StepResponse> {
+    let mut cpu = cpu.lock().await;
+
+    let reason = cpu.step_out(STEP_BUDGET);
+    Json(stopped(&mut cpu, reason))
+}
+
+pub async fn post_run_until(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<RunUntilPayload>,
+) -> Json<StepResponse> {
+    let mut cpu = cpu.lock().await;
+
+    let target = match (payload.address, payload.symbol) {
+        (Some(address), _) => Some(address),
+        (None, Some(symbol)) => match cpu.symbol_address(&symbol) {
+            Some(address) => Some(address),
+            None => {
+                return Json(StepResponse::new(
+                    cpu.pc,
+                    cpu.fetch().unwrap_or(0xffffffff) as u32,
+                    format!("Symbol not found: {}.", symbol),
+                ))
+            }
+        },
+        (None, None) => None,
+    };
+
+    let budget = payload.budget.unwrap_or(STEP_BUDGET).min(STEP_BUDGET);
+    let reason = cpu.run_until(target, budget);
+    Json(stopped(&mut cpu, reason))
+}
+
+pub async fn post_step_back(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<StepResponse> {
+    let mut cpu = cpu.lock().await;
+
+    let reason = cpu.step_back();
+    Json(stopped(&mut cpu, reason))
+}
+
+pub async fn post_reverse_continue(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+) -> Json<StepResponse> {
+    let mut cpu = cpu.lock().await;
+
+    let reason = cpu.reverse_continue();
+    Json(stopped(&mut cpu, reason))
+}
+
+pub async fn post_goto(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<GotoPayload>,
+) -> Json<StepResponse> {
+    let mut cpu = cpu.lock().await;
+
+    let reason = cpu.goto(payload.instret, STEP_BUDGET);
+    Json(stopped(&mut cpu, reason))
+}
+
+pub async fn post_breakpoints(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<BreakpointsPayload>,
+) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    cpu.breakpoints = payload.addresses;
+    Json(vec![format!(
+        "{} breakpoint(s) set.",
+        cpu.breakpoints.len()
+    )])
+}
+
+pub async fn post_backtrace(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<BacktraceResponse> {
+    let cpu = cpu.lock().await;
+
+    Json(BacktraceResponse {
+        frames: cpu
+            .backtrace()
+            .into_iter()
+            .map(|frame| FrameResponse {
+                pc: frame.pc,
+                symbol: frame.symbol,
+                sp: frame.sp,
+                return_address: frame.return_address,
+            })
+            .collect(),
+        mismatches: cpu
+            .call_stack
+            .mismatches()
+            .map(|m| MismatchResponse {
+                pc: m.pc,
+                expected: m.expected,
+                actual: m.actual,
+            })
+            .collect(),
+        stale: cpu.call_stack.stale(),
+    })
+}
+
+pub async fn post_source(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+) -> Result<Json<SourceLineResponse>, Json<Vec<String>>> {
+    let cpu = cpu.lock().await;
+
+    let line = cpu
+        .source_line()
+        .map_err(|e| Json(vec![format!("Failed to find the source line: {}.", e)]))?;
+    Ok(Json(SourceLineResponse {
+        pc: cpu.pc,
+        file: line.file,
+        line: line.line,
+        function: line.function,
+    }))
+}
+
+pub async fn post_source_step(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+) -> Result<Json<StepResponse>, Json<Vec<String>>> {
+    let mut cpu = cpu.lock().await;
+
+    let reason = cpu
+        .step_line(STEP_BUDGET)
+        .map_err(|e| Json(vec![format!("Failed to step by line: {}.", e)]))?;
+    Ok(Json(stopped(&mut cpu, reason)))
+}
+
+pub async fn post_source_locals(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+) -> Result<Json<Vec<LocalResponse>>, Json<Vec<String>>> {
+    let cpu = cpu.lock().await;
+
+    let locals = cpu
+        .locals()
+        .map_err(|e| Json(vec![format!("Failed to list locals: {}.", e)]))?;
+    Ok(Json(
+        locals
+            .into_iter()
+            .map(|local| LocalResponse {
+                name: local.name,
+                parameter: local.parameter,
+                type_name: local.type_name,
+                location: local.location,
+                address: local.address,
+                value: local.value,
+            })
+            .collect(),
+    ))
+}
+
+pub async fn post_source_backtrace(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+) -> Result<Json<Vec<SourceFrameResponse>>, Json<Vec<String>>> {
+    let cpu = cpu.lock().await;
+
+    let frames = cpu
+        .source_backtrace()
+        .map_err(|e| Json(vec![format!("Failed to unwind the stack: {}.", e)]))?;
+    Ok(Json(
+        frames
+            .into_iter()
+            .map(|frame| SourceFrameResponse {
+                pc: frame.pc,
+                sp: frame.sp,
+                function: frame.function,
+                file: frame.file,
+                line: frame.line,
+            })
+            .collect(),
+    ))
+}
+
+fn stopped(cpu: &mut Cpu, reason: StopReason) -> StepResponse {
+    cpu.running = false;
+    let insn = cpu.fetch().unwrap_or(0xffffffff);
+    StepResponse::new(cpu.pc, insn as u32, reason.to_string())
+}
+
 pub async fn post_run(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<String>> {
     let mut cpu = cpu.lock().await;
 
@@ -59,8 +462,15 @@ pub async fn post_run(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<St
     let mut code = Vec::new();
     file.read_to_end(&mut code).unwrap();
 
-    cpu.bus.replace(code);
-    cpu.pc = DRAM_BASE;
+    if let Err(e) = cpu.bus.replace(code) {
+        return Json(vec![format!("Failed to load the payload: {}.", e)]);
+    }
+    cpu.bus.reset();
+    if let Err(e) = cpu.reset() {
+        return Json(vec![format!("Failed to reset the target: {}.", e)]);
+    }
+    // symbols, when the payload was compiled here
+    let _ = cpu.load_symbols(Path::new("/tmp/risque-temp/payload.elf"));
     cpu.running = true;
     Json(vec!["Target started to run.".into()])
 }
@@ -73,7 +483,128 @@ pub async fn post_stop(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<S
 
 pub async fn post_restart(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> Json<Vec<String>> {
     let mut cpu = cpu.lock().await;
-    cpu.pc = DRAM_BASE;
     cpu.running = false;
-    Json(vec![format!("Target pc reseted to 0x{:016x}.", DRAM_BASE)])
+
+    if let Some(snapshot) = cpu.boot_snapshot.clone() {
+        if cpu.restore(&snapshot).is_ok() {
+            return Json(vec!["Target restored to its state after loading.".into()]);
+        }
+    }
+
+    if let Err(e) = cpu.reset() {
+        return Json(vec![format!("Failed to reset the target: {}.", e)]);
+    }
+    Json(vec![format!("Target pc reseted to 0x{:016x}.", cpu.pc)])
+}
+
+pub async fn post_machine(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(mut payload): Json<MachineConfig>,
+) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    if let Err(e) = confine(&mut payload).and_then(|_| payload.validate()) {
+        return Json(vec![format!("Invalid machine configuration: {}.", e)]);
+    }
+    // the current machine stays in place unless the new one comes up
+    match Cpu::new(payload, Vec::new()) {
+        Ok(machine) => {
+            *cpu = machine;
+            Json(vec!["Machine created.".into()])
+        }
+        Err(e) => Json(vec![format!("Failed to create the machine: {}.", e)]),
+    }
+}
+
+pub async fn post_fdt(Extension(cpu): Extension<Arc<Mutex<Cpu>>>) -> impl IntoResponse {
+    let cpu = cpu.lock().await;
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"risque.dtb\"",
+            ),
+        ],
+        fdt::build(&cpu.config),
+    )
+}
+
+pub async fn post_snapshot_save(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<SnapshotPayload>,
+) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    let snapshot = cpu.snapshot();
+    let mut messages = Vec::new();
+    if let Some(path) = &payload.path {
+        match api_file(path).and_then(|file| snapshot.save(file).map_err(|e| e.to_string())) {
+            Ok(()) => messages.push(format!("Snapshot written to {}.", path)),
+            Err(e) => messages.push(format!("Failed to write {}: {}.", path, e)),
+        }
+    }
+    messages.push(format!("Snapshot {} saved.", payload.name));
+    cpu.snapshots.insert(payload.name, snapshot);
+    Json(messages)
+}
+
+pub async fn post_snapshot_restore(
+    Extension(cpu): Extension<Arc<Mutex<Cpu>>>,
+    Json(payload): Json<SnapshotPayload>,
+) -> Json<Vec<String>> {
+    let mut cpu = cpu.lock().await;
+
+    if let Some(path) = &payload.path {
+        match api_file(path).and_then(|file| Snapshot::load(file).map_err(|e| e.to_string())) {
+            Ok(snapshot) => {
+                cpu.snapshots.insert(payload.name.clone(), snapshot);
+            }
+            Err(e) => return Json(vec![format!("Failed to read {}: {}.", path, e)]),
+        }
+    }
+
+    let Some(snapshot) = cpu.snapshots.get(&payload.name).cloned() else {
+        return Json(vec![format!("No snapshot named {}.", payload.name)]);
+    };
+    if let Err(e) = cpu.restore(&snapshot) {
+        return Json(vec![format!("Cannot restore {}: {}.", payload.name, e)]);
+    }
+    cpu.running = false;
+    Json(vec![format!("Snapshot {} restored.", payload.name)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{LinuxProgram, Personality};
+
+    #[test]
+    fn test_api_files_stay_in_their_directory() {
+        for path in ["", "/etc/passwd", "../x", "a/../../x", "./x"] {
+            assert!(api_file(path).is_err(), "{} accepted", path);
+        }
+        let linux = |program: &str| MachineConfig {
+            personality: Personality::Linux(LinuxProgram {
+                program: program.into(),
+                args: Vec::new(),
+                env: Vec::new(),
+                root: ".".into(),
+            }),
+            ..MachineConfig::default()
+        };
+        assert!(confine(&mut linux("/bin/sh")).is_err());
+        let mut config = linux("bin/sh");
+        confine(&mut config).unwrap();
+        let Personality::Linux(linux) = &config.personality else {
+            unreachable!();
+        };
+        assert_eq!(linux.program, Path::new(API_FILES_DIR).join("bin/sh"));
+        assert_eq!(linux.root, Path::new(API_FILES_DIR));
+        assert_eq!(
+            api_file("runs/a.snap").unwrap(),
+            Path::new(API_FILES_DIR).join("runs/a.snap")
+        );
+    }
 }
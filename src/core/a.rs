@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::kit::bits::*;
+use crate::kit::insn::*;
+use crate::vdepart;
+
+use super::{
+    cpu::Cpu,
+    except::Exception,
+    isa::{install, IsaDefine},
+    mmu::Access,
+};
+
+// funct5, funct3 and opcode; aq/rl are ignored on a single hart
+const AMO_MASK: u32 = 0xf800707f;
+// lr additionally requires rs2 = 0
+const LR_MASK: u32 = 0xf9f0707f;
+
+fn ident(funct5: u32, width: u64) -> u32 {
+    let funct3 = if width == 32 { 2 } else { 3 };
+    funct5 << 27 | funct3 << 12 | 0x2f
+}
+
+/// Sign-extend the low word, or keep the whole doubleword.
+fn extend(value: u64, width: u64) -> u64 {
+    if width == 32 {
+        sext(zext(value, 32), 32)
+    } else {
+        value
+    }
+}
+
+/// Address in rs1, which must be naturally aligned.
+fn address(cpu: &Cpu, rs1: u32, width: u64, store: bool) -> Result<u64, Exception> {
+    let addr = cpu.rgpr(rs1);
+    if !addr.is_multiple_of(width / 8) {
+        return Err(if store {
+            Exception::StoreAMOAddressMisaligned(addr)
+        } else {
+            Exception::LoadAddressMisaligned(addr)
+        });
+    }
+    Ok(addr)
+}
+
+fn lr(mnemonic: &'static str, width: u64) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            let r = vdepart!(insn, InsnType::R);
+            let addr = address(cpu, r.rs1, width, false)?;
+            let value = cpu.load(addr, width)?;
+            cpu.reservation = Some(addr);
+            *cpu.wgpr(r.rd) = extend(value, width);
+            Ok(0)
+        })),
+        mnemonic,
+        ident(0x02, width),
+        InsnType::R,
+    )
+    .masked(LR_MASK)
+}
+
+fn sc(mnemonic: &'static str, width: u64) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            let r = vdepart!(insn, InsnType::R);
+            let addr = address(cpu, r.rs1, width, true)?;
+            let failed = cpu.reservation.take() != Some(addr);
+            if !failed {
+                cpu.store(addr, width, cpu.rgpr(r.rs2))?;
+            }
+            *cpu.wgpr(r.rd) = failed as u64;
+            Ok(0)
+        })),
+        mnemonic,
+        ident(0x03, width),
+        InsnType::R,
+    )
+    .masked(AMO_MASK)
+}
+
+/// Read-modify-write: rd gets the old value, memory gets `op(old, rs2)`.
+fn amo(mnemonic: &'static str, funct5: u32, width: u64, op: fn(u64, u64) -> u64) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            let r = vdepart!(insn, InsnType::R);
+            let addr = address(cpu, r.rs1, width, true)?;
+            // AMOs fault as stores even on the read
+            cpu.translate(addr, Access::Store)?;
+            let old = cpu
+                .load(addr, width)
+                .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+            let old = extend(old, width);
+            let src = extend(cpu.rgpr(r.rs2), width);
+            cpu.store(addr, width, op(old, src))?;
+            *cpu.wgpr(r.rd) = old;
+            Ok(0)
+        })),
+        mnemonic,
+        ident(funct5, width),
+        InsnType::R,
+    )
+    .masked(AMO_MASK)
+}
+
+fn min(a: u64, b: u64) -> u64 {
+    (a as i64).min(b as i64) as u64
+}
+
+fn max(a: u64, b: u64) -> u64 {
+    (a as i64).max(b as i64) as u64
+}
+
+// unsigned word comparisons look at the low 32 bits only
+fn min_word(a: u64, b: u64) -> u64 {
+    extend((a as u32).min(b as u32) as u64, 32)
+}
+
+fn max_word(a: u64, b: u64) -> u64 {
+    extend((a as u32).max(b as u32) as u64, 32)
+}
+
+pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
+    install(map, lr("lr.w", 32));
+    install(map, sc("sc.w", 32));
+    install(map, amo("amoswap.w", 0x01, 32, |_, b| b));
+    install(map, amo("amoadd.w", 0x00, 32, |a, b| a.wrapping_add(b)));
+    install(map, amo("amoxor.w", 0x04, 32, |a, b| a ^ b));
+    install(map, amo("amoand.w", 0x0c, 32, |a, b| a & b));
+    install(map, amo("amoor.w", 0x08, 32, |a, b| a | b));
+    install(map, amo("amomin.w", 0x10, 32, min));
+    install(map, amo("amomax.w", 0x14, 32, max));
+    install(map, amo("amominu.w", 0x18, 32, min_word));
+    install(map, amo("amomaxu.w", 0x1c, 32, max_word));
+
+    install(map, lr("lr.d", 64));
+    install(map, sc("sc.d", 64));
+    install(map, amo("amoswap.d", 0x01, 64, |_, b| b));
+    install(map, amo("amoadd.d", 0x00, 64, |a, b| a.wrapping_add(b)));
+    install(map, amo("amoxor.d", 0x04, 64, |a, b| a ^ b));
+    install(map, amo("amoand.d", 0x0c, 64, |a, b| a & b));
+    install(map, amo("amoor.d", 0x08, 64, |a, b| a | b));
+    install(map, amo("amomin.d", 0x10, 64, min));
+    install(map, amo("amomax.d", 0x14, 64, max));
+    install(map, amo("amominu.d", 0x18, 64, |a, b| a.min(b)));
+    install(map, amo("amomaxu.d", 0x1c, 64, |a, b| a.max(b)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::{Extension, MachineConfig};
+
+    const A0: usize = 10;
+    const A1: usize = 11;
+    const A2: usize = 12;
+    const A3: usize = 13;
+    const ADDR: u64 = 0x1000;
+
+    fn machine() -> Cpu {
+        let config = MachineConfig::builder()
+            .extensions(&[Extension::I, Extension::A])
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::with_code(config, &[]);
+        cpu.regs[A0] = ADDR;
+        cpu
+    }
+
+    /// Execute `insn`, an `amo a2, a1, (a0)`, on the word -16 with `src` in
+    /// a1, returning a2 and the word left in memory.
+    fn amo_word(insn: u32, src: u64) -> (u64, u64) {
+        let mut cpu = machine();
+        cpu.store(ADDR, 32, 0xffff_fff0).unwrap();
+        cpu.regs[A1] = src;
+        cpu.execute(insn).unwrap();
+        (cpu.regs[A2], cpu.load(ADDR, 32).unwrap())
+    }
+
+    #[test]
+    fn test_word_min_max() {
+        // the upper half of rs2 plays no part
+        let src = 0xffff_ffff_0000_0005;
+        let old = 0xffff_ffff_ffff_fff0;
+        assert_eq!(amo_word(0x80b5262f, src), (old, 0xffff_fff0)); // amomin.w
+        assert_eq!(amo_word(0xa0b5262f, src), (old, 5)); // amomax.w
+        assert_eq!(amo_word(0xc0b5262f, src), (old, 5)); // amominu.w
+        assert_eq!(amo_word(0xe0b5262f, src), (old, 0xffff_fff0)); // amomaxu.w
+    }
+
+    #[test]
+    fn test_store_conditional_needs_the_reservation() {
+        let (lr, sc, sc_a2) = (0x1005262f, 0x18b526af, 0x18b626af);
+        let mut cpu = machine();
+        cpu.store(ADDR, 32, 1).unwrap();
+        cpu.regs[A1] = 2;
+
+        cpu.execute(lr).unwrap();
+        assert_eq!(cpu.regs[A2], 1);
+        cpu.execute(sc).unwrap();
+        assert_eq!((cpu.regs[A3], cpu.load(ADDR, 32).unwrap()), (0, 2));
+
+        // the reservation is used up by the first sc
+        cpu.regs[A1] = 3;
+        cpu.execute(sc).unwrap();
+        assert_eq!((cpu.regs[A3], cpu.load(ADDR, 32).unwrap()), (1, 2));
+
+        // an sc elsewhere fails and still clears it
+        cpu.execute(lr).unwrap();
+        cpu.regs[A2] = ADDR + 8;
+        cpu.execute(sc_a2).unwrap();
+        assert_eq!(cpu.regs[A3], 1);
+        cpu.execute(sc).unwrap();
+        assert_eq!((cpu.regs[A3], cpu.load(ADDR, 32).unwrap()), (1, 2));
+
+        cpu.regs[A0] = ADDR + 2;
+        assert_eq!(
+            cpu.execute(lr),
+            Err(Exception::LoadAddressMisaligned(ADDR + 2))
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::fs;
+use std::path::Path;
+
+use super::{cpu::Cpu, elf::Elf, htif::Htif};
+
+impl Cpu {
+    /// Copy the configured firmware, kernel and initrd into DRAM.
+    pub fn load_boot_images(&mut self) -> Result<(), String> {
+        for (name, image) in self.config.boot.images() {
+            let data = fs::read(&image.path)
+                .map_err(|e| format!("{} {}: {}", name, image.path.display(), e))?;
+            self.bus.write_dram(image.addr, &data).map_err(|e| {
+                format!(
+                    "{} {}: cannot place at 0x{:x}: {:?}",
+                    name,
+                    image.path.display(),
+                    image.addr,
+                    e
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Copy the loadable segments of the executable at `path` into DRAM.
+    pub(super) fn load_elf(&mut self, path: &Path) -> Result<Elf, String> {
+        let name = path.display();
+        let data = fs::read(path).map_err(|e| format!("{}: {}", name, e))?;
+        let elf = Elf::parse(&data).map_err(|e| format!("{}: {}", name, e))?;
+        for segment in &elf.segments {
+            // checked before the zero fill is allocated
+            let outside = || {
+                format!(
+                    "{}: segment at 0x{:x} lies outside DRAM",
+                    name, segment.vaddr
+                )
+            };
+            let dram = self.bus.dram();
+            if dram.range(segment.vaddr, segment.memsz as usize).is_none() {
+                return Err(outside());
+            }
+            let start = segment.offset as usize;
+            let mut bytes = data[start..start + segment.filesz as usize].to_vec();
+            bytes.resize(segment.memsz as usize, 0);
+            self.bus
+                .write_dram(segment.vaddr, &bytes)
+                .map_err(|_| outside())?;
+        }
+        self.elf = Some(elf.clone());
+        Ok(elf)
+    }
+
+    /// Take the symbols of the executable at `path` without loading it, for
+    /// a program placed in memory some other way.
+    pub fn load_symbols(&mut self, path: &Path) -> Result<(), String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.elf = Some(Elf::parse(&data).map_err(|e| format!("{}: {}", path.display(), e))?);
+        Ok(())
+    }
+
+    /// Load the configured bare-metal program, if any, and start at its
+    /// entry point, with HTIF on when it has a `tohost` symbol.
+    pub fn load_program(&mut self) -> Result<(), String> {
+        let Some(path) = self.config.boot.program.clone() else {
+            return Ok(());
+        };
+        let elf = self.load_elf(&path)?;
+        self.pc = elf.entry;
+        self.htif = elf.symbol("tohost").map(|tohost| Htif {
+            tohost: tohost.addr,
+            fromhost: elf.symbol("fromhost").map(|fromhost| fromhost.addr),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::MachineConfig;
+    use std::env;
+
+    #[test]
+    fn test_missing_image_is_reported() {
+        let path = env::temp_dir().join(format!("risque-firmware-{}.bin", std::process::id()));
+        std::fs::write(&path, [0x13, 0, 0, 0]).unwrap();
+        let config = MachineConfig::builder().firmware(&path, 0).build().unwrap();
+        // gone between validation and loading
+        std::fs::remove_file(&path).unwrap();
+        assert!(Cpu::new(config, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_firmware_is_handed_the_device_tree() {
+        // prints Y on the UART if hart 0 finds the FDT magic at a1, else N
+        let firmware: [u32; 10] = [
+            0x0005a283, // lw t0, 0(a1)
+            0x10000337, // lui t1, 0x10000
+            0x04e00393, // li t2, 'N'
+            0x00051a63, // bnez a0, 1f
+            0xedfe1e37, // lui t3, 0xedfe1
+            0xdd0e0e1b, // addiw t3, t3, -560 (0xd00dfeed read little-endian)
+            0x01c29463, // bne t0, t3, 1f
+            0x05900393, // li t2, 'Y'
+            0x00730023, // 1: sb t2, 0(t1)
+            0x0000006f, // j .
+        ];
+        let path = env::temp_dir().join(format!("risque-dtb-{}.bin", std::process::id()));
+        let bytes: Vec<u8> = firmware
+            .iter()
+            .flat_map(|insn| insn.to_le_bytes())
+            .collect();
+        std::fs::write(&path, bytes).unwrap();
+        let config = MachineConfig::virt()
+            .firmware(&path, 0x8000_0000)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(config, Vec::new()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        cpu.trace = false;
+
+        cpu.step_n(firmware.len() as u64);
+        assert_eq!(cpu.console.output_since(0).1, b"Y");
+    }
+
+    /// Boot OpenSBI, Linux and a rootfs to a shell prompt. The images are
+    /// large and not part of the tree, so this only runs when asked for:
+    ///
+    ///     RISQUE_OPENSBI=fw_jump.bin RISQUE_KERNEL=Image \
+    ///     RISQUE_ROOTFS=rootfs.cpio cargo test --release -- --ignored
+    #[test]
+    #[ignore]
+    fn test_boots_linux_to_shell() {
+        let image = |var: &str| env::var(var).unwrap_or_else(|_| panic!("{} is not set", var));
+        let config = MachineConfig::virt()
+            .firmware(image("RISQUE_OPENSBI"), 0x8000_0000)
+            .kernel(image("RISQUE_KERNEL"), 0x8020_0000)
+            .initrd(image("RISQUE_ROOTFS"), 0x8800_0000)
+            .bootargs("console=ttyS0 earlycon rdinit=/bin/sh")
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(config, Vec::new()).unwrap();
+        cpu.trace = false;
+
+        let mut offset = 0;
+        let mut output = Vec::new();
+        for _ in 0..2_000_000 {
+            for _ in 0..1000 {
+                if let Err(e) = cpu.step() {
+                    panic!(
+                        "unhandled {:?} at 0x{:x}\n{}",
+                        e,
+                        cpu.pc,
+                        String::from_utf8_lossy(&output)
+                    );
+                }
+            }
+            let (end, bytes) = cpu.console.output_since(offset);
+            offset = end;
+            output.extend(bytes);
+            if output.ends_with(b"# ") {
+                return;
+            }
+        }
+        panic!("no shell prompt:\n{}", String::from_utf8_lossy(&output));
+    }
+}
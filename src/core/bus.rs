@@ -1,34 +1,325 @@
+use super::config::MachineConfig;
+use super::console::Console;
+use super::device::{self, Device, PacketQueue};
 use super::dram::Dram;
 use super::except::Exception;
-use super::param::DRAM_BASE;
-use super::param::DRAM_END;
+
+/// A device mapped at `base..base + size`.
+struct Region {
+    name: String,
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+    /// Interrupt controller input driven by `device.interrupt()`.
+    irq: Option<u32>,
+}
+
+impl Region {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < self.size
+    }
+
+    fn overlaps(&self, base: u64, size: u64) -> bool {
+        base < self.base + self.size && self.base < base + size
+    }
+}
 
 pub struct Bus {
     dram: Dram,
+    regions: Vec<Region>,
+    // device registers accessed and DRAM written behind the MMU's back
+    side_effects: u64,
 }
 
 impl Bus {
-    pub fn new(code: Vec<u8>) -> Bus {
-        Self {
-            dram: Dram::new(code),
+    /// Build the bus for `config`, with its devices attached to `console` and
+    /// `network`.
+    pub fn new(
+        config: &MachineConfig,
+        code: Vec<u8>,
+        console: &Console,
+        network: &PacketQueue,
+    ) -> Result<Bus, String> {
+        let mut bus = Self {
+            dram: Dram::new(config.dram_base, config.dram_size, code)?,
+            regions: Vec::new(),
+            side_effects: 0,
+        };
+        for dev in &config.devices {
+            // backing files can vanish or sockets fail to bind after
+            // MachineConfig::validate looked at them
+            let device = device::create(dev, config, console, network)
+                .map_err(|e| format!("{}: {}", dev.name(), e))?;
+            bus.map(&dev.name(), dev.base(), dev.size(), device)
+                .expect("device layout is checked by MachineConfig::validate");
+            if let Some(irq) = dev.irq() {
+                bus.connect(&dev.name(), irq);
+            }
         }
+        Ok(bus)
+    }
+
+    pub fn replace(&mut self, new_code: Vec<u8>) -> Result<(), String> {
+        self.dram = Dram::new(self.dram.base(), self.dram.size(), new_code)?;
+        Ok(())
     }
 
-    pub fn replace(&mut self, new_code: Vec<u8>) {
-        self.dram = Dram::new(new_code);
+    pub fn dram(&self) -> &Dram {
+        &self.dram
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        match addr {
-            DRAM_BASE..=DRAM_END => self.dram.load(addr, size),
-            _ => Err(Exception::LoadAccessFault(addr)),
+    /// Copy `data` into DRAM, bypassing devices, e.g. to load boot images.
+    pub fn write_dram(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        self.side_effects += 1;
+        self.dram.write(addr, data)
+    }
+
+    /// Put back DRAM contents saved for step-back, bypassing devices.
+    pub fn restore_dram_word(&mut self, addr: u64, size: u64, value: u64) {
+        let _ = self.dram.store(addr, size, value);
+    }
+
+    /// How often a device register was accessed, a device wrote DRAM or
+    /// DRAM was written directly. None of these can be undone, so a change
+    /// between two points means history cannot rewind across it.
+    pub fn side_effects(&self) -> u64 {
+        self.side_effects
+    }
+
+    pub fn restore_dram(&mut self, dram: Dram) {
+        self.dram = dram;
+    }
+
+    /// Map `device` at `base..base + size`, refusing ranges that overlap DRAM
+    /// or another device.
+    pub fn map(
+        &mut self,
+        name: &str,
+        base: u64,
+        size: u64,
+        device: Box<dyn Device>,
+    ) -> Result<(), String> {
+        if size == 0 || base.checked_add(size).is_none() {
+            return Err(format!("{}: invalid range 0x{:x}+0x{:x}", name, base, size));
+        }
+        if base < self.dram.base() + self.dram.size() && self.dram.base() < base + size {
+            return Err(format!("{}: overlaps dram", name));
+        }
+        if let Some(other) = self.regions.iter().find(|r| r.overlaps(base, size)) {
+            return Err(format!("{}: overlaps {}", name, other.name));
+        }
+
+        self.regions.push(Region {
+            name: name.into(),
+            base,
+            size,
+            device,
+            irq: None,
+        });
+        self.regions.sort_by_key(|r| r.base);
+        Ok(())
+    }
+
+    /// Wire the interrupt output of the device `name` to controller input `irq`.
+    pub fn connect(&mut self, name: &str, irq: u32) {
+        if let Some(r) = self.regions.iter_mut().find(|r| r.name == name) {
+            r.irq = Some(irq);
+        }
+    }
+
+    /// All mapped regions in address order, DRAM included.
+    pub fn memory_map(&self) -> Vec<(String, u64, u64)> {
+        let mut map: Vec<_> = self
+            .regions
+            .iter()
+            .map(|r| (r.name.clone(), r.base, r.size))
+            .collect();
+        map.push(("dram".into(), self.dram.base(), self.dram.size()));
+        map.sort_by_key(|&(_, base, _)| base);
+        map
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if self.dram.contains(addr) {
+            return self.dram.load(addr, size);
+        }
+        match self.regions.iter_mut().find(|r| r.contains(addr)) {
+            Some(r) => {
+                self.side_effects += 1;
+                r.device
+                    .load(addr - r.base, size)
+                    .map_err(|_| Exception::LoadAccessFault(addr))
+            }
+            None => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        match addr {
-            DRAM_BASE..=DRAM_END => self.dram.store(addr, size, value),
-            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        if self.dram.contains(addr) {
+            return self.dram.store(addr, size, value);
+        }
+        match self.regions.iter_mut().find(|r| r.contains(addr)) {
+            Some(r) => {
+                self.side_effects += 1;
+                r.device
+                    .store(addr - r.base, size, value)
+                    .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+                r.device.dma(&mut self.dram);
+                Ok(())
+            }
+            None => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    /// Read DRAM without going through devices, so reads never have side effects.
+    pub fn peek(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if self.dram.contains(addr) {
+            return self.dram.load(addr, size);
+        }
+        Err(Exception::LoadAccessFault(addr))
+    }
+
+    /// `mip` bits asserted by all devices for `hart`.
+    pub fn mip(&self, hart: usize) -> u64 {
+        self.regions
+            .iter()
+            .fold(0, |mip, r| mip | r.device.mip(hart))
+    }
+
+    /// Advance devices by one instruction, then propagate interrupt lines
+    /// to the interrupt controllers.
+    pub fn tick(&mut self) {
+        for r in self.regions.iter_mut() {
+            r.device.tick();
+            if r.device.dma(&mut self.dram) {
+                self.side_effects += 1;
+            }
+        }
+
+        let levels: Vec<_> = self
+            .regions
+            .iter()
+            .filter_map(|r| r.irq.map(|irq| (irq, r.device.interrupt())))
+            .collect();
+        if levels.is_empty() {
+            return;
+        }
+        for r in self.regions.iter_mut() {
+            for &(irq, level) in &levels {
+                r.device.set_irq(irq, level);
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for r in self.regions.iter_mut() {
+            r.device.reset();
         }
     }
+
+    pub fn snapshot_devices(&self) -> Vec<(String, Vec<u8>)> {
+        self.regions
+            .iter()
+            .map(|r| (r.name.clone(), r.device.snapshot()))
+            .collect()
+    }
+
+    pub fn restore_devices(&mut self, states: &[(String, Vec<u8>)]) -> Result<(), String> {
+        for (name, state) in states {
+            match self.regions.iter_mut().find(|r| &r.name == name) {
+                Some(r) => r.device.restore(state)?,
+                None => return Err(format!("no device named {}", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Scratch(u64);
+
+    impl Device for Scratch {
+        fn load(&mut self, _offset: u64, _size: u64) -> Result<u64, Exception> {
+            Ok(self.0)
+        }
+
+        fn store(&mut self, _offset: u64, _size: u64, value: u64) -> Result<(), Exception> {
+            self.0 = value;
+            Ok(())
+        }
+    }
+
+    fn bus() -> Bus {
+        let config = MachineConfig::builder()
+            .dram(0x8000_0000, 0x10_0000)
+            .devices(&[])
+            .build()
+            .unwrap();
+        Bus::new(
+            &config,
+            Vec::new(),
+            &Console::default(),
+            &PacketQueue::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_map_rejects_overlap() {
+        let mut bus = bus();
+        assert!(bus
+            .map("a", 0x1000_0000, 0x100, Box::new(Scratch(0)))
+            .is_ok());
+        assert!(bus
+            .map("b", 0x1000_00f0, 0x100, Box::new(Scratch(0)))
+            .is_err());
+        assert!(bus
+            .map("c", 0x8000_1000, 0x100, Box::new(Scratch(0)))
+            .is_err());
+        assert!(bus
+            .map("d", 0x1000_0100, 0x100, Box::new(Scratch(0)))
+            .is_ok());
+
+        let names: Vec<_> = bus.memory_map().into_iter().map(|(n, _, _)| n).collect();
+        assert_eq!(names, ["a", "d", "dram"]);
+    }
+
+    #[test]
+    fn test_device_access_is_routed() {
+        let mut bus = bus();
+        bus.map("scratch", 0x1000_0000, 0x100, Box::new(Scratch(0)))
+            .unwrap();
+
+        bus.store(0x1000_0008, 32, 0x55).unwrap();
+        assert_eq!(bus.load(0x1000_0000, 32).unwrap(), 0x55);
+        assert!(bus.peek(0x1000_0000, 32).is_err());
+        assert!(bus.load(0x2000_0000, 32).is_err());
+    }
+
+    #[test]
+    fn test_uart_interrupt_reaches_plic() {
+        use crate::core::csr::SEIP;
+
+        let console = Console::default();
+        let config = MachineConfig::builder()
+            .dram(0x8000_0000, 0x10_0000)
+            .build()
+            .unwrap();
+        let mut bus = Bus::new(&config, Vec::new(), &console, &PacketQueue::default()).unwrap();
+        let (uart, plic) = (0x1000_0000, 0x0c00_0000);
+
+        // priority 1 for source 10, enabled for hart 0 S-mode
+        bus.store(plic + 4 * 10, 32, 1).unwrap();
+        bus.store(plic + 0x2080, 32, 1 << 10).unwrap();
+        bus.store(uart + 1, 8, 1).unwrap();
+
+        console.push_input(b"a");
+        bus.tick();
+        assert_eq!(bus.mip(0), SEIP);
+        assert_eq!(bus.load(plic + 0x20_1004, 32).unwrap(), 10);
+        assert_eq!(bus.mip(0), 0);
+    }
 }
@@ -0,0 +1,217 @@
+//! RVC: each 16-bit instruction is expanded to the 32-bit instruction it
+//! stands for, which then runs through the ordinary decoder.
+
+const OP_LOAD: u32 = 0x03;
+const OP_LOAD_FP: u32 = 0x07;
+const OP_IMM: u32 = 0x13;
+const OP_IMM_32: u32 = 0x1b;
+const OP_STORE: u32 = 0x23;
+const OP_STORE_FP: u32 = 0x27;
+const OP: u32 = 0x33;
+const OP_LUI: u32 = 0x37;
+const OP_32: u32 = 0x3b;
+const OP_BRANCH: u32 = 0x63;
+const OP_JALR: u32 = 0x67;
+const OP_JAL: u32 = 0x6f;
+
+const EBREAK: u32 = 0x0010_0073;
+
+const SP: u32 = 2;
+const RA: u32 = 1;
+
+fn r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+fn b(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | OP_BRANCH
+}
+
+fn j(imm: u32, rd: u32) -> u32 {
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | rd << 7
+        | OP_JAL
+}
+
+/// Bit `from` of `x` moved to bit `to`.
+fn bit(x: u32, from: u32, to: u32) -> u32 {
+    (x >> from & 1) << to
+}
+
+/// Sign-extend the low `bits` bits of `x`.
+fn sext(x: u32, bits: u32) -> u32 {
+    ((x << (32 - bits)) as i32 >> (32 - bits)) as u32
+}
+
+/// The 32-bit equivalent of a compressed instruction, or `None` for
+/// reserved and illegal encodings.
+pub fn expand(insn: u16) -> Option<u32> {
+    let x = insn as u32;
+    let funct3 = x >> 13;
+    // full and compressed (x8-x15) register fields
+    let rd = x >> 7 & 0x1f;
+    let rs2 = x >> 2 & 0x1f;
+    let rd_c = (x >> 2 & 7) + 8;
+    let rs1_c = (x >> 7 & 7) + 8;
+    // imm[5] at bit 12, imm[4:0] at bits 6:2
+    let imm6 = bit(x, 12, 5) | (x >> 2 & 0x1f);
+
+    let expanded = match (x & 3, funct3) {
+        (0, 0) => {
+            let imm = (x >> 7 & 0x30) | (x >> 1 & 0x3c0) | bit(x, 6, 2) | bit(x, 5, 3);
+            if imm == 0 {
+                return None;
+            }
+            i(imm, SP, 0, rd_c, OP_IMM)
+        }
+        (0, 1) | (0, 3) => {
+            let imm = (x >> 7 & 0x38) | (x << 1 & 0xc0);
+            let opcode = if funct3 == 1 { OP_LOAD_FP } else { OP_LOAD };
+            i(imm, rs1_c, 3, rd_c, opcode)
+        }
+        (0, 2) => {
+            let imm = (x >> 7 & 0x38) | bit(x, 6, 2) | bit(x, 5, 6);
+            i(imm, rs1_c, 2, rd_c, OP_LOAD)
+        }
+        (0, 5) | (0, 7) => {
+            let imm = (x >> 7 & 0x38) | (x << 1 & 0xc0);
+            let opcode = if funct3 == 5 { OP_STORE_FP } else { OP_STORE };
+            s(imm, rd_c, rs1_c, 3, opcode)
+        }
+        (0, 6) => {
+            let imm = (x >> 7 & 0x38) | bit(x, 6, 2) | bit(x, 5, 6);
+            s(imm, rd_c, rs1_c, 2, OP_STORE)
+        }
+        (1, 0) => i(sext(imm6, 6), rd, 0, rd, OP_IMM),
+        (1, 1) if rd != 0 => i(sext(imm6, 6), rd, 0, rd, OP_IMM_32),
+        (1, 2) => i(sext(imm6, 6), 0, 0, rd, OP_IMM),
+        (1, 3) if rd == SP => {
+            let imm = bit(x, 12, 9) | bit(x, 6, 4) | bit(x, 5, 6) | (x << 4 & 0x180) | bit(x, 2, 5);
+            if imm == 0 {
+                return None;
+            }
+            i(sext(imm, 10), SP, 0, SP, OP_IMM)
+        }
+        (1, 3) => {
+            if imm6 == 0 {
+                return None;
+            }
+            (sext(imm6, 6) & 0xfffff) << 12 | rd << 7 | OP_LUI
+        }
+        (1, 4) => {
+            let rd = rs1_c;
+            match x >> 10 & 3 {
+                0 => i(imm6, rd, 5, rd, OP_IMM),
+                1 => i(imm6 | 0x400, rd, 5, rd, OP_IMM),
+                2 => i(sext(imm6, 6), rd, 7, rd, OP_IMM),
+                _ => match (x >> 12 & 1, x >> 5 & 3) {
+                    (0, 0) => r(0x20, rd_c, rd, 0, rd, OP),
+                    (0, 1) => r(0, rd_c, rd, 4, rd, OP),
+                    (0, 2) => r(0, rd_c, rd, 6, rd, OP),
+                    (0, 3) => r(0, rd_c, rd, 7, rd, OP),
+                    (1, 0) => r(0x20, rd_c, rd, 0, rd, OP_32),
+                    (1, 1) => r(0, rd_c, rd, 0, rd, OP_32),
+                    _ => return None,
+                },
+            }
+        }
+        (1, 5) => {
+            let imm = bit(x, 12, 11)
+                | bit(x, 11, 4)
+                | (x >> 1 & 0x300)
+                | bit(x, 8, 10)
+                | bit(x, 7, 6)
+                | bit(x, 6, 7)
+                | (x >> 2 & 0xe)
+                | bit(x, 2, 5);
+            j(sext(imm, 12), 0)
+        }
+        (1, 6) | (1, 7) => {
+            let imm =
+                bit(x, 12, 8) | (x >> 7 & 0x18) | (x << 1 & 0xc0) | (x >> 2 & 6) | bit(x, 2, 5);
+            b(sext(imm, 9), 0, rs1_c, funct3 - 6)
+        }
+        (2, 0) => i(imm6, rd, 1, rd, OP_IMM),
+        (2, 1) | (2, 3) => {
+            if funct3 == 3 && rd == 0 {
+                return None;
+            }
+            let imm = bit(x, 12, 5) | (x >> 2 & 0x18) | (x << 4 & 0x1c0);
+            let opcode = if funct3 == 1 { OP_LOAD_FP } else { OP_LOAD };
+            i(imm, SP, 3, rd, opcode)
+        }
+        (2, 2) if rd != 0 => {
+            let imm = bit(x, 12, 5) | (x >> 2 & 0x1c) | (x << 4 & 0xc0);
+            i(imm, SP, 2, rd, OP_LOAD)
+        }
+        (2, 4) => match (x >> 12 & 1, rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => i(0, rd, 0, 0, OP_JALR),
+            (0, _, _) => r(0, rs2, 0, 0, rd, OP),
+            (1, 0, 0) => EBREAK,
+            (1, _, 0) => i(0, rd, 0, RA, OP_JALR),
+            (1, _, _) => r(0, rs2, rd, 0, rd, OP),
+            _ => unreachable!(),
+        },
+        (2, 5) | (2, 7) => {
+            let imm = (x >> 7 & 0x38) | (x >> 1 & 0x1c0);
+            let opcode = if funct3 == 5 { OP_STORE_FP } else { OP_STORE };
+            s(imm, rs2, SP, 3, opcode)
+        }
+        (2, 6) => {
+            let imm = (x >> 7 & 0x3c) | (x >> 1 & 0xc0);
+            s(imm, rs2, SP, 2, OP_STORE)
+        }
+        _ => return None,
+    };
+    Some(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expands_arithmetic() {
+        // c.addi a0, -1 => addi a0, a0, -1
+        assert_eq!(expand(0x157d), Some(0xfff50513));
+        // c.lui a5, 0x1 => lui a5, 0x1
+        assert_eq!(expand(0x6785), Some(0x000017b7));
+        // c.addi16sp sp, -64 => addi sp, sp, -64
+        assert_eq!(expand(0x7139), Some(0xfc010113));
+        // c.sub s0, a0
+        assert_eq!(expand(0x8c09), Some(0x40a40433));
+    }
+
+    #[test]
+    fn test_expands_memory_and_control_flow() {
+        // c.sdsp ra, 8(sp)
+        assert_eq!(expand(0xe406), Some(0x00113423));
+        // c.ld a0, 8(a0)
+        assert_eq!(expand(0x6508), Some(0x00853503));
+        // c.j -4
+        assert_eq!(expand(0xbff5), Some(0xffdff06f));
+        // c.ret
+        assert_eq!(expand(0x8082), Some(0x00008067));
+        // the all-zero halfword is illegal
+        assert_eq!(expand(0x0000), None);
+    }
+}
@@ -0,0 +1,197 @@
+//! A shadow call stack kept as instructions retire, for backtraces that
+//! need no debugging information. A `jal`/`jalr` linking `ra` pushes a
+//! frame, a `ret` pops it. A return that does not land on the return
+//! address of the innermost frame is recorded as a mismatch: the stack was
+//! switched, `ra` overwritten, or the code unwinds by other means. When it
+//! lands on the return address of an outer frame, the frames in between are
+//! dropped, as after a `longjmp`.
+//!
+//! Stepping backwards or restoring a snapshot empties the shadow stack and
+//! marks it stale: the frames built up from then on lack the callers that
+//! were undone.
+
+use std::collections::VecDeque;
+
+use super::{
+    cpu::Cpu,
+    debug::{is_call, is_return},
+    param::{CALL_STACK_DEPTH, CALL_STACK_MISMATCHES},
+};
+
+const SP: usize = 2;
+
+/// A call not yet returned from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    /// Where the call was made.
+    pub call_site: u64,
+    pub target: u64,
+    pub return_address: u64,
+    /// `sp` at the call, as the caller left it.
+    pub sp: u64,
+}
+
+/// A `ret` that did not return to the innermost caller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub pc: u64,
+    /// The return address on the shadow stack, if it held any frame.
+    pub expected: Option<u64>,
+    pub actual: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+    // calls beyond CALL_STACK_DEPTH, which are counted but not stacked
+    overflow: u64,
+    // the latest mismatched returns
+    mismatches: VecDeque<Mismatch>,
+    // emptied by stepping back since the start of the run
+    stale: bool,
+}
+
+impl CallStack {
+    /// Follow `insn`, retired at `pc` with `next` to follow and `sp` as it
+    /// now stands.
+    pub(super) fn retire(&mut self, pc: u64, insn: u32, next: u64, sp: u64) {
+        if is_call(insn) {
+            if self.frames.len() == CALL_STACK_DEPTH {
+                self.overflow += 1;
+                return;
+            }
+            let len = if insn & 3 == 3 { 4 } else { 2 };
+            self.frames.push(CallFrame {
+                call_site: pc,
+                target: next,
+                return_address: pc.wrapping_add(len),
+                sp,
+            });
+        } else if is_return(insn) {
+            if self.overflow > 0 {
+                self.overflow -= 1;
+                return;
+            }
+            let expected = self.frames.last().map(|frame| frame.return_address);
+            if expected == Some(next) {
+                self.frames.pop();
+                return;
+            }
+            if let Some(caller) = self.frames.iter().rposition(|f| f.return_address == next) {
+                self.frames.truncate(caller);
+            }
+            if self.mismatches.len() == CALL_STACK_MISMATCHES {
+                self.mismatches.pop_front();
+            }
+            self.mismatches.push_back(Mismatch {
+                pc,
+                expected,
+                actual: next,
+            });
+        }
+    }
+
+    /// Forget the frames after history was rewound past them.
+    pub(super) fn invalidate(&mut self) {
+        self.frames.clear();
+        self.overflow = 0;
+        self.stale = true;
+    }
+
+    /// Whether outer frames may be missing because history was rewound.
+    pub fn stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Calls not yet returned from, outermost first.
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    /// The latest returns that missed the innermost caller, oldest first.
+    pub fn mismatches(&self) -> impl Iterator<Item = &Mismatch> {
+        self.mismatches.iter()
+    }
+}
+
+/// One frame of a backtrace, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// pc in the innermost frame, the call site in the others.
+    pub pc: u64,
+    pub symbol: Option<String>,
+    pub sp: u64,
+    /// Where the frame returns to, unless it is the outermost.
+    pub return_address: Option<u64>,
+}
+
+impl Cpu {
+    /// The shadow call stack as a backtrace, innermost frame first.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let calls = self.call_stack.frames();
+        let mut frames = vec![Frame {
+            pc: self.pc,
+            symbol: self.symbol_at(self.pc),
+            sp: self.regs[SP],
+            return_address: calls.last().map(|call| call.return_address),
+        }];
+        for (i, call) in calls.iter().enumerate().rev() {
+            frames.push(Frame {
+                pc: call.call_site,
+                symbol: self.symbol_at(call.call_site),
+                sp: call.sp,
+                return_address: i.checked_sub(1).map(|outer| calls[outer].return_address),
+            });
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::MachineConfig;
+
+    #[test]
+    fn test_frames_follow_calls_and_returns() {
+        let code = [
+            0x008000ef, // main: jal ra, 8 (f)
+            0x0000006f, // j .
+            0x00000013, // f: nop
+            0x00008067, // ret
+        ];
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &code);
+        cpu.regs[SP] = 0x1000;
+        cpu.step_n(2);
+        assert!(!cpu.call_stack.stale());
+
+        let frames = cpu.backtrace();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].pc, frames[0].return_address), (0xc, Some(4)));
+        assert_eq!((frames[1].pc, frames[1].sp), (0, 0x1000));
+        assert_eq!(frames[1].return_address, None);
+
+        cpu.step_n(1);
+        assert_eq!(cpu.backtrace().len(), 1);
+        assert_eq!(cpu.call_stack.mismatches().count(), 0);
+
+        // returning again, with no call left to return from
+        cpu.pc = 0xc;
+        cpu.regs[1] = 0x8;
+        cpu.step_n(1);
+        let mismatches: Vec<_> = cpu.call_stack.mismatches().collect();
+        assert_eq!(
+            mismatches,
+            [&Mismatch {
+                pc: 0xc,
+                expected: None,
+                actual: 0x8
+            }]
+        );
+
+        // stepping back does not rewind the stack, it empties it
+        cpu.step_back();
+        assert_eq!(cpu.backtrace().len(), 1);
+        assert!(cpu.call_stack.stale());
+    }
+}
@@ -0,0 +1,214 @@
+//! A trace of retired instructions in the format of Spike's `--log-commits`,
+//! so a run can be diffed line by line against Spike or another emulator.
+//! Each line gives the privilege level the instruction ran at, its pc and
+//! encoding, then the registers it wrote and the memory it accessed:
+//!
+//! ```text
+//! core   0: 3 0x0000000080000000 (0x02a00513) x10 0x000000000000002a
+//! core   0: 3 0x0000000080000004 (0x00a12023) mem 0x0000000080001000 0x0000002a
+//! ```
+//!
+//! Loads show their address, stores their address and value. Lines are kept
+//! for API clients to poll by offset, and optionally written to a file.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::{
+    cpu::Cpu,
+    csr::{self, Csr},
+    param::COMMIT_LOG_CAPACITY,
+    trap::Privilege,
+};
+
+/// What the instruction in flight has written and accessed so far.
+#[derive(Default)]
+struct Commit {
+    privilege: u64,
+    pc: u64,
+    regs: Vec<u32>,
+    fregs: Vec<u32>,
+    csrs: Vec<u32>,
+    loads: Vec<u64>,
+    // (address, size in bits, value)
+    stores: Vec<(u64, u64, u64)>,
+}
+
+pub struct CommitLog {
+    file: Option<BufWriter<File>>,
+    lines: VecDeque<String>,
+    // lines already dropped from the front of `lines`
+    discarded: u64,
+    pending: Commit,
+}
+
+impl CommitLog {
+    /// Start a log, also writing it to `path` when given.
+    pub fn new(path: Option<&Path>) -> Result<Self, String> {
+        let file = match path {
+            Some(path) => Some(BufWriter::new(
+                File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            file,
+            lines: VecDeque::new(),
+            discarded: 0,
+            pending: Commit::default(),
+        })
+    }
+
+    /// Lines logged at or after line `offset`, and the offset following them.
+    pub fn lines_since(&self, offset: u64) -> (u64, Vec<String>) {
+        let end = self.discarded + self.lines.len() as u64;
+        let skip = offset
+            .saturating_sub(self.discarded)
+            .min(self.lines.len() as u64);
+        (
+            end,
+            self.lines.iter().skip(skip as usize).cloned().collect(),
+        )
+    }
+
+    /// The line of the instruction that retired last.
+    pub fn last(&self) -> Option<&str> {
+        self.lines.back().map(String::as_str)
+    }
+
+    /// Forget what earlier attempts at the instruction at `pc` recorded, as
+    /// when it trapped and the handler runs instead.
+    pub(super) fn begin(&mut self, privilege: Privilege, pc: u64) {
+        self.pending = Commit {
+            privilege: privilege as u64,
+            pc,
+            ..Commit::default()
+        };
+    }
+
+    pub(super) fn reg(&mut self, id: u32) {
+        if id != 0 && !self.pending.regs.contains(&id) {
+            self.pending.regs.push(id);
+        }
+    }
+
+    pub(super) fn freg(&mut self, id: u32) {
+        if !self.pending.fregs.contains(&id) {
+            self.pending.fregs.push(id);
+        }
+    }
+
+    pub(super) fn csr(&mut self, addr: u32) {
+        if !self.pending.csrs.contains(&addr) {
+            self.pending.csrs.push(addr);
+        }
+    }
+
+    pub(super) fn load(&mut self, addr: u64) {
+        self.pending.loads.push(addr);
+    }
+
+    pub(super) fn store(&mut self, addr: u64, size: u64, value: u64) {
+        self.pending.stores.push((addr, size, value));
+    }
+
+    /// Log the instruction in flight, which has retired as `insn`, with the
+    /// registers it wrote as they now stand.
+    pub(super) fn retire(&mut self, insn: u32, regs: &[u64; 32], fregs: &[u64; 32], csr: &Csr) {
+        let commit = std::mem::take(&mut self.pending);
+        let mut line = format!("core   0: {} 0x{:016x}", commit.privilege, commit.pc);
+        if insn & 3 == 3 {
+            line += &format!(" (0x{:08x})", insn);
+        } else {
+            line += &format!(" (0x{:04x})", insn & 0xffff);
+        }
+        for id in commit.regs {
+            line += &format!(" x{:<2} 0x{:016x}", id, regs[id as usize]);
+        }
+        for id in commit.fregs {
+            line += &format!(" f{:<2} 0x{:016x}", id, fregs[id as usize]);
+        }
+        for addr in commit.csrs {
+            line += &format!(" c{}_{} 0x{:016x}", addr, csr::name(addr), csr.read(addr));
+        }
+        for addr in commit.loads {
+            line += &format!(" mem 0x{:016x}", addr);
+        }
+        for (addr, size, value) in commit.stores {
+            let value = value & (u64::MAX >> (64 - size));
+            line += &format!(
+                " mem 0x{:016x} 0x{:0width$x}",
+                addr,
+                value,
+                width = size as usize / 4
+            );
+        }
+
+        if let Some(file) = &mut self.file {
+            let _ = writeln!(file, "{}", line);
+        }
+        if self.lines.len() == COMMIT_LOG_CAPACITY {
+            self.lines.pop_front();
+            self.discarded += 1;
+        }
+        self.lines.push_back(line);
+    }
+}
+
+impl Cpu {
+    /// Trace retired instructions from now on, replacing any earlier log.
+    pub fn start_commit_log(&mut self, path: Option<&Path>) -> Result<(), String> {
+        self.commit_log = Some(CommitLog::new(path)?);
+        Ok(())
+    }
+
+    /// Stop tracing, flushing the file being written.
+    pub fn stop_commit_log(&mut self) -> Result<(), String> {
+        match self.commit_log.take().and_then(|log| log.file) {
+            Some(mut file) => file.flush().map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{
+        config::{Extension, MachineConfig},
+        cpu::Cpu,
+    };
+
+    #[test]
+    fn test_lines_match_spike() {
+        let code = [
+            0x000012b7u32, // lui t0, 0x1
+            0x02a00513,    // li a0, 42
+            0x00a2a023,    // sw a0, 0(t0)
+            0x0002a583,    // lw a1, 0(t0)
+            0x4505,        // c.li a0, 1
+        ];
+        let config = MachineConfig::builder()
+            .extensions(&[Extension::I, Extension::C])
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::with_code(config, &code);
+        cpu.start_commit_log(None).unwrap();
+        cpu.step_n(5);
+
+        let (offset, lines) = cpu.commit_log.as_ref().unwrap().lines_since(0);
+        assert_eq!(offset, 5);
+        assert_eq!(
+            lines,
+            [
+                "core   0: 3 0x0000000000000000 (0x000012b7) x5  0x0000000000001000",
+                "core   0: 3 0x0000000000000004 (0x02a00513) x10 0x000000000000002a",
+                "core   0: 3 0x0000000000000008 (0x00a2a023) mem 0x0000000000001000 0x0000002a",
+                "core   0: 3 0x000000000000000c (0x0002a583) x11 0x000000000000002a mem 0x0000000000001000",
+                "core   0: 3 0x0000000000000010 (0x4505) x10 0x0000000000000001",
+            ]
+        );
+        assert_eq!(cpu.commit_log.as_ref().unwrap().lines_since(4).1.len(), 1);
+    }
+}
@@ -0,0 +1,793 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::device::clint::{ClockSource, CLINT_SIZE};
+use super::device::plic::{PLIC_SIZE, PLIC_SOURCES};
+use super::device::uart::UART_SIZE;
+use super::device::virtio::{DiskMode, NetBackend, VIRTIO_SIZE};
+use super::elf::Elf;
+use super::param::{
+    DEFAULT_CLINT_BASE, DEFAULT_DRAM_BASE, DEFAULT_DRAM_SIZE, DEFAULT_MAC, DEFAULT_PLIC_BASE,
+    DEFAULT_RNG_SEED, DEFAULT_UART_BASE, DEFAULT_UART_IRQ, DEFAULT_VIRTIO_BASE, DEFAULT_VIRTIO_IRQ,
+    DRAM_PAGE_SIZE, VIRT_DRAM_BASE, VIRT_DRAM_SIZE,
+};
+
+/// ISA extensions that can be switched on for a machine.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Extension {
+    I,
+    M,
+    A,
+    F,
+    D,
+    C,
+    Zicsr,
+    Zifencei,
+}
+
+impl Extension {
+    /// Bit reported for this extension in `misa`.
+    pub fn misa_bit(self) -> u64 {
+        match self {
+            Extension::A => 1 << 0,
+            Extension::C => 1 << 2,
+            Extension::D => 1 << 3,
+            Extension::F => 1 << 5,
+            Extension::I => 1 << 8,
+            Extension::M => 1 << 12,
+            Extension::Zicsr | Extension::Zifencei => 0,
+        }
+    }
+
+    /// Name in an ISA string such as `rv64im_zicsr`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Extension::I => "i",
+            Extension::M => "m",
+            Extension::A => "a",
+            Extension::F => "f",
+            Extension::D => "d",
+            Extension::C => "c",
+            Extension::Zicsr => "zicsr",
+            Extension::Zifencei => "zifencei",
+        }
+    }
+}
+
+/// A file copied into DRAM at `addr` before the hart starts.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BootImage {
+    pub path: PathBuf,
+    pub addr: u64,
+}
+
+/// Images placed in DRAM at reset, as a boot ROM would leave them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct BootConfig {
+    /// Firmware such as OpenSBI `fw_jump`, normally at the reset vector.
+    pub firmware: Option<BootImage>,
+    /// Kernel image, at the address the firmware jumps to.
+    pub kernel: Option<BootImage>,
+    /// Initial ramdisk, advertised in the device tree's `/chosen` node.
+    pub initrd: Option<BootImage>,
+    /// A bare-metal ELF executable, loaded by its program headers and
+    /// started at its entry point instead of the reset vector. A `tohost`
+    /// symbol turns on HTIF.
+    pub program: Option<PathBuf>,
+}
+
+impl BootConfig {
+    pub fn images(&self) -> impl Iterator<Item = (&'static str, &BootImage)> {
+        [
+            ("firmware", &self.firmware),
+            ("kernel", &self.kernel),
+            ("initrd", &self.initrd),
+        ]
+        .into_iter()
+        .filter_map(|(name, image)| image.as_ref().map(|image| (name, image)))
+    }
+}
+
+/// A static RV64 Linux executable and the process it starts as.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LinuxProgram {
+    pub program: PathBuf,
+    /// `argv[1..]`; `argv[0]` is the program path.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// `NAME=value` pairs.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Host directory the program sees as `/`; paths cannot leave it.
+    #[serde(default = "LinuxProgram::default_root")]
+    pub root: PathBuf,
+}
+
+impl LinuxProgram {
+    fn default_root() -> PathBuf {
+        PathBuf::from(".")
+    }
+}
+
+/// What an `ecall` the guest does not handle itself is taken to mean.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Personality {
+    /// Bare metal: environment calls trap like any other exception.
+    #[default]
+    Machine,
+    /// User-mode emulation: the ELF is loaded instead of booting, the hart
+    /// starts in U-mode, and `ecall` is a Linux system call served by the host.
+    Linux(LinuxProgram),
+    /// Every `ecall` is a RARS service: the number in `a7`, arguments from
+    /// `a0`, running on the console.
+    Rars,
+    /// Every `ecall` is a Venus service: the number in `a0`, arguments from
+    /// `a1`.
+    Venus,
+}
+
+/// A peripheral mapped on the bus.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DeviceConfig {
+    Uart {
+        #[serde(default = "default_uart_base")]
+        base: u64,
+        /// PLIC source the interrupt output is wired to.
+        #[serde(default = "default_uart_irq")]
+        irq: u32,
+    },
+    Clint {
+        #[serde(default = "default_clint_base")]
+        base: u64,
+        #[serde(default)]
+        clock: ClockSource,
+    },
+    Plic {
+        #[serde(default = "default_plic_base")]
+        base: u64,
+    },
+    VirtioBlock {
+        #[serde(default = "default_virtio_base")]
+        base: u64,
+        #[serde(default = "default_virtio_irq")]
+        irq: u32,
+        /// Raw disk image file; without one the drive is empty.
+        #[serde(default)]
+        image: Option<PathBuf>,
+        #[serde(default)]
+        mode: DiskMode,
+    },
+    VirtioNet {
+        #[serde(default = "default_virtio_base")]
+        base: u64,
+        #[serde(default = "default_virtio_irq")]
+        irq: u32,
+        #[serde(default = "default_mac")]
+        mac: [u8; 6],
+        #[serde(default)]
+        backend: NetBackend,
+    },
+    /// virtio-console on the session console, an alternative to the UART.
+    VirtioConsole {
+        #[serde(default = "default_virtio_base")]
+        base: u64,
+        #[serde(default = "default_virtio_irq")]
+        irq: u32,
+    },
+    VirtioRng {
+        #[serde(default = "default_virtio_base")]
+        base: u64,
+        #[serde(default = "default_virtio_irq")]
+        irq: u32,
+        /// PRNG seed; an explicit null seeds from the host for a different
+        /// stream on every run.
+        #[serde(default = "default_rng_seed")]
+        seed: Option<u64>,
+    },
+}
+
+fn default_uart_base() -> u64 {
+    DEFAULT_UART_BASE
+}
+
+fn default_uart_irq() -> u32 {
+    DEFAULT_UART_IRQ
+}
+
+fn default_clint_base() -> u64 {
+    DEFAULT_CLINT_BASE
+}
+
+fn default_plic_base() -> u64 {
+    DEFAULT_PLIC_BASE
+}
+
+fn default_virtio_base() -> u64 {
+    DEFAULT_VIRTIO_BASE
+}
+
+fn default_virtio_irq() -> u32 {
+    DEFAULT_VIRTIO_IRQ
+}
+
+fn default_mac() -> [u8; 6] {
+    DEFAULT_MAC
+}
+
+fn default_rng_seed() -> Option<u64> {
+    Some(DEFAULT_RNG_SEED)
+}
+
+impl DeviceConfig {
+    /// Unique name in device tree style, e.g. `uart@10000000`.
+    pub fn name(&self) -> String {
+        let kind = match self {
+            DeviceConfig::Uart { .. } => "uart",
+            DeviceConfig::Clint { .. } => "clint",
+            DeviceConfig::Plic { .. } => "plic",
+            DeviceConfig::VirtioBlock { .. } => "virtio-blk",
+            DeviceConfig::VirtioNet { .. } => "virtio-net",
+            DeviceConfig::VirtioConsole { .. } => "virtio-console",
+            DeviceConfig::VirtioRng { .. } => "virtio-rng",
+        };
+        format!("{}@{:x}", kind, self.base())
+    }
+
+    pub fn base(&self) -> u64 {
+        match *self {
+            DeviceConfig::Uart { base, .. }
+            | DeviceConfig::Clint { base, .. }
+            | DeviceConfig::Plic { base }
+            | DeviceConfig::VirtioBlock { base, .. }
+            | DeviceConfig::VirtioNet { base, .. }
+            | DeviceConfig::VirtioConsole { base, .. }
+            | DeviceConfig::VirtioRng { base, .. } => base,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            DeviceConfig::Uart { .. } => UART_SIZE,
+            DeviceConfig::Clint { .. } => CLINT_SIZE,
+            DeviceConfig::Plic { .. } => PLIC_SIZE,
+            DeviceConfig::VirtioBlock { .. }
+            | DeviceConfig::VirtioNet { .. }
+            | DeviceConfig::VirtioConsole { .. }
+            | DeviceConfig::VirtioRng { .. } => VIRTIO_SIZE,
+        }
+    }
+
+    /// PLIC source driven by this device's interrupt output, if any.
+    pub fn irq(&self) -> Option<u32> {
+        match *self {
+            DeviceConfig::Uart { irq, .. }
+            | DeviceConfig::VirtioBlock { irq, .. }
+            | DeviceConfig::VirtioNet { irq, .. }
+            | DeviceConfig::VirtioConsole { irq, .. }
+            | DeviceConfig::VirtioRng { irq, .. } => Some(irq),
+            DeviceConfig::Clint { .. } | DeviceConfig::Plic { .. } => None,
+        }
+    }
+}
+
+/// Shape of the emulated machine, fixed when a `Cpu` is created.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MachineConfig {
+    pub dram_base: u64,
+    pub dram_size: u64,
+    pub reset_vector: u64,
+    /// Must be 1 until the interpreter steps more than hart 0; the device
+    /// tree and interrupt controllers already size themselves by it.
+    pub harts: usize,
+    pub extensions: Vec<Extension>,
+    pub devices: Vec<DeviceConfig>,
+    /// Kernel command line passed in the device tree's `/chosen` node.
+    pub bootargs: String,
+    pub boot: BootConfig,
+    pub personality: Personality,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            dram_base: DEFAULT_DRAM_BASE,
+            dram_size: DEFAULT_DRAM_SIZE,
+            reset_vector: DEFAULT_DRAM_BASE,
+            harts: 1,
+            extensions: vec![Extension::I, Extension::M, Extension::Zicsr],
+            devices: vec![
+                DeviceConfig::Uart {
+                    base: DEFAULT_UART_BASE,
+                    irq: DEFAULT_UART_IRQ,
+                },
+                DeviceConfig::Plic {
+                    base: DEFAULT_PLIC_BASE,
+                },
+            ],
+            bootargs: String::new(),
+            boot: BootConfig::default(),
+            personality: Personality::default(),
+        }
+    }
+}
+
+impl MachineConfig {
+    pub fn builder() -> MachineConfigBuilder {
+        MachineConfigBuilder {
+            config: Self::default(),
+        }
+    }
+
+    /// A machine along the lines of QEMU's `virt`: RV64GC with 256 MB of
+    /// DRAM at 0x8000_0000, a CLINT, a PLIC, a UART and an empty virtio-blk
+    /// drive. Add a disk and boot images to the returned builder.
+    pub fn virt() -> MachineConfigBuilder {
+        Self::builder()
+            .dram(VIRT_DRAM_BASE, VIRT_DRAM_SIZE)
+            .reset_vector(VIRT_DRAM_BASE)
+            .extensions(&[
+                Extension::I,
+                Extension::M,
+                Extension::A,
+                Extension::F,
+                Extension::D,
+                Extension::C,
+                Extension::Zicsr,
+                Extension::Zifencei,
+            ])
+            .devices(&[
+                DeviceConfig::Clint {
+                    base: DEFAULT_CLINT_BASE,
+                    clock: ClockSource::default(),
+                },
+                DeviceConfig::Plic {
+                    base: DEFAULT_PLIC_BASE,
+                },
+                DeviceConfig::Uart {
+                    base: DEFAULT_UART_BASE,
+                    irq: DEFAULT_UART_IRQ,
+                },
+                DeviceConfig::VirtioBlock {
+                    base: DEFAULT_VIRTIO_BASE,
+                    irq: DEFAULT_VIRTIO_IRQ,
+                    image: None,
+                    mode: DiskMode::default(),
+                },
+            ])
+    }
+
+    /// An RV64GC machine with no devices that runs the static Linux executable
+    /// `program` in U-mode, with `args` as `argv[1..]`.
+    pub fn linux(program: impl Into<PathBuf>, args: &[&str]) -> MachineConfigBuilder {
+        Self::builder()
+            .extensions(&[
+                Extension::I,
+                Extension::M,
+                Extension::A,
+                Extension::F,
+                Extension::D,
+                Extension::C,
+                Extension::Zicsr,
+                Extension::Zifencei,
+            ])
+            .devices(&[])
+            .personality(Personality::Linux(LinuxProgram {
+                program: program.into(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                env: Vec::new(),
+                root: LinuxProgram::default_root(),
+            }))
+    }
+
+    /// Read a configuration from a `.toml` file, or JSON otherwise.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let config: Self = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&text).map_err(|e| e.to_string())?
+        } else {
+            serde_json::from_str(&text).map_err(|e| e.to_string())?
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Every host file or directory the machine would open, create or bind.
+    pub fn host_paths_mut(&mut self) -> Vec<&mut PathBuf> {
+        let boot = &mut self.boot;
+        let mut paths: Vec<&mut PathBuf> = [&mut boot.firmware, &mut boot.kernel, &mut boot.initrd]
+            .into_iter()
+            .flatten()
+            .map(|image| &mut image.path)
+            .chain(&mut boot.program)
+            .collect();
+        if let Personality::Linux(linux) = &mut self.personality {
+            paths.push(&mut linux.program);
+            paths.push(&mut linux.root);
+        }
+        for device in &mut self.devices {
+            match device {
+                DeviceConfig::VirtioBlock {
+                    image: Some(image), ..
+                } => paths.push(image),
+                DeviceConfig::VirtioNet {
+                    backend: NetBackend::Socket { path, peer },
+                    ..
+                } => {
+                    paths.push(path);
+                    paths.push(peer);
+                }
+                _ => {}
+            }
+        }
+        paths
+    }
+
+    pub fn dram_end(&self) -> u64 {
+        self.dram_base + self.dram_size - 1
+    }
+
+    pub fn has(&self, ext: Extension) -> bool {
+        self.extensions.contains(&ext)
+    }
+
+    /// `misa` for RV64 with the enabled extensions.
+    pub fn misa(&self) -> u64 {
+        self.extensions
+            .iter()
+            .fold(2 << 62, |misa, ext| misa | ext.misa_bit())
+    }
+
+    /// ISA string for the enabled extensions in canonical order, e.g.
+    /// `rv64im_zicsr`.
+    pub fn isa(&self) -> String {
+        let mut isa = String::from("rv64");
+        for ext in [
+            Extension::I,
+            Extension::M,
+            Extension::A,
+            Extension::F,
+            Extension::D,
+            Extension::C,
+            Extension::Zicsr,
+            Extension::Zifencei,
+        ] {
+            if self.has(ext) {
+                if ext.name().len() > 1 {
+                    isa.push('_');
+                }
+                isa.push_str(ext.name());
+            }
+        }
+        isa
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.dram_size == 0 || !self.dram_size.is_multiple_of(DRAM_PAGE_SIZE) {
+            return Err(format!(
+                "DRAM size 0x{:x} is not a non-zero multiple of the page size",
+                self.dram_size
+            ));
+        }
+        if !self.dram_base.is_multiple_of(DRAM_PAGE_SIZE) {
+            return Err(format!(
+                "DRAM base 0x{:x} is not page aligned",
+                self.dram_base
+            ));
+        }
+        if self.dram_base.checked_add(self.dram_size).is_none() {
+            return Err("DRAM extends past the end of the address space".into());
+        }
+        if self.harts != 1 {
+            return Err(format!(
+                "{} harts requested, but only single-hart machines run",
+                self.harts
+            ));
+        }
+        if !self.has(Extension::I) {
+            return Err("the I extension is required".into());
+        }
+        if self.has(Extension::D) && !self.has(Extension::F) {
+            return Err("the D extension requires F".into());
+        }
+        for (name, image) in self.boot.images() {
+            let len = fs::metadata(&image.path)
+                .map_err(|e| format!("{} {}: {}", name, image.path.display(), e))?
+                .len();
+            let fits = image.addr >= self.dram_base
+                && image
+                    .addr
+                    .checked_add(len)
+                    .is_some_and(|end| end <= self.dram_base + self.dram_size);
+            if !fits {
+                return Err(format!(
+                    "{} {} does not fit in DRAM at 0x{:x}",
+                    name,
+                    image.path.display(),
+                    image.addr
+                ));
+            }
+        }
+        if let Some(program) = &self.boot.program {
+            self.validate_elf(program)?;
+        }
+        if let Personality::Linux(linux) = &self.personality {
+            self.validate_elf(&linux.program)?;
+            if !linux.root.is_dir() {
+                return Err(format!("root {} is not a directory", linux.root.display()));
+            }
+        }
+
+        let mut regions = vec![("dram".to_string(), self.dram_base, self.dram_size)];
+        for device in &self.devices {
+            let (base, size) = (device.base(), device.size());
+            if base.checked_add(size).is_none() {
+                return Err(format!(
+                    "{} extends past the end of the address space",
+                    device.name()
+                ));
+            }
+            if let Some((other, _, _)) = regions
+                .iter()
+                .find(|&&(_, b, s)| base < b + s && b < base + size)
+            {
+                return Err(format!("{} overlaps {}", device.name(), other));
+            }
+            regions.push((device.name(), base, size));
+
+            if let Some(irq) = device.irq() {
+                if irq == 0 || irq as usize >= PLIC_SOURCES {
+                    return Err(format!("{}: irq {} out of range", device.name(), irq));
+                }
+            }
+            if let DeviceConfig::VirtioBlock {
+                image: Some(image), ..
+            } = device
+            {
+                if !image.is_file() {
+                    return Err(format!(
+                        "{}: disk image {} is not a file",
+                        device.name(),
+                        image.display()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `path` is an executable whose segments all land in DRAM.
+    fn validate_elf(&self, path: &Path) -> Result<(), String> {
+        let data = fs::read(path).map_err(|e| format!("program {}: {}", path.display(), e))?;
+        let elf = Elf::parse(&data).map_err(|e| format!("program {}: {}", path.display(), e))?;
+        if let Some(segment) = elf.segments.iter().find(|segment| {
+            let end = segment.vaddr.checked_add(segment.memsz);
+            let limit = self.dram_base + self.dram_size;
+            segment.vaddr < self.dram_base || end.is_none_or(|end| end > limit)
+        }) {
+            return Err(format!(
+                "program {}: segment at 0x{:x} lies outside DRAM",
+                path.display(),
+                segment.vaddr
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct MachineConfigBuilder {
+    config: MachineConfig,
+}
+
+impl MachineConfigBuilder {
+    pub fn dram(mut self, base: u64, size: u64) -> Self {
+        self.config.dram_base = base;
+        self.config.dram_size = size;
+        self
+    }
+
+    pub fn reset_vector(mut self, addr: u64) -> Self {
+        self.config.reset_vector = addr;
+        self
+    }
+
+    pub fn harts(mut self, harts: usize) -> Self {
+        self.config.harts = harts;
+        self
+    }
+
+    pub fn extensions(mut self, extensions: &[Extension]) -> Self {
+        self.config.extensions = extensions.to_vec();
+        self
+    }
+
+    pub fn devices(mut self, devices: &[DeviceConfig]) -> Self {
+        self.config.devices = devices.to_vec();
+        self
+    }
+
+    pub fn bootargs(mut self, bootargs: &str) -> Self {
+        self.config.bootargs = bootargs.into();
+        self
+    }
+
+    pub fn device(mut self, device: DeviceConfig) -> Self {
+        self.config.devices.push(device);
+        self
+    }
+
+    /// Put the disk image at `path` in the first virtio-blk drive, adding a
+    /// drive at the default virtio address if there is none.
+    pub fn disk(mut self, path: impl Into<PathBuf>, mode: DiskMode) -> Self {
+        let drive = self
+            .config
+            .devices
+            .iter_mut()
+            .find(|device| matches!(device, DeviceConfig::VirtioBlock { .. }));
+        match drive {
+            Some(DeviceConfig::VirtioBlock { image, mode: m, .. }) => {
+                *image = Some(path.into());
+                *m = mode;
+            }
+            _ => self.config.devices.push(DeviceConfig::VirtioBlock {
+                base: DEFAULT_VIRTIO_BASE,
+                irq: DEFAULT_VIRTIO_IRQ,
+                image: Some(path.into()),
+                mode,
+            }),
+        }
+        self
+    }
+
+    pub fn firmware(mut self, path: impl Into<PathBuf>, addr: u64) -> Self {
+        self.config.boot.firmware = Some(BootImage {
+            path: path.into(),
+            addr,
+        });
+        self
+    }
+
+    pub fn kernel(mut self, path: impl Into<PathBuf>, addr: u64) -> Self {
+        self.config.boot.kernel = Some(BootImage {
+            path: path.into(),
+            addr,
+        });
+        self
+    }
+
+    pub fn initrd(mut self, path: impl Into<PathBuf>, addr: u64) -> Self {
+        self.config.boot.initrd = Some(BootImage {
+            path: path.into(),
+            addr,
+        });
+        self
+    }
+
+    pub fn program(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.boot.program = Some(path.into());
+        self
+    }
+
+    pub fn personality(mut self, personality: Personality) -> Self {
+        self.config.personality = personality;
+        self
+    }
+
+    /// Host directory a Linux program sees as `/`.
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        if let Personality::Linux(linux) = &mut self.config.personality {
+            linux.root = root.into();
+        }
+        self
+    }
+
+    /// Environment of a Linux program, as `NAME=value` pairs.
+    pub fn env(mut self, env: &[&str]) -> Self {
+        if let Personality::Linux(linux) = &mut self.config.personality {
+            linux.env = env.iter().map(|var| var.to_string()).collect();
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<MachineConfig, String> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toml_fills_defaults() {
+        let config: MachineConfig = toml::from_str(
+            r#"
+            dram_base = 0x80000000
+            reset_vector = 0x80000000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.dram_base, 0x8000_0000);
+        assert_eq!(config.dram_size, DEFAULT_DRAM_SIZE);
+        assert!(config.has(Extension::M));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_device_overlap_is_rejected() {
+        let devices = [
+            DeviceConfig::Uart {
+                base: 0x1000_0000,
+                irq: 10,
+            },
+            DeviceConfig::Uart {
+                base: 0x1000_0080,
+                irq: 11,
+            },
+        ];
+        assert!(MachineConfig::builder().devices(&devices).build().is_err());
+        assert!(MachineConfig::builder()
+            .devices(&[DeviceConfig::Uart { base: 0x0, irq: 10 }])
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_virt_profile() {
+        let config = MachineConfig::virt().build().unwrap();
+        assert_eq!(config.isa(), "rv64imafdc_zicsr_zifencei");
+        assert_eq!(config.misa() & 0x3ff_ffff, 0x112d);
+        assert!(matches!(
+            config.devices.last(),
+            Some(DeviceConfig::VirtioBlock { image: None, .. })
+        ));
+
+        // the disk goes in the existing drive rather than a second one
+        let config = MachineConfig::virt()
+            .disk("Cargo.toml", DiskMode::ReadOnly)
+            .build()
+            .unwrap();
+        assert_eq!(config.devices.len(), 4);
+        assert!(MachineConfig::virt()
+            .disk("/nonexistent/disk.img", DiskMode::Overlay)
+            .build()
+            .is_err());
+
+        // boot images must exist and land in DRAM
+        assert!(MachineConfig::virt()
+            .kernel("/nonexistent/Image", VIRT_DRAM_BASE)
+            .build()
+            .is_err());
+        assert!(MachineConfig::virt()
+            .kernel("Cargo.toml", VIRT_DRAM_BASE + VIRT_DRAM_SIZE - 4)
+            .build()
+            .is_err());
+        assert!(MachineConfig::virt()
+            .kernel("Cargo.toml", VIRT_DRAM_BASE)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_builder_rejects_unaligned_dram() {
+        assert!(MachineConfig::builder()
+            .dram(0x8000_0010, 0x10000)
+            .build()
+            .is_err());
+        assert!(MachineConfig::builder()
+            .extensions(&[Extension::M])
+            .build()
+            .is_err());
+        assert!(MachineConfig::builder().harts(2).build().is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::param::CONSOLE_CAPACITY;
+
+#[derive(Default)]
+struct Buffers {
+    output: VecDeque<u8>,
+    // bytes already dropped from the front of `output`
+    discarded: u64,
+    input: VecDeque<u8>,
+}
+
+/// Host side of the guest console, shared between a session and its devices.
+#[derive(Clone, Default)]
+pub struct Console {
+    buffers: Arc<Mutex<Buffers>>,
+}
+
+impl Console {
+    /// Append guest output, dropping the oldest bytes beyond the capacity.
+    pub fn write(&self, bytes: &[u8]) {
+        let mut b = self.buffers.lock().unwrap();
+        b.output.extend(bytes);
+        let excess = b.output.len().saturating_sub(CONSOLE_CAPACITY);
+        b.output.drain(..excess);
+        b.discarded += excess as u64;
+    }
+
+    /// Output written at or after stream `offset`, and the offset following it.
+    pub fn output_since(&self, offset: u64) -> (u64, Vec<u8>) {
+        let b = self.buffers.lock().unwrap();
+        let end = b.discarded + b.output.len() as u64;
+        let skip = offset
+            .saturating_sub(b.discarded)
+            .min(b.output.len() as u64);
+        (end, b.output.iter().skip(skip as usize).copied().collect())
+    }
+
+    /// Queue bytes for the guest to read.
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.buffers.lock().unwrap().input.extend(bytes);
+    }
+
+    pub fn read(&self) -> Option<u8> {
+        self.buffers.lock().unwrap().input.pop_front()
+    }
+
+    /// Take up to `max` bytes of pending input.
+    pub fn read_input(&self, max: usize) -> Vec<u8> {
+        let mut b = self.buffers.lock().unwrap();
+        let n = max.min(b.input.len());
+        b.input.drain(..n).collect()
+    }
+
+    /// Take one line of pending input, newline included, once all of it has
+    /// arrived.
+    pub fn read_line(&self) -> Option<Vec<u8>> {
+        let mut b = self.buffers.lock().unwrap();
+        let end = b.input.iter().position(|&byte| byte == b'\n')?;
+        Some(b.input.drain(..=end).collect())
+    }
+
+    pub fn has_line(&self) -> bool {
+        self.buffers.lock().unwrap().input.contains(&b'\n')
+    }
+
+    pub fn has_input(&self) -> bool {
+        !self.buffers.lock().unwrap().input.is_empty()
+    }
+}
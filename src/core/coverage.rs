@@ -0,0 +1,187 @@
+//! Source line coverage: the pcs that executed, mapped to lines of the
+//! program through the DWARF line table and exported in lcov's `.info`
+//! format, as `genhtml` and most CI tools read it.
+//!
+//! A line counts as often as its first statement ran; a function as often
+//! as its entry point did. Code without line information, such as a C
+//! library built without `-g`, is left out.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use super::{cpu::Cpu, dwarf::LineTable, elf::Symbol};
+
+#[derive(Default)]
+pub struct Coverage {
+    /// Cleared while coverage is stopped.
+    pub(super) running: bool,
+    // pc -> instructions retired there
+    counts: HashMap<u64, u64>,
+}
+
+impl Coverage {
+    pub(super) fn retire(&mut self, pc: u64) {
+        *self.counts.entry(pc).or_default() += 1;
+    }
+}
+
+/// How much of one function ran.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCoverage {
+    pub name: String,
+    pub file: String,
+    pub line: u64,
+    pub calls: u64,
+    /// Source lines with code in the function.
+    pub lines: u64,
+    /// Those of them that ran.
+    pub hit: u64,
+}
+
+/// Execution counts by file and line, and the functions in them.
+struct Report {
+    files: BTreeMap<String, BTreeMap<u64, u64>>,
+    functions: Vec<FunctionCoverage>,
+}
+
+fn report(table: &LineTable, symbols: &[Symbol], counts: &HashMap<u64, u64>) -> Report {
+    let count = |addr: u64| counts.get(&addr).copied().unwrap_or(0);
+    let mut files: BTreeMap<String, BTreeMap<u64, u64>> = BTreeMap::new();
+    for row in &table.rows {
+        let hits = files
+            .entry(row.file.clone())
+            .or_default()
+            .entry(row.line)
+            .or_default();
+        *hits = (*hits).max(count(row.addr));
+    }
+
+    let mut functions = Vec::new();
+    for symbol in symbols.iter().filter(|s| s.func && s.size > 0) {
+        let Some(entry) = table.find(symbol.addr) else {
+            continue;
+        };
+        let start = table.rows.partition_point(|row| row.addr < symbol.addr);
+        let lines: BTreeSet<(&str, u64)> = table.rows[start..]
+            .iter()
+            .take_while(|row| row.addr < symbol.addr + symbol.size)
+            .map(|row| (row.file.as_str(), row.line))
+            .collect();
+        let hit = lines
+            .iter()
+            .filter(|&&(file, line)| files[file][&line] > 0)
+            .count();
+        functions.push(FunctionCoverage {
+            name: symbol.name.clone(),
+            file: entry.file.clone(),
+            line: entry.line,
+            calls: count(symbol.addr),
+            lines: lines.len() as u64,
+            hit: hit as u64,
+        });
+    }
+    functions.sort_by(|a, b| (&a.file, a.line, &a.name).cmp(&(&b.file, b.line, &b.name)));
+    Report { files, functions }
+}
+
+/// One lcov record per source file.
+fn lcov(report: &Report) -> String {
+    let mut info = String::new();
+    for (file, lines) in &report.files {
+        info += &format!("TN:\nSF:{}\n", file);
+        let functions: Vec<_> = report
+            .functions
+            .iter()
+            .filter(|f| &f.file == file)
+            .collect();
+        for function in &functions {
+            info += &format!("FN:{},{}\n", function.line, function.name);
+        }
+        for function in &functions {
+            info += &format!("FNDA:{},{}\n", function.calls, function.name);
+        }
+        let called = functions.iter().filter(|f| f.calls > 0).count();
+        info += &format!("FNF:{}\nFNH:{}\n", functions.len(), called);
+        for (line, count) in lines {
+            info += &format!("DA:{},{}\n", line, count);
+        }
+        let hit = lines.values().filter(|&&count| count > 0).count();
+        info += &format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit);
+    }
+    info
+}
+
+impl Cpu {
+    /// Record coverage from now on, discarding what was recorded before.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Coverage {
+            running: true,
+            ..Coverage::default()
+        });
+    }
+
+    /// Stop recording, keeping the coverage for export.
+    pub fn stop_coverage(&mut self) {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.running = false;
+        }
+    }
+
+    fn coverage_report(&self) -> Result<Report, String> {
+        let coverage = self
+            .coverage
+            .as_ref()
+            .ok_or("no coverage has been recorded")?;
+        let elf = self.elf.as_ref().ok_or("no symbols are loaded")?;
+        let table = elf.lines()?;
+        if table.rows.is_empty() {
+            return Err("the program has no line information; compile it with -g".into());
+        }
+        Ok(report(&table, &elf.symbols, &coverage.counts))
+    }
+
+    /// The coverage recorded so far as an lcov tracefile.
+    pub fn coverage_lcov(&self) -> Result<String, String> {
+        Ok(lcov(&self.coverage_report()?))
+    }
+
+    /// The coverage of each function with line information.
+    pub fn coverage_functions(&self) -> Result<Vec<FunctionCoverage>, String> {
+        Ok(self.coverage_report()?.functions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::dwarf::Line;
+
+    #[test]
+    fn test_lcov_records() {
+        let row = |addr, line| Line {
+            addr,
+            file: "/src/main.c".into(),
+            line,
+        };
+        // main on lines 3-5, of which the branch on line 4 never ran
+        let table = LineTable {
+            rows: vec![row(0x100, 3), row(0x108, 4), row(0x110, 5)],
+            ends: vec![0x118],
+        };
+        let symbols = [Symbol {
+            name: "main".into(),
+            addr: 0x100,
+            size: 0x18,
+            func: true,
+        }];
+        let counts = HashMap::from([(0x100, 1), (0x104, 1), (0x110, 1)]);
+
+        let report = report(&table, &symbols, &counts);
+        assert_eq!(
+            lcov(&report),
+            "TN:\nSF:/src/main.c\nFN:3,main\nFNDA:1,main\nFNF:1\nFNH:1\n\
+             DA:3,1\nDA:4,0\nDA:5,1\nLF:3\nLH:2\nend_of_record\n"
+        );
+        assert_eq!(report.functions[0].lines, 3);
+        assert_eq!(report.functions[0].hit, 2);
+    }
+}
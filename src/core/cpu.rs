@@ -10,26 +10,77 @@ use crate::{
 
 use super::{
     bus::Bus,
+    call_stack::CallStack,
+    commit_log::CommitLog,
+    config::{DeviceConfig, Extension, MachineConfig, Personality},
+    console::Console,
+    coverage::Coverage,
+    csr::{self, Csr},
+    device::{clint::MTIME_OFFSET, PacketQueue},
+    elf::Elf,
     except::Exception,
+    fdt,
+    history::History,
+    htif::Htif,
     isa::IsaDefine,
-    param::{ABINAME, DRAM_BASE, DRAM_END},
+    linux::Linux,
+    lockstep::Lockstep,
+    param::{ABINAME, HISTORY_DEPTH},
+    profile::Profile,
+    rars::Rars,
+    snapshot::Snapshot,
+    trap::Privilege,
 };
 
 use crate::kit::insn::*;
 use crate::vdepart;
 
 pub struct Cpu {
+    pub config: MachineConfig,
     pub regs: [u64; 32],
+    /// Floating-point registers, NaN-boxed when holding single precision.
+    pub fregs: [u64; 32],
     pub pc: u64,
     pub pcimm: u64,
+    pub csr: Csr,
+    pub privilege: Privilege,
+    /// Stalled in `wfi` until an interrupt becomes pending.
+    pub waiting: bool,
+    /// Address reserved by the last `lr`, cleared by `sc` and traps.
+    pub reservation: Option<u64>,
     pub bus: Bus,
+    pub console: Console,
+    pub network: PacketQueue,
     pub running: bool,
+    /// Print each executed instruction.
+    pub trace: bool,
     pub isa_define_map: HashMap<u32, Vec<IsaDefine>>,
     pub breakpoints: Vec<u64>,
+    pub instret: u64,
+    pub history: History,
+    pub snapshots: HashMap<String, Snapshot>,
+    pub boot_snapshot: Option<Snapshot>,
+    /// Set once the program has exited, under a personality that can tell.
+    pub exit_code: Option<u64>,
+    pub(super) linux: Option<Linux>,
+    pub(super) rars: Option<Rars>,
+    pub(super) htif: Option<Htif>,
+    /// The executable last loaded, for its symbols.
+    pub(super) elf: Option<Elf>,
+    /// Trace of retired instructions, while one is being taken.
+    pub commit_log: Option<CommitLog>,
+    /// Comparison of each retired instruction against a reference trace.
+    pub lockstep: Option<Lockstep>,
+    pub profile: Option<Profile>,
+    pub coverage: Option<Coverage>,
+    /// Calls made and not yet returned from, for backtraces.
+    pub call_stack: CallStack,
 }
 
 impl Cpu {
-    pub fn new(code: Vec<u8>) -> Self {
+    /// Build the machine described by `config`, with `code` at the start of
+    /// DRAM, and reset it.
+    pub fn new(config: MachineConfig, code: Vec<u8>) -> Result<Self, String> {
         let mut rng = rand::rng();
 
         let mut regs = [0; 32];
@@ -37,68 +88,149 @@ impl Cpu {
             regs[i] = rng.next_u64(); // Set random values for the rest of the elements
         }
         regs[0] = 0;
-        regs[2] = DRAM_END;
 
-        let bus = Bus::new(code);
+        let console = Console::default();
+        let network = PacketQueue::default();
+        let bus = Bus::new(&config, code, &console, &network)?;
 
-        // for i in (DRAM_BASE..=DRAM_BASE + 0x1c).step_by(8) {
+        // for i in (config.dram_base..=config.dram_base + 0x1c).step_by(8) {
         //     bus.store(i, 64, rng.next_u64()).unwrap();
         // }
 
         let mut map = HashMap::new();
         super::i::register_ext(&mut map);
-        super::m::register_ext(&mut map);
+        super::privileged::register_ext(&mut map);
+        if config.has(Extension::M) {
+            super::m::register_ext(&mut map);
+        }
+        if config.has(Extension::A) {
+            super::a::register_ext(&mut map);
+        }
+        if config.has(Extension::F) {
+            super::f::register_ext(&mut map);
+        }
+        if config.has(Extension::D) {
+            super::d::register_ext(&mut map);
+        }
+        if config.has(Extension::Zicsr) {
+            super::zicsr::register_ext(&mut map);
+        }
+        if config.has(Extension::Zifencei) {
+            super::zifencei::register_ext(&mut map);
+        }
 
-        Self {
+        let mut cpu = Self {
+            csr: Csr::new(config.misa(), 0),
+            privilege: Privilege::Machine,
+            waiting: false,
+            pc: config.reset_vector,
+            config,
             regs,
-            pc: DRAM_BASE,
+            fregs: [0; 32],
+            reservation: None,
             pcimm: 4,
             bus: bus,
+            console,
+            network,
             running: false,
+            trace: true,
             isa_define_map: map,
             breakpoints: Vec::new(),
-        }
+            instret: 0,
+            history: History::new(HISTORY_DEPTH),
+            snapshots: HashMap::new(),
+            boot_snapshot: None,
+            exit_code: None,
+            linux: None,
+            rars: None,
+            htif: None,
+            elf: None,
+            commit_log: None,
+            lockstep: None,
+            profile: None,
+            coverage: None,
+            call_stack: CallStack::default(),
+        };
+        cpu.reset()?;
+        Ok(cpu)
     }
 
-    /// Load a value from a dram.
-    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        self.bus.load(addr, size)
-    }
+    /// Put hart 0 in its reset state, load the boot images and any ELF
+    /// program (starting at its entry point), and place the device tree at
+    /// the top of DRAM, following the boot convention of
+    /// `a0` = hart id and `a1` = DTB address. The stack starts just below the
+    /// DTB. A Linux program is loaded and started in U-mode instead. The
+    /// machine as loaded is kept as the boot snapshot to restart from.
+    ///
+    /// Fails when an image or program can no longer be read, which leaves
+    /// the machine partly loaded.
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.pc = self.config.reset_vector;
+        self.privilege = Privilege::Machine;
+        self.waiting = false;
+        self.reservation = None;
+        self.instret = 0;
+        self.history.clear();
+        self.call_stack = CallStack::default();
+        self.exit_code = None;
+        self.linux = None;
+        self.htif = None;
+        self.elf = None;
+        self.rars = match self.config.personality {
+            Personality::Rars | Personality::Venus => Some(Rars::new(&self.config)),
+            _ => None,
+        };
 
-    /// Store a value to a dram.
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        self.bus.store(addr, size, value)
-    }
+        if let Personality::Linux(_) = self.config.personality {
+            self.start_linux()?;
+            self.boot_snapshot = Some(self.snapshot());
+            return Ok(());
+        }
+        self.load_boot_images()?;
+        self.load_program()?;
 
-    /// Get an instruction from the dram.
-    pub fn fetch(&mut self) -> Result<u64, Exception> {
-        self.bus.load(self.pc, 32)
+        let dtb = fdt::build(&self.config);
+        let top = self.config.dram_base + self.config.dram_size;
+        // the DTB must be 8-byte aligned
+        let addr = top.wrapping_sub(dtb.len() as u64) & !7;
+        if dtb.len() as u64 <= self.config.dram_size / 2 && self.bus.write_dram(addr, &dtb).is_ok()
+        {
+            self.regs[10] = 0;
+            self.regs[11] = addr;
+            self.regs[2] = addr;
+        } else {
+            // too little DRAM to spare for a device tree
+            self.regs[11] = 0;
+            self.regs[2] = self.config.dram_end();
+        }
+        self.boot_snapshot = Some(self.snapshot());
+        Ok(())
     }
 
     pub fn explain(&self, insn: u32) -> String {
+        self.explain_at(self.pc, insn)
+    }
+
+    /// Disassemble `insn` as if it were at `pc`.
+    pub fn explain_at(&self, pc: u64, insn: u32) -> String {
+        // compressed instructions are shown as what they expand to
+        let insn = if insn & 3 != 3 {
+            super::c::expand(insn as u16).unwrap_or(insn)
+        } else {
+            insn
+        };
         let isa_defines = self.isa_define_map.get(&(insn & 0x7f)).cloned();
 
         match isa_defines {
             Some(isa_defines) => {
                 for isa in isa_defines {
-                    if isa.mtype == InsnType::R && (insn & 0xfe00707f) == isa.ident
-                        || isa.mtype == InsnType::I
-                            && [0x1013u32, 0x5013].contains(&(insn & 0x707f))
-                            && (insn & 0xfc00707f) == isa.ident
-                        || (isa.mtype == InsnType::I
-                            && ![0x1013u32, 0x5013].contains(&(insn & 0x707f))
-                            || isa.mtype == InsnType::S
-                            || isa.mtype == InsnType::B)
-                            && (insn & 0x707f) == isa.ident
-                        || (isa.mtype == InsnType::U || isa.mtype == InsnType::J)
-                            && (insn & 0x7f) == isa.ident
-                    {
+                    if isa.matches(insn) {
                         match isa.mtype {
                             InsnType::U => {
                                 let u = vdepart!(insn, InsnType::U);
                                 return format!(
                                     "{:012x}: {}\t{}, 0x{:05x}",
-                                    self.pc, isa.mnemonic, ABINAME[u.rd as usize], u.imm
+                                    pc, isa.mnemonic, ABINAME[u.rd as usize], u.imm
                                 );
                             }
                             InsnType::I => {
@@ -107,7 +239,7 @@ impl Cpu {
                                 if (insn & 0x7f) == 0x03 || (insn & 0x7f) == 0x67 {
                                     return format!(
                                         "{:012x}: {}\t{}, 0x{:x}({})",
-                                        self.pc,
+                                        pc,
                                         isa.mnemonic,
                                         ABINAME[i.rd as usize],
                                         i.imm,
@@ -116,7 +248,7 @@ impl Cpu {
                                 }
                                 return format!(
                                     "{:012x}: {}\t{}, {}, 0x{:03x}",
-                                    self.pc,
+                                    pc,
                                     isa.mnemonic,
                                     ABINAME[i.rd as usize],
                                     ABINAME[i.rs1 as usize],
@@ -127,7 +259,7 @@ impl Cpu {
                                 let s = vdepart!(insn, InsnType::S);
                                 return format!(
                                     "{:012x}: {}\t{}, 0x{:x}({})",
-                                    self.pc,
+                                    pc,
                                     isa.mnemonic,
                                     ABINAME[s.rs1 as usize],
                                     s.imm,
@@ -138,34 +270,41 @@ impl Cpu {
                                 let b = vdepart!(insn, InsnType::B);
                                 return format!(
                                     "{:012x}: {}\t{}, {}, 0x{:x} -> 0x{:x}",
-                                    self.pc,
+                                    pc,
                                     isa.mnemonic,
                                     ABINAME[b.rs1 as usize],
                                     ABINAME[b.rs2 as usize],
                                     b.imm,
-                                    self.pc.wrapping_add(sext(b.imm as u64, 13))
+                                    pc.wrapping_add(sext(b.imm as u64, 13))
                                 );
                             }
                             InsnType::R => {
                                 let r = vdepart!(insn, InsnType::R);
                                 return format!(
                                     "{:012x}: {}\t{}, {}, {}",
-                                    self.pc,
+                                    pc,
                                     isa.mnemonic,
                                     ABINAME[r.rd as usize],
                                     ABINAME[r.rs1 as usize],
                                     ABINAME[r.rs2 as usize]
                                 );
                             }
+                            InsnType::R4 => {
+                                let r = vdepart!(insn, InsnType::R4);
+                                return format!(
+                                    "{:012x}: {}\tf{}, f{}, f{}, f{}",
+                                    pc, isa.mnemonic, r.rd, r.rs1, r.rs2, r.rs3
+                                );
+                            }
                             InsnType::J => {
                                 let j = vdepart!(insn, InsnType::J);
                                 return format!(
                                     "{:012x}: {}\t{}, 0x{:x} -> 0x{:x}",
-                                    self.pc,
+                                    pc,
                                     isa.mnemonic,
                                     ABINAME[j.rd as usize],
                                     j.imm,
-                                    self.pc.wrapping_add(sext(j.imm as u64, 21))
+                                    pc.wrapping_add(sext(j.imm as u64, 21))
                                 );
                             }
                         }
@@ -174,45 +313,35 @@ impl Cpu {
             }
             None => {}
         }
-        format!("{:012x}: ?\t\t0x{:08x}", self.pc, insn)
+        format!("{:012x}: ?\t\t0x{:08x}", pc, insn)
     }
 
     pub fn execute(&mut self, insn: u32) -> Result<u64, Exception> {
         // x0 is hardwired zero
         self.regs[0] = 0;
-        self.pcimm = 4;
 
-        println!("{}", self.explain(insn));
+        if self.trace {
+            println!("{}", self.explain(insn));
+        }
 
-        let isa_defines = self.isa_define_map.get(&(insn & 0x7f)).cloned();
+        let insn = if insn & 3 != 3 {
+            let half = insn & 0xffff;
+            self.pcimm = 2;
+            super::c::expand(half as u16)
+                .filter(|_| self.config.has(Extension::C))
+                .ok_or(Exception::IllegalInstruction(half))?
+        } else {
+            self.pcimm = 4;
+            insn
+        };
 
-        match isa_defines {
-            Some(isa_defines) => {
-                for isa in isa_defines {
-                    if isa.mtype == InsnType::R && (insn & 0xfe00707f) == isa.ident
-                        || isa.mtype == InsnType::I
-                            && [0x1013u32, 0x5013].contains(&(insn & 0x707f))
-                            && (insn & 0xfc00707f) == isa.ident
-                        || (isa.mtype == InsnType::I
-                            && ![0x1013u32, 0x5013].contains(&(insn & 0x707f))
-                            || isa.mtype == InsnType::S
-                            || isa.mtype == InsnType::B)
-                            && (insn & 0x707f) == isa.ident
-                        || (isa.mtype == InsnType::U || isa.mtype == InsnType::J)
-                            && (insn & 0x7f) == isa.ident
-                    {
-                        // Now call the processor with a mutable borrow of `self`
-                        if let Err(e) = (isa.processor)(self, insn) {
-                            return Err(e);
-                        }
-                    }
-                }
-            }
-            None => {
-                dbg!(format!("Invalid opcode: {:#x}", insn));
-                return Err(Exception::IllegalInstruction(insn));
-            }
-        }
+        let isa = self
+            .isa_define_map
+            .get(&(insn & 0x7f))
+            .and_then(|defines| defines.iter().find(|isa| isa.matches(insn)))
+            .cloned()
+            .ok_or(Exception::IllegalInstruction(insn))?;
+        (isa.processor)(self, insn)?;
 
         // x0 is hardwired zero
         self.regs[0] = 0;
@@ -220,7 +349,56 @@ impl Cpu {
         Ok(self.pc.wrapping_add(self.pcimm))
     }
 
+    /// Redirect execution to `target`, which must be aligned to IALIGN.
+    pub fn jump(&mut self, target: u64) -> Result<(), Exception> {
+        let align = if self.config.has(Extension::C) { 2 } else { 4 };
+        if !target.is_multiple_of(align) {
+            return Err(Exception::InstructionAddressMisaligned(target));
+        }
+        self.pc = target;
+        self.pcimm = 0;
+        Ok(())
+    }
+
+    /// Read a CSR, including the counters that live outside the CSR file.
+    pub fn read_csr(&mut self, addr: u32) -> u64 {
+        match addr {
+            csr::CYCLE | csr::INSTRET | csr::MCYCLE | csr::MINSTRET => self.instret,
+            csr::TIME => self.read_mtime(),
+            _ => self.csr.read(addr),
+        }
+    }
+
+    pub fn write_csr(&mut self, addr: u32, value: u64) {
+        let (reg, old) = self.csr.raw(addr);
+        self.history.record_csr(reg, old);
+        if let Some(log) = &mut self.commit_log {
+            log.csr(addr);
+        }
+        self.csr.write(addr, value);
+    }
+
+    /// Switch privilege level, recording the old one for step-back.
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.history.record_privilege(self.privilege);
+        self.privilege = privilege;
+    }
+
+    /// `mtime` of the first CLINT on the bus, or 0 without one.
+    fn read_mtime(&mut self) -> u64 {
+        let clint = self.config.devices.iter().find_map(|dev| match *dev {
+            DeviceConfig::Clint { base, .. } => Some(base),
+            _ => None,
+        });
+        clint.map_or(0, |base| {
+            self.bus.load(base + MTIME_OFFSET, 64).unwrap_or(0)
+        })
+    }
+
     pub fn wgpr(&mut self, id: u32) -> &mut u64 {
+        if let Some(log) = &mut self.commit_log {
+            log.reg(id);
+        }
         &mut self.regs[id as usize]
     }
 
@@ -249,7 +427,7 @@ impl Cpu {
         for address in (begin..=end).step_by(4) {
             vec.push(MemoryValueResponse::new(
                 address,
-                self.bus.load(address, 32).unwrap() as u32,
+                self.bus.peek(address, 32).unwrap() as u32,
             ));
         }
 
@@ -257,3 +435,14 @@ impl Cpu {
         vec
     }
 }
+
+#[cfg(test)]
+impl Cpu {
+    /// A quiet machine for `config` with `code` at the start of DRAM.
+    pub(crate) fn with_code(config: MachineConfig, code: &[u32]) -> Self {
+        let code = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut cpu = Self::new(config, code).unwrap();
+        cpu.trace = false;
+        cpu
+    }
+}
@@ -0,0 +1,353 @@
+// Floating point
+pub const FFLAGS: u32 = 0x001;
+pub const FRM: u32 = 0x002;
+pub const FCSR: u32 = 0x003;
+
+// Supervisor
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SENVCFG: u32 = 0x10a;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+
+// Machine
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MENVCFG: u32 = 0x30a;
+pub const MCOUNTINHIBIT: u32 = 0x320;
+pub const MHPMEVENT3: u32 = 0x323;
+pub const MHPMEVENT31: u32 = 0x33f;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const PMPCFG0: u32 = 0x3a0;
+pub const PMPCFG15: u32 = 0x3af;
+pub const PMPADDR0: u32 = 0x3b0;
+pub const PMPADDR63: u32 = 0x3ef;
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+pub const MHPMCOUNTER3: u32 = 0xb03;
+pub const MHPMCOUNTER31: u32 = 0xb1f;
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+pub const MCONFIGPTR: u32 = 0xf15;
+
+// Unprivileged counters
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const HPMCOUNTER3: u32 = 0xc03;
+pub const HPMCOUNTER31: u32 = 0xc1f;
+
+// mip/mie bits
+pub const SSIP: u64 = 1 << 1;
+pub const MSIP: u64 = 1 << 3;
+pub const STIP: u64 = 1 << 5;
+pub const MTIP: u64 = 1 << 7;
+pub const SEIP: u64 = 1 << 9;
+pub const MEIP: u64 = 1 << 11;
+
+// mstatus bits
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 3 << 11;
+pub const MSTATUS_FS: u64 = 3 << 13;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+// UXL and SXL, both fixed at 64 bits
+pub const MSTATUS_XLEN: u64 = 0xa << 32;
+const MSTATUS_SD: u64 = 1 << 63;
+
+// satp fields; only Bare and Sv39 are accepted
+pub const SATP_MODE_SV39: u64 = 8;
+const SATP_PPN: u64 = (1 << 44) - 1;
+
+// misa bits consulted by the CSR file
+const MISA_C: u64 = 1 << 2;
+const MISA_F: u64 = 1 << 5;
+
+// mstatus bits visible through sstatus
+const SSTATUS_MASK: u64 = 0x8000_0003_000d_e762;
+// mstatus bits software may write
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+// mip bits software may write; the rest are driven by devices
+const MIP_WRITABLE: u64 = SSIP | STIP | SEIP;
+const MIE_WRITABLE: u64 = SSIP | MSIP | STIP | MTIP | SEIP | MEIP;
+// environment calls from M-mode cannot be delegated
+const MEDELEG_WRITABLE: u64 = 0xb3ff;
+// pmpaddr holds bits 55:2 of an address
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+/// Control and status registers of one hart.
+///
+/// Supervisor views (`sstatus`, `sie`, `sip`) alias their machine
+/// counterparts, and `mip` reads as the software-written bits ORed with the
+/// interrupt lines driven by devices. WARL fields only keep legal values.
+#[derive(Clone)]
+pub struct Csr {
+    regs: Vec<u64>,
+    /// mip bits currently asserted by devices.
+    pub lines: u64,
+}
+
+impl Csr {
+    pub fn new(misa: u64, hartid: u64) -> Self {
+        let mut regs = vec![0; 4096];
+        regs[MISA as usize] = misa;
+        regs[MHARTID as usize] = hartid;
+        Self { regs, lines: 0 }
+    }
+
+    /// Whether `addr` names a CSR this hart implements.
+    pub fn exists(&self, addr: u32) -> bool {
+        match addr {
+            FFLAGS | FRM | FCSR => self.regs[MISA as usize] & MISA_F != 0,
+            // RV64 only has the even pmpcfg registers
+            PMPCFG0..=PMPCFG15 => addr.is_multiple_of(2),
+            SSTATUS | SIE | STVEC | SCOUNTEREN | SENVCFG | SSCRATCH | SEPC | SCAUSE | STVAL
+            | SIP | SATP | MSTATUS | MISA | MEDELEG | MIDELEG | MIE | MTVEC | MCOUNTEREN
+            | MENVCFG | MCOUNTINHIBIT | MSCRATCH | MEPC | MCAUSE | MTVAL | MIP | MCYCLE
+            | MINSTRET | CYCLE | TIME | INSTRET | MVENDORID | MARCHID | MIMPID | MHARTID
+            | MCONFIGPTR => true,
+            MHPMEVENT3..=MHPMEVENT31
+            | PMPADDR0..=PMPADDR63
+            | MHPMCOUNTER3..=MHPMCOUNTER31
+            | HPMCOUNTER3..=HPMCOUNTER31 => true,
+            _ => false,
+        }
+    }
+
+    pub fn read(&self, addr: u32) -> u64 {
+        let addr = addr & 0xfff;
+        match addr {
+            FFLAGS => self.regs[FCSR as usize] & 0x1f,
+            FRM => self.regs[FCSR as usize] >> 5 & 7,
+            SSTATUS => self.read(MSTATUS) & SSTATUS_MASK,
+            SIE => self.regs[MIE as usize] & self.regs[MIDELEG as usize],
+            SIP => self.read(MIP) & self.regs[MIDELEG as usize],
+            MSTATUS => {
+                let mstatus = self.regs[MSTATUS as usize] | MSTATUS_XLEN;
+                if mstatus & MSTATUS_FS == MSTATUS_FS {
+                    mstatus | MSTATUS_SD
+                } else {
+                    mstatus
+                }
+            }
+            MIP => self.regs[MIP as usize] | self.lines,
+            _ => self.regs[addr as usize],
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, value: u64) {
+        let addr = addr & 0xfff;
+        match addr {
+            FFLAGS => self.set_masked(FCSR, 0x1f, value),
+            FRM => self.set_masked(FCSR, 0xe0, value << 5),
+            FCSR => self.regs[addr as usize] = value & 0xff,
+            SSTATUS => self.set_masked(MSTATUS, SSTATUS_MASK & MSTATUS_WRITABLE, value),
+            SIE => {
+                let mask = self.regs[MIDELEG as usize];
+                self.set_masked(MIE, mask, value);
+            }
+            SIP => {
+                let mask = self.regs[MIDELEG as usize] & SSIP;
+                self.set_masked(MIP, mask, value);
+            }
+            SATP => match value >> 60 {
+                0 | SATP_MODE_SV39 => self.regs[addr as usize] = value & (0xf << 60 | SATP_PPN),
+                // unsupported modes leave satp unchanged
+                _ => {}
+            },
+            MSTATUS => {
+                let mut value = value;
+                // MPP cannot hold the reserved level 2
+                if value & MSTATUS_MPP == 2 << 11 {
+                    value &= !MSTATUS_MPP;
+                }
+                self.set_masked(MSTATUS, MSTATUS_WRITABLE, value);
+            }
+            MEDELEG => self.regs[addr as usize] = value & MEDELEG_WRITABLE,
+            MIDELEG => self.regs[addr as usize] = value & (SSIP | STIP | SEIP),
+            MIE => self.regs[addr as usize] = value & MIE_WRITABLE,
+            MIP => self.set_masked(MIP, MIP_WRITABLE, value),
+            // only Direct and Vectored modes
+            MTVEC | STVEC => self.regs[addr as usize] = value & !(value & 2),
+            MEPC | SEPC => self.regs[addr as usize] = value & !self.ialign_mask(),
+            MCOUNTEREN | SCOUNTEREN => self.regs[addr as usize] = value & 0xffff_ffff,
+            MENVCFG | SENVCFG => self.regs[addr as usize] = value & 1,
+            PMPADDR0..=PMPADDR63 => self.regs[addr as usize] = value & PMPADDR_MASK,
+            // read-only, or hardwired to zero
+            MISA | MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR => {}
+            MHPMEVENT3..=MHPMEVENT31 | MHPMCOUNTER3..=MHPMCOUNTER31 => {}
+            _ => self.regs[addr as usize] = value,
+        }
+    }
+
+    /// Low pc bits that must be clear: IALIGN is 16 with the C extension
+    /// and 32 without.
+    fn ialign_mask(&self) -> u64 {
+        if self.regs[MISA as usize] & MISA_C != 0 {
+            1
+        } else {
+            3
+        }
+    }
+
+    fn set_masked(&mut self, addr: u32, mask: u64, value: u64) {
+        let reg = &mut self.regs[addr as usize];
+        *reg = (*reg & !mask) | (value & mask);
+    }
+
+    /// The stored bits behind `addr` as (register, value), for undo: the
+    /// M-mode register under an S-mode view, `fcsr` under its fields, and
+    /// without the device lines or read-only bits `read` merges in.
+    pub fn raw(&self, addr: u32) -> (u32, u64) {
+        let reg = match addr & 0xfff {
+            FFLAGS | FRM => FCSR,
+            SSTATUS => MSTATUS,
+            SIE => MIE,
+            SIP => MIP,
+            addr => addr,
+        };
+        (reg, self.regs[reg as usize])
+    }
+
+    /// Put back a value taken with `raw`.
+    pub fn set_raw(&mut self, reg: u32, value: u64) {
+        self.regs[reg as usize] = value;
+    }
+
+    /// Non-zero registers as (address, value), for snapshots.
+    pub fn dump(&self) -> Vec<(u32, u64)> {
+        self.regs
+            .iter()
+            .enumerate()
+            .filter(|(_, &v)| v != 0)
+            .map(|(a, &v)| (a as u32, v))
+            .collect()
+    }
+
+    pub fn load(&mut self, entries: &[(u32, u64)]) {
+        self.regs.fill(0);
+        for &(addr, value) in entries {
+            self.regs[(addr & 0xfff) as usize] = value;
+        }
+    }
+}
+
+/// The assembler name of a CSR, as Spike prints it in commit logs.
+pub fn name(addr: u32) -> String {
+    let name = match addr {
+        FFLAGS => "fflags",
+        FRM => "frm",
+        FCSR => "fcsr",
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        SENVCFG => "senvcfg",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        MENVCFG => "menvcfg",
+        MCOUNTINHIBIT => "mcountinhibit",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        MCONFIGPTR => "mconfigptr",
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        MHPMEVENT3..=MHPMEVENT31 => return format!("mhpmevent{}", addr - MHPMEVENT3 + 3),
+        PMPCFG0..=PMPCFG15 => return format!("pmpcfg{}", addr - PMPCFG0),
+        PMPADDR0..=PMPADDR63 => return format!("pmpaddr{}", addr - PMPADDR0),
+        MHPMCOUNTER3..=MHPMCOUNTER31 => return format!("mhpmcounter{}", addr - MHPMCOUNTER3 + 3),
+        HPMCOUNTER3..=HPMCOUNTER31 => return format!("hpmcounter{}", addr - HPMCOUNTER3 + 3),
+        _ => return format!("csr{}", addr),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supervisor_views_alias_machine_registers() {
+        let mut csr = Csr::new(0, 0);
+        csr.write(MIDELEG, SSIP | STIP | SEIP);
+        csr.write(SIE, MTIP | STIP);
+        assert_eq!(csr.read(MIE), STIP);
+
+        // the sstatus view leaves machine-only fields alone
+        csr.write(MSTATUS, MSTATUS_MPP);
+        csr.write(SSTATUS, u64::MAX);
+        assert_eq!(
+            csr.read(MSTATUS),
+            MSTATUS_MPP | (MSTATUS_WRITABLE & SSTATUS_MASK) | MSTATUS_XLEN | MSTATUS_SD
+        );
+    }
+
+    #[test]
+    fn test_mip_merges_device_lines() {
+        let mut csr = Csr::new(0, 0);
+        csr.write(MIP, MTIP | SSIP);
+        assert_eq!(csr.read(MIP), SSIP);
+
+        csr.lines = MTIP;
+        assert_eq!(csr.read(MIP), SSIP | MTIP);
+        assert_eq!(csr.read(SIP), 0);
+    }
+}
@@ -0,0 +1,85 @@
+//! D: double-precision floating point, built from the generic definitions
+//! in the F extension.
+
+use std::collections::HashMap;
+
+use super::{
+    f::{self, OP_MADD, OP_MSUB, OP_NMADD, OP_NMSUB},
+    isa::{install, IsaDefine},
+};
+
+pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
+    install(map, f::load::<f64>("fld"));
+    install(map, f::store::<f64>("fsd"));
+    install(map, f::arith::<f64>("fadd.d", 0x00, f::add));
+    install(map, f::arith::<f64>("fsub.d", 0x01, f::sub));
+    install(map, f::arith::<f64>("fmul.d", 0x02, f::mul));
+    install(map, f::arith::<f64>("fdiv.d", 0x03, f::div));
+    install(map, f::fsqrt::<f64>("fsqrt.d"));
+    install(map, f::fused_op::<f64>("fmadd.d", OP_MADD, false, false));
+    install(map, f::fused_op::<f64>("fmsub.d", OP_MSUB, false, true));
+    install(map, f::fused_op::<f64>("fnmsub.d", OP_NMSUB, true, false));
+    install(map, f::fused_op::<f64>("fnmadd.d", OP_NMADD, true, true));
+    install(map, f::sign_inject::<f64>("fsgnj.d", 0));
+    install(map, f::sign_inject::<f64>("fsgnjn.d", 1));
+    install(map, f::sign_inject::<f64>("fsgnjx.d", 2));
+    install(map, f::min_max_op::<f64>("fmin.d", false));
+    install(map, f::min_max_op::<f64>("fmax.d", true));
+    install(map, f::compare::<f64>("feq.d", 2));
+    install(map, f::compare::<f64>("flt.d", 1));
+    install(map, f::compare::<f64>("fle.d", 0));
+    install(map, f::fclass::<f64>("fclass.d"));
+    install(map, f::move_to_int::<f64>("fmv.x.d"));
+    install(map, f::move_from_int::<f64>("fmv.d.x"));
+    install(map, f::convert_to_int::<f64>("fcvt.w.d", 0));
+    install(map, f::convert_to_int::<f64>("fcvt.wu.d", 1));
+    install(map, f::convert_to_int::<f64>("fcvt.l.d", 2));
+    install(map, f::convert_to_int::<f64>("fcvt.lu.d", 3));
+    install(map, f::convert_from_int::<f64>("fcvt.d.w", 0));
+    install(map, f::convert_from_int::<f64>("fcvt.d.wu", 1));
+    install(map, f::convert_from_int::<f64>("fcvt.d.l", 2));
+    install(map, f::convert_from_int::<f64>("fcvt.d.lu", 3));
+    install(map, f::convert::<f32, f64>("fcvt.s.d"));
+    install(map, f::convert::<f64, f32>("fcvt.d.s"));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{
+        config::{Extension, MachineConfig},
+        cpu::Cpu,
+        csr,
+        except::Exception,
+    };
+
+    const A0: usize = 10;
+    const A2: usize = 12;
+
+    #[test]
+    fn test_conversions_and_boxing() {
+        let config = MachineConfig::builder()
+            .extensions(&[Extension::I, Extension::F, Extension::D, Extension::Zicsr])
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::with_code(config, &[]);
+        cpu.regs[A0] = -3i64 as u64;
+
+        // fcvt.d.w f1, a0 is illegal until mstatus.FS is turned on
+        cpu.write_csr(csr::MSTATUS, 0);
+        assert_eq!(
+            cpu.execute(0xd20570d3),
+            Err(Exception::IllegalInstruction(0xd20570d3))
+        );
+        cpu.write_csr(csr::MSTATUS, csr::MSTATUS_FS);
+        cpu.execute(0xd20570d3).unwrap();
+        assert_eq!(f64::from_bits(cpu.fregs[1]), -3.0);
+
+        // fcvt.s.d f2, f1 NaN-boxes the single
+        cpu.execute(0x4010f153).unwrap();
+        assert_eq!(cpu.fregs[2], 0xffff_ffff_c040_0000);
+        // fsgnjn.d f3, f1, f1 then fmv.x.d a2, f3
+        cpu.execute(0x221091d3).unwrap();
+        cpu.execute(0xe2018653).unwrap();
+        assert_eq!(cpu.regs[A2], 3.0f64.to_bits());
+    }
+}
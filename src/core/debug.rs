@@ -0,0 +1,499 @@
+use std::fmt;
+
+use super::{c, config::Personality, cpu::Cpu, csr, except::Exception};
+
+const RA: u32 = 1;
+const SP: u32 = 2;
+
+const WFI: u32 = 0x10500073;
+
+// Faults taken in a row within one step before giving up, e.g. when the
+// handler itself cannot be fetched
+const NESTED_TRAPS: usize = 4;
+
+/// Why a stepping command handed control back to the caller.
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    Stepped,
+    Breakpoint(u64),
+    Reached(u64),
+    Returned,
+    Budget(u64),
+    Exception(Exception),
+    SteppedBack,
+    Arrived(u64),
+    NoHistory,
+    /// History ends at an instruction that accessed a device or changed
+    /// host state through an emulated system call.
+    DeviceAccess,
+    Exited(u64),
+    /// The run no longer matches the reference trace; holds the report.
+    Diverged(String),
+    /// Reached the first statement of a source line: file and line.
+    Line(String, u64),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Stepped => write!(f, "Stepped."),
+            StopReason::Breakpoint(addr) => write!(f, "Breakpoint hit at 0x{:016x}.", addr),
+            StopReason::Reached(addr) => write!(f, "Reached 0x{:016x}.", addr),
+            StopReason::Returned => write!(f, "Returned from frame."),
+            StopReason::Budget(n) => write!(f, "Instruction budget of {} exhausted.", n),
+            StopReason::Exception(e) => write!(f, "Stopped on exception: {:?}.", e),
+            StopReason::SteppedBack => write!(f, "Stepped back."),
+            StopReason::Arrived(n) => write!(f, "Arrived at instruction #{}.", n),
+            StopReason::NoHistory => write!(f, "No earlier history recorded."),
+            StopReason::DeviceAccess => {
+                write!(f, "Cannot step back across a device access or system call.")
+            }
+            StopReason::Exited(0) => write!(f, "Program exited: pass."),
+            StopReason::Exited(code) => write!(f, "Program exited: fail, status {}.", code),
+            StopReason::Diverged(report) => write!(f, "{}", report.trim_end()),
+            StopReason::Line(file, line) => write!(f, "Stepped to {}:{}.", file, line),
+        }
+    }
+}
+
+/// The 32-bit form of `insn`, expanding compressed instructions.
+fn expand(insn: u32) -> u32 {
+    if insn & 3 == 3 {
+        insn
+    } else {
+        c::expand(insn as u16).unwrap_or(insn)
+    }
+}
+
+/// `jal ra, ...` or `jalr ra, ...(...)`.
+pub(super) fn is_call(insn: u32) -> bool {
+    let insn = expand(insn);
+    let rd = (insn >> 7) & 0x1f;
+    let op = insn & 0x7f;
+    rd == RA && (op == 0x6f || (op == 0x67 && (insn >> 12) & 0x7 == 0))
+}
+
+/// `jalr x0, 0(ra)`, i.e. `ret`.
+pub(super) fn is_return(insn: u32) -> bool {
+    expand(insn) == 0x00008067
+}
+
+impl Cpu {
+    /// Fetch and execute a single instruction, advancing pc on success.
+    ///
+    /// A pending interrupt is taken first, so the instruction executed is the
+    /// first one of its handler; likewise an exception with a handler installed
+    /// traps and the handler's first instruction runs in its place. Exceptions
+    /// nothing would handle are returned. While stalled in `wfi` no
+    /// instruction is executed; devices still tick so that time passes. The
+    /// same holds once the program has exited.
+    ///
+    /// Under the Linux, RARS and Venus personalities an `ecall` is served by
+    /// the emulator and completes without trapping.
+    ///
+    /// A step that accesses a device register, during which a device writes
+    /// guest memory, or whose emulated system call changes host state, ends
+    /// the history that `step_back` can undo.
+    pub fn step(&mut self) -> Result<u32, Exception> {
+        let side_effects = self.bus.side_effects();
+        let result = self.advance();
+        if self.bus.side_effects() != side_effects {
+            self.history.barrier();
+        }
+        result
+    }
+
+    fn advance(&mut self) -> Result<u32, Exception> {
+        self.csr.lines = self.bus.mip(0);
+        if self.exit_code.is_some() {
+            self.bus.tick();
+            return Ok(WFI);
+        }
+        if self.waiting {
+            // wfi wakes on any enabled interrupt, even with mstatus.xIE clear
+            if self.csr.read(csr::MIP) & self.csr.read(csr::MIE) == 0 {
+                self.bus.tick();
+                return Ok(WFI);
+            }
+            self.waiting = false;
+        }
+
+        let (pc, regs) = (self.pc, self.regs);
+        if let Some(cause) = self.pending_interrupt() {
+            self.trap(cause, 0);
+        }
+
+        // where the instruction that retires was fetched, as jumps move pc
+        // before they complete
+        let mut insn_pc = self.pc;
+        let mut result = self.fetch_execute();
+        if let Err(e) = result {
+            result = self.emulated_ecall(e).unwrap_or(result);
+        }
+        for _ in 0..NESTED_TRAPS {
+            match result {
+                Err(e) if self.has_handler(e.code()) => {
+                    self.trap(e.code(), e.tval());
+                    insn_pc = self.pc;
+                    result = self.fetch_execute();
+                }
+                _ => break,
+            }
+        }
+        match result {
+            Ok((insn, next)) => {
+                self.call_stack
+                    .retire(insn_pc, insn, next, self.regs[SP as usize]);
+                if let Some(profile) = self.profile.as_mut().filter(|p| p.running) {
+                    profile.retire(insn_pc, insn, next, &self.call_stack);
+                }
+                if let Some(coverage) = self.coverage.as_mut().filter(|c| c.running) {
+                    coverage.retire(insn_pc);
+                }
+                self.pc = next;
+                self.instret += 1;
+                self.bus.tick();
+                self.history.commit(pc, &regs, &self.regs);
+                if let Some(log) = &mut self.commit_log {
+                    log.retire(insn, &self.regs, &self.fregs, &self.csr);
+                }
+                self.check_lockstep();
+                self.poll_htif();
+                Ok(insn)
+            }
+            Err(e) => {
+                self.history.discard();
+                Err(e)
+            }
+        }
+    }
+
+    /// Serve an environment call in the emulator, for personalities where
+    /// `ecall` does not reach the guest.
+    fn emulated_ecall(&mut self, e: Exception) -> Option<Result<(u32, u64), Exception>> {
+        match (&self.config.personality, e) {
+            (Personality::Linux(_), Exception::EnvironmentCallFromUMode) => Some(self.syscall()),
+            (
+                Personality::Rars | Personality::Venus,
+                Exception::EnvironmentCallFromUMode
+                | Exception::EnvironmentCallFromSMode
+                | Exception::EnvironmentCallFromMMode,
+            ) => Some(self.rars_ecall(e)),
+            _ => None,
+        }
+    }
+
+    fn fetch_execute(&mut self) -> Result<(u32, u64), Exception> {
+        if let Some(log) = &mut self.commit_log {
+            log.begin(self.privilege, self.pc);
+        }
+        let insn = self.fetch()? as u32;
+        let next = self.execute(insn)?;
+        Ok((insn, next))
+    }
+
+    /// Execute up to `count` instructions, stopping early on breakpoints.
+    pub fn step_n(&mut self, count: u64) -> StopReason {
+        for i in 0..count {
+            if let Err(e) = self.step() {
+                return StopReason::Exception(e);
+            }
+            if let Some(reason) = self.halted() {
+                return reason;
+            }
+            if i + 1 < count && self.breakpoints.contains(&self.pc) {
+                return StopReason::Breakpoint(self.pc);
+            }
+        }
+        StopReason::Stepped
+    }
+
+    /// Why running must stop after the last step, breakpoints aside: the
+    /// program exited, or no longer matches the lockstep reference, which
+    /// is then dropped.
+    pub(super) fn halted(&mut self) -> Option<StopReason> {
+        if let Some(code) = self.exit_code {
+            return Some(StopReason::Exited(code));
+        }
+        let report = self.lockstep.as_mut()?.divergence.take()?;
+        self.lockstep = None;
+        Some(StopReason::Diverged(report))
+    }
+
+    /// Like `step`, but a call is executed until it returns to the next instruction.
+    ///
+    /// A recursive callee passes the same address on a deeper stack, so the
+    /// call has only returned once `sp` is back to at least its value now.
+    pub fn step_over(&mut self, budget: u64) -> StopReason {
+        let insn = match self.fetch() {
+            Ok(insn) => insn as u32,
+            Err(e) => return StopReason::Exception(e),
+        };
+
+        if is_call(insn) {
+            let len = if insn & 3 == 3 { 4 } else { 2 };
+            let (next, sp) = (self.pc.wrapping_add(len), self.rgpr(SP));
+            self.run_to(budget, |cpu| cpu.pc == next && cpu.rgpr(SP) >= sp)
+        } else {
+            self.step_n(1)
+        }
+    }
+
+    /// Run until the current frame returns to its caller.
+    ///
+    /// Nested calls are tracked by depth, and a `ret` only ends the frame once
+    /// `sp` has been restored to at least its value on entry.
+    pub fn step_out(&mut self, budget: u64) -> StopReason {
+        let sp = self.rgpr(SP);
+        let mut depth = 0u64;
+
+        for _ in 0..budget {
+            let insn = match self.step() {
+                Ok(insn) => insn,
+                Err(e) => return StopReason::Exception(e),
+            };
+            if let Some(reason) = self.halted() {
+                return reason;
+            }
+
+            if is_call(insn) {
+                depth += 1;
+            } else if is_return(insn) {
+                if depth == 0 && self.rgpr(SP) >= sp {
+                    return StopReason::Returned;
+                }
+                depth = depth.saturating_sub(1);
+            }
+
+            if self.breakpoints.contains(&self.pc) {
+                return StopReason::Breakpoint(self.pc);
+            }
+        }
+        StopReason::Budget(budget)
+    }
+
+    /// Run until pc reaches `target` (if any) or a breakpoint, within `budget` instructions.
+    pub fn run_until(&mut self, target: Option<u64>, budget: u64) -> StopReason {
+        self.run_to(budget, |cpu| target == Some(cpu.pc))
+    }
+
+    /// Run until `arrived` holds after a step, or a breakpoint, within
+    /// `budget` instructions.
+    fn run_to(&mut self, budget: u64, arrived: impl Fn(&Self) -> bool) -> StopReason {
+        for _ in 0..budget {
+            if let Err(e) = self.step() {
+                return StopReason::Exception(e);
+            }
+            if let Some(reason) = self.halted() {
+                return reason;
+            }
+            if arrived(self) {
+                return StopReason::Reached(self.pc);
+            }
+            if self.breakpoints.contains(&self.pc) {
+                return StopReason::Breakpoint(self.pc);
+            }
+        }
+        StopReason::Budget(budget)
+    }
+
+    /// Undo the most recently retired instruction.
+    pub fn step_back(&mut self) -> StopReason {
+        if self.history.at_barrier() {
+            return StopReason::DeviceAccess;
+        }
+        let Some(record) = self.history.pop() else {
+            return StopReason::NoHistory;
+        };
+
+        // only DRAM stores are recorded; putting them back through the bus
+        // would run device side effects again
+        for &(addr, size, old) in record.mem.iter().rev() {
+            self.bus.restore_dram_word(addr, size, old);
+        }
+        for &(reg, old) in record.csrs.iter().rev() {
+            self.csr.set_raw(reg, old);
+        }
+        for &(id, old) in &record.regs {
+            self.regs[id as usize] = old;
+        }
+        for &(id, old) in record.fregs.iter().rev() {
+            self.fregs[id as usize] = old;
+        }
+        if let Some(privilege) = record.privilege {
+            self.privilege = privilege;
+        }
+        // every recorded instruction ran with the hart awake and the
+        // program still running
+        self.waiting = false;
+        self.exit_code = None;
+        self.pc = record.pc;
+        self.instret = self.instret.saturating_sub(1);
+        self.call_stack.invalidate();
+        StopReason::SteppedBack
+    }
+
+    /// Step backwards until pc lands on a breakpoint or history runs out.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        loop {
+            match self.step_back() {
+                StopReason::SteppedBack if self.breakpoints.contains(&self.pc) => {
+                    return StopReason::Breakpoint(self.pc);
+                }
+                StopReason::SteppedBack => {}
+                reason => return reason,
+            }
+        }
+    }
+
+    /// Move to the point where exactly `instret` instructions have retired,
+    /// rewinding through history or executing forward as needed.
+    pub fn goto(&mut self, instret: u64, budget: u64) -> StopReason {
+        while self.instret > instret {
+            match self.step_back() {
+                StopReason::SteppedBack => {}
+                reason => return reason,
+            }
+        }
+
+        if instret - self.instret > budget {
+            return StopReason::Budget(budget);
+        }
+        // cycles stalled in wfi retire nothing, so count steps separately
+        for _ in 0..budget {
+            if self.instret == instret {
+                return StopReason::Arrived(self.instret);
+            }
+            if let Err(e) = self.step() {
+                return StopReason::Exception(e);
+            }
+        }
+        if self.instret == instret {
+            StopReason::Arrived(self.instret)
+        } else {
+            StopReason::Budget(budget)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::MachineConfig;
+
+    const T0: usize = 5;
+
+    fn machine(code: &[u32]) -> Cpu {
+        Cpu::with_code(MachineConfig::default(), code)
+    }
+
+    // main: a0 = 2, then f(), which calls itself until a0 reaches zero
+    const RECURSION: [u32; 11] = [
+        0x00200513, // li a0, 2
+        0x008000ef, // jal ra, f
+        0x0000006f, // j .
+        0xff010113, // f: addi sp, sp, -16
+        0x00113423, // sd ra, 8(sp)
+        0xfff50513, // addi a0, a0, -1
+        0x00050463, // beqz a0, 1f
+        0xff1ff0ef, // jal ra, f
+        0x00813083, // 1: ld ra, 8(sp)
+        0x01010113, // addi sp, sp, 16
+        0x00008067, // ret
+    ];
+
+    #[test]
+    fn test_step_n_and_run_until_stop_where_asked() {
+        let mut cpu = machine(&RECURSION);
+        cpu.regs[SP as usize] = 0x1000;
+        cpu.breakpoints.push(12);
+        assert_eq!(cpu.step_n(10), StopReason::Breakpoint(12));
+        assert_eq!(cpu.instret, 2);
+        assert_eq!(cpu.step_n(2), StopReason::Stepped);
+        assert_eq!(cpu.pc, 20);
+
+        // the first time through, in the innermost call
+        cpu.breakpoints.clear();
+        assert_eq!(cpu.run_until(Some(32), 100), StopReason::Reached(32));
+        assert_eq!(cpu.regs[SP as usize], 0x1000 - 32);
+        assert_eq!(cpu.run_until(None, 5), StopReason::Budget(5));
+    }
+
+    #[test]
+    fn test_step_over_and_out_follow_frames() {
+        let mut cpu = machine(&RECURSION);
+        cpu.regs[SP as usize] = 0x1000;
+        assert_eq!(cpu.step_over(100), StopReason::Stepped);
+        cpu.step_n(5);
+        assert_eq!(cpu.pc, 28);
+
+        // the recursive call passes 32 on a deeper stack first
+        assert_eq!(cpu.step_over(100), StopReason::Reached(32));
+        assert_eq!(cpu.regs[SP as usize], 0x1000 - 16);
+        assert_eq!(cpu.regs[10], 0);
+
+        assert_eq!(cpu.step_out(100), StopReason::Returned);
+        assert_eq!((cpu.pc, cpu.regs[SP as usize]), (8, 0x1000));
+
+        // from the top of f, the nested call returns first
+        let mut cpu = machine(&RECURSION);
+        cpu.regs[SP as usize] = 0x1000;
+        cpu.step_n(2);
+        assert_eq!(cpu.step_out(100), StopReason::Returned);
+        assert_eq!((cpu.pc, cpu.regs[SP as usize]), (8, 0x1000));
+    }
+
+    #[test]
+    fn test_step_back_restores_stored_csr_bits() {
+        let mut cpu = machine(&[]);
+        // a device line shows in mip but is not one of its stored bits
+        cpu.csr.lines = csr::SEIP;
+        let regs = cpu.regs;
+        cpu.write_csr(csr::MIP, csr::SSIP);
+        cpu.history.commit(cpu.pc, &regs, &regs);
+        assert_eq!(cpu.csr.read(csr::MIP), csr::SEIP | csr::SSIP);
+
+        assert_eq!(cpu.step_back(), StopReason::SteppedBack);
+        cpu.csr.lines = 0;
+        assert_eq!(cpu.csr.read(csr::MIP), 0);
+    }
+
+    #[test]
+    fn test_step_back_stops_at_device_accesses() {
+        let mut cpu = machine(&[
+            0x00500293, // li t0, 5
+            0x10502023, // sw t0, 0x100(zero)
+            0x00128293, // addi t0, t0, 1
+            0x10000337, // lui t1, 0x10000 (the UART)
+            0x00530023, // sb t0, 0(t1)
+            0x00128293, // addi t0, t0, 1
+            0x0000006f, // j .
+        ]);
+        cpu.step_n(3);
+        assert_eq!((cpu.regs[T0], cpu.bus.peek(0x100, 32)), (6, Ok(5)));
+
+        assert_eq!(cpu.step_back(), StopReason::SteppedBack);
+        assert_eq!(cpu.step_back(), StopReason::SteppedBack);
+        assert_eq!((cpu.pc, cpu.instret), (4, 1));
+        assert_eq!((cpu.regs[T0], cpu.bus.peek(0x100, 32)), (5, Ok(0)));
+
+        assert_eq!(cpu.goto(3, 10), StopReason::Arrived(3));
+        assert_eq!(
+            (cpu.pc, cpu.regs[T0], cpu.bus.peek(0x100, 32)),
+            (12, 6, Ok(5))
+        );
+
+        cpu.breakpoints.push(4);
+        assert_eq!(cpu.reverse_continue(), StopReason::Breakpoint(4));
+        assert_eq!(cpu.reverse_continue(), StopReason::NoHistory);
+        assert_eq!(cpu.pc, 0);
+
+        // the store to the UART cannot be taken back
+        assert_eq!(cpu.goto(6, 10), StopReason::Arrived(6));
+        assert_eq!(cpu.step_back(), StopReason::SteppedBack);
+        assert_eq!(cpu.pc, 20);
+        assert_eq!(cpu.step_back(), StopReason::DeviceAccess);
+        assert_eq!(cpu.goto(0, 10), StopReason::DeviceAccess);
+        assert_eq!((cpu.pc, cpu.instret, cpu.regs[T0]), (20, 5, 6));
+    }
+}
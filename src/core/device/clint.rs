@@ -0,0 +1,219 @@
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use super::Device;
+use crate::core::{
+    csr::{MSIP, MTIP},
+    except::Exception,
+    param::TIMEBASE_FREQUENCY,
+};
+
+pub const CLINT_SIZE: u64 = 0x10000;
+
+const MSIP_BASE: u64 = 0x0;
+const MTIMECMP_BASE: u64 = 0x4000;
+const MTIME: u64 = MTIME_OFFSET;
+
+/// Offset of `mtime` from the CLINT base.
+pub const MTIME_OFFSET: u64 = 0xbff8;
+
+/// What advances `mtime`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClockSource {
+    /// One tick per retired instruction, so runs are reproducible.
+    #[default]
+    Instret,
+    /// Host wall clock at `TIMEBASE_FREQUENCY`.
+    Host,
+}
+
+/// SiFive-compatible core-local interruptor: `msip`, `mtimecmp` per hart and
+/// a shared `mtime`.
+pub struct Clint {
+    clock: ClockSource,
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    // instret mode: the counter itself; host mode: the value at `epoch`
+    mtime: u64,
+    epoch: Instant,
+}
+
+impl Clint {
+    pub fn new(harts: usize, clock: ClockSource) -> Self {
+        Self {
+            clock,
+            msip: vec![false; harts],
+            mtimecmp: vec![u64::MAX; harts],
+            mtime: 0,
+            epoch: Instant::now(),
+        }
+    }
+
+    fn mtime(&self) -> u64 {
+        match self.clock {
+            ClockSource::Instret => self.mtime,
+            ClockSource::Host => {
+                let elapsed = self.epoch.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128;
+                self.mtime.wrapping_add((elapsed / 1_000_000_000) as u64)
+            }
+        }
+    }
+
+    fn set_mtime(&mut self, value: u64) {
+        self.mtime = value;
+        self.epoch = Instant::now();
+    }
+
+    /// Locate the 64-bit register containing `offset`, as (register, hart).
+    fn decode(&self, offset: u64) -> Option<(u64, usize)> {
+        let harts = self.msip.len() as u64;
+        match offset {
+            o if (MSIP_BASE..MSIP_BASE + 4 * harts).contains(&o) => {
+                Some((MSIP_BASE, ((o - MSIP_BASE) / 4) as usize))
+            }
+            o if (MTIMECMP_BASE..MTIMECMP_BASE + 8 * harts).contains(&o) => {
+                Some((MTIMECMP_BASE, ((o - MTIMECMP_BASE) / 8) as usize))
+            }
+            o if (MTIME..MTIME + 8).contains(&o) => Some((MTIME, 0)),
+            _ => None,
+        }
+    }
+}
+
+/// Extract `size` bits at byte `shift` of `reg`.
+fn read_part(reg: u64, shift: u64, size: u64) -> u64 {
+    let value = reg >> (shift * 8);
+    if size == 64 {
+        value
+    } else {
+        value & ((1 << size) - 1)
+    }
+}
+
+/// Replace `size` bits at byte `shift` of `reg` with `value`.
+fn write_part(reg: u64, shift: u64, size: u64, value: u64) -> u64 {
+    let mask = if size == 64 {
+        u64::MAX
+    } else {
+        (1 << size) - 1
+    } << (shift * 8);
+    (reg & !mask) | ((value << (shift * 8)) & mask)
+}
+
+impl Device for Clint {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 && size != 64 || !offset.is_multiple_of(size / 8) {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        let Some((reg, hart)) = self.decode(offset) else {
+            return Err(Exception::LoadAccessFault(offset));
+        };
+
+        let value = match reg {
+            MSIP_BASE => self.msip[hart] as u64,
+            MTIMECMP_BASE => read_part(self.mtimecmp[hart], offset % 8, size),
+            _ => read_part(self.mtime(), offset % 8, size),
+        };
+        Ok(value)
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 && size != 64 || !offset.is_multiple_of(size / 8) {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        let Some((reg, hart)) = self.decode(offset) else {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        };
+
+        match reg {
+            MSIP_BASE => self.msip[hart] = value & 1 != 0,
+            MTIMECMP_BASE => {
+                self.mtimecmp[hart] = write_part(self.mtimecmp[hart], offset % 8, size, value)
+            }
+            _ => {
+                let mtime = write_part(self.mtime(), offset % 8, size, value);
+                self.set_mtime(mtime);
+            }
+        }
+        Ok(())
+    }
+
+    fn mip(&self, hart: usize) -> u64 {
+        if hart >= self.msip.len() {
+            return 0;
+        }
+        let mut mip = 0;
+        if self.msip[hart] {
+            mip |= MSIP;
+        }
+        if self.mtime() >= self.mtimecmp[hart] {
+            mip |= MTIP;
+        }
+        mip
+    }
+
+    fn tick(&mut self) {
+        if self.clock == ClockSource::Instret {
+            self.mtime = self.mtime.wrapping_add(1);
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.msip.len(), self.clock);
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut state = self.mtime().to_le_bytes().to_vec();
+        for (&msip, &mtimecmp) in self.msip.iter().zip(&self.mtimecmp) {
+            state.push(msip as u8);
+            state.extend_from_slice(&mtimecmp.to_le_bytes());
+        }
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != 8 + 9 * self.msip.len() {
+            return Err("clint: malformed state".into());
+        }
+        let (mtime, harts) = state.split_at(8);
+        self.set_mtime(u64::from_le_bytes(mtime.try_into().unwrap()));
+        for (i, hart) in harts.chunks(9).enumerate() {
+            self.msip[i] = hart[0] != 0;
+            self.mtimecmp[i] = u64::from_le_bytes(hart[1..].try_into().unwrap());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_fires_after_mtimecmp() {
+        let mut clint = Clint::new(1, ClockSource::Instret);
+        clint.store(MTIMECMP_BASE, 64, 3).unwrap();
+        assert_eq!(clint.mip(0), 0);
+
+        for _ in 0..3 {
+            clint.tick();
+        }
+        assert_eq!(clint.load(MTIME, 64).unwrap(), 3);
+        assert_eq!(clint.mip(0), MTIP);
+    }
+
+    #[test]
+    fn test_msip_and_split_access() {
+        let mut clint = Clint::new(2, ClockSource::Instret);
+        clint.store(MSIP_BASE + 4, 32, 1).unwrap();
+        assert_eq!(clint.mip(0), 0);
+        assert_eq!(clint.mip(1), MSIP);
+
+        clint.store(MTIMECMP_BASE + 4, 32, 0x1).unwrap();
+        clint.store(MTIMECMP_BASE, 32, 0x2).unwrap();
+        assert_eq!(clint.load(MTIMECMP_BASE, 64).unwrap(), 0x1_0000_0002);
+        assert_eq!(clint.load(MTIMECMP_BASE + 4, 32).unwrap(), 0x1);
+    }
+}
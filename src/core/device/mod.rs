@@ -0,0 +1,92 @@
+pub mod clint;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
+
+pub use clint::Clint;
+pub use plic::Plic;
+pub use uart::Uart;
+pub use virtio::{PacketQueue, VirtioMmio};
+
+use super::{
+    config::{DeviceConfig, MachineConfig},
+    console::Console,
+    dram::Dram,
+    except::Exception,
+};
+
+/// A memory-mapped peripheral. Offsets passed to `load`/`store` are relative
+/// to the base of the region the device is mapped at.
+pub trait Device: Send {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception>;
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception>;
+
+    /// Complete work that reads or writes guest memory, such as DMA started
+    /// by the last register write. Returns whether guest memory may have
+    /// changed.
+    fn dma(&mut self, _dram: &mut Dram) -> bool {
+        false
+    }
+
+    /// Level of the device's interrupt output.
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    /// Drive interrupt input `source`; only interrupt controllers listen.
+    fn set_irq(&mut self, _source: u32, _level: bool) {}
+
+    /// `mip` bits this device drives for `hart`.
+    fn mip(&self, _hart: usize) -> u64 {
+        0
+    }
+
+    /// Return to power-on state.
+    fn reset(&mut self) {}
+
+    /// Advance by one retired instruction.
+    fn tick(&mut self) {}
+
+    /// Serialize internal state for a machine snapshot.
+    fn snapshot(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Instantiate the device described by `config` for `machine`, attached to
+/// the session's `console` and `network`.
+pub fn create(
+    config: &DeviceConfig,
+    machine: &MachineConfig,
+    console: &Console,
+    network: &PacketQueue,
+) -> Result<Box<dyn Device>, String> {
+    let device: Box<dyn Device> = match *config {
+        DeviceConfig::Uart { .. } => Box::new(Uart::new(console.clone())),
+        DeviceConfig::Clint { clock, .. } => Box::new(Clint::new(machine.harts, clock)),
+        DeviceConfig::Plic { .. } => Box::new(Plic::new(machine.harts)),
+        DeviceConfig::VirtioBlock {
+            ref image, mode, ..
+        } => Box::new(VirtioMmio::new(match image {
+            Some(image) => virtio::VirtioBlock::open(image, mode)?,
+            None => virtio::VirtioBlock::empty(mode),
+        })),
+        DeviceConfig::VirtioNet {
+            mac, ref backend, ..
+        } => Box::new(VirtioMmio::new(virtio::VirtioNet::new(
+            mac, backend, network,
+        )?)),
+        DeviceConfig::VirtioConsole { .. } => {
+            Box::new(VirtioMmio::new(virtio::VirtioConsole::new(console.clone())))
+        }
+        DeviceConfig::VirtioRng { seed, .. } => Box::new(VirtioMmio::new(virtio::VirtioRng::new(
+            seed.unwrap_or_else(rand::random),
+        ))),
+    };
+    Ok(device)
+}
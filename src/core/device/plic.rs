@@ -0,0 +1,268 @@
+use super::Device;
+use crate::core::{
+    csr::{MEIP, SEIP},
+    except::Exception,
+};
+
+pub const PLIC_SIZE: u64 = 0x60_0000;
+
+/// Interrupt sources, including the reserved source 0.
+pub const PLIC_SOURCES: usize = 96;
+
+const PRIORITY_BASE: u64 = 0x0;
+const PENDING_BASE: u64 = 0x1000;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+const WORDS: usize = PLIC_SOURCES.div_ceil(32);
+
+/// SiFive-compatible platform-level interrupt controller.
+///
+/// Each hart has two contexts, as on QEMU virt: `2 * hart` targets M-mode
+/// (`mip.MEIP`) and `2 * hart + 1` targets S-mode (`mip.SEIP`). Gateways are
+/// level triggered: a source becomes pending while its line is high and it
+/// is not already claimed.
+pub struct Plic {
+    priority: Vec<u32>,
+    pending: Vec<bool>,
+    claimed: Vec<bool>,
+    enable: Vec<[u32; WORDS]>,
+    threshold: Vec<u32>,
+}
+
+impl Plic {
+    pub fn new(harts: usize) -> Self {
+        let contexts = 2 * harts;
+        Self {
+            priority: vec![0; PLIC_SOURCES],
+            pending: vec![false; PLIC_SOURCES],
+            claimed: vec![false; PLIC_SOURCES],
+            enable: vec![[0; WORDS]; contexts],
+            threshold: vec![0; contexts],
+        }
+    }
+
+    fn enabled(&self, context: usize, source: usize) -> bool {
+        self.enable[context][source / 32] >> (source % 32) & 1 != 0
+    }
+
+    /// Highest-priority pending source above the context threshold, lowest id
+    /// first on ties.
+    fn best(&self, context: usize) -> Option<usize> {
+        (1..PLIC_SOURCES)
+            .filter(|&s| self.pending[s] && self.enabled(context, s))
+            .filter(|&s| self.priority[s] > self.threshold[context])
+            .fold(None, |best: Option<usize>, s| match best {
+                Some(b) if self.priority[b] >= self.priority[s] => Some(b),
+                _ => Some(s),
+            })
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending[source] = false;
+                self.claimed[source] = true;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u64) {
+        let source = source as usize;
+        if source > 0 && source < PLIC_SOURCES && self.enabled(context, source) {
+            self.claimed[source] = false;
+        }
+    }
+}
+
+impl Device for Plic {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 || !offset.is_multiple_of(4) {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        let contexts = self.threshold.len() as u64;
+
+        let value = match offset {
+            o if o < PENDING_BASE => {
+                let source = ((o - PRIORITY_BASE) / 4) as usize;
+                *self.priority.get(source).unwrap_or(&0)
+            }
+            o if o < ENABLE_BASE => {
+                let word = ((o - PENDING_BASE) / 4) as usize;
+                (0..32)
+                    .map(|bit| word * 32 + bit)
+                    .filter(|&s| s < PLIC_SOURCES && self.pending[s])
+                    .fold(0, |w, s| w | 1 << (s % 32))
+            }
+            o if o < ENABLE_BASE + ENABLE_STRIDE * contexts => {
+                let context = ((o - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+                let word = ((o - ENABLE_BASE) % ENABLE_STRIDE / 4) as usize;
+                *self.enable[context].get(word).unwrap_or(&0)
+            }
+            o if (CONTEXT_BASE..CONTEXT_BASE + CONTEXT_STRIDE * contexts).contains(&o) => {
+                let context = ((o - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+                match (o - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.threshold[context],
+                    4 => self.claim(context),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 || !offset.is_multiple_of(4) {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        let contexts = self.threshold.len() as u64;
+        let value32 = value as u32;
+
+        match offset {
+            o if o < PENDING_BASE => {
+                let source = ((o - PRIORITY_BASE) / 4) as usize;
+                if source > 0 && source < PLIC_SOURCES {
+                    self.priority[source] = value32 & 0x7;
+                }
+            }
+            // pending bits are read-only
+            o if o < ENABLE_BASE => {}
+            o if o < ENABLE_BASE + ENABLE_STRIDE * contexts => {
+                let context = ((o - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+                let word = ((o - ENABLE_BASE) % ENABLE_STRIDE / 4) as usize;
+                if word < WORDS {
+                    // source 0 does not exist
+                    let mask = if word == 0 { !1 } else { u32::MAX };
+                    self.enable[context][word] = value32 & mask;
+                }
+            }
+            o if (CONTEXT_BASE..CONTEXT_BASE + CONTEXT_STRIDE * contexts).contains(&o) => {
+                let context = ((o - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+                match (o - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.threshold[context] = value32 & 0x7,
+                    4 => self.complete(context, value),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn set_irq(&mut self, source: u32, level: bool) {
+        let source = source as usize;
+        if level && source > 0 && source < PLIC_SOURCES && !self.claimed[source] {
+            self.pending[source] = true;
+        }
+    }
+
+    fn mip(&self, hart: usize) -> u64 {
+        let mut mip = 0;
+        if 2 * hart + 1 >= self.threshold.len() {
+            return mip;
+        }
+        if self.best(2 * hart).is_some() {
+            mip |= MEIP;
+        }
+        if self.best(2 * hart + 1).is_some() {
+            mip |= SEIP;
+        }
+        mip
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.threshold.len() / 2);
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        for s in 0..PLIC_SOURCES {
+            state.extend_from_slice(&self.priority[s].to_le_bytes());
+            state.push(self.pending[s] as u8 | (self.claimed[s] as u8) << 1);
+        }
+        for (enable, threshold) in self.enable.iter().zip(&self.threshold) {
+            for word in enable {
+                state.extend_from_slice(&word.to_le_bytes());
+            }
+            state.extend_from_slice(&threshold.to_le_bytes());
+        }
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let contexts = self.threshold.len();
+        if state.len() != 5 * PLIC_SOURCES + contexts * 4 * (WORDS + 1) {
+            return Err("plic: malformed state".into());
+        }
+        let word = |i: usize| u32::from_le_bytes(state[i..i + 4].try_into().unwrap());
+
+        for s in 0..PLIC_SOURCES {
+            self.priority[s] = word(5 * s);
+            self.pending[s] = state[5 * s + 4] & 1 != 0;
+            self.claimed[s] = state[5 * s + 4] & 2 != 0;
+        }
+        let base = 5 * PLIC_SOURCES;
+        for c in 0..contexts {
+            let at = base + c * 4 * (WORDS + 1);
+            for w in 0..WORDS {
+                self.enable[c][w] = word(at + 4 * w);
+            }
+            self.threshold[c] = word(at + 4 * WORDS);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIM_M: u64 = CONTEXT_BASE + 4;
+    const CLAIM_S: u64 = CONTEXT_BASE + CONTEXT_STRIDE + 4;
+
+    #[test]
+    fn test_claim_complete_cycle() {
+        let mut plic = Plic::new(1);
+        plic.store(PRIORITY_BASE + 4 * 10, 32, 1).unwrap();
+        plic.store(ENABLE_BASE + ENABLE_STRIDE, 32, 1 << 10)
+            .unwrap();
+
+        plic.set_irq(10, true);
+        assert_eq!(plic.mip(0), SEIP);
+        assert_eq!(plic.load(PENDING_BASE, 32).unwrap(), 1 << 10);
+
+        assert_eq!(plic.load(CLAIM_S, 32).unwrap(), 10);
+        assert_eq!(plic.mip(0), 0);
+        // still claimed, so the line being high does not re-trigger
+        plic.set_irq(10, true);
+        assert_eq!(plic.load(CLAIM_S, 32).unwrap(), 0);
+
+        plic.store(CLAIM_S, 32, 10).unwrap();
+        plic.set_irq(10, true);
+        assert_eq!(plic.mip(0), SEIP);
+        assert_eq!(plic.load(CLAIM_M, 32).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_priority_and_threshold() {
+        let mut plic = Plic::new(1);
+        plic.store(PRIORITY_BASE + 4 * 3, 32, 2).unwrap();
+        plic.store(PRIORITY_BASE + 4 * 5, 32, 6).unwrap();
+        plic.store(ENABLE_BASE, 32, (1 << 3) | (1 << 5)).unwrap();
+        plic.set_irq(3, true);
+        plic.set_irq(5, true);
+
+        plic.store(CONTEXT_BASE, 32, 6).unwrap();
+        assert_eq!(plic.mip(0), 0);
+
+        plic.store(CONTEXT_BASE, 32, 1).unwrap();
+        assert_eq!(plic.mip(0), MEIP);
+        assert_eq!(plic.load(CLAIM_M, 32).unwrap(), 5);
+        assert_eq!(plic.load(CLAIM_M, 32).unwrap(), 3);
+    }
+}
@@ -0,0 +1,211 @@
+use super::Device;
+use crate::core::{console::Console, except::Exception};
+
+pub const UART_SIZE: u64 = 0x100;
+
+// Register offsets; DLL/DLM alias RBR/IER while LCR.DLAB is set
+const RBR: u64 = 0;
+const THR: u64 = 0;
+const IER: u64 = 1;
+const IIR: u64 = 2;
+const FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDI: u8 = 0x01;
+const IER_THRI: u8 = 0x02;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 0x80;
+
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+const FCR_ENABLE: u8 = 0x01;
+
+/// NS16550A-compatible UART. Transmission completes instantly into the
+/// session console, and received bytes come from the console input queue.
+pub struct Uart {
+    console: Console,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fcr: u8,
+    dll: u8,
+    dlm: u8,
+    // THR-empty interrupt, raised by an empty transmitter and cleared by
+    // reading IIR or writing THR
+    thre_pending: bool,
+}
+
+impl Uart {
+    pub fn new(console: Console) -> Self {
+        Self {
+            console,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            fcr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_pending: false,
+        }
+    }
+
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & FCR_ENABLE != 0 {
+            IIR_FIFO
+        } else {
+            0
+        };
+        if self.ier & IER_RDI != 0 && self.console.has_input() {
+            fifo | IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thre_pending {
+            fifo | IIR_THRI
+        } else {
+            fifo | IIR_NO_INT
+        }
+    }
+}
+
+impl Device for Uart {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 8 {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        let value = match offset {
+            RBR if dlab => self.dll,
+            RBR => self.console.read().unwrap_or(0),
+            IER if dlab => self.dlm,
+            IER => self.ier,
+            IIR => {
+                let iir = self.iir();
+                if iir & 0x0f == IIR_THRI {
+                    self.thre_pending = false;
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let ready = if self.console.has_input() { LSR_DR } else { 0 };
+                LSR_THRE | LSR_TEMT | ready
+            }
+            MSR => 0,
+            SCR => self.scr,
+            _ => return Err(Exception::LoadAccessFault(offset)),
+        };
+        Ok(value as u64)
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 8 {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            THR if dlab => self.dll = value,
+            THR => {
+                self.console.write(&[value]);
+                self.thre_pending = true;
+            }
+            IER if dlab => self.dlm = value,
+            IER => {
+                // enabling THRI with an empty transmitter raises it at once
+                if value & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            FCR => self.fcr = value,
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            LSR | MSR => {}
+            SCR => self.scr = value,
+            _ => return Err(Exception::StoreAMOAccessFault(offset)),
+        }
+        Ok(())
+    }
+
+    fn interrupt(&self) -> bool {
+        self.iir() & IIR_NO_INT == 0
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.console.clone());
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        vec![
+            self.ier,
+            self.lcr,
+            self.mcr,
+            self.scr,
+            self.fcr,
+            self.dll,
+            self.dlm,
+            self.thre_pending as u8,
+        ]
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let &[ier, lcr, mcr, scr, fcr, dll, dlm, thre_pending] = state else {
+            return Err("uart: malformed state".into());
+        };
+        self.ier = ier;
+        self.lcr = lcr;
+        self.mcr = mcr;
+        self.scr = scr;
+        self.fcr = fcr;
+        self.dll = dll;
+        self.dlm = dlm;
+        self.thre_pending = thre_pending != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transmit_reaches_console() {
+        let console = Console::default();
+        let mut uart = Uart::new(console.clone());
+        for &b in b"hi\n" {
+            uart.store(THR, 8, b as u64).unwrap();
+        }
+
+        assert_eq!(console.output_since(0), (3, b"hi\n".to_vec()));
+        assert_eq!(uart.load(LSR, 8).unwrap() as u8 & LSR_THRE, LSR_THRE);
+    }
+
+    #[test]
+    fn test_receive_raises_interrupt() {
+        let console = Console::default();
+        let mut uart = Uart::new(console.clone());
+        uart.store(IER, 8, IER_RDI as u64).unwrap();
+        assert!(!uart.interrupt());
+
+        console.push_input(b"x");
+        assert!(uart.interrupt());
+        assert_eq!(uart.load(IIR, 8).unwrap() as u8 & 0x0f, IIR_RDI);
+        assert_eq!(uart.load(RBR, 8).unwrap(), b'x' as u64);
+        assert!(!uart.interrupt());
+        assert_eq!(uart.load(LSR, 8).unwrap() as u8 & LSR_DR, 0);
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    queue::{Chain, Queue},
+    take, VirtioDevice,
+};
+use crate::core::{dram::Dram, except::Exception};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const DEVICE_ID: &[u8] = b"risque-virtio-blk";
+
+/// How guest writes reach the disk image.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskMode {
+    /// The device is offered read-only.
+    ReadOnly,
+    /// Writes are kept in memory and the image is never modified; they are
+    /// part of machine snapshots.
+    #[default]
+    Overlay,
+    /// Writes go straight to the image, outside the reach of snapshots.
+    ReadWrite,
+}
+
+/// virtio-blk backed by a raw disk image, or an empty drive without one.
+pub struct VirtioBlock {
+    file: Option<File>,
+    mode: DiskMode,
+    sectors: u64,
+    overlay: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl VirtioBlock {
+    /// A drive with no medium: zero sectors, so every transfer fails.
+    pub fn empty(mode: DiskMode) -> Self {
+        Self {
+            file: None,
+            mode,
+            sectors: 0,
+            overlay: BTreeMap::new(),
+        }
+    }
+
+    pub fn open(path: &Path, mode: DiskMode) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let len = file
+            .metadata()
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .len();
+
+        Ok(Self {
+            file: Some(file),
+            mode,
+            sectors: len / SECTOR_SIZE,
+            overlay: BTreeMap::new(),
+        })
+    }
+
+    fn file(&self) -> io::Result<&File> {
+        self.file.as_ref().ok_or(io::ErrorKind::NotFound.into())
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+            let sector = sector + i as u64;
+            match self.overlay.get(&sector) {
+                Some(data) => chunk.copy_from_slice(data),
+                None => self.file()?.read_exact_at(chunk, sector * SECTOR_SIZE)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadOnly => Err(io::ErrorKind::PermissionDenied.into()),
+            DiskMode::Overlay => {
+                for (i, chunk) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+                    self.overlay.insert(sector + i as u64, chunk.into());
+                }
+                Ok(())
+            }
+            DiskMode::ReadWrite => self.file()?.write_all_at(data, sector * SECTOR_SIZE),
+        }
+    }
+
+    /// Whether `len` bytes from `sector` are whole sectors inside the disk.
+    fn in_range(&self, sector: u64, len: usize) -> bool {
+        let count = len as u64 / SECTOR_SIZE;
+        (len as u64).is_multiple_of(SECTOR_SIZE)
+            && sector
+                .checked_add(count)
+                .is_some_and(|end| end <= self.sectors)
+    }
+
+    /// Carry out one request, returning the bytes written back to the guest.
+    fn handle(&mut self, chain: &Chain, dram: &mut Dram) -> Result<u32, Exception> {
+        let request = chain.read(dram)?;
+        // the last device-writable byte holds the status
+        let Some(reply_len) = chain.writable_len().checked_sub(1) else {
+            return Ok(0);
+        };
+        if request.len() < 16 {
+            return self.reply(chain, dram, Vec::new(), reply_len, VIRTIO_BLK_S_IOERR);
+        }
+        let kind = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+
+        let (data, status) = match kind {
+            VIRTIO_BLK_T_IN => {
+                let mut data = vec![0; reply_len];
+                let ok = self.in_range(sector, reply_len)
+                    && self.read_sectors(sector, &mut data).is_ok();
+                (data, status(ok))
+            }
+            VIRTIO_BLK_T_OUT => {
+                let data = &request[16..];
+                let ok =
+                    self.in_range(sector, data.len()) && self.write_sectors(sector, data).is_ok();
+                (Vec::new(), status(ok))
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                let ok = self.mode != DiskMode::ReadWrite
+                    || self.file().and_then(|file| file.sync_data()).is_ok();
+                (Vec::new(), status(ok))
+            }
+            VIRTIO_BLK_T_GET_ID => (DEVICE_ID.to_vec(), VIRTIO_BLK_S_OK),
+            _ => (Vec::new(), VIRTIO_BLK_S_UNSUPP),
+        };
+        self.reply(chain, dram, data, reply_len, status)
+    }
+
+    fn reply(
+        &self,
+        chain: &Chain,
+        dram: &mut Dram,
+        mut data: Vec<u8>,
+        len: usize,
+        status: u8,
+    ) -> Result<u32, Exception> {
+        data.resize(len, 0);
+        data.push(status);
+        chain.write(dram, &data)
+    }
+}
+
+fn status(ok: bool) -> u8 {
+    if ok {
+        VIRTIO_BLK_S_OK
+    } else {
+        VIRTIO_BLK_S_IOERR
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        match self.mode {
+            DiskMode::ReadOnly => VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH,
+            _ => VIRTIO_BLK_F_FLUSH,
+        }
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // capacity in 512-byte sectors
+        self.sectors.to_le_bytes().to_vec()
+    }
+
+    fn notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        let queue = &mut queues[index];
+        let mut used = false;
+        while let Some(chain) = queue.pop(dram)? {
+            let len = self.handle(&chain, dram)?;
+            queue.push(dram, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        for (sector, data) in &self.overlay {
+            state.extend_from_slice(&sector.to_le_bytes());
+            state.extend_from_slice(data);
+        }
+        state
+    }
+
+    fn restore(&mut self, mut state: &[u8]) -> Result<(), String> {
+        self.overlay.clear();
+        while !state.is_empty() {
+            let sector = u64::from_le_bytes(take(&mut state)?);
+            let data = take::<{ SECTOR_SIZE as usize }>(&mut state)?;
+            self.overlay.insert(sector, Box::new(data));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{post, rings, DRAM_BASE};
+    use super::super::VirtioMmio;
+    use super::*;
+    use crate::core::device::Device;
+
+    const USED: u64 = rings(0).2;
+    const HEADER: u64 = DRAM_BASE + 0x4000;
+    const DATA: u64 = DRAM_BASE + 0x5000;
+    const STATUS: u64 = DRAM_BASE + 0x6000;
+
+    fn image(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("risque-{}-{}.img", name, std::process::id()));
+        let mut disk = vec![0u8; 4 * SECTOR_SIZE as usize];
+        disk[SECTOR_SIZE as usize..][..5].copy_from_slice(b"hello");
+        std::fs::write(&path, disk).unwrap();
+        path
+    }
+
+    /// Post a three-descriptor request and kick queue 0.
+    fn request(dev: &mut VirtioMmio<VirtioBlock>, dram: &mut Dram, kind: u32, sector: u64) {
+        request_len(dev, dram, kind, sector, SECTOR_SIZE);
+    }
+
+    fn request_len(
+        dev: &mut VirtioMmio<VirtioBlock>,
+        dram: &mut Dram,
+        kind: u32,
+        sector: u64,
+        len: u64,
+    ) {
+        dram.store(HEADER, 32, kind as u64).unwrap();
+        dram.store(HEADER + 8, 64, sector).unwrap();
+        let chain = [
+            (HEADER, 16, false),
+            (DATA, len, kind == VIRTIO_BLK_T_IN),
+            (STATUS, 1, true),
+        ];
+        post(dev, dram, 0, &chain);
+    }
+
+    fn setup(mode: DiskMode, path: &Path) -> (VirtioMmio<VirtioBlock>, Dram) {
+        let block = VirtioBlock::open(path, mode).unwrap();
+        super::super::testing::setup(block, 1)
+    }
+
+    #[test]
+    fn test_read_sector_through_queue() {
+        let path = image("read");
+        let (mut dev, mut dram) = setup(DiskMode::ReadOnly, &path);
+        assert_eq!(dev.load(0x000, 32).unwrap(), 0x7472_6976);
+        assert_eq!(dev.load(0x100, 64).unwrap(), 4);
+
+        request(&mut dev, &mut dram, VIRTIO_BLK_T_IN, 1);
+        let mut data = [0u8; 5];
+        dram.read(DATA, &mut data).unwrap();
+        assert_eq!(&data, b"hello");
+        assert_eq!(dram.load(STATUS, 8).unwrap(), VIRTIO_BLK_S_OK as u64);
+        assert_eq!(dram.load(USED + 2, 16).unwrap(), 1);
+        assert!(dev.interrupt());
+
+        request(&mut dev, &mut dram, VIRTIO_BLK_T_OUT, 1);
+        assert_eq!(dram.load(STATUS, 8).unwrap(), VIRTIO_BLK_S_IOERR as u64);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_overlay_keeps_image_intact() {
+        let path = image("overlay");
+        let (mut dev, mut dram) = setup(DiskMode::Overlay, &path);
+        dram.write(DATA, b"world").unwrap();
+        request(&mut dev, &mut dram, VIRTIO_BLK_T_OUT, 1);
+        assert_eq!(dram.load(STATUS, 8).unwrap(), VIRTIO_BLK_S_OK as u64);
+
+        dram.write(DATA, &[0; 5]).unwrap();
+        request(&mut dev, &mut dram, VIRTIO_BLK_T_IN, 1);
+        let mut data = [0u8; 5];
+        dram.read(DATA, &mut data).unwrap();
+        assert_eq!(&data, b"world");
+
+        let disk = std::fs::read(&path).unwrap();
+        assert_eq!(&disk[SECTOR_SIZE as usize..][..5], b"hello");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_oversized_buffer_is_refused() {
+        let path = image("oversized");
+        let (mut dev, mut dram) = setup(DiskMode::ReadOnly, &path);
+        request_len(&mut dev, &mut dram, VIRTIO_BLK_T_IN, 0, 0xffff_ffff);
+        assert_eq!(dram.load(USED + 2, 16).unwrap(), 0);
+        // DEVICE_NEEDS_RESET
+        assert_ne!(dev.load(0x070, 32).unwrap() & 0x40, 0);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,109 @@
+use super::{queue::Queue, VirtioDevice};
+use crate::core::{console::Console, dram::Dram, except::Exception};
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// virtio-console on the session console, with a single port and no
+/// multiport or resize support.
+pub struct VirtioConsole {
+    console: Console,
+}
+
+impl VirtioConsole {
+    pub fn new(console: Console) -> Self {
+        Self { console }
+    }
+
+    /// Write everything the guest queued to the console.
+    fn transmit(&mut self, queue: &mut Queue, dram: &mut Dram) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(dram)? {
+            self.console.write(&chain.read(dram)?);
+            queue.push(dram, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Hand pending input to the guest while it has receive buffers posted.
+    fn receive(&mut self, queue: &mut Queue, dram: &mut Dram) -> Result<bool, Exception> {
+        let mut used = false;
+        while self.console.has_input() && queue.has_available(dram)? {
+            let chain = queue.pop(dram)?.expect("buffer checked above");
+            let input = self.console.read_input(chain.writable_len());
+            let len = chain.write(dram, &input)?;
+            queue.push(dram, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // cols, rows, max_nr_ports and emerg_wr, none of which are offered
+        vec![0; 12]
+    }
+
+    fn notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        match index {
+            TX_QUEUE => self.transmit(&mut queues[TX_QUEUE], dram),
+            _ => self.receive(&mut queues[RX_QUEUE], dram),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], dram: &mut Dram) -> Result<bool, Exception> {
+        self.receive(&mut queues[RX_QUEUE], dram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{post, rings, setup, DRAM_BASE};
+    use super::*;
+    use crate::core::device::Device;
+
+    const RX_USED: u64 = rings(0).2;
+    const RX_BUF: u64 = DRAM_BASE + 0x7000;
+    const TX_BUF: u64 = DRAM_BASE + 0x8000;
+
+    #[test]
+    fn test_console_round_trip() {
+        let console = Console::default();
+        let (mut dev, mut dram) = setup(VirtioConsole::new(console.clone()), 2);
+        assert_eq!(dev.load(0x008, 32).unwrap(), VIRTIO_ID_CONSOLE as u64);
+
+        dram.write(TX_BUF, b"hello\n").unwrap();
+        post(&mut dev, &mut dram, 1, &[(TX_BUF, 6, false)]);
+        assert_eq!(console.output_since(0).1, b"hello\n");
+
+        // input longer than the buffer is left for the next one
+        console.push_input(b"abcdef");
+        post(&mut dev, &mut dram, 0, &[(RX_BUF, 4, true)]);
+        let mut data = [0u8; 4];
+        dram.read(RX_BUF, &mut data).unwrap();
+        assert_eq!(&data, b"abcd");
+        assert_eq!(dram.load(RX_USED + 8, 32).unwrap(), 4);
+        assert_eq!(console.read_input(8), b"ef");
+    }
+}
@@ -0,0 +1,425 @@
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod queue;
+pub mod rng;
+
+pub use block::{DiskMode, VirtioBlock};
+pub use console::VirtioConsole;
+pub use net::{NetBackend, PacketQueue, VirtioNet};
+pub use rng::VirtioRng;
+
+use super::Device;
+use crate::core::{dram::Dram, except::Exception};
+use queue::{Queue, QUEUE_SIZE_MAX};
+
+pub const VIRTIO_SIZE: u64 = 0x1000;
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554d_4551; // "QEMU", which guests already know
+
+/// Offered by every device: the modern, non-legacy interface.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Register offsets
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION_REG: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID_REG: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// The device-specific half of a virtio device; `VirtioMmio` supplies the
+/// register interface and queue bookkeeping.
+pub trait VirtioDevice: Send {
+    fn device_id(&self) -> u32;
+
+    /// Device-specific feature bits.
+    fn features(&self) -> u64;
+
+    fn num_queues(&self) -> usize;
+
+    /// Current contents of the configuration space.
+    fn config(&self) -> Vec<u8>;
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Process buffers the driver made available on queue `index`, returning
+    /// whether any were used.
+    fn notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception>;
+
+    /// Look for work that does not wait for a notification, such as
+    /// incoming packets. Called once per tick.
+    fn poll(&mut self, _queues: &mut [Queue], _dram: &mut Dram) -> Result<bool, Exception> {
+        Ok(false)
+    }
+
+    fn reset(&mut self) {}
+
+    fn snapshot(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// virtio-mmio (version 2) transport around a `VirtioDevice`.
+pub struct VirtioMmio<D> {
+    device: D,
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+    // queues notified since the last DMA pass
+    notified: Vec<usize>,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> Self {
+        let queues = vec![Queue::default(); device.num_queues()];
+        Self {
+            device,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            notified: Vec::new(),
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Raise the interrupt for the outcome of queue processing.
+    fn complete(&mut self, result: Result<bool, Exception>) {
+        match result {
+            Ok(true) => self.interrupt_status |= INTERRUPT_USED_BUFFER,
+            Ok(false) => {}
+            // a ring outside guest memory is a driver bug we cannot recover from
+            Err(_) => {
+                self.status |= STATUS_DEVICE_NEEDS_RESET;
+                self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+            }
+        }
+    }
+
+    fn reset_transport(&mut self) {
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queues.fill(Queue::default());
+        self.interrupt_status = 0;
+        self.notified.clear();
+        self.device.reset();
+    }
+}
+
+/// Replace the low or high half of `reg`.
+fn set_half(reg: &mut u64, high: bool, value: u32) {
+    *reg = if high {
+        (*reg & 0xffff_ffff) | (value as u64) << 32
+    } else {
+        (*reg & !0xffff_ffff) | value as u64
+    };
+}
+
+impl<D: VirtioDevice> Device for VirtioMmio<D> {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let start = (offset - CONFIG) as usize;
+            let bytes = config.get(start..start + size as usize / 8);
+            return match bytes {
+                Some(bytes) if [8, 16, 32, 64].contains(&size) => {
+                    let mut value = [0u8; 8];
+                    value[..bytes.len()].copy_from_slice(bytes);
+                    Ok(u64::from_le_bytes(value))
+                }
+                _ => Err(Exception::LoadAccessFault(offset)),
+            };
+        }
+        if size != 32 || !offset.is_multiple_of(4) {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+
+        let queue = self.queues.get(self.queue_sel as usize);
+        let value = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE_MAX as u32),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            QUEUE_DESC_LOW => queue.map_or(0, |q| q.desc as u32),
+            QUEUE_DESC_HIGH => queue.map_or(0, |q| (q.desc >> 32) as u32),
+            QUEUE_DRIVER_LOW => queue.map_or(0, |q| q.driver as u32),
+            QUEUE_DRIVER_HIGH => queue.map_or(0, |q| (q.driver >> 32) as u32),
+            QUEUE_DEVICE_LOW => queue.map_or(0, |q| q.device as u32),
+            QUEUE_DEVICE_HIGH => queue.map_or(0, |q| (q.device >> 32) as u32),
+            CONFIG_GENERATION => 0,
+            // write-only or reserved
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if offset >= CONFIG {
+            if ![8, 16, 32, 64].contains(&size) {
+                return Err(Exception::StoreAMOAccessFault(offset));
+            }
+            let bytes = value.to_le_bytes();
+            self.device
+                .write_config(offset - CONFIG, &bytes[..size as usize / 8]);
+            return Ok(());
+        }
+        if size != 32 || !offset.is_multiple_of(4) {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+
+        let value = value as u32;
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 | 1 => {
+                    let high = self.driver_features_sel == 1;
+                    set_half(&mut self.driver_features, high, value);
+                    self.driver_features &= self.features();
+                }
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM => {
+                if let Some(q) = self.queue() {
+                    q.num = value.min(QUEUE_SIZE_MAX as u32) as u16;
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.queue() {
+                    q.ready = value & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY if (value as usize) < self.queues.len() => {
+                self.notified.push(value as usize);
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS if value == 0 => self.reset_transport(),
+            STATUS => self.status = value,
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => {
+                if let Some(q) = self.queue() {
+                    set_half(&mut q.desc, offset == QUEUE_DESC_HIGH, value);
+                }
+            }
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => {
+                if let Some(q) = self.queue() {
+                    set_half(&mut q.driver, offset == QUEUE_DRIVER_HIGH, value);
+                }
+            }
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(q) = self.queue() {
+                    set_half(&mut q.device, offset == QUEUE_DEVICE_HIGH, value);
+                }
+            }
+            // read-only or reserved
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn dma(&mut self, dram: &mut Dram) -> bool {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0 {
+            self.notified.clear();
+            return false;
+        }
+        let mut wrote = false;
+        for index in std::mem::take(&mut self.notified) {
+            let result = self.device.notify(index, &mut self.queues, dram);
+            wrote |= !matches!(result, Ok(false));
+            self.complete(result);
+        }
+        if self.status & STATUS_DEVICE_NEEDS_RESET == 0 {
+            let result = self.device.poll(&mut self.queues, dram);
+            wrote |= !matches!(result, Ok(false));
+            self.complete(result);
+        }
+        wrote
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn reset(&mut self) {
+        self.reset_transport();
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        for reg in [
+            self.status,
+            self.device_features_sel,
+            self.driver_features_sel,
+            self.queue_sel,
+            self.interrupt_status,
+        ] {
+            state.extend_from_slice(&reg.to_le_bytes());
+        }
+        state.extend_from_slice(&self.driver_features.to_le_bytes());
+        for q in &self.queues {
+            state.extend_from_slice(&q.num.to_le_bytes());
+            state.push(q.ready as u8);
+            state.extend_from_slice(&q.desc.to_le_bytes());
+            state.extend_from_slice(&q.driver.to_le_bytes());
+            state.extend_from_slice(&q.device.to_le_bytes());
+            state.extend_from_slice(&q.last_avail.to_le_bytes());
+        }
+        state.extend_from_slice(&self.device.snapshot());
+        state
+    }
+
+    fn restore(&mut self, mut state: &[u8]) -> Result<(), String> {
+        let s = &mut state;
+        self.status = u32::from_le_bytes(take(s)?);
+        self.device_features_sel = u32::from_le_bytes(take(s)?);
+        self.driver_features_sel = u32::from_le_bytes(take(s)?);
+        self.queue_sel = u32::from_le_bytes(take(s)?);
+        self.interrupt_status = u32::from_le_bytes(take(s)?);
+        self.driver_features = u64::from_le_bytes(take(s)?);
+        for q in self.queues.iter_mut() {
+            q.num = u16::from_le_bytes(take(s)?);
+            q.ready = take::<1>(s)?[0] != 0;
+            q.desc = u64::from_le_bytes(take(s)?);
+            q.driver = u64::from_le_bytes(take(s)?);
+            q.device = u64::from_le_bytes(take(s)?);
+            q.last_avail = u16::from_le_bytes(take(s)?);
+        }
+        self.notified.clear();
+        self.device.restore(state)
+    }
+}
+
+/// Split `N` bytes off the front of a snapshot.
+fn take<const N: usize>(state: &mut &[u8]) -> Result<[u8; N], String> {
+    if state.len() < N {
+        return Err("virtio: malformed state".into());
+    }
+    let (head, rest) = state.split_at(N);
+    *state = rest;
+    Ok(head.try_into().unwrap())
+}
+
+/// Driver side of the device tests: queues of eight entries laid out at
+/// fixed addresses in a small DRAM.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{VirtioDevice, VirtioMmio};
+    use crate::core::device::Device;
+    use crate::core::dram::Dram;
+
+    pub const DRAM_BASE: u64 = 0x8000_0000;
+
+    /// Descriptor table, available ring and used ring of queue `sel`.
+    pub const fn rings(sel: u32) -> (u64, u64, u64) {
+        let desc = DRAM_BASE + 0x1000 + 0x3000 * sel as u64;
+        (desc, desc + 0x1000, desc + 0x2000)
+    }
+
+    /// `device` with its first `queues` queues set up and the driver ready.
+    pub fn setup<D: VirtioDevice>(device: D, queues: u32) -> (VirtioMmio<D>, Dram) {
+        let mut dev = VirtioMmio::new(device);
+        for sel in 0..queues {
+            let (desc, avail, used) = rings(sel);
+            for (reg, value) in [
+                (0x030, sel),
+                (0x038, 8),
+                (0x080, desc as u32),
+                (0x090, avail as u32),
+                (0x0a0, used as u32),
+                (0x044, 1),
+            ] {
+                dev.store(reg, 32, value as u64).unwrap();
+            }
+        }
+        dev.store(0x070, 32, 0xf).unwrap();
+        (dev, Dram::new(DRAM_BASE, 0x10_0000, Vec::new()).unwrap())
+    }
+
+    /// Make a chain of (address, length, device-writable) buffers available
+    /// on queue `sel` and kick it.
+    pub fn post<D: VirtioDevice>(
+        dev: &mut VirtioMmio<D>,
+        dram: &mut Dram,
+        sel: u32,
+        chain: &[(u64, u64, bool)],
+    ) {
+        let (desc, avail, _) = rings(sel);
+        for (i, &(addr, len, writable)) in chain.iter().enumerate() {
+            let d = desc + 16 * i as u64;
+            let next = i + 1 < chain.len();
+            dram.store(d, 64, addr).unwrap();
+            dram.store(d + 8, 32, len).unwrap();
+            dram.store(d + 12, 16, next as u64 | (writable as u64) << 1)
+                .unwrap();
+            dram.store(d + 14, 16, if next { i as u64 + 1 } else { 0 })
+                .unwrap();
+        }
+        let idx = dram.load(avail + 2, 16).unwrap();
+        dram.store(avail + 4 + 2 * (idx % 8), 16, 0).unwrap();
+        dram.store(avail + 2, 16, idx + 1).unwrap();
+        dev.store(0x050, 32, sel as u64).unwrap();
+        dev.dma(dram);
+    }
+}
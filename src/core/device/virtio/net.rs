@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use super::{queue::Queue, VirtioDevice};
+use crate::core::{dram::Dram, except::Exception};
+
+const VIRTIO_ID_NET: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// struct virtio_net_hdr with VIRTIO_F_VERSION_1, num_buffers included
+const NET_HDR_SIZE: usize = 12;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+// Largest Ethernet frame accepted from a socket peer, VLAN tag included
+const MAX_FRAME: usize = 1518;
+
+// Frames held in each direction of a PacketQueue; later ones are dropped
+// until the other side catches up
+const MAX_QUEUED_FRAMES: usize = 256;
+
+// Ticks between checks of a socket for incoming frames
+const SOCKET_POLL_INTERVAL: u32 = 256;
+
+/// Where frames sent by the guest go and received frames come from.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum NetBackend {
+    /// The machine's in-process `PacketQueue`.
+    #[default]
+    Queue,
+    /// A Unix datagram socket bound at `path` exchanging frames with `peer`,
+    /// typically another risque instance configured the other way round.
+    Socket { path: PathBuf, peer: PathBuf },
+}
+
+#[derive(Default)]
+struct Frames {
+    to_guest: VecDeque<Vec<u8>>,
+    from_guest: VecDeque<Vec<u8>>,
+}
+
+/// Queue `frame` unless `queue` is full, as a NIC drops frames that find
+/// its ring full.
+fn enqueue(queue: &mut VecDeque<Vec<u8>>, frame: Vec<u8>) {
+    if queue.len() < MAX_QUEUED_FRAMES {
+        queue.push_back(frame);
+    }
+}
+
+/// Host side of in-process networking, shared between a session and its
+/// virtio-net devices.
+#[derive(Clone, Default)]
+pub struct PacketQueue {
+    frames: Arc<Mutex<Frames>>,
+}
+
+impl PacketQueue {
+    /// Queue an Ethernet frame for the guest to receive.
+    pub fn send_to_guest(&self, frame: Vec<u8>) {
+        enqueue(&mut self.frames.lock().unwrap().to_guest, frame);
+    }
+
+    /// Next Ethernet frame transmitted by the guest.
+    pub fn recv_from_guest(&self) -> Option<Vec<u8>> {
+        self.frames.lock().unwrap().from_guest.pop_front()
+    }
+}
+
+/// Guest-facing end of a backend.
+trait Link: Send {
+    fn send(&mut self, frame: &[u8]);
+
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+impl Link for PacketQueue {
+    fn send(&mut self, frame: &[u8]) {
+        enqueue(&mut self.frames.lock().unwrap().from_guest, frame.to_vec());
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.frames.lock().unwrap().to_guest.pop_front()
+    }
+}
+
+struct SocketLink {
+    socket: UnixDatagram,
+    peer: PathBuf,
+    countdown: u32,
+}
+
+impl SocketLink {
+    fn bind(path: &Path, peer: &Path) -> io::Result<Self> {
+        // a socket file left behind by an earlier run would make bind fail;
+        // anything else at the path is left alone
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(_) => {}
+        }
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            peer: peer.into(),
+            countdown: 0,
+        })
+    }
+}
+
+impl Link for SocketLink {
+    fn send(&mut self, frame: &[u8]) {
+        // like an unplugged cable, frames are dropped while the peer is away
+        let _ = self.socket.send_to(frame, &self.peer);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return None;
+        }
+        let mut buf = vec![0u8; MAX_FRAME];
+        match self.socket.recv(&mut buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                Some(buf)
+            }
+            Err(_) => {
+                self.countdown = SOCKET_POLL_INTERVAL;
+                None
+            }
+        }
+    }
+}
+
+/// virtio-net with a single RX/TX queue pair and no offloads.
+pub struct VirtioNet {
+    mac: [u8; 6],
+    link: Box<dyn Link>,
+}
+
+impl VirtioNet {
+    pub fn new(mac: [u8; 6], backend: &NetBackend, queue: &PacketQueue) -> Result<Self, String> {
+        let link: Box<dyn Link> = match backend {
+            NetBackend::Queue => Box::new(queue.clone()),
+            NetBackend::Socket { path, peer } => Box::new(
+                SocketLink::bind(path, peer).map_err(|e| format!("{}: {}", path.display(), e))?,
+            ),
+        };
+        Ok(Self { mac, link })
+    }
+
+    /// Send every frame queued by the guest.
+    fn transmit(&mut self, queue: &mut Queue, dram: &mut Dram) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(dram)? {
+            let packet = chain.read(dram)?;
+            if packet.len() > NET_HDR_SIZE {
+                self.link.send(&packet[NET_HDR_SIZE..]);
+            }
+            queue.push(dram, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Deliver incoming frames while the guest has receive buffers posted.
+    fn receive(&mut self, queue: &mut Queue, dram: &mut Dram) -> Result<bool, Exception> {
+        let mut used = false;
+        while queue.has_available(dram)? {
+            let Some(frame) = self.link.recv() else {
+                break;
+            };
+            let chain = queue.pop(dram)?.expect("buffer checked above");
+
+            let mut packet = vec![0u8; NET_HDR_SIZE];
+            packet[10] = 1; // num_buffers
+            packet.extend_from_slice(&frame);
+            // frames larger than the buffer are truncated
+            let len = chain.write(dram, &packet)?;
+            queue.push(dram, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config
+    }
+
+    fn notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        match index {
+            TX_QUEUE => self.transmit(&mut queues[TX_QUEUE], dram),
+            _ => self.receive(&mut queues[RX_QUEUE], dram),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], dram: &mut Dram) -> Result<bool, Exception> {
+        self.receive(&mut queues[RX_QUEUE], dram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{post, rings, DRAM_BASE};
+    use super::super::VirtioMmio;
+    use super::*;
+    use crate::core::device::Device;
+
+    const RX_USED: u64 = rings(0).2;
+    const RX_BUF: u64 = DRAM_BASE + 0x7000;
+    const TX_BUF: u64 = DRAM_BASE + 0x8000;
+
+    fn setup(queue: &PacketQueue) -> (VirtioMmio<VirtioNet>, Dram) {
+        let net = VirtioNet::new([2, 0, 0, 0, 0, 1], &NetBackend::Queue, queue).unwrap();
+        super::super::testing::setup(net, 2)
+    }
+
+    #[test]
+    fn test_transmit_reaches_packet_queue() {
+        let queue = PacketQueue::default();
+        let (mut dev, mut dram) = setup(&queue);
+        assert_eq!(dev.load(0x100, 32).unwrap(), 0x0000_0002);
+
+        dram.write(TX_BUF + NET_HDR_SIZE as u64, b"frame").unwrap();
+        post(
+            &mut dev,
+            &mut dram,
+            1,
+            &[(TX_BUF, NET_HDR_SIZE as u64 + 5, false)],
+        );
+        assert_eq!(queue.recv_from_guest().unwrap(), b"frame");
+        assert!(dev.interrupt());
+    }
+
+    #[test]
+    fn test_frames_wait_for_receive_buffers() {
+        let queue = PacketQueue::default();
+        let (mut dev, mut dram) = setup(&queue);
+        queue.send_to_guest(b"ping".to_vec());
+        dev.dma(&mut dram);
+        assert!(!dev.interrupt());
+
+        post(&mut dev, &mut dram, 0, &[(RX_BUF, 64, true)]);
+        let mut data = [0u8; 4];
+        dram.read(RX_BUF + NET_HDR_SIZE as u64, &mut data).unwrap();
+        assert_eq!(&data, b"ping");
+        assert_eq!(dram.load(RX_USED + 8, 32).unwrap(), NET_HDR_SIZE as u64 + 4);
+        assert!(dev.interrupt());
+    }
+
+    #[test]
+    fn test_queues_drop_frames_beyond_the_cap() {
+        let mut queue = PacketQueue::default();
+        for i in 0..MAX_QUEUED_FRAMES + 1 {
+            queue.send_to_guest(vec![i as u8]);
+            queue.send(&[i as u8]);
+        }
+        let frames = queue.frames.lock().unwrap();
+        assert_eq!(frames.to_guest.len(), MAX_QUEUED_FRAMES);
+        assert_eq!(frames.from_guest.len(), MAX_QUEUED_FRAMES);
+        assert_eq!(frames.to_guest.back(), Some(&vec![255]));
+    }
+
+    #[test]
+    fn test_bind_leaves_other_files_alone() {
+        let path = std::env::temp_dir().join(format!("risque-net-{}", std::process::id()));
+        std::fs::write(&path, b"keep").unwrap();
+        assert!(SocketLink::bind(&path, Path::new("peer")).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"keep");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
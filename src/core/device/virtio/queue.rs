@@ -0,0 +1,161 @@
+use crate::core::{dram::Dram, except::Exception};
+
+/// Largest queue size offered to drivers.
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+// Largest total size of the buffers of one chain. Lengths come from the
+// guest, and devices allocate the whole request up front.
+const CHAIN_LEN_MAX: u64 = 16 << 20;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// One guest buffer of a descriptor chain.
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    /// Device-writable rather than device-readable.
+    pub writable: bool,
+}
+
+/// A descriptor chain popped from the available ring.
+#[derive(Debug)]
+pub struct Chain {
+    pub head: u16,
+    pub buffers: Vec<Buffer>,
+}
+
+impl Chain {
+    /// All device-readable bytes, concatenated.
+    pub fn read(&self, dram: &Dram) -> Result<Vec<u8>, Exception> {
+        let mut data = Vec::new();
+        for b in self.buffers.iter().filter(|b| !b.writable) {
+            let start = data.len();
+            data.resize(start + b.len as usize, 0);
+            dram.read(b.addr, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Total size of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.buffers
+            .iter()
+            .filter(|b| b.writable)
+            .map(|b| b.len as usize)
+            .sum()
+    }
+
+    /// Fill the device-writable buffers in order with `data`, returning the
+    /// number of bytes written.
+    pub fn write(&self, dram: &mut Dram, data: &[u8]) -> Result<u32, Exception> {
+        let mut done = 0;
+        for b in self.buffers.iter().filter(|b| b.writable) {
+            if done == data.len() {
+                break;
+            }
+            let n = (b.len as usize).min(data.len() - done);
+            dram.write(b.addr, &data[done..done + n])?;
+            done += n;
+        }
+        Ok(done as u32)
+    }
+}
+
+/// Split virtqueue state shared between the transport and the device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Queue {
+    pub num: u16,
+    pub ready: bool,
+    /// Guest addresses of the descriptor table, available ring and used ring.
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    /// Next available ring entry the device has not consumed.
+    pub last_avail: u16,
+}
+
+impl Queue {
+    /// Whether the driver has made a chain available that was not yet taken.
+    pub fn has_available(&self, dram: &Dram) -> Result<bool, Exception> {
+        if !self.ready || self.num == 0 {
+            return Ok(false);
+        }
+        Ok(load(dram, self.driver.wrapping_add(2), 16)? as u16 != self.last_avail)
+    }
+
+    /// Take the next descriptor chain the driver has made available. Every
+    /// buffer must lie in DRAM, and the chain must not exceed
+    /// `CHAIN_LEN_MAX` in total.
+    pub fn pop(&mut self, dram: &Dram) -> Result<Option<Chain>, Exception> {
+        if !self.ready || self.num == 0 {
+            return Ok(None);
+        }
+        let avail_idx = load(dram, self.driver.wrapping_add(2), 16)? as u16;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+
+        let slot = (self.last_avail % self.num) as u64;
+        let head = load(dram, self.driver.wrapping_add(4 + 2 * slot), 16)? as u16;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut buffers = Vec::new();
+        let mut total = 0u64;
+        let mut index = head;
+        loop {
+            // a chain longer than the queue must contain a loop
+            if index >= self.num || buffers.len() == self.num as usize {
+                return Err(Exception::LoadAccessFault(self.desc));
+            }
+            let desc = self.desc.wrapping_add(16 * index as u64);
+            let flags = load(dram, desc.wrapping_add(12), 16)? as u16;
+            let buffer = Buffer {
+                addr: load(dram, desc, 64)?,
+                len: load(dram, desc.wrapping_add(8), 32)? as u32,
+                writable: flags & VIRTQ_DESC_F_WRITE != 0,
+            };
+            total += buffer.len as u64;
+            if dram.range(buffer.addr, buffer.len as usize).is_none() || total > CHAIN_LEN_MAX {
+                return Err(Exception::LoadAccessFault(buffer.addr));
+            }
+            buffers.push(buffer);
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = load(dram, desc.wrapping_add(14), 16)? as u16;
+        }
+        Ok(Some(Chain { head, buffers }))
+    }
+
+    /// Return a chain to the driver through the used ring.
+    pub fn push(&mut self, dram: &mut Dram, head: u16, len: u32) -> Result<(), Exception> {
+        let used_idx = load(dram, self.device.wrapping_add(2), 16)? as u16;
+        let slot = (used_idx % self.num) as u64;
+        let elem = self.device.wrapping_add(4 + 8 * slot);
+        store(dram, elem, 32, head as u64)?;
+        store(dram, elem.wrapping_add(4), 32, len as u64)?;
+        store(
+            dram,
+            self.device.wrapping_add(2),
+            16,
+            used_idx.wrapping_add(1) as u64,
+        )
+    }
+}
+
+// Ring addresses come from the guest, so check them before touching DRAM
+fn load(dram: &Dram, addr: u64, size: u64) -> Result<u64, Exception> {
+    if !dram.contains(addr) {
+        return Err(Exception::LoadAccessFault(addr));
+    }
+    dram.load(addr, size)
+}
+
+fn store(dram: &mut Dram, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+    if !dram.contains(addr) {
+        return Err(Exception::StoreAMOAccessFault(addr));
+    }
+    dram.store(addr, size, value)
+}
@@ -1,16 +1,53 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use super::except::Exception;
-use super::param::DRAM_BASE;
-use super::param::DRAM_SIZE;
+use super::param::DRAM_PAGE_SIZE;
+
+const PAGE_SIZE: usize = DRAM_PAGE_SIZE as usize;
 
+type Page = [u8; PAGE_SIZE];
+
+/// Sparse DRAM. Pages are allocated on first write and shared between clones
+/// until one of them writes, so cloning for a snapshot is cheap.
+#[derive(Clone)]
 pub struct Dram {
-    pub dram: Vec<u8>,
+    base: u64,
+    size: u64,
+    pages: BTreeMap<u64, Arc<Page>>,
 }
 
 impl Dram {
-    pub fn new(code: Vec<u8>) -> Dram {
-        let mut dram = vec![0; DRAM_SIZE as usize];
-        dram.splice(..code.len(), code.into_iter());
-        Self { dram }
+    /// DRAM of `size` bytes at `base`, with `code` at its start.
+    pub fn new(base: u64, size: u64, code: Vec<u8>) -> Result<Dram, String> {
+        if code.len() as u64 > size {
+            return Err(format!(
+                "the program is {} bytes, larger than DRAM ({} bytes)",
+                code.len(),
+                size
+            ));
+        }
+        let mut dram = Self {
+            base,
+            size,
+            pages: BTreeMap::new(),
+        };
+        for (index, chunk) in code.chunks(PAGE_SIZE).enumerate() {
+            dram.write_page(index as u64, chunk);
+        }
+        Ok(dram)
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < self.size
     }
 
     // addr/size must be valid. Check in bus
@@ -19,14 +56,27 @@ impl Dram {
             return Err(Exception::LoadAccessFault(addr));
         }
         let nbytes = size / 8;
-        let index = (addr - DRAM_BASE) as usize;
-        let mut code = self.dram[index] as u64;
-        // shift the bytes to build up the desired value
-        for i in 1..nbytes {
-            code |= (self.dram[index + i as usize] as u64) << (i * 8);
+        let offset = addr - self.base;
+        if offset + nbytes > self.size {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+
+        let within = (offset % DRAM_PAGE_SIZE) as usize;
+        if within + nbytes as usize <= PAGE_SIZE {
+            // fast path: the access does not straddle a page boundary
+            let Some(page) = self.pages.get(&(offset / DRAM_PAGE_SIZE)) else {
+                return Ok(0);
+            };
+            let mut bytes = [0u8; 8];
+            bytes[..nbytes as usize].copy_from_slice(&page[within..within + nbytes as usize]);
+            return Ok(u64::from_le_bytes(bytes));
         }
 
-        return Ok(code);
+        let mut code = 0;
+        for i in 0..nbytes {
+            code |= (self.read_byte(offset + i) as u64) << (i * 8);
+        }
+        Ok(code)
     }
 
     // addr/size must be valid. Check in bus
@@ -35,11 +85,148 @@ impl Dram {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
         let nbytes = size / 8;
-        let index = (addr - DRAM_BASE) as usize;
+        let offset = addr - self.base;
+        if offset + nbytes > self.size {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+
+        let within = (offset % DRAM_PAGE_SIZE) as usize;
+        if within + nbytes as usize <= PAGE_SIZE {
+            let page = self.page_mut(offset / DRAM_PAGE_SIZE);
+            page[within..within + nbytes as usize]
+                .copy_from_slice(&value.to_le_bytes()[..nbytes as usize]);
+            return Ok(());
+        }
+
         for i in 0..nbytes {
-            let offset = 8 * i as usize;
-            self.dram[index + i as usize] = ((value >> offset) & 0xff) as u8;
+            self.write_byte(offset + i, (value >> (i * 8)) as u8);
         }
-        return Ok(());
+        Ok(())
+    }
+
+    /// Copy `buf.len()` bytes starting at `addr` into `buf`.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        let offset = self
+            .range(addr, buf.len())
+            .ok_or(Exception::LoadAccessFault(addr))?;
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let within = (at % DRAM_PAGE_SIZE) as usize;
+            let n = (PAGE_SIZE - within).min(buf.len() - done);
+            match self.pages.get(&(at / DRAM_PAGE_SIZE)) {
+                Some(page) => buf[done..done + n].copy_from_slice(&page[within..within + n]),
+                None => buf[done..done + n].fill(0),
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Copy `data` into DRAM starting at `addr`.
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        let offset = self
+            .range(addr, data.len())
+            .ok_or(Exception::StoreAMOAccessFault(addr))?;
+        let mut done = 0;
+        while done < data.len() {
+            let at = offset + done as u64;
+            let within = (at % DRAM_PAGE_SIZE) as usize;
+            let n = (PAGE_SIZE - within).min(data.len() - done);
+            self.page_mut(at / DRAM_PAGE_SIZE)[within..within + n]
+                .copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Offset of `addr` if `len` bytes from it lie inside DRAM.
+    pub fn range(&self, addr: u64, len: usize) -> Option<u64> {
+        let offset = addr.checked_sub(self.base)?;
+        let end = offset.checked_add(len as u64)?;
+        (end <= self.size).then_some(offset)
+    }
+
+    /// Iterate over the allocated pages, by page index.
+    pub fn pages(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.pages
+            .iter()
+            .map(|(&index, page)| (index, page.as_slice()))
+    }
+
+    /// Overwrite the start of the page at `index` with `data`.
+    pub fn write_page(&mut self, index: u64, data: &[u8]) {
+        self.page_mut(index)[..data.len()].copy_from_slice(data);
+    }
+
+    /// Bytes of host memory backing this DRAM.
+    pub fn resident_size(&self) -> u64 {
+        self.pages.len() as u64 * DRAM_PAGE_SIZE
+    }
+
+    fn read_byte(&self, offset: u64) -> u8 {
+        self.pages
+            .get(&(offset / DRAM_PAGE_SIZE))
+            .map_or(0, |page| page[(offset % DRAM_PAGE_SIZE) as usize])
+    }
+
+    fn write_byte(&mut self, offset: u64, value: u8) {
+        self.page_mut(offset / DRAM_PAGE_SIZE)[(offset % DRAM_PAGE_SIZE) as usize] = value;
+    }
+
+    fn page_mut(&mut self, index: u64) -> &mut Page {
+        let page = self
+            .pages
+            .entry(index)
+            .or_insert_with(|| Arc::new([0; PAGE_SIZE]));
+        Arc::make_mut(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRAM_BASE: u64 = 0x8000_0000;
+    const DRAM_SIZE: u64 = 0x10_0000;
+
+    #[test]
+    fn test_unwritten_memory_reads_zero() {
+        let dram = Dram::new(DRAM_BASE, DRAM_SIZE, Vec::new()).unwrap();
+        assert_eq!(dram.load(DRAM_BASE + 0x1234, 64).unwrap(), 0);
+        assert_eq!(dram.resident_size(), 0);
+    }
+
+    #[test]
+    fn test_store_across_page_boundary() {
+        let mut dram = Dram::new(DRAM_BASE, DRAM_SIZE, Vec::new()).unwrap();
+        let addr = DRAM_BASE + DRAM_PAGE_SIZE - 3;
+        dram.store(addr, 64, 0x0807_0605_0403_0201).unwrap();
+
+        assert_eq!(dram.load(addr, 64).unwrap(), 0x0807_0605_0403_0201);
+        assert_eq!(dram.load(DRAM_BASE + DRAM_PAGE_SIZE, 8).unwrap(), 0x04);
+        assert_eq!(dram.resident_size(), 2 * DRAM_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_clone_is_copy_on_write() {
+        let mut dram = Dram::new(DRAM_BASE, DRAM_SIZE, vec![1, 2, 3, 4]).unwrap();
+        let snapshot = dram.clone();
+        dram.store(DRAM_BASE, 8, 0xff).unwrap();
+
+        assert_eq!(dram.load(DRAM_BASE, 32).unwrap(), 0x040302ff);
+        assert_eq!(snapshot.load(DRAM_BASE, 32).unwrap(), 0x04030201);
+    }
+
+    #[test]
+    fn test_code_must_fit() {
+        assert!(Dram::new(DRAM_BASE, 4, vec![0; 4]).is_ok());
+        assert!(Dram::new(DRAM_BASE, 4, vec![0; 5]).is_err());
+    }
+
+    #[test]
+    fn test_access_past_end_faults() {
+        let dram = Dram::new(DRAM_BASE, DRAM_SIZE, Vec::new()).unwrap();
+        assert!(dram.load(DRAM_BASE + DRAM_SIZE - 4, 64).is_err());
     }
 }
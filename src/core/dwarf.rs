@@ -0,0 +1,514 @@
+//! Source positions, variables and call frames from the DWARF debugging
+//! information of the loaded executable, read with `gimli`.
+
+use std::path::PathBuf;
+
+use gimli::{
+    constants, AttributeValue, BaseAddresses, CfaRule, DebugFrame, DebuggingInformationEntry,
+    EhFrame, EndianSlice, EntriesTreeNode, Expression, FileEntry, LineProgramHeader, LittleEndian,
+    Operation, RangeIter, RegisterRule, SectionId, Unit, UnitOffset, UnwindContext, UnwindSection,
+};
+
+use super::elf::Elf;
+
+pub type Reader<'a> = EndianSlice<'a, LittleEndian>;
+pub type Dwarf<'a> = gimli::Dwarf<Reader<'a>>;
+
+pub fn error(e: gimli::Error) -> String {
+    format!("bad debugging information: {}", e)
+}
+
+/// The DWARF sections of `elf`; missing ones read as empty.
+pub fn load(elf: &Elf) -> Result<Dwarf<'_>, String> {
+    gimli::Dwarf::load(|id: SectionId| -> Result<Reader<'_>, String> {
+        let data = elf.section(id.name()).map_or(&[][..], |s| &s.data[..]);
+        Ok(EndianSlice::new(data, LittleEndian))
+    })
+}
+
+/// A statement in the line table: code from `addr` on comes from `line` of
+/// `file`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub addr: u64,
+    pub file: String,
+    pub line: u64,
+}
+
+/// The statements of every compilation unit, by address.
+#[derive(Debug, Default)]
+pub struct LineTable {
+    pub rows: Vec<Line>,
+    /// Addresses just past each sequence of rows, where no source applies.
+    pub ends: Vec<u64>,
+}
+
+impl LineTable {
+    pub fn parse(elf: &Elf) -> Result<Self, String> {
+        let dwarf = load(elf)?;
+        let mut table = LineTable::default();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next().map_err(error)? {
+            let unit = dwarf.unit(header).map_err(error)?;
+            let Some(program) = unit.line_program.clone() else {
+                continue;
+            };
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row().map_err(error)? {
+                if row.end_sequence() {
+                    table.ends.push(row.address());
+                    continue;
+                }
+                let (Some(line), Some(file)) = (row.line(), row.file(header)) else {
+                    continue;
+                };
+                if row.is_stmt() {
+                    table.rows.push(Line {
+                        addr: row.address(),
+                        file: path(&dwarf, &unit, header, file)?,
+                        line: line.get(),
+                    });
+                }
+            }
+        }
+        table.rows.sort_by_key(|row| row.addr);
+        table.ends.sort();
+        Ok(table)
+    }
+
+    /// The statement `addr` belongs to.
+    pub fn find(&self, addr: u64) -> Option<&Line> {
+        let index = self.rows.partition_point(|row| row.addr <= addr);
+        let row = self.rows.get(index.checked_sub(1)?)?;
+        // past the end of its sequence, the code has no source
+        let end = self.ends.partition_point(|&end| end <= row.addr);
+        match self.ends.get(end) {
+            Some(&end) if end <= addr => None,
+            _ => Some(row),
+        }
+    }
+}
+
+/// Where `file` lives, relative to the compilation directory if need be.
+fn path(
+    dwarf: &Dwarf,
+    unit: &Unit<Reader>,
+    header: &LineProgramHeader<Reader>,
+    file: &FileEntry<Reader>,
+) -> Result<String, String> {
+    let mut path = PathBuf::new();
+    if let Some(dir) = &unit.comp_dir {
+        path.push(&*dir.to_string_lossy());
+    }
+    if let Some(dir) = file.directory(header) {
+        path.push(
+            &*dwarf
+                .attr_string(unit, dir)
+                .map_err(error)?
+                .to_string_lossy(),
+        );
+    }
+    path.push(
+        &*dwarf
+            .attr_string(unit, file.path_name())
+            .map_err(error)?
+            .to_string_lossy(),
+    );
+    Ok(path.display().to_string())
+}
+
+/// How to recover the caller's registers from a frame, according to the
+/// call frame information.
+#[derive(Debug)]
+pub struct FrameRule {
+    /// The canonical frame address is this register plus the offset.
+    pub cfa: (u16, i64),
+    /// Registers the frame saved, at these offsets from the CFA.
+    pub saved: Vec<(u16, i64)>,
+    pub ra: u16,
+    /// The return address is undefined, as it is in the outermost frame.
+    pub outermost: bool,
+}
+
+/// The rule for a frame at `pc`, from `.debug_frame` or else `.eh_frame`.
+pub fn frame_rule(elf: &Elf, pc: u64) -> Result<Option<FrameRule>, String> {
+    if let Some(section) = elf.section(".debug_frame") {
+        let mut frame = DebugFrame::new(&section.data, LittleEndian);
+        frame.set_address_size(8);
+        if let Some(rule) = unwind(&frame, &BaseAddresses::default(), pc)? {
+            return Ok(Some(rule));
+        }
+    }
+    if let Some(section) = elf.section(".eh_frame") {
+        let mut frame = EhFrame::new(&section.data, LittleEndian);
+        frame.set_address_size(8);
+        let bases = BaseAddresses::default().set_eh_frame(section.addr);
+        return unwind(&frame, &bases, pc);
+    }
+    Ok(None)
+}
+
+fn unwind<'a, S: UnwindSection<Reader<'a>>>(
+    section: &S,
+    bases: &BaseAddresses,
+    pc: u64,
+) -> Result<Option<FrameRule>, String> {
+    let fde = match section.fde_for_address(bases, pc, S::cie_from_offset) {
+        Ok(fde) => fde,
+        Err(gimli::Error::NoUnwindInfoForAddress) => return Ok(None),
+        Err(e) => return Err(error(e)),
+    };
+    let mut context = UnwindContext::new();
+    let row = fde
+        .unwind_info_for_address(section, bases, &mut context, pc)
+        .map_err(error)?;
+    // CFAs computed by expressions are beyond this unwinder
+    let &CfaRule::RegisterAndOffset { register, offset } = row.cfa() else {
+        return Ok(None);
+    };
+    let ra = fde.cie().return_address_register();
+    let mut rule = FrameRule {
+        cfa: (register.0, offset),
+        saved: Vec::new(),
+        ra: ra.0,
+        outermost: false,
+    };
+    for (reg, how) in row.registers() {
+        match how {
+            RegisterRule::Offset(offset) => rule.saved.push((reg.0, *offset)),
+            RegisterRule::Undefined if *reg == ra => rule.outermost = true,
+            _ => {}
+        }
+    }
+    Ok(Some(rule))
+}
+
+/// Where a variable is kept, as far as a location expression of a single
+/// operation can say.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    /// DWARF register number: x0-x31, then f0-f31.
+    Register(u16),
+    /// An offset from the frame base of the function.
+    FrameOffset(i64),
+    RegisterOffset(u16, i64),
+    Address(u64),
+    /// The canonical frame address, as frame bases often are.
+    Cfa,
+    /// Optimised out, not live at pc, or beyond what is decoded here.
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub parameter: bool,
+    pub type_name: String,
+    /// Size in bytes, when the type has one.
+    pub size: Option<u64>,
+    pub location: Location,
+}
+
+/// The variables in scope at a pc, and how the function finds its frame.
+#[derive(Debug, Clone)]
+pub struct Scope {
+    pub frame_base: Location,
+    /// Parameters and locals, outer blocks first.
+    pub variables: Vec<Variable>,
+}
+
+/// The innermost function with debugging information around `pc`.
+pub fn scope_at(elf: &Elf, pc: u64) -> Result<Option<Scope>, String> {
+    let dwarf = load(elf)?;
+    let mut units = dwarf.units();
+    while let Some(header) = units.next().map_err(error)? {
+        let unit = dwarf.unit(header).map_err(error)?;
+        if !contains(dwarf.unit_ranges(&unit), pc)? {
+            continue;
+        }
+        let mut tree = unit.entries_tree(None).map_err(error)?;
+        let root = tree.root().map_err(error)?;
+        if let Some(scope) = find_function(&dwarf, &unit, root, pc)? {
+            return Ok(Some(scope));
+        }
+    }
+    Ok(None)
+}
+
+fn contains(ranges: gimli::Result<RangeIter<Reader>>, pc: u64) -> Result<bool, String> {
+    let mut ranges = ranges.map_err(error)?;
+    while let Some(range) = ranges.next().map_err(error)? {
+        if range.begin <= pc && pc < range.end {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn find_function(
+    dwarf: &Dwarf,
+    unit: &Unit<Reader>,
+    node: EntriesTreeNode<Reader>,
+    pc: u64,
+) -> Result<Option<Scope>, String> {
+    let mut children = node.children();
+    while let Some(child) = children.next().map_err(error)? {
+        let entry = child.entry();
+        match entry.tag() {
+            constants::DW_TAG_subprogram if contains(dwarf.die_ranges(unit, entry), pc)? => {
+                let mut scope = Scope {
+                    frame_base: location(dwarf, unit, entry, constants::DW_AT_frame_base, pc)?,
+                    variables: Vec::new(),
+                };
+                collect(dwarf, unit, child, pc, &mut scope.variables)?;
+                return Ok(Some(scope));
+            }
+            constants::DW_TAG_namespace => {
+                if let Some(scope) = find_function(dwarf, unit, child, pc)? {
+                    return Ok(Some(scope));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// The variables of `node` and of its blocks that contain `pc`.
+fn collect(
+    dwarf: &Dwarf,
+    unit: &Unit<Reader>,
+    node: EntriesTreeNode<Reader>,
+    pc: u64,
+    variables: &mut Vec<Variable>,
+) -> Result<(), String> {
+    let mut children = node.children();
+    while let Some(child) = children.next().map_err(error)? {
+        let entry = child.entry();
+        match entry.tag() {
+            tag @ (constants::DW_TAG_formal_parameter | constants::DW_TAG_variable) => {
+                let Some(name) = name(dwarf, unit, entry)? else {
+                    continue;
+                };
+                let (type_name, size) =
+                    match entry.attr_value(constants::DW_AT_type).map_err(error)? {
+                        Some(AttributeValue::UnitRef(offset)) => describe(dwarf, unit, offset, 0)?,
+                        _ => ("void".into(), None),
+                    };
+                variables.push(Variable {
+                    name,
+                    parameter: tag == constants::DW_TAG_formal_parameter,
+                    type_name,
+                    size,
+                    location: location(dwarf, unit, entry, constants::DW_AT_location, pc)?,
+                });
+            }
+            constants::DW_TAG_lexical_block => {
+                // a block without addresses of its own spans its parent
+                let bounded = entry
+                    .attr(constants::DW_AT_low_pc)
+                    .map_err(error)?
+                    .is_some()
+                    || entry
+                        .attr(constants::DW_AT_ranges)
+                        .map_err(error)?
+                        .is_some();
+                if !bounded || contains(dwarf.die_ranges(unit, entry), pc)? {
+                    collect(dwarf, unit, child, pc, variables)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn name(
+    dwarf: &Dwarf,
+    unit: &Unit<Reader>,
+    entry: &DebuggingInformationEntry<Reader>,
+) -> Result<Option<String>, String> {
+    match entry.attr_value(constants::DW_AT_name).map_err(error)? {
+        Some(value) => Ok(Some(
+            dwarf
+                .attr_string(unit, value)
+                .map_err(error)?
+                .to_string_lossy()
+                .into_owned(),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// The C spelling and size of the type at `offset`.
+fn describe(
+    dwarf: &Dwarf,
+    unit: &Unit<Reader>,
+    offset: UnitOffset,
+    depth: usize,
+) -> Result<(String, Option<u64>), String> {
+    let entry = unit.entry(offset).map_err(error)?;
+    let size = entry
+        .attr_value(constants::DW_AT_byte_size)
+        .map_err(error)?
+        .and_then(|size| size.udata_value());
+    let inner = || match entry.attr_value(constants::DW_AT_type).map_err(error)? {
+        // types can refer to themselves through pointers
+        Some(AttributeValue::UnitRef(offset)) if depth < 8 => {
+            describe(dwarf, unit, offset, depth + 1)
+        }
+        _ => Ok(("void".to_string(), None)),
+    };
+    let name = name(dwarf, unit, &entry)?.unwrap_or_default();
+    Ok(match entry.tag() {
+        constants::DW_TAG_pointer_type => (format!("{} *", inner()?.0), size.or(Some(8))),
+        constants::DW_TAG_const_type => {
+            let (inner, size) = inner()?;
+            (format!("const {}", inner), size)
+        }
+        constants::DW_TAG_volatile_type => {
+            let (inner, size) = inner()?;
+            (format!("volatile {}", inner), size)
+        }
+        constants::DW_TAG_typedef => (name, inner()?.1),
+        constants::DW_TAG_array_type => (format!("{}[]", inner()?.0), size),
+        constants::DW_TAG_structure_type => (format!("struct {}", name), size),
+        constants::DW_TAG_union_type => (format!("union {}", name), size),
+        constants::DW_TAG_enumeration_type => (format!("enum {}", name), size),
+        _ => (name, size),
+    })
+}
+
+/// The location `attr` of `entry` gives at `pc`, picking from a location
+/// list where there is one.
+fn location(
+    dwarf: &Dwarf,
+    unit: &Unit<Reader>,
+    entry: &DebuggingInformationEntry<Reader>,
+    attr: constants::DwAt,
+    pc: u64,
+) -> Result<Location, String> {
+    let Some(value) = entry.attr_value(attr).map_err(error)? else {
+        return Ok(Location::Unknown);
+    };
+    if let AttributeValue::Exprloc(expr) = value {
+        return Ok(decode(expr, unit));
+    }
+    if let Some(mut list) = dwarf.attr_locations(unit, value).map_err(error)? {
+        while let Some(entry) = list.next().map_err(error)? {
+            if entry.range.begin <= pc && pc < entry.range.end {
+                return Ok(decode(entry.data, unit));
+            }
+        }
+    }
+    Ok(Location::Unknown)
+}
+
+fn decode(expr: Expression<Reader>, unit: &Unit<Reader>) -> Location {
+    let mut ops = expr.operations(unit.encoding());
+    let Ok(Some(op)) = ops.next() else {
+        return Location::Unknown;
+    };
+    if !matches!(ops.next(), Ok(None)) {
+        return Location::Unknown;
+    }
+    match op {
+        Operation::Register { register } => Location::Register(register.0),
+        Operation::FrameOffset { offset } => Location::FrameOffset(offset),
+        Operation::RegisterOffset {
+            register, offset, ..
+        } => Location::RegisterOffset(register.0, offset),
+        Operation::Address { address } => Location::Address(address),
+        Operation::CallFrameCFA => Location::Cfa,
+        _ => Location::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `tests/data/add-g.elf`, written by hand in the shape of `gcc -O0 -g`
+    /// output for `/src/fixture.c`:
+    ///
+    ///      1  volatile long tohost;
+    ///      2
+    ///      3  long add(long a, long b)
+    ///      4  {
+    ///      5      long sum = a + b;
+    ///      6      return sum;
+    ///      7  }
+    ///      8
+    ///      9  void _start(void)
+    ///     10  {
+    ///     11      tohost = add(3, 4) == 7 ? 1 : 3;
+    ///     12      for (;;);
+    ///     13  }
+    ///
+    /// `_start` is at 0x8000_0000 and `add` at 0x8000_002c; `add` keeps its
+    /// frame in `s0`, with `sum` at `frame-24`.
+    fn fixture() -> Elf {
+        Elf::parse(include_bytes!("../../tests/data/add-g.elf")).unwrap()
+    }
+
+    #[test]
+    fn test_line_table() {
+        let table = LineTable::parse(&fixture()).unwrap();
+        let lines: Vec<_> = table.rows.iter().map(|row| (row.addr, row.line)).collect();
+        assert_eq!(
+            lines,
+            [
+                (0x8000_0000, 10),
+                (0x8000_0004, 11),
+                (0x8000_0010, 11),
+                (0x8000_0028, 12),
+                (0x8000_002c, 4),
+                (0x8000_0038, 5),
+                (0x8000_0040, 6),
+                (0x8000_0044, 7),
+            ]
+        );
+        assert_eq!(table.ends, [0x8000_0050]);
+        assert_eq!(table.rows[0].file, "/src/fixture.c");
+
+        let line = |addr| table.find(addr).map(|row| row.line);
+        assert_eq!(line(0x8000_003c), Some(5));
+        assert_eq!(line(0x8000_004c), Some(7));
+        assert_eq!(line(0x8000_0050), None);
+        assert_eq!(line(0x7fff_fffc), None);
+    }
+
+    #[test]
+    fn test_line_table_is_parsed_once() {
+        let elf = fixture();
+        let table = elf.lines().unwrap();
+        assert_eq!(table.rows.len(), 8);
+        assert!(std::sync::Arc::ptr_eq(&table, &elf.lines().unwrap()));
+    }
+
+    #[test]
+    fn test_scope_at() {
+        let elf = fixture();
+        let scope = scope_at(&elf, 0x8000_0040).unwrap().unwrap();
+        assert_eq!(scope.frame_base, Location::Register(8));
+        let variables: Vec<_> = scope
+            .variables
+            .iter()
+            .map(|v| (v.name.as_str(), v.parameter, v.size, v.location.clone()))
+            .collect();
+        assert_eq!(
+            variables,
+            [
+                ("a", true, Some(8), Location::Register(10)),
+                ("b", true, Some(8), Location::Register(11)),
+                ("sum", false, Some(8), Location::FrameOffset(-24)),
+            ]
+        );
+        assert_eq!(scope.variables[2].type_name, "long");
+
+        let scope = scope_at(&elf, 0x8000_0004).unwrap().unwrap();
+        assert_eq!(scope.frame_base, Location::Cfa);
+        assert!(scope.variables.is_empty());
+        assert!(scope_at(&elf, 0x8000_0050).unwrap().is_none());
+    }
+}
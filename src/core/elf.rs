@@ -0,0 +1,335 @@
+//! Just enough of ELF64 to load a statically linked RISC-V executable.
+
+use std::sync::{Arc, OnceLock};
+
+use super::dwarf::LineTable;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const STT_FUNC: u8 = 2;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+/// A `PT_LOAD` segment: `filesz` bytes from `offset` in the file, followed
+/// by zeros up to `memsz`.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub offset: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// An entry of `.symtab` with a name.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+    /// Typed as a function, as compilers mark them; assembly labels are not.
+    pub func: bool,
+}
+
+/// A section kept for its contents: the DWARF ones and `.eh_frame`.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub addr: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: u64,
+    /// Address of the program headers once loaded, for `AT_PHDR`.
+    pub phdr: u64,
+    pub phnum: u64,
+    pub segments: Vec<Segment>,
+    /// Empty for stripped files.
+    pub symbols: Vec<Symbol>,
+    /// Empty for files without debugging information.
+    pub sections: Vec<Section>,
+    /// The line table, once something has asked for it.
+    pub lines: OnceLock<Arc<LineTable>>,
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, String> {
+    at.checked_add(2)
+        .and_then(|end| data.get(at..end))
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated ELF file".to_string())
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, String> {
+    at.checked_add(4)
+        .and_then(|end| data.get(at..end))
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated ELF file".to_string())
+}
+
+fn u64_at(data: &[u8], at: usize) -> Result<u64, String> {
+    at.checked_add(8)
+        .and_then(|end| data.get(at..end))
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated ELF file".to_string())
+}
+
+/// Where entry `index` of a table of `size`-byte entries at `offset` starts,
+/// provided the whole entry lies within the file.
+fn entry_at(data: &[u8], offset: usize, index: usize, size: usize) -> Result<usize, String> {
+    index
+        .checked_mul(size)
+        .and_then(|relative| offset.checked_add(relative))
+        .filter(|at| at.checked_add(size).is_some_and(|end| end <= data.len()))
+        .ok_or_else(|| "truncated ELF file".to_string())
+}
+
+/// The named entries of the first symbol table.
+fn symbols(data: &[u8]) -> Result<Vec<Symbol>, String> {
+    let shoff = u64_at(data, 40)? as usize;
+    let shnum = u16_at(data, 60)? as usize;
+    if shoff == 0 || u16_at(data, 58)? as usize != SHDR_SIZE {
+        return Ok(Vec::new());
+    }
+    let section = |index: usize| -> Result<(u32, &[u8], usize), String> {
+        let at = entry_at(data, shoff, index, SHDR_SIZE)?;
+        let offset = u64_at(data, at + 24)? as usize;
+        let size = u64_at(data, at + 32)? as usize;
+        let body = offset
+            .checked_add(size)
+            .and_then(|end| data.get(offset..end))
+            .ok_or("truncated ELF file")?;
+        Ok((u32_at(data, at + 4)?, body, u32_at(data, at + 40)? as usize))
+    };
+
+    for index in 0..shnum {
+        let (kind, table, link) = section(index)?;
+        if kind != SHT_SYMTAB {
+            continue;
+        }
+        let (_, names, _) = section(link)?;
+        let mut symbols = Vec::new();
+        for entry in table.chunks_exact(SYM_SIZE) {
+            let start = u32_at(entry, 0)? as usize;
+            let name = names.get(start..).unwrap_or_default();
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            if name.is_empty() {
+                continue;
+            }
+            symbols.push(Symbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                addr: u64_at(entry, 8)?,
+                size: u64_at(entry, 16)?,
+                func: entry[4] & 0xf == STT_FUNC,
+            });
+        }
+        return Ok(symbols);
+    }
+    Ok(Vec::new())
+}
+
+/// The debugging sections, found by name through `.shstrtab`.
+fn debug_sections(data: &[u8]) -> Result<Vec<Section>, String> {
+    let shoff = u64_at(data, 40)? as usize;
+    let shnum = u16_at(data, 60)? as usize;
+    let shstrndx = u16_at(data, 62)? as usize;
+    if shoff == 0 || shstrndx >= shnum || u16_at(data, 58)? as usize != SHDR_SIZE {
+        return Ok(Vec::new());
+    }
+    let body = |at: usize| -> Result<&[u8], String> {
+        let offset = u64_at(data, at + 24)? as usize;
+        let size = u64_at(data, at + 32)? as usize;
+        offset
+            .checked_add(size)
+            .and_then(|end| data.get(offset..end))
+            .ok_or_else(|| "truncated ELF file".to_string())
+    };
+    let names = body(entry_at(data, shoff, shstrndx, SHDR_SIZE)?)?;
+
+    let mut sections = Vec::new();
+    for index in 0..shnum {
+        let at = entry_at(data, shoff, index, SHDR_SIZE)?;
+        let start = u32_at(data, at)? as usize;
+        let name = names.get(start..).unwrap_or_default();
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        let name = String::from_utf8_lossy(name);
+        if !(name.starts_with(".debug_") || name == ".eh_frame")
+            || u32_at(data, at + 4)? == SHT_NOBITS
+        {
+            continue;
+        }
+        sections.push(Section {
+            name: name.into_owned(),
+            addr: u64_at(data, at + 16)?,
+            data: body(at)?.to_vec(),
+        });
+    }
+    Ok(sections)
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Elf, String> {
+        if data.len() < EHDR_SIZE || &data[..4] != MAGIC {
+            return Err("not an ELF file".into());
+        }
+        if data[4] != CLASS_64 || data[5] != DATA_LSB {
+            return Err("not a little-endian ELF64 file".into());
+        }
+        if u16_at(data, 18)? != EM_RISCV {
+            return Err("not a RISC-V executable".into());
+        }
+        if u16_at(data, 16)? != ET_EXEC {
+            return Err("only statically linked executables can be loaded".into());
+        }
+
+        let entry = u64_at(data, 24)?;
+        let phoff = u64_at(data, 32)? as usize;
+        let phentsize = u16_at(data, 54)? as usize;
+        let phnum = u16_at(data, 56)? as usize;
+        if phentsize != PHDR_SIZE {
+            return Err(format!("unexpected program header size {}", phentsize));
+        }
+
+        let mut segments = Vec::new();
+        let mut phdr = None;
+        for i in 0..phnum {
+            let at = entry_at(data, phoff, i, PHDR_SIZE)?;
+            let vaddr = u64_at(data, at + 16)?;
+            match u32_at(data, at)? {
+                PT_LOAD => {
+                    let segment = Segment {
+                        vaddr,
+                        offset: u64_at(data, at + 8)?,
+                        filesz: u64_at(data, at + 32)?,
+                        memsz: u64_at(data, at + 40)?,
+                    };
+                    let end = segment.offset.checked_add(segment.filesz);
+                    if segment.filesz > segment.memsz
+                        || end.is_none_or(|e| e > data.len() as u64)
+                        || vaddr.checked_add(segment.memsz).is_none()
+                    {
+                        return Err(format!("malformed segment at 0x{:x}", vaddr));
+                    }
+                    segments.push(segment);
+                }
+                PT_INTERP => return Err("dynamically linked executables are not supported".into()),
+                PT_PHDR => phdr = Some(vaddr),
+                _ => {}
+            }
+        }
+
+        // without PT_PHDR, find the headers inside a loaded segment
+        let phdr = phdr.or_else(|| {
+            segments
+                .iter()
+                .find(|s| s.offset <= phoff as u64 && (phoff as u64) < s.offset + s.filesz)
+                .map(|s| s.vaddr + (phoff as u64 - s.offset))
+        });
+
+        Ok(Elf {
+            entry,
+            phdr: phdr.unwrap_or(0),
+            phnum: phnum as u64,
+            segments,
+            symbols: symbols(data)?,
+            sections: debug_sections(data)?,
+            lines: OnceLock::new(),
+        })
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// The line table of the debugging information, parsed on first use.
+    pub fn lines(&self) -> Result<Arc<LineTable>, String> {
+        if let Some(table) = self.lines.get() {
+            return Ok(table.clone());
+        }
+        let table = Arc::new(LineTable::parse(self)?);
+        Ok(self.lines.get_or_init(|| table).clone())
+    }
+
+    /// The function `addr` lies in: the function symbol covering it or,
+    /// for code without sizes such as hand-written assembly, the nearest
+    /// untyped label or function at or below it.
+    pub fn function_at(&self, addr: u64) -> Option<&Symbol> {
+        let covering = self
+            .symbols
+            .iter()
+            .find(|s| s.func && s.addr <= addr && addr - s.addr < s.size);
+        covering.or_else(|| {
+            self.symbols
+                .iter()
+                .filter(|s| (s.func || s.size == 0) && s.addr <= addr)
+                .max_by_key(|s| s.addr)
+        })
+    }
+
+    /// First address past the highest segment.
+    pub fn end(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|s| s.vaddr + s.memsz)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An executable with one `PT_LOAD` segment at `vaddr` of `memsz` bytes
+    /// and the header tables at `phoff` and `shoff`.
+    fn executable(phoff: u64, shoff: u64, vaddr: u64, memsz: u64) -> Vec<u8> {
+        let mut elf = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0".to_vec();
+        elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+        elf.extend_from_slice(&EM_RISCV.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        for word in [vaddr, phoff, shoff] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        for half in [64u16, 56, 1, 64, 1, 0] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [PT_LOAD, 5] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        for word in [0, vaddr, vaddr, 0, memsz, 8] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn test_malformed_headers_are_refused() {
+        let elf = Elf::parse(&executable(64, 0, 0x1000, 0x100)).unwrap();
+        assert_eq!((elf.entry, elf.end()), (0x1000, 0x1100));
+
+        // tables that run off the end of the file, or of the address space
+        assert!(Elf::parse(&executable(u64::MAX - 8, 0, 0x1000, 0x100)).is_err());
+        assert!(Elf::parse(&executable(64, u64::MAX - 8, 0x1000, 0x100)).is_err());
+        assert!(Elf::parse(&executable(64, 1 << 40, 0x1000, 0x100)).is_err());
+        // a segment that wraps around
+        assert!(Elf::parse(&executable(64, 0, u64::MAX - 8, 0x100)).is_err());
+    }
+}
@@ -1,6 +1,59 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Exception {
-    StoreAMOAccessFault(u64),
-    LoadAccessFault(u64),
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
     IllegalInstruction(u32),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAMOAddressMisaligned(u64),
+    StoreAMOAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StoreAMOPageFault(u64),
+}
+
+impl Exception {
+    /// Exception code written to `xcause`.
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAMOAddressMisaligned(_) => 6,
+            Exception::StoreAMOAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StoreAMOPageFault(_) => 15,
+        }
+    }
+
+    /// Value written to `xtval`: the faulting address or instruction.
+    pub fn tval(&self) -> u64 {
+        match *self {
+            Exception::IllegalInstruction(insn) => insn as u64,
+            Exception::InstructionAddressMisaligned(addr)
+            | Exception::InstructionAccessFault(addr)
+            | Exception::Breakpoint(addr)
+            | Exception::LoadAddressMisaligned(addr)
+            | Exception::LoadAccessFault(addr)
+            | Exception::StoreAMOAddressMisaligned(addr)
+            | Exception::StoreAMOAccessFault(addr)
+            | Exception::InstructionPageFault(addr)
+            | Exception::LoadPageFault(addr)
+            | Exception::StoreAMOPageFault(addr) => addr,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
 }
@@ -0,0 +1,959 @@
+//! F: single-precision floating point, and the machinery shared with D.
+//!
+//! Arithmetic runs on the host's IEEE 754 operations, which round to
+//! nearest-even. The other rounding modes are derived from the exact error
+//! of that result (an error-free transform per operation), stepping one ulp
+//! where the mode rounds the other way. Fused multiply-adds recover theirs
+//! with Boldo and Muller's ErrFma.
+
+use std::collections::HashMap;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::sync::Arc;
+
+use crate::kit::bits::*;
+use crate::kit::insn::*;
+use crate::vdepart;
+
+use super::{
+    cpu::Cpu,
+    csr,
+    except::Exception,
+    isa::{install, IsaDefine},
+};
+
+// fflags
+const NX: u64 = 1 << 0;
+const UF: u64 = 1 << 1;
+const OF: u64 = 1 << 2;
+const DZ: u64 = 1 << 3;
+const NV: u64 = 1 << 4;
+
+// rounding modes
+const RNE: u64 = 0;
+const RTZ: u64 = 1;
+const RDN: u64 = 2;
+const RUP: u64 = 3;
+const RMM: u64 = 4;
+const DYN: u64 = 7;
+
+const OP_LOAD_FP: u32 = 0x07;
+const OP_STORE_FP: u32 = 0x27;
+const OP_FP: u32 = 0x53;
+pub(super) const OP_MADD: u32 = 0x43;
+pub(super) const OP_MSUB: u32 = 0x47;
+pub(super) const OP_NMSUB: u32 = 0x4b;
+pub(super) const OP_NMADD: u32 = 0x4f;
+
+// funct7 and opcode, with the rounding mode in funct3
+const MASK_RM: u32 = 0xfe00007f;
+// as above, with rs2 selecting the variant
+const MASK_RM_RS2: u32 = 0xfff0007f;
+// funct7, funct3 and opcode
+const MASK_F3: u32 = 0xfe00707f;
+// funct7, rs2, funct3 and opcode
+const MASK_F3_RS2: u32 = 0xfff0707f;
+
+/// An IEEE 754 format held in the f registers.
+pub trait Float:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + Send
+    + Sync
+    + 'static
+{
+    /// `fmt` field of the encoding: 0 for single, 1 for double.
+    const FMT: u32;
+    const WIDTH: u64;
+    const SIGN: u64;
+    const QUIET: u64;
+    const CANONICAL_NAN: u64;
+    const ZERO: Self;
+    const MAX: Self;
+    const MIN_POSITIVE: Self;
+
+    fn from_bits(bits: u64) -> Self;
+    fn bits(self) -> u64;
+    fn to_f64(self) -> f64;
+    /// Round to nearest-even.
+    fn from_f64(x: f64) -> Self;
+    /// Round to nearest-even.
+    fn from_i128(x: i128) -> Self;
+    fn sqrt(self) -> Self;
+    fn mul_add(self, b: Self, c: Self) -> Self;
+    fn next_up(self) -> Self;
+    fn next_down(self) -> Self;
+    fn is_nan(self) -> bool;
+    fn is_finite(self) -> bool;
+
+    /// Read a register, where a narrower value must be NaN-boxed.
+    fn unbox(reg: u64) -> Self {
+        if Self::WIDTH < 64 && reg >> Self::WIDTH != mask(64 - Self::WIDTH as u32) {
+            Self::from_bits(Self::CANONICAL_NAN)
+        } else {
+            Self::from_bits(reg & mask(Self::WIDTH as u32))
+        }
+    }
+
+    /// Register value, NaN-boxed if narrower than 64 bits.
+    fn boxed(self) -> u64 {
+        self.bits() | !mask(Self::WIDTH as u32)
+    }
+
+    fn is_infinite(self) -> bool {
+        !self.is_nan() && !self.is_finite()
+    }
+
+    fn is_negative(self) -> bool {
+        self.bits() & Self::SIGN != 0
+    }
+
+    fn is_signaling(self) -> bool {
+        self.is_nan() && self.bits() & Self::QUIET == 0
+    }
+
+    fn abs(self) -> Self {
+        Self::from_bits(self.bits() & !Self::SIGN)
+    }
+}
+
+macro_rules! float_impl {
+    ($t:ty, $fmt:expr, $width:expr, $nan:expr) => {
+        impl Float for $t {
+            const FMT: u32 = $fmt;
+            const WIDTH: u64 = $width;
+            const SIGN: u64 = 1 << ($width - 1);
+            const QUIET: u64 = 1 << (<$t>::MANTISSA_DIGITS - 2);
+            const CANONICAL_NAN: u64 = $nan;
+            const ZERO: Self = 0.0;
+            const MAX: Self = <$t>::MAX;
+            const MIN_POSITIVE: Self = <$t>::MIN_POSITIVE;
+
+            fn from_bits(bits: u64) -> Self {
+                <$t>::from_bits(bits as _)
+            }
+
+            fn bits(self) -> u64 {
+                self.to_bits() as u64
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn from_f64(x: f64) -> Self {
+                x as $t
+            }
+
+            fn from_i128(x: i128) -> Self {
+                x as $t
+            }
+
+            fn sqrt(self) -> Self {
+                <$t>::sqrt(self)
+            }
+
+            fn mul_add(self, b: Self, c: Self) -> Self {
+                <$t>::mul_add(self, b, c)
+            }
+
+            fn next_up(self) -> Self {
+                <$t>::next_up(self)
+            }
+
+            fn next_down(self) -> Self {
+                <$t>::next_down(self)
+            }
+
+            fn is_nan(self) -> bool {
+                <$t>::is_nan(self)
+            }
+
+            fn is_finite(self) -> bool {
+                <$t>::is_finite(self)
+            }
+        }
+    };
+}
+
+float_impl!(f32, 0, 32, 0x7fc0_0000);
+float_impl!(f64, 1, 64, 0x7ff8_0000_0000_0000);
+
+/// Round `value` to mode `rm`. `value` is the round-to-nearest-even result
+/// of an operation on `inputs` whose exact result is `value + err`; the
+/// exceptions raised are added to `flags`.
+fn round<F: Float>(rm: u64, inputs: &[F], value: F, err: f64, flags: &mut u64) -> F {
+    if inputs.iter().any(|x| x.is_signaling()) {
+        *flags |= NV;
+    }
+    if value.is_nan() {
+        if !inputs.iter().any(|x| x.is_nan()) {
+            *flags |= NV;
+        }
+        return F::from_bits(F::CANONICAL_NAN);
+    }
+    if value.is_infinite() {
+        if inputs.iter().all(|x| x.is_finite()) {
+            *flags |= OF | NX;
+            // directed modes stop at the largest finite value
+            let negative = value.is_negative();
+            let saturate = match rm {
+                RTZ => true,
+                RDN => !negative,
+                RUP => negative,
+                _ => false,
+            };
+            if saturate {
+                return if negative { -F::MAX } else { F::MAX };
+            }
+        }
+        return value;
+    }
+    if err == 0.0 || err.is_nan() {
+        return value;
+    }
+
+    *flags |= NX;
+    let ulp = value.abs().next_up().to_f64() - value.abs().to_f64();
+    let value = match rm {
+        RTZ if value > F::ZERO && err < 0.0 => value.next_down(),
+        RTZ if value < F::ZERO && err > 0.0 => value.next_up(),
+        RDN if err < 0.0 => value.next_down(),
+        RUP if err > 0.0 => value.next_up(),
+        // a tie that nearest-even resolved towards zero
+        RMM if (err > 0.0) == (value > F::ZERO) && err.abs() * 2.0 == ulp => {
+            if value > F::ZERO {
+                value.next_up()
+            } else {
+                value.next_down()
+            }
+        }
+        _ => value,
+    };
+    // stepping past the largest finite value
+    if value.is_infinite() {
+        *flags |= OF;
+    }
+    if value.abs() < F::MIN_POSITIVE {
+        *flags |= UF;
+    }
+    value
+}
+
+/// `a + b` and its exact error (TwoSum).
+fn two_sum<F: Float>(a: F, b: F) -> (F, F) {
+    let sum = a + b;
+    let bv = sum - a;
+    let av = sum - bv;
+    (sum, (a - av) + (b - bv))
+}
+
+/// `r / d` as an error term, keeping its sign even when it underflows.
+fn quotient_err(r: f64, d: f64) -> f64 {
+    let err = r / d;
+    if err == 0.0 && r != 0.0 {
+        f64::from_bits(1).copysign(r * d.signum())
+    } else {
+        err
+    }
+}
+
+pub(super) fn add<F: Float>(a: F, b: F, rm: u64, flags: &mut u64) -> F {
+    let sum = a + b;
+    // an exact zero sum is -0 when rounding down, unless both were +0
+    if sum == F::ZERO && rm == RDN && (a.bits() | b.bits()) != 0 && a.is_finite() {
+        return round(rm, &[a, b], -F::ZERO, 0.0, flags);
+    }
+    let err = if sum.is_finite() {
+        two_sum(a, b).1.to_f64()
+    } else {
+        0.0
+    };
+    round(rm, &[a, b], sum, err, flags)
+}
+
+pub(super) fn sub<F: Float>(a: F, b: F, rm: u64, flags: &mut u64) -> F {
+    if b.is_nan() {
+        return round(rm, &[a, b], b, 0.0, flags);
+    }
+    add(a, -b, rm, flags)
+}
+
+pub(super) fn mul<F: Float>(a: F, b: F, rm: u64, flags: &mut u64) -> F {
+    let product = a * b;
+    let err = if product.is_finite() {
+        a.mul_add(b, -product).to_f64()
+    } else {
+        0.0
+    };
+    round(rm, &[a, b], product, err, flags)
+}
+
+pub(super) fn div<F: Float>(a: F, b: F, rm: u64, flags: &mut u64) -> F {
+    if b == F::ZERO && a.is_finite() && a != F::ZERO {
+        *flags |= DZ;
+        return a / b;
+    }
+    let quotient = a / b;
+    let err = if quotient.is_finite() && b.is_finite() && b != F::ZERO {
+        let r = (-quotient).mul_add(b, a);
+        quotient_err(r.to_f64(), b.to_f64())
+    } else {
+        0.0
+    };
+    round(rm, &[a, b], quotient, err, flags)
+}
+
+fn sqrt<F: Float>(a: F, rm: u64, flags: &mut u64) -> F {
+    let root = a.sqrt();
+    let err = if root.is_finite() && root > F::ZERO {
+        let r = (-root).mul_add(root, a);
+        quotient_err(r.to_f64(), 2.0 * root.to_f64())
+    } else {
+        0.0
+    };
+    round(rm, &[a], root, err, flags)
+}
+
+/// `±(a * b) ± c`, negating the product and/or the addend.
+fn fused<F: Float>(a: F, b: F, c: F, negate: bool, subtract: bool, rm: u64, flags: &mut u64) -> F {
+    let a = if negate { -a } else { a };
+    let c = if subtract { -c } else { c };
+    // inf * 0 is invalid even when the addend is a quiet NaN
+    if (a.is_infinite() && b == F::ZERO) || (a == F::ZERO && b.is_infinite()) {
+        *flags |= NV;
+    }
+    let result = a.mul_add(b, c);
+    let err = if result.is_finite() {
+        fused_err(a, b, c, result)
+    } else {
+        0.0
+    };
+    round(rm, &[a, b, c], result, err, flags)
+}
+
+/// The error `a * b + c - r` of the fused result `r`. ErrFma gives it
+/// exactly as `r2 + r3`; `r2` carries the sign, and `r3` only decides
+/// whether `r2` is a tie.
+fn fused_err<F: Float>(a: F, b: F, c: F, r: F) -> f64 {
+    let u1 = a * b;
+    let u2 = a.mul_add(b, -u1);
+    let (alpha1, alpha2) = two_sum(c, u2);
+    let (beta1, beta2) = two_sum(u1, alpha1);
+    let gamma = (beta1 - r) + beta2;
+    let (r2, r3) = two_sum(gamma, alpha2);
+    let err = r2.to_f64();
+    if r3 > F::ZERO {
+        err.next_up()
+    } else if r3 < F::ZERO {
+        err.next_down()
+    } else {
+        err
+    }
+}
+
+fn min_max<F: Float>(a: F, b: F, max: bool, flags: &mut u64) -> F {
+    if a.is_signaling() || b.is_signaling() {
+        *flags |= NV;
+    }
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::from_bits(F::CANONICAL_NAN),
+        (true, false) => b,
+        (false, true) => a,
+        // -0 is below +0
+        _ if a == b => {
+            if a.is_negative() != max {
+                a
+            } else {
+                b
+            }
+        }
+        _ if (a > b) == max => a,
+        _ => b,
+    }
+}
+
+/// Round to an integral value in mode `rm`.
+fn round_integral(x: f64, rm: u64) -> f64 {
+    match rm {
+        RNE => x.round_ties_even(),
+        RTZ => x.trunc(),
+        RDN => x.floor(),
+        RUP => x.ceil(),
+        // RMM
+        _ => x.round(),
+    }
+}
+
+/// Convert to an integer of `bits` bits, saturating out-of-range values
+/// and NaN. 32-bit results are sign-extended.
+fn to_int<F: Float>(x: F, signed: bool, bits: u32, rm: u64, flags: &mut u64) -> u64 {
+    let (lo, hi) = if signed {
+        (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+    } else {
+        (0, (1i128 << bits) - 1)
+    };
+    let value = if x.is_nan() {
+        *flags |= NV;
+        hi
+    } else {
+        let v = x.to_f64();
+        let r = round_integral(v, rm);
+        // float to int casts saturate, which keeps infinities in order
+        let n = r as i128;
+        if n < lo || n > hi {
+            *flags |= NV;
+            if v < 0.0 {
+                lo
+            } else {
+                hi
+            }
+        } else {
+            if r != v {
+                *flags |= NX;
+            }
+            n
+        }
+    };
+    if bits == 32 {
+        sext(value as u32 as u64, 32)
+    } else {
+        value as u64
+    }
+}
+
+fn from_int<F: Float>(x: i128, rm: u64, flags: &mut u64) -> F {
+    let value = F::from_i128(x);
+    let err = (x - value.to_f64() as i128) as f64;
+    round(rm, &[], value, err, flags)
+}
+
+/// Ten-bit class mask written by `fclass`.
+fn classify<F: Float>(x: F) -> u64 {
+    let negative = x.is_negative();
+    let bit = if x.is_nan() {
+        if x.is_signaling() {
+            8
+        } else {
+            9
+        }
+    } else if x.is_infinite() {
+        if negative {
+            0
+        } else {
+            7
+        }
+    } else if x == F::ZERO {
+        if negative {
+            3
+        } else {
+            4
+        }
+    } else if x.abs() < F::MIN_POSITIVE {
+        if negative {
+            2
+        } else {
+            5
+        }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+impl Cpu {
+    /// FP instructions are illegal while `mstatus.FS` is Off.
+    pub fn fp_check(&self, insn: u32) -> Result<(), Exception> {
+        if self.csr.read(csr::MSTATUS) & csr::MSTATUS_FS == 0 {
+            return Err(Exception::IllegalInstruction(insn));
+        }
+        Ok(())
+    }
+
+    /// Set `mstatus.FS` to Dirty after the FP state changed.
+    pub(super) fn fp_dirty(&mut self) {
+        let mstatus = self.csr.read(csr::MSTATUS);
+        if mstatus & csr::MSTATUS_FS != csr::MSTATUS_FS {
+            self.write_csr(csr::MSTATUS, mstatus | csr::MSTATUS_FS);
+        }
+    }
+
+    pub fn rfpr(&self, id: u32) -> u64 {
+        self.fregs[id as usize]
+    }
+
+    /// Write an f register, recording the old value for step-back.
+    pub fn wfpr(&mut self, id: u32, value: u64) {
+        self.history.record_freg(id, self.fregs[id as usize]);
+        if let Some(log) = &mut self.commit_log {
+            log.freg(id);
+        }
+        self.fregs[id as usize] = value;
+        self.fp_dirty();
+    }
+
+    /// Accrue exception flags in `fflags`.
+    fn raise(&mut self, flags: u64) {
+        let fflags = self.csr.read(csr::FFLAGS);
+        if fflags | flags != fflags {
+            self.write_csr(csr::FFLAGS, fflags | flags);
+            self.fp_dirty();
+        }
+    }
+
+    /// The rounding mode for `insn`, resolving the dynamic mode from `frm`.
+    fn rounding_mode(&self, insn: u32) -> Result<u64, Exception> {
+        let rm = match (insn >> 12 & 7) as u64 {
+            DYN => self.csr.read(csr::FRM),
+            rm => rm,
+        };
+        if rm > RMM {
+            return Err(Exception::IllegalInstruction(insn));
+        }
+        Ok(rm)
+    }
+}
+
+fn ident<F: Float>(funct5: u32, rs2: u32, funct3: u32) -> u32 {
+    funct5 << 27 | F::FMT << 25 | rs2 << 20 | funct3 << 12 | OP_FP
+}
+
+pub(super) fn load<F: Float>(mnemonic: &'static str) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            cpu.fp_check(insn)?;
+            let i = vdepart!(insn, InsnType::I);
+            let addr = cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12));
+            let value = cpu.load(addr, F::WIDTH)?;
+            cpu.wfpr(i.rd, F::from_bits(value).boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        OP_LOAD_FP | (F::FMT + 2) << 12,
+        InsnType::I,
+    )
+}
+
+pub(super) fn store<F: Float>(mnemonic: &'static str) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            cpu.fp_check(insn)?;
+            let s = vdepart!(insn, InsnType::S);
+            let addr = cpu.rgpr(s.rs1).wrapping_add(sext(s.imm as u64, 12));
+            cpu.store(addr, F::WIDTH, cpu.rfpr(s.rs2))?;
+            Ok(0)
+        })),
+        mnemonic,
+        OP_STORE_FP | (F::FMT + 2) << 12,
+        InsnType::S,
+    )
+}
+
+pub(super) type BinaryOp<F> = fn(F, F, u64, &mut u64) -> F;
+
+pub(super) fn arith<F: Float>(mnemonic: &'static str, funct5: u32, op: BinaryOp<F>) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            cpu.fp_check(insn)?;
+            let rm = cpu.rounding_mode(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let mut flags = 0;
+            let result = op(
+                F::unbox(cpu.rfpr(r.rs1)),
+                F::unbox(cpu.rfpr(r.rs2)),
+                rm,
+                &mut flags,
+            );
+            cpu.raise(flags);
+            cpu.wfpr(r.rd, result.boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(funct5, 0, 0),
+        InsnType::R,
+    )
+    .masked(MASK_RM)
+}
+
+pub(super) fn fsqrt<F: Float>(mnemonic: &'static str) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            cpu.fp_check(insn)?;
+            let rm = cpu.rounding_mode(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let mut flags = 0;
+            let result = sqrt(F::unbox(cpu.rfpr(r.rs1)), rm, &mut flags);
+            cpu.raise(flags);
+            cpu.wfpr(r.rd, result.boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x0b, 0, 0),
+        InsnType::R,
+    )
+    .masked(MASK_RM_RS2)
+}
+
+pub(super) fn fused_op<F: Float>(
+    mnemonic: &'static str,
+    opcode: u32,
+    negate: bool,
+    subtract: bool,
+) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            cpu.fp_check(insn)?;
+            let rm = cpu.rounding_mode(insn)?;
+            let r = vdepart!(insn, InsnType::R4);
+            let mut flags = 0;
+            let result = fused(
+                F::unbox(cpu.rfpr(r.rs1)),
+                F::unbox(cpu.rfpr(r.rs2)),
+                F::unbox(cpu.rfpr(r.rs3)),
+                negate,
+                subtract,
+                rm,
+                &mut flags,
+            );
+            cpu.raise(flags);
+            cpu.wfpr(r.rd, result.boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        F::FMT << 25 | opcode,
+        InsnType::R4,
+    )
+}
+
+/// fsgnj, fsgnjn and fsgnjx, selected by funct3.
+pub(super) fn sign_inject<F: Float>(mnemonic: &'static str, funct3: u32) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            cpu.fp_check(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let a = F::unbox(cpu.rfpr(r.rs1)).bits();
+            let b = F::unbox(cpu.rfpr(r.rs2)).bits();
+            let sign = match funct3 {
+                0 => b,
+                1 => !b,
+                _ => a ^ b,
+            } & F::SIGN;
+            cpu.wfpr(r.rd, F::from_bits(a & !F::SIGN | sign).boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x04, 0, funct3),
+        InsnType::R,
+    )
+    .masked(MASK_F3)
+}
+
+pub(super) fn min_max_op<F: Float>(mnemonic: &'static str, max: bool) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            cpu.fp_check(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let mut flags = 0;
+            let result = min_max(
+                F::unbox(cpu.rfpr(r.rs1)),
+                F::unbox(cpu.rfpr(r.rs2)),
+                max,
+                &mut flags,
+            );
+            cpu.raise(flags);
+            cpu.wfpr(r.rd, result.boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x05, 0, max as u32),
+        InsnType::R,
+    )
+    .masked(MASK_F3)
+}
+
+/// feq (funct3 2), flt (1) and fle (0). Only feq is quiet about quiet NaNs.
+pub(super) fn compare<F: Float>(mnemonic: &'static str, funct3: u32) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            cpu.fp_check(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let a = F::unbox(cpu.rfpr(r.rs1));
+            let b = F::unbox(cpu.rfpr(r.rs2));
+            let invalid = if funct3 == 2 {
+                a.is_signaling() || b.is_signaling()
+            } else {
+                a.is_nan() || b.is_nan()
+            };
+            if invalid {
+                cpu.raise(NV);
+            }
+            let result = match funct3 {
+                2 => a == b,
+                1 => a < b,
+                _ => a <= b,
+            };
+            *cpu.wgpr(r.rd) = result as u64;
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x14, 0, funct3),
+        InsnType::R,
+    )
+    .masked(MASK_F3)
+}
+
+pub(super) fn fclass<F: Float>(mnemonic: &'static str) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            cpu.fp_check(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            *cpu.wgpr(r.rd) = classify(F::unbox(cpu.rfpr(r.rs1)));
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x1c, 0, 1),
+        InsnType::R,
+    )
+    .masked(MASK_F3_RS2)
+}
+
+/// fmv.x.w / fmv.x.d: the raw bits, sign-extended from the format's width.
+pub(super) fn move_to_int<F: Float>(mnemonic: &'static str) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            cpu.fp_check(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            *cpu.wgpr(r.rd) = sext(cpu.rfpr(r.rs1) & mask(F::WIDTH as u32), F::WIDTH as u32);
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x1c, 0, 0),
+        InsnType::R,
+    )
+    .masked(MASK_F3_RS2)
+}
+
+/// fmv.w.x / fmv.d.x.
+pub(super) fn move_from_int<F: Float>(mnemonic: &'static str) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            cpu.fp_check(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let value = F::from_bits(cpu.rgpr(r.rs1) & mask(F::WIDTH as u32));
+            cpu.wfpr(r.rd, value.boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x1e, 0, 0),
+        InsnType::R,
+    )
+    .masked(MASK_F3_RS2)
+}
+
+/// fcvt to w (rs2 0), wu (1), l (2) or lu (3).
+pub(super) fn convert_to_int<F: Float>(mnemonic: &'static str, variant: u32) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            cpu.fp_check(insn)?;
+            let rm = cpu.rounding_mode(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let bits = if variant < 2 { 32 } else { 64 };
+            let mut flags = 0;
+            let result = to_int(
+                F::unbox(cpu.rfpr(r.rs1)),
+                variant.is_multiple_of(2),
+                bits,
+                rm,
+                &mut flags,
+            );
+            cpu.raise(flags);
+            *cpu.wgpr(r.rd) = result;
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x18, variant, 0),
+        InsnType::R,
+    )
+    .masked(MASK_RM_RS2)
+}
+
+/// fcvt from w (rs2 0), wu (1), l (2) or lu (3).
+pub(super) fn convert_from_int<F: Float>(mnemonic: &'static str, variant: u32) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(move |cpu, insn| {
+            cpu.fp_check(insn)?;
+            let rm = cpu.rounding_mode(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let x = cpu.rgpr(r.rs1);
+            let x = match variant {
+                0 => x as i32 as i128,
+                1 => x as u32 as i128,
+                2 => x as i64 as i128,
+                _ => x as i128,
+            };
+            let mut flags = 0;
+            let result: F = from_int(x, rm, &mut flags);
+            cpu.raise(flags);
+            cpu.wfpr(r.rd, result.boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<F>(0x1a, variant, 0),
+        InsnType::R,
+    )
+    .masked(MASK_RM_RS2)
+}
+
+/// fcvt between formats: `To` from `From`, with rs2 naming the source format.
+pub(super) fn convert<To: Float, From: Float>(mnemonic: &'static str) -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            cpu.fp_check(insn)?;
+            let rm = cpu.rounding_mode(insn)?;
+            let r = vdepart!(insn, InsnType::R);
+            let x = From::unbox(cpu.rfpr(r.rs1));
+            let mut flags = 0;
+            let result = if x.is_nan() {
+                if x.is_signaling() {
+                    flags |= NV;
+                }
+                To::from_bits(To::CANONICAL_NAN)
+            } else {
+                let v = x.to_f64();
+                let value = To::from_f64(v);
+                let err = if value.is_finite() {
+                    v - value.to_f64()
+                } else {
+                    0.0
+                };
+                let inputs = if x.is_finite() { &[][..] } else { &[value][..] };
+                round(rm, inputs, value, err, &mut flags)
+            };
+            cpu.raise(flags);
+            cpu.wfpr(r.rd, result.boxed());
+            Ok(0)
+        })),
+        mnemonic,
+        ident::<To>(0x08, From::FMT, 0),
+        InsnType::R,
+    )
+    .masked(MASK_RM_RS2)
+}
+
+pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
+    install(map, load::<f32>("flw"));
+    install(map, store::<f32>("fsw"));
+    install(map, arith::<f32>("fadd.s", 0x00, add));
+    install(map, arith::<f32>("fsub.s", 0x01, sub));
+    install(map, arith::<f32>("fmul.s", 0x02, mul));
+    install(map, arith::<f32>("fdiv.s", 0x03, div));
+    install(map, fsqrt::<f32>("fsqrt.s"));
+    install(map, fused_op::<f32>("fmadd.s", OP_MADD, false, false));
+    install(map, fused_op::<f32>("fmsub.s", OP_MSUB, false, true));
+    install(map, fused_op::<f32>("fnmsub.s", OP_NMSUB, true, false));
+    install(map, fused_op::<f32>("fnmadd.s", OP_NMADD, true, true));
+    install(map, sign_inject::<f32>("fsgnj.s", 0));
+    install(map, sign_inject::<f32>("fsgnjn.s", 1));
+    install(map, sign_inject::<f32>("fsgnjx.s", 2));
+    install(map, min_max_op::<f32>("fmin.s", false));
+    install(map, min_max_op::<f32>("fmax.s", true));
+    install(map, compare::<f32>("feq.s", 2));
+    install(map, compare::<f32>("flt.s", 1));
+    install(map, compare::<f32>("fle.s", 0));
+    install(map, fclass::<f32>("fclass.s"));
+    install(map, move_to_int::<f32>("fmv.x.w"));
+    install(map, move_from_int::<f32>("fmv.w.x"));
+    install(map, convert_to_int::<f32>("fcvt.w.s", 0));
+    install(map, convert_to_int::<f32>("fcvt.wu.s", 1));
+    install(map, convert_to_int::<f32>("fcvt.l.s", 2));
+    install(map, convert_to_int::<f32>("fcvt.lu.s", 3));
+    install(map, convert_from_int::<f32>("fcvt.s.w", 0));
+    install(map, convert_from_int::<f32>("fcvt.s.wu", 1));
+    install(map, convert_from_int::<f32>("fcvt.s.l", 2));
+    install(map, convert_from_int::<f32>("fcvt.s.lu", 3));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directed_rounding_steps_one_ulp() {
+        let mut flags = 0;
+        // 1 + 2^-30 is inexact in single precision
+        let tiny = 2f32.powi(-30);
+        assert_eq!(add(1.0f32, tiny, RNE, &mut flags), 1.0);
+        assert_eq!(flags, NX);
+        assert_eq!(add(1.0f32, tiny, RUP, &mut flags), 1.0f32.next_up());
+        assert_eq!(add(1.0f32, -tiny, RTZ, &mut flags), 1.0f32.next_down());
+        assert_eq!(add(1.0f32, -tiny, RNE, &mut flags), 1.0);
+
+        // (1 + e)^2 - 2e^2 = 1 + 2e - e^2: an error far below the ulp of the
+        // addend decides the direction
+        let a = 1.0 + f64::EPSILON;
+        let c = -2.0 * f64::EPSILON * f64::EPSILON;
+        let nearest = 1.0 + 2.0 * f64::EPSILON;
+        let mut flags = 0;
+        assert_eq!(fused(a, a, c, false, false, RUP, &mut flags), nearest);
+        assert_eq!(flags, NX);
+        assert_eq!(
+            fused(a, a, c, false, false, RDN, &mut flags),
+            nearest.next_down()
+        );
+        assert_eq!(
+            fused(-a, a, -c, false, false, RUP, &mut flags),
+            -nearest.next_down()
+        );
+
+        let mut flags = 0;
+        let down = div(1.0f64, 3.0, RDN, &mut flags);
+        assert_eq!(div(1.0f64, 3.0, RUP, &mut flags), down.next_up());
+        assert_eq!(flags, NX);
+    }
+
+    #[test]
+    fn test_invalid_and_special_results() {
+        let mut flags = 0;
+        let nan = div(0.0f32, 0.0, RNE, &mut flags);
+        assert_eq!(nan.to_bits(), 0x7fc0_0000);
+        assert_eq!(flags, NV);
+
+        let mut flags = 0;
+        assert_eq!(div(1.0f32, 0.0, RNE, &mut flags), f32::INFINITY);
+        assert_eq!(flags, DZ);
+
+        let mut flags = 0;
+        assert_eq!(mul(f64::MAX, 2.0, RTZ, &mut flags), f64::MAX);
+        assert_eq!(flags, OF | NX);
+
+        // just above the largest finite value, so only RUP/RDN leave it
+        let half_ulp = 2f64.powi(969);
+        let mut flags = 0;
+        assert_eq!(add(f64::MAX, half_ulp, RNE, &mut flags), f64::MAX);
+        assert_eq!(flags, NX);
+        assert_eq!(add(f64::MAX, half_ulp, RUP, &mut flags), f64::INFINITY);
+        assert_eq!(flags, OF | NX);
+        let mut flags = 0;
+        assert_eq!(
+            add(-f64::MAX, -half_ulp, RDN, &mut flags),
+            f64::NEG_INFINITY
+        );
+        assert_eq!(flags, OF | NX);
+
+        let mut flags = 0;
+        assert_eq!(to_int(f32::NAN, true, 32, RNE, &mut flags), i32::MAX as u64);
+        assert_eq!(to_int(-1.5f32, false, 64, RNE, &mut flags), 0);
+        assert_eq!(flags, NV);
+        assert_eq!(to_int(-2.5f64, true, 64, RMM, &mut 0), -3i64 as u64);
+
+        // improperly boxed singles read as the canonical NaN
+        assert!(f32::unbox(0x3f80_0000).is_nan());
+        assert_eq!(f32::unbox(1.0f32.boxed()), 1.0);
+    }
+}
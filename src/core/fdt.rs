@@ -0,0 +1,312 @@
+use super::config::{DeviceConfig, MachineConfig};
+use super::device::plic::PLIC_SOURCES;
+use super::param::TIMEBASE_FREQUENCY;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const HEADER_SIZE: usize = 40;
+// a single terminating entry: no reserved memory
+const RSVMAP_SIZE: usize = 16;
+
+// ns16550a input clock, as on QEMU virt
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+// hart-local interrupt numbers used in interrupts-extended
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+/// Serializer for the structure block, collecting property names as it goes.
+#[derive(Default)]
+struct Writer {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl Writer {
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+    }
+
+    fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    fn name_offset(&mut self, name: &str) -> u32 {
+        let mut needle = name.as_bytes().to_vec();
+        needle.push(0);
+        // names are reused a lot, so share them in the strings block
+        let mut start = 0;
+        for entry in self.strings.split_inclusive(|&b| b == 0) {
+            if entry == needle.as_slice() {
+                return start as u32;
+            }
+            start += entry.len();
+        }
+        self.strings.extend_from_slice(&needle);
+        start as u32
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8]) {
+        let offset = self.name_offset(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+
+    fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    fn prop_u32(&mut self, name: &str, value: u32) {
+        self.prop_cells(name, &[value]);
+    }
+
+    fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.prop(name, &value);
+    }
+
+    /// `reg` with two address and two size cells.
+    fn prop_reg(&mut self, base: u64, size: u64) {
+        self.prop_cells(
+            "reg",
+            &[
+                (base >> 32) as u32,
+                base as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ],
+        );
+    }
+
+    fn prop_str(&mut self, name: &str, value: &str) {
+        self.prop_strs(name, &[value]);
+    }
+
+    fn prop_strs(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.prop(name, &value);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + RSVMAP_SIZE;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total);
+        for field in [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.resize(off_struct, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// Flattened device tree describing `config`, in the layout of QEMU's virt
+/// machine so that existing firmware and kernels recognise it.
+pub fn build(config: &MachineConfig) -> Vec<u8> {
+    // phandles: one interrupt controller per hart, then the PLIC
+    let intc = |hart: usize| hart as u32 + 1;
+    let plic = config.harts as u32 + 1;
+    let has_plic = config
+        .devices
+        .iter()
+        .any(|d| matches!(d, DeviceConfig::Plic { .. }));
+
+    let mut w = Writer::default();
+    w.begin_node("");
+    w.prop_u32("#address-cells", 2);
+    w.prop_u32("#size-cells", 2);
+    w.prop_str("compatible", "riscv-virtio");
+    w.prop_str("model", "risque,virt");
+
+    w.begin_node("chosen");
+    if !config.bootargs.is_empty() {
+        w.prop_str("bootargs", &config.bootargs);
+    }
+    if let Some(initrd) = &config.boot.initrd {
+        // the file was sized by MachineConfig::validate
+        let len = std::fs::metadata(&initrd.path).map_or(0, |m| m.len());
+        w.prop_cells(
+            "linux,initrd-start",
+            &[(initrd.addr >> 32) as u32, initrd.addr as u32],
+        );
+        let end = initrd.addr + len;
+        w.prop_cells("linux,initrd-end", &[(end >> 32) as u32, end as u32]);
+    }
+    if let Some(uart) = config
+        .devices
+        .iter()
+        .find(|d| matches!(d, DeviceConfig::Uart { .. }))
+    {
+        w.prop_str("stdout-path", &format!("/soc/serial@{:x}", uart.base()));
+    }
+    w.end_node();
+
+    w.begin_node(&format!("memory@{:x}", config.dram_base));
+    w.prop_str("device_type", "memory");
+    w.prop_reg(config.dram_base, config.dram_size);
+    w.end_node();
+
+    w.begin_node("cpus");
+    w.prop_u32("#address-cells", 1);
+    w.prop_u32("#size-cells", 0);
+    w.prop_u32("timebase-frequency", TIMEBASE_FREQUENCY as u32);
+    let isa = config.isa();
+    for hart in 0..config.harts {
+        w.begin_node(&format!("cpu@{}", hart));
+        w.prop_str("device_type", "cpu");
+        w.prop_u32("reg", hart as u32);
+        w.prop_str("status", "okay");
+        w.prop_str("compatible", "riscv");
+        w.prop_str("riscv,isa", &isa);
+        w.prop_str("mmu-type", "riscv,sv39");
+        w.begin_node("interrupt-controller");
+        w.prop_u32("#interrupt-cells", 1);
+        w.prop_empty("interrupt-controller");
+        w.prop_str("compatible", "riscv,cpu-intc");
+        w.prop_u32("phandle", intc(hart));
+        w.end_node();
+        w.end_node();
+    }
+    w.end_node();
+
+    w.begin_node("soc");
+    w.prop_u32("#address-cells", 2);
+    w.prop_u32("#size-cells", 2);
+    w.prop_str("compatible", "simple-bus");
+    w.prop_empty("ranges");
+    for device in &config.devices {
+        let (base, size) = (device.base(), device.size());
+        match device {
+            DeviceConfig::Clint { .. } => {
+                w.begin_node(&format!("clint@{:x}", base));
+                w.prop_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+                w.prop_reg(base, size);
+                let cells: Vec<u32> = (0..config.harts)
+                    .flat_map(|h| [intc(h), IRQ_M_SOFT, intc(h), IRQ_M_TIMER])
+                    .collect();
+                w.prop_cells("interrupts-extended", &cells);
+            }
+            DeviceConfig::Plic { .. } => {
+                w.begin_node(&format!("plic@{:x}", base));
+                w.prop_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+                w.prop_reg(base, size);
+                w.prop_u32("#address-cells", 0);
+                w.prop_u32("#interrupt-cells", 1);
+                w.prop_empty("interrupt-controller");
+                w.prop_u32("riscv,ndev", PLIC_SOURCES as u32 - 1);
+                let cells: Vec<u32> = (0..config.harts)
+                    .flat_map(|h| [intc(h), IRQ_M_EXT, intc(h), IRQ_S_EXT])
+                    .collect();
+                w.prop_cells("interrupts-extended", &cells);
+                w.prop_u32("phandle", plic);
+            }
+            DeviceConfig::Uart { .. } => {
+                w.begin_node(&format!("serial@{:x}", base));
+                w.prop_str("compatible", "ns16550a");
+                w.prop_reg(base, size);
+                w.prop_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+            }
+            DeviceConfig::VirtioBlock { .. }
+            | DeviceConfig::VirtioNet { .. }
+            | DeviceConfig::VirtioConsole { .. }
+            | DeviceConfig::VirtioRng { .. } => {
+                w.begin_node(&format!("virtio_mmio@{:x}", base));
+                w.prop_str("compatible", "virtio,mmio");
+                w.prop_reg(base, size);
+            }
+        }
+        if let Some(irq) = device.irq().filter(|_| has_plic) {
+            w.prop_u32("interrupts", irq);
+            w.prop_u32("interrupt-parent", plic);
+        }
+        w.end_node();
+    }
+    w.end_node();
+
+    w.end_node();
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_header_and_contents() {
+        let config = MachineConfig::builder()
+            .bootargs("console=ttyS0")
+            .build()
+            .unwrap();
+        let blob = build(&config);
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        let off_struct = be32(&blob, 8) as usize;
+        let off_strings = be32(&blob, 12) as usize;
+        assert_eq!(off_struct % 4, 0);
+        assert_eq!(off_strings, off_struct + be32(&blob, 36) as usize);
+        assert_eq!(be32(&blob, off_strings - 4), FDT_END);
+
+        let contains = |s: &[u8]| blob.windows(s.len()).any(|w| w == s);
+        assert!(contains(b"rv64im_zicsr\0"));
+        assert!(contains(b"console=ttyS0\0"));
+        assert!(contains(b"/soc/serial@10000000\0"));
+        assert!(contains(b"plic@c000000\0"));
+        // property names are stored once
+        let strings = &blob[off_strings..];
+        assert_eq!(
+            strings.split(|&b| b == 0).filter(|s| s == b"reg").count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Per-instruction undo records behind `step_back`, `reverse_continue` and
+//! `goto`, served by the API's step-back, reverse-continue and goto routes.
+//! The tree has no GDB remote stub, so GDB's reverse packets (`bs`, `bc`)
+//! are out of scope until one exists.
+
+use std::collections::VecDeque;
+
+use super::trap::Privilege;
+
+/// State overwritten by one retired instruction.
+#[derive(Debug, Default)]
+pub struct UndoRecord {
+    pub pc: u64,
+    pub regs: Vec<(u32, u64)>,
+    // (address, size, previous value), in store order
+    pub mem: Vec<(u64, u64, u64)>,
+    // (csr register, previous stored bits), in write order
+    pub csrs: Vec<(u32, u64)>,
+    // (f register, previous value), in write order
+    pub fregs: Vec<(u32, u64)>,
+    // privilege level before a trap or xRET changed it
+    pub privilege: Option<Privilege>,
+}
+
+/// Bounded ring buffer of undo records; the oldest are dropped first.
+pub struct History {
+    records: VecDeque<UndoRecord>,
+    capacity: usize,
+    // the oldest record follows a device access that cannot be undone
+    barrier: bool,
+    pending: Vec<(u64, u64, u64)>,
+    pending_csrs: Vec<(u32, u64)>,
+    pending_fregs: Vec<(u32, u64)>,
+    pending_privilege: Option<Privilege>,
+    pending_side_effect: bool,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            capacity,
+            barrier: false,
+            pending: Vec::new(),
+            pending_csrs: Vec::new(),
+            pending_fregs: Vec::new(),
+            pending_privilege: None,
+            pending_side_effect: false,
+        }
+    }
+
+    /// Note the previous value of a memory location about to be overwritten.
+    pub fn record_store(&mut self, addr: u64, size: u64, old: u64) {
+        self.pending.push((addr, size, old));
+    }
+
+    /// Note the stored bits of a CSR register about to be written.
+    pub fn record_csr(&mut self, reg: u32, old: u64) {
+        self.pending_csrs.push((reg, old));
+    }
+
+    /// Note the previous value of a floating-point register about to be written.
+    pub fn record_freg(&mut self, id: u32, old: u64) {
+        self.pending_fregs.push((id, old));
+    }
+
+    /// Note the privilege level about to be left; only the first one counts.
+    pub fn record_privilege(&mut self, old: Privilege) {
+        self.pending_privilege.get_or_insert(old);
+    }
+
+    /// Note that the instruction changes state outside the machine, such as
+    /// host files or the console, which undoing cannot take back.
+    pub fn record_side_effect(&mut self) {
+        self.pending_side_effect = true;
+    }
+
+    /// Close the record for the instruction that just retired.
+    pub fn commit(&mut self, pc: u64, before: &[u64; 32], after: &[u64; 32]) {
+        if self.pending_side_effect {
+            self.barrier();
+            return;
+        }
+        let regs = (0..32)
+            .filter(|&i| before[i] != after[i])
+            .map(|i| (i as u32, before[i]))
+            .collect();
+
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+            self.barrier = false;
+        }
+        self.records.push_back(UndoRecord {
+            pc,
+            regs,
+            mem: std::mem::take(&mut self.pending),
+            csrs: std::mem::take(&mut self.pending_csrs),
+            fregs: std::mem::take(&mut self.pending_fregs),
+            privilege: self.pending_privilege.take(),
+        });
+    }
+
+    /// Drop stores noted for an instruction that did not retire.
+    pub fn discard(&mut self) {
+        if self.pending_side_effect {
+            self.barrier();
+            return;
+        }
+        self.pending.clear();
+        self.pending_csrs.clear();
+        self.pending_fregs.clear();
+        self.pending_privilege = None;
+    }
+
+    /// Forget everything up to now: the instruction that just ran touched a
+    /// device, let one write memory or changed host state, which undoing
+    /// cannot take back.
+    pub fn barrier(&mut self) {
+        self.clear();
+        self.barrier = true;
+    }
+
+    /// Whether history ends at a device access rather than at its capacity
+    /// or the start of the run.
+    pub fn at_barrier(&self) -> bool {
+        self.barrier && self.records.is_empty()
+    }
+
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.barrier = false;
+        self.pending.clear();
+        self.pending_csrs.clear();
+        self.pending_fregs.clear();
+        self.pending_privilege = None;
+        self.pending_side_effect = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_records_changed_registers_only() {
+        let mut history = History::new(2);
+        let before = [0u64; 32];
+        let mut after = before;
+        after[5] = 42;
+
+        history.record_store(0x100, 32, 7);
+        history.commit(0x10, &before, &after);
+
+        let record = history.pop().unwrap();
+        assert_eq!(record.pc, 0x10);
+        assert_eq!(record.regs, vec![(5, 0)]);
+        assert_eq!(record.mem, vec![(0x100, 32, 7)]);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn test_capacity_drops_oldest() {
+        let mut history = History::new(2);
+        let regs = [0u64; 32];
+        for pc in [0x0, 0x4, 0x8] {
+            history.commit(pc, &regs, &regs);
+        }
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop().unwrap().pc, 0x8);
+        assert_eq!(history.pop().unwrap().pc, 0x4);
+        assert!(history.pop().is_none());
+    }
+}
@@ -0,0 +1,189 @@
+//! HTIF, the host-target interface of Spike that riscv-tests and many
+//! bare-metal harnesses use: the guest writes a command to `tohost` and, for
+//! commands that answer, waits for the reply in `fromhost`.
+//!
+//! A command carries a device in bits 63:56, a command in 55:48 and a
+//! payload below. Device 0 with an odd payload exits with `payload >> 1`,
+//! so riscv-tests report a pass as 0 and a failure as the test number.
+
+use super::cpu::Cpu;
+
+const DEV_SYSCALL: u64 = 0;
+const DEV_CONSOLE: u64 = 1;
+const CMD_GETCHAR: u64 = 0;
+const CMD_PUTCHAR: u64 = 1;
+
+// Proxied system calls, as numbered by riscv-pk
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const EFAULT: u64 = 14;
+const ENOSYS: u64 = 38;
+
+// Largest buffer a proxied write may pass
+const MAX_WRITE: u64 = 1 << 20;
+
+/// Where the program keeps its mailboxes.
+#[derive(Clone)]
+pub struct Htif {
+    pub tohost: u64,
+    pub fromhost: Option<u64>,
+}
+
+impl Cpu {
+    /// Carry out a command the last instruction left in `tohost`.
+    pub(super) fn poll_htif(&mut self) {
+        let Some(htif) = &self.htif else {
+            return;
+        };
+        let (tohost, fromhost) = (htif.tohost, htif.fromhost);
+        let command = match self.bus.dram().load(tohost, 64) {
+            Ok(0) | Err(_) => return,
+            Ok(command) => command,
+        };
+        let (device, cmd) = (command >> 56, command >> 48 & 0xff);
+        let payload = command & 0xffff_ffff_ffff;
+
+        let reply = match (device, cmd) {
+            (DEV_SYSCALL, _) if payload & 1 == 1 => {
+                self.exit_code = Some(payload >> 1);
+                None
+            }
+            (DEV_SYSCALL, _) => Some(self.htif_syscall(payload)),
+            (DEV_CONSOLE, CMD_PUTCHAR) => {
+                self.console.write(&[payload as u8]);
+                Some(0x100 | (payload & 0xff))
+            }
+            // the command stays in tohost until there is input to answer with
+            (DEV_CONSOLE, CMD_GETCHAR) => match self.console.read() {
+                Some(byte) => Some(0x100 | byte as u64),
+                None => return,
+            },
+            _ => None,
+        };
+
+        let _ = self.bus.write_dram(tohost, &0u64.to_le_bytes());
+        if let (Some(value), Some(fromhost)) = (reply, fromhost) {
+            let response = device << 56 | cmd << 48 | value;
+            let _ = self.bus.write_dram(fromhost, &response.to_le_bytes());
+        }
+    }
+
+    /// The memory between the `begin_signature` and `end_signature` symbols
+    /// of the loaded program, one 32-bit word per line in hex, as
+    /// riscv-arch-test reference outputs have it.
+    pub fn signature(&self) -> Result<String, String> {
+        let symbol = |name: &str| {
+            self.elf
+                .as_ref()
+                .and_then(|elf| elf.symbol(name))
+                .map(|symbol| symbol.addr)
+                .ok_or(format!("the program has no {} symbol", name))
+        };
+        let (begin, end) = (symbol("begin_signature")?, symbol("end_signature")?);
+        // checked before allocating, as the symbols can say anything
+        let len = usize::try_from(end.saturating_sub(begin))
+            .ok()
+            .filter(|&len| self.bus.dram().range(begin, len).is_some())
+            .ok_or("the signature lies outside DRAM")?;
+        let mut bytes = vec![0u8; len];
+        self.bus
+            .dram()
+            .read(begin, &mut bytes)
+            .map_err(|_| "the signature lies outside DRAM".to_string())?;
+        Ok(bytes
+            .chunks(4)
+            .map(|word| {
+                let mut padded = [0u8; 4];
+                padded[..word.len()].copy_from_slice(word);
+                format!("{:08x}\n", u32::from_le_bytes(padded))
+            })
+            .collect())
+    }
+
+    /// `payload` points at the call number and its arguments; the result is
+    /// written back over the number.
+    fn htif_syscall(&mut self, payload: u64) -> u64 {
+        let mut args = [0u8; 32];
+        if self.bus.dram().read(payload, &mut args).is_err() {
+            return 1;
+        }
+        let arg = |i: usize| u64::from_le_bytes(args[i * 8..i * 8 + 8].try_into().unwrap());
+
+        let result = match arg(0) {
+            SYS_WRITE if arg(1) == 1 || arg(1) == 2 => {
+                let mut bytes = vec![0u8; arg(3).min(MAX_WRITE) as usize];
+                match self.bus.dram().read(arg(2), &mut bytes) {
+                    Ok(()) => {
+                        self.console.write(&bytes);
+                        bytes.len() as u64
+                    }
+                    Err(_) => EFAULT.wrapping_neg(),
+                }
+            }
+            SYS_EXIT => {
+                self.exit_code = Some(arg(1));
+                0
+            }
+            _ => ENOSYS.wrapping_neg(),
+        };
+        let _ = self.bus.write_dram(payload, &result.to_le_bytes());
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::MachineConfig;
+    use crate::core::debug::StopReason;
+    use crate::core::elf::{Elf, Symbol};
+
+    const TOHOST: u64 = 0x1000;
+
+    fn run(status: u32) -> StopReason {
+        let code = [
+            0x000012b7,                // lui t0, 0x1 (tohost)
+            status << 20 | 0x00000313, // li t1, status
+            0x0062b023,                // sd t1, 0(t0)
+            0x0000006f,                // j .
+        ];
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &code);
+        cpu.htif = Some(Htif {
+            tohost: TOHOST,
+            fromhost: None,
+        });
+        cpu.run_until(None, 10)
+    }
+
+    #[test]
+    fn test_tohost_reports_pass_and_fail() {
+        assert!(matches!(run(1), StopReason::Exited(0)));
+        // riscv-tests report failure of test 3 as (3 << 1) | 1
+        assert!(matches!(run(7), StopReason::Exited(3)));
+    }
+
+    #[test]
+    fn test_signature_stays_within_dram() {
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &[0x12345678]);
+        let symbol = |name: &str, addr| Symbol {
+            name: name.into(),
+            addr,
+            size: 0,
+            func: false,
+        };
+        let elf = |end| Elf {
+            entry: 0,
+            phdr: 0,
+            phnum: 0,
+            segments: Vec::new(),
+            symbols: vec![symbol("begin_signature", 0), symbol("end_signature", end)],
+            sections: Vec::new(),
+            lines: Default::default(),
+        };
+        cpu.elf = Some(elf(4));
+        assert_eq!(cpu.signature().unwrap(), "12345678\n");
+        // refused without trying to allocate the whole span
+        cpu.elf = Some(elf(u64::MAX));
+        assert!(cpu.signature().is_err());
+    }
+}
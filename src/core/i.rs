@@ -5,7 +5,11 @@ use crate::kit::bits::*;
 use crate::kit::insn::*;
 use crate::vdepart;
 
-use super::isa::{install, IsaDefine};
+use super::{
+    except::Exception,
+    isa::{install, IsaDefine},
+    trap::Privilege,
+};
 
 fn lui() -> IsaDefine {
     IsaDefine::new(
@@ -37,9 +41,9 @@ fn jal() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let j = vdepart!(insn, InsnType::J);
-            *cpu.wgpr(j.rd) = cpu.pc.wrapping_add(4);
-            cpu.pc = cpu.pc.wrapping_add(sext(j.imm as u64, 21));
-            cpu.pcimm = 0;
+            let t = cpu.pc.wrapping_add(cpu.pcimm);
+            cpu.jump(cpu.pc.wrapping_add(sext(j.imm as u64, 21)))?;
+            *cpu.wgpr(j.rd) = t;
             Ok(0)
         })),
         "jal",
@@ -52,9 +56,8 @@ fn jalr() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let i = vdepart!(insn, InsnType::I);
-            let t = cpu.pc.wrapping_add(4);
-            cpu.pc = (cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12))) & !mask(1);
-            cpu.pcimm = 0;
+            let t = cpu.pc.wrapping_add(cpu.pcimm);
+            cpu.jump((cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12))) & !mask(1))?;
             *cpu.wgpr(i.rd) = t;
             Ok(0)
         })),
@@ -69,8 +72,7 @@ fn beq() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let b = vdepart!(insn, InsnType::B);
             if cpu.rgpr(b.rs1) == cpu.rgpr(b.rs2) {
-                cpu.pc = cpu.pc.wrapping_add(sext(b.imm as u64, 13));
-                cpu.pcimm = 0;
+                cpu.jump(cpu.pc.wrapping_add(sext(b.imm as u64, 13)))?;
             }
             Ok(0)
         })),
@@ -85,8 +87,7 @@ fn bne() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let b = vdepart!(insn, InsnType::B);
             if cpu.rgpr(b.rs1) != cpu.rgpr(b.rs2) {
-                cpu.pc = cpu.pc.wrapping_add(sext(b.imm as u64, 13));
-                cpu.pcimm = 0;
+                cpu.jump(cpu.pc.wrapping_add(sext(b.imm as u64, 13)))?;
             }
             Ok(0)
         })),
@@ -101,8 +102,7 @@ fn blt() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let b = vdepart!(insn, InsnType::B);
             if (cpu.rgpr(b.rs1) as i64) < (cpu.rgpr(b.rs2) as i64) {
-                cpu.pc = cpu.pc.wrapping_add(sext(b.imm as u64, 13));
-                cpu.pcimm = 0;
+                cpu.jump(cpu.pc.wrapping_add(sext(b.imm as u64, 13)))?;
             }
             Ok(0)
         })),
@@ -117,8 +117,7 @@ fn bge() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let b = vdepart!(insn, InsnType::B);
             if cpu.rgpr(b.rs1) as i64 >= cpu.rgpr(b.rs2) as i64 {
-                cpu.pc = cpu.pc.wrapping_add(sext(b.imm as u64, 13));
-                cpu.pcimm = 0;
+                cpu.jump(cpu.pc.wrapping_add(sext(b.imm as u64, 13)))?;
             }
             Ok(0)
         })),
@@ -133,8 +132,7 @@ fn bltu() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let b = vdepart!(insn, InsnType::B);
             if cpu.rgpr(b.rs1) < cpu.rgpr(b.rs2) {
-                cpu.pc = cpu.pc.wrapping_add(sext(b.imm as u64, 13));
-                cpu.pcimm = 0;
+                cpu.jump(cpu.pc.wrapping_add(sext(b.imm as u64, 13)))?;
             }
             Ok(0)
         })),
@@ -149,8 +147,7 @@ fn bgeu() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let b = vdepart!(insn, InsnType::B);
             if cpu.rgpr(b.rs1) >= cpu.rgpr(b.rs2) {
-                cpu.pc = cpu.pc.wrapping_add(sext(b.imm as u64, 13));
-                cpu.pcimm = 0;
+                cpu.jump(cpu.pc.wrapping_add(sext(b.imm as u64, 13)))?;
             }
             Ok(0)
         })),
@@ -165,8 +162,7 @@ fn lb() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let i = vdepart!(insn, InsnType::I);
             *cpu.wgpr(i.rd) = sext(
-                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 8)
-                    .unwrap(),
+                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 8)?,
                 8,
             );
             Ok(0)
@@ -182,8 +178,7 @@ fn lh() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let i = vdepart!(insn, InsnType::I);
             *cpu.wgpr(i.rd) = sext(
-                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 16)
-                    .unwrap(),
+                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 16)?,
                 16,
             );
             Ok(0)
@@ -199,8 +194,7 @@ fn lw() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let i = vdepart!(insn, InsnType::I);
             *cpu.wgpr(i.rd) = sext(
-                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 32)
-                    .unwrap(),
+                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 32)?,
                 32,
             );
             Ok(0)
@@ -215,9 +209,7 @@ fn ld() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let i = vdepart!(insn, InsnType::I);
-            *cpu.wgpr(i.rd) = cpu
-                .load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 64)
-                .unwrap();
+            *cpu.wgpr(i.rd) = cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 64)?;
             Ok(0)
         })),
         "ld",
@@ -231,8 +223,7 @@ fn lbu() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let i = vdepart!(insn, InsnType::I);
             *cpu.wgpr(i.rd) = zext(
-                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 8)
-                    .unwrap(),
+                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 8)?,
                 8,
             );
             Ok(0)
@@ -248,8 +239,7 @@ fn lhu() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let i = vdepart!(insn, InsnType::I);
             *cpu.wgpr(i.rd) = zext(
-                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 16)
-                    .unwrap(),
+                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 16)?,
                 16,
             );
             Ok(0)
@@ -265,8 +255,7 @@ fn lwu() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let i = vdepart!(insn, InsnType::I);
             *cpu.wgpr(i.rd) = zext(
-                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 32)
-                    .unwrap(),
+                cpu.load(cpu.rgpr(i.rs1).wrapping_add(sext(i.imm as u64, 12)), 32)?,
                 32,
             );
             Ok(0)
@@ -285,8 +274,7 @@ fn sb() -> IsaDefine {
                 cpu.rgpr(s.rs1).wrapping_add(sext(s.imm as u64, 12)),
                 8,
                 cpu.rgpr(s.rs2),
-            )
-            .unwrap();
+            )?;
             Ok(0)
         })),
         "sb",
@@ -303,8 +291,7 @@ fn sh() -> IsaDefine {
                 cpu.rgpr(s.rs1).wrapping_add(sext(s.imm as u64, 12)),
                 16,
                 cpu.rgpr(s.rs2),
-            )
-            .unwrap();
+            )?;
             Ok(0)
         })),
         "sh",
@@ -321,8 +308,7 @@ fn sw() -> IsaDefine {
                 cpu.rgpr(s.rs1).wrapping_add(sext(s.imm as u64, 12)),
                 32,
                 cpu.rgpr(s.rs2),
-            )
-            .unwrap();
+            )?;
             Ok(0)
         })),
         "sw",
@@ -339,8 +325,7 @@ fn sd() -> IsaDefine {
                 cpu.rgpr(s.rs1).wrapping_add(sext(s.imm as u64, 12)),
                 64,
                 cpu.rgpr(s.rs2),
-            )
-            .unwrap();
+            )?;
             Ok(0)
         })),
         "sd",
@@ -628,8 +613,144 @@ fn addw() -> IsaDefine {
     )
 }
 
+fn fence() -> IsaDefine {
+    IsaDefine::new(
+        // memory is sequentially consistent here, so there is nothing to order
+        Arc::new(Box::new(|_cpu, _insn| Ok(0))),
+        "fence",
+        0xf,
+        InsnType::I,
+    )
+}
+
+fn ecall() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, _insn| {
+            Err(match cpu.privilege {
+                Privilege::User => Exception::EnvironmentCallFromUMode,
+                Privilege::Supervisor => Exception::EnvironmentCallFromSMode,
+                Privilege::Machine => Exception::EnvironmentCallFromMMode,
+            })
+        })),
+        "ecall",
+        0x73,
+        InsnType::I,
+    )
+}
+
+fn ebreak() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, _insn| Err(Exception::Breakpoint(cpu.pc)))),
+        "ebreak",
+        0x00100073,
+        InsnType::I,
+    )
+}
+
+fn slliw() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            *cpu.wgpr(i.rd) = sext((cpu.rgpr(i.rs1) << (i.imm & 0x1f)) as u32 as u64, 32);
+            Ok(0)
+        })),
+        "slliw",
+        0x101b,
+        InsnType::I,
+    )
+    .masked(0xfe00707f)
+}
+
+fn srliw() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            *cpu.wgpr(i.rd) = sext((cpu.rgpr(i.rs1) as u32 >> (i.imm & 0x1f)) as u64, 32);
+            Ok(0)
+        })),
+        "srliw",
+        0x501b,
+        InsnType::I,
+    )
+    .masked(0xfe00707f)
+}
+
+fn sraiw() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            *cpu.wgpr(i.rd) = (cpu.rgpr(i.rs1) as i32 >> (i.imm & 0x1f)) as u64;
+            Ok(0)
+        })),
+        "sraiw",
+        0x4000501b,
+        InsnType::I,
+    )
+    .masked(0xfe00707f)
+}
+
+fn subw() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let r = vdepart!(insn, InsnType::R);
+            *cpu.wgpr(r.rd) = sext(
+                cpu.rgpr(r.rs1).wrapping_sub(cpu.rgpr(r.rs2)) as u32 as u64,
+                32,
+            );
+            Ok(0)
+        })),
+        "subw",
+        0x4000003b,
+        InsnType::R,
+    )
+}
+
+fn sllw() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let r = vdepart!(insn, InsnType::R);
+            *cpu.wgpr(r.rd) = sext(
+                (cpu.rgpr(r.rs1) << (cpu.rgpr(r.rs2) & 0x1f)) as u32 as u64,
+                32,
+            );
+            Ok(0)
+        })),
+        "sllw",
+        0x103b,
+        InsnType::R,
+    )
+}
+
+fn srlw() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let r = vdepart!(insn, InsnType::R);
+            *cpu.wgpr(r.rd) = sext(
+                (cpu.rgpr(r.rs1) as u32 >> (cpu.rgpr(r.rs2) & 0x1f)) as u64,
+                32,
+            );
+            Ok(0)
+        })),
+        "srlw",
+        0x503b,
+        InsnType::R,
+    )
+}
+
+fn sraw() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let r = vdepart!(insn, InsnType::R);
+            *cpu.wgpr(r.rd) = (cpu.rgpr(r.rs1) as i32 >> (cpu.rgpr(r.rs2) & 0x1f)) as u64;
+            Ok(0)
+        })),
+        "sraw",
+        0x4000503b,
+        InsnType::R,
+    )
+}
+
 pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
-    // TBD: add more instructions
     install(map, lui());
     install(map, auipc());
     install(map, jal());
@@ -672,7 +793,6 @@ pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
     install(map, and());
     install(map, addiw());
     install(map, addw());
-    /*
     install(map, fence());
     install(map, ecall());
     install(map, ebreak());
@@ -683,5 +803,57 @@ pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
     install(map, sllw());
     install(map, srlw());
     install(map, sraw());
-     */
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{config::MachineConfig, cpu::Cpu};
+
+    const A0: usize = 10;
+    const A1: usize = 11;
+    const A2: usize = 12;
+
+    /// Execute `insn`, an `op a2, a0, a1`, and return a2.
+    fn op(insn: u32, a: u64, b: u64) -> u64 {
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &[]);
+        cpu.regs[A0] = a;
+        cpu.regs[A1] = b;
+        cpu.execute(insn).unwrap();
+        cpu.regs[A2]
+    }
+
+    #[test]
+    fn test_signed_and_word_arithmetic() {
+        let (sra, sraw, slt, sltu, addiw) = (0x40b55633, 0x40b5563b, 0xb52633, 0xb53633, 0x15061b);
+        assert_eq!(op(sra, i64::MIN as u64, 63), u64::MAX);
+        // sraw shifts the low word by the low five bits of rs2
+        assert_eq!(op(sraw, 0x8000_0000, 33), 0xffff_ffff_c000_0000);
+        assert_eq!(op(slt, u64::MAX, 1), 1);
+        assert_eq!(op(sltu, u64::MAX, 1), 0);
+        // addiw a2, a0, 1 wraps in 32 bits and sign-extends
+        assert_eq!(op(addiw, 0x7fff_ffff, 0), 0xffff_ffff_8000_0000);
+    }
+
+    #[test]
+    fn test_loads_extend_and_jalr_clears_bit_zero() {
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &[]);
+        cpu.store(0x1000, 8, 0x80).unwrap();
+        cpu.regs[A0] = 0x1000;
+        // lb a2, 0(a0) and lbu a2, 0(a0)
+        cpu.execute(0x50603).unwrap();
+        assert_eq!(cpu.regs[A2], 0xffff_ffff_ffff_ff80);
+        cpu.execute(0x54603).unwrap();
+        assert_eq!(cpu.regs[A2], 0x80);
+
+        // jalr ra, 1(a0) lands on a0; one more is misaligned without C
+        cpu.regs[A0] = 0x100;
+        assert_eq!(cpu.execute(0x1500e7), Ok(0x100));
+        assert_eq!(cpu.regs[1], 4);
+        cpu.regs[A0] = 0x101;
+        assert_eq!(
+            cpu.execute(0x1500e7),
+            Err(Exception::InstructionAddressMisaligned(0x102))
+        );
+    }
 }
@@ -12,6 +12,9 @@ pub struct IsaDefine {
     pub mtype: InsnType,
     pub mnemonic: &'static str,
     pub processor: IsaProcessor,
+    /// Bits compared against `ident`, when the type's usual fields are not
+    /// the right ones.
+    pub mask: Option<u32>,
 }
 
 impl IsaDefine {
@@ -26,6 +29,32 @@ impl IsaDefine {
             mtype,
             mnemonic,
             processor,
+            mask: None,
+        }
+    }
+
+    /// Match on `mask` instead of the fields implied by the type.
+    pub fn masked(mut self, mask: u32) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    /// Whether `insn` encodes this instruction.
+    pub fn matches(&self, insn: u32) -> bool {
+        if let Some(mask) = self.mask {
+            return (insn & mask) == self.ident;
+        }
+        match self.mtype {
+            InsnType::R => (insn & 0xfe00707f) == self.ident,
+            InsnType::R4 => (insn & 0x0600007f) == self.ident,
+            // shifts by immediate carry funct6 in the upper bits
+            InsnType::I if [0x1013u32, 0x5013].contains(&(insn & 0x707f)) => {
+                (insn & 0xfc00707f) == self.ident
+            }
+            // privileged SYSTEM instructions are told apart by funct12 and rs1
+            InsnType::I if (insn & 0x707f) == 0x73 => insn == self.ident,
+            InsnType::I | InsnType::S | InsnType::B => (insn & 0x707f) == self.ident,
+            InsnType::U | InsnType::J => (insn & 0x7f) == self.ident,
         }
     }
 }
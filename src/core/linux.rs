@@ -0,0 +1,870 @@
+//! User-mode Linux: a static executable runs in U-mode and each `ecall` is a
+//! system call carried out on the host, the way `qemu-riscv64` does it.
+//!
+//! Translation is off in this mode, so guest addresses are DRAM addresses.
+//! Paths are looked up under the configured root and cannot leave it.
+
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+
+use super::{
+    config::{LinuxProgram, Personality},
+    cpu::Cpu,
+    csr,
+    elf::Elf,
+    except::Exception,
+    param::DRAM_PAGE_SIZE,
+    trap::Privilege,
+};
+
+const ECALL: u32 = 0x00000073;
+
+const SP: usize = 2;
+const A0: usize = 10;
+const A7: usize = 17;
+
+// Space below the top of DRAM kept for the stack; mmap grows down from here
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+// Largest single transfer, so a bogus length cannot exhaust host memory
+const MAX_IO: u64 = 1 << 20;
+const MAX_IOV: u64 = 1024;
+const PATH_MAX: u64 = 4096;
+
+// mstatus.FS = Initial, so floating point works from the first instruction
+const FS_INITIAL: u64 = 1 << 13;
+
+// System call numbers, from asm-generic/unistd.h
+const SYS_GETCWD: u64 = 17;
+const SYS_IOCTL: u64 = 29;
+const SYS_MKDIRAT: u64 = 34;
+const SYS_UNLINKAT: u64 = 35;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_READLINKAT: u64 = 78;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_GETRANDOM: u64 = 278;
+const SYS_FACCESSAT2: u64 = 439;
+
+// errno values as the guest knows them, independent of the host
+const ENOENT: u64 = 2;
+const EIO: u64 = 5;
+const EBADF: u64 = 9;
+const ENOMEM: u64 = 12;
+const EACCES: u64 = 13;
+const EFAULT: u64 = 14;
+const EEXIST: u64 = 17;
+const ENOTDIR: u64 = 20;
+const EISDIR: u64 = 21;
+const EINVAL: u64 = 22;
+const ENOTTY: u64 = 25;
+const ESPIPE: u64 = 29;
+const ERANGE: u64 = 34;
+const ENAMETOOLONG: u64 = 36;
+const ENOSYS: u64 = 38;
+const ENOTEMPTY: u64 = 39;
+// Kernel-internal: the call is retried once it can make progress
+const ERESTARTSYS: u64 = 512;
+
+const AT_FDCWD: u64 = -100i64 as u64;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR: u64 = 0x200;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const O_ACCMODE: u64 = 3;
+const O_WRONLY: u64 = 1;
+const O_RDWR: u64 = 2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const TIOCGWINSZ: u64 = 0x5413;
+const CLOCK_REALTIME: u64 = 0;
+const RLIMIT_STACK: u64 = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+// Auxiliary vector keys
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_PLATFORM: u64 = 15;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+const S_IFCHR: u64 = 0o020000;
+
+/// Process state kept alongside the hart.
+pub struct Linux {
+    /// Canonical host path of the guest's `/`.
+    root: PathBuf,
+    /// Host files by guest descriptor; 0, 1 and 2 are the console.
+    files: HashMap<u64, File>,
+    brk_start: u64,
+    brk: u64,
+    /// Anonymous mappings are handed out downwards from here.
+    mmap_top: u64,
+    started: Instant,
+}
+
+impl Linux {
+    /// Start of the program break, the break and the mapping limit.
+    pub(super) fn layout(&self) -> (u64, u64, u64) {
+        (self.brk_start, self.brk, self.mmap_top)
+    }
+
+    /// Go back to `layout` from a snapshot. Host files cannot be put back,
+    /// so every file opened since start-up is closed.
+    pub(super) fn restore_layout(&mut self, (brk_start, brk, mmap_top): (u64, u64, u64)) {
+        self.files.clear();
+        (self.brk_start, self.brk, self.mmap_top) = (brk_start, brk, mmap_top);
+    }
+}
+
+fn errno(e: io::Error) -> u64 {
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::NotADirectory => ENOTDIR,
+        io::ErrorKind::IsADirectory => EISDIR,
+        io::ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
+        _ => EIO,
+    }
+}
+
+fn page_up(addr: u64) -> u64 {
+    addr.next_multiple_of(DRAM_PAGE_SIZE)
+}
+
+/// `struct stat` as laid out for riscv64.
+fn stat(meta: &Metadata) -> [u8; 128] {
+    let fields: [(usize, u64, usize); 15] = [
+        (0, meta.dev(), 8),
+        (8, meta.ino(), 8),
+        (16, meta.mode() as u64, 4),
+        (20, meta.nlink(), 4),
+        (24, meta.uid() as u64, 4),
+        (28, meta.gid() as u64, 4),
+        (32, meta.rdev(), 8),
+        (48, meta.size(), 8),
+        (56, meta.blksize(), 4),
+        (64, meta.blocks(), 8),
+        (72, meta.atime() as u64, 8),
+        (80, meta.atime_nsec() as u64, 8),
+        (88, meta.mtime() as u64, 8),
+        (96, meta.mtime_nsec() as u64, 8),
+        (104, meta.ctime() as u64, 8),
+    ];
+    let mut buf = [0u8; 128];
+    for (at, value, len) in fields {
+        buf[at..at + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    }
+    buf[112..120].copy_from_slice(&(meta.ctime_nsec() as u64).to_le_bytes());
+    buf
+}
+
+/// The console descriptors look like a terminal.
+fn console_stat() -> [u8; 128] {
+    let mut buf = [0u8; 128];
+    buf[16..20].copy_from_slice(&((S_IFCHR | 0o620) as u32).to_le_bytes());
+    buf[20..24].copy_from_slice(&1u32.to_le_bytes());
+    buf[56..60].copy_from_slice(&1024u32.to_le_bytes());
+    buf
+}
+
+impl Cpu {
+    /// Load the configured Linux program and set up its initial stack, in
+    /// place of booting. Does nothing for other personalities.
+    pub(super) fn start_linux(&mut self) -> Result<(), String> {
+        let Personality::Linux(program) = self.config.personality.clone() else {
+            return Ok(());
+        };
+        let elf = self.load_elf(&program.program)?;
+
+        let root = program
+            .root
+            .canonicalize()
+            .map_err(|e| format!("root {}: {}", program.root.display(), e))?;
+        let brk = page_up(elf.end());
+        self.linux = Some(Linux {
+            root,
+            files: HashMap::new(),
+            brk_start: brk,
+            brk,
+            mmap_top: self.config.dram_end() - STACK_SIZE,
+            started: Instant::now(),
+        });
+
+        self.regs = [0; 32];
+        self.fregs = [0; 32];
+        self.regs[SP] = self.initial_stack(&program, &elf)?;
+        self.pc = elf.entry;
+        self.privilege = Privilege::User;
+        let mstatus = self.csr.read(csr::MSTATUS);
+        self.csr.write(csr::MSTATUS, mstatus | FS_INITIAL);
+        Ok(())
+    }
+
+    /// Lay out `argc`, `argv`, `envp` and the auxiliary vector at the top of
+    /// DRAM as the kernel's `execve` would, returning the new `sp`.
+    fn initial_stack(&mut self, program: &LinuxProgram, elf: &Elf) -> Result<u64, String> {
+        let mut sp = self.config.dram_end();
+        let mut push = |cpu: &mut Cpu, bytes: &[u8]| -> Result<u64, String> {
+            sp -= bytes.len() as u64;
+            cpu.bus
+                .write_dram(sp, bytes)
+                .map_err(|_| "no room for the initial stack".to_string())?;
+            Ok(sp)
+        };
+
+        let execfn = program.program.to_string_lossy().into_owned();
+        let mut argv = Vec::new();
+        for arg in std::iter::once(&execfn).chain(&program.args) {
+            argv.push(push(self, format!("{}\0", arg).as_bytes())?);
+        }
+        let mut envp = Vec::new();
+        for var in &program.env {
+            envp.push(push(self, format!("{}\0", var).as_bytes())?);
+        }
+        let platform = push(self, b"riscv64\0")?;
+        let mut random = [0u8; 16];
+        rand::rng().fill_bytes(&mut random);
+        let random = push(self, &random)?;
+
+        let auxv = [
+            (AT_PHDR, elf.phdr),
+            (AT_PHENT, 56),
+            (AT_PHNUM, elf.phnum),
+            (AT_PAGESZ, DRAM_PAGE_SIZE),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_PLATFORM, platform),
+            (AT_HWCAP, self.config.misa() & 0x3ff_ffff),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_EXECFN, argv[0]),
+            (AT_NULL, 0),
+        ];
+        let mut words = vec![argv.len() as u64];
+        words.extend(&argv);
+        words.push(0);
+        words.extend(&envp);
+        words.push(0);
+        for (key, value) in auxv {
+            words.extend([key, value]);
+        }
+
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let sp = (sp & !15) - bytes.len() as u64;
+        // the ABI wants sp 16-byte aligned at entry
+        let sp = sp & !15;
+        self.bus
+            .write_dram(sp, &bytes)
+            .map_err(|_| "no room for the initial stack".to_string())?;
+        Ok(sp)
+    }
+
+    /// Carry out the system call of the `ecall` at pc, returning the
+    /// instruction and the next pc as `fetch_execute` would.
+    pub(super) fn syscall(&mut self) -> Result<(u32, u64), Exception> {
+        let nr = self.regs[A7];
+        let args: [u64; 6] = self.regs[A0..A0 + 6].try_into().unwrap();
+        let result = self.dispatch(nr, args);
+        // host files, console input and output, and the process's memory
+        // layout are not in the undo history
+        let changes_host = matches!(
+            nr,
+            SYS_MKDIRAT
+                | SYS_UNLINKAT
+                | SYS_OPENAT
+                | SYS_CLOSE
+                | SYS_LSEEK
+                | SYS_READ
+                | SYS_WRITE
+                | SYS_READV
+                | SYS_WRITEV
+                | SYS_BRK
+                | SYS_MUNMAP
+                | SYS_MMAP
+        );
+        if changes_host && result != Err(ERESTARTSYS) {
+            self.history.record_side_effect();
+        }
+        match result {
+            // stay on the ecall, as if the process were sleeping
+            Err(ERESTARTSYS) => return Ok((ECALL, self.pc)),
+            Ok(value) => *self.wgpr(A0 as u32) = value,
+            Err(e) => *self.wgpr(A0 as u32) = e.wrapping_neg(),
+        }
+        Ok((ECALL, self.pc + 4))
+    }
+
+    fn dispatch(&mut self, nr: u64, a: [u64; 6]) -> Result<u64, u64> {
+        match nr {
+            SYS_READ => self.sys_read(a[0], a[1], a[2]),
+            SYS_WRITE => self.sys_write(a[0], a[1], a[2]),
+            SYS_READV | SYS_WRITEV => {
+                let mut total = 0;
+                for i in 0..a[2].min(MAX_IOV) {
+                    let iov = self.read_guest(a[1].checked_add(i * 16).ok_or(EFAULT)?, 16)?;
+                    let base = u64::from_le_bytes(iov[..8].try_into().unwrap());
+                    let len = u64::from_le_bytes(iov[8..].try_into().unwrap());
+                    let done = if nr == SYS_READV {
+                        self.sys_read(a[0], base, len)
+                    } else {
+                        self.sys_write(a[0], base, len)
+                    };
+                    match done {
+                        Ok(n) if n < len => return Ok(total + n),
+                        Ok(n) => total += n,
+                        Err(e) if total == 0 => return Err(e),
+                        Err(_) => break,
+                    }
+                }
+                Ok(total)
+            }
+            SYS_OPENAT => self.sys_openat(a[0], a[1], a[2], a[3]),
+            SYS_CLOSE => {
+                if a[0] > 2 && self.linux_mut().files.remove(&a[0]).is_none() {
+                    return Err(EBADF);
+                }
+                Ok(0)
+            }
+            SYS_LSEEK => {
+                let pos = match a[2] {
+                    0 => SeekFrom::Start(a[1]),
+                    1 => SeekFrom::Current(a[1] as i64),
+                    2 => SeekFrom::End(a[1] as i64),
+                    _ => return Err(EINVAL),
+                };
+                self.file(a[0])?.seek(pos).map_err(errno)
+            }
+            SYS_FSTAT => {
+                let buf = self.fstat(a[0])?;
+                self.write_guest(a[1], &buf)?;
+                Ok(0)
+            }
+            SYS_NEWFSTATAT => {
+                let path = self.read_path(a[1])?;
+                let buf = if path.is_empty() && a[3] & AT_EMPTY_PATH != 0 {
+                    self.fstat(a[0])?
+                } else {
+                    let host = self.resolve(a[0], &path)?;
+                    let meta = if a[3] & AT_SYMLINK_NOFOLLOW != 0 {
+                        fs::symlink_metadata(host)
+                    } else {
+                        fs::metadata(host)
+                    };
+                    stat(&meta.map_err(errno)?)
+                };
+                self.write_guest(a[2], &buf)?;
+                Ok(0)
+            }
+            SYS_FACCESSAT | SYS_FACCESSAT2 => {
+                let path = self.read_path(a[1])?;
+                let host = self.resolve(a[0], &path)?;
+                fs::metadata(host).map(|_| 0).map_err(errno)
+            }
+            SYS_MKDIRAT => {
+                let path = self.read_path(a[1])?;
+                fs::create_dir(self.resolve(a[0], &path)?)
+                    .map(|_| 0)
+                    .map_err(errno)
+            }
+            SYS_UNLINKAT => {
+                let path = self.read_path(a[1])?;
+                let host = self.resolve(a[0], &path)?;
+                let removed = if a[2] & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(host)
+                } else {
+                    fs::remove_file(host)
+                };
+                removed.map(|_| 0).map_err(errno)
+            }
+            SYS_GETCWD => {
+                if a[1] < 2 {
+                    return Err(ERANGE);
+                }
+                self.write_guest(a[0], b"/\0")?;
+                Ok(a[0])
+            }
+            // there is no /proc to read links from
+            SYS_READLINKAT => Err(ENOENT),
+            SYS_IOCTL => {
+                if a[0] > 2 {
+                    self.file(a[0])?;
+                    return Err(ENOTTY);
+                }
+                if a[1] != TIOCGWINSZ {
+                    return Err(ENOTTY);
+                }
+                // 24 rows by 80 columns
+                self.write_guest(a[2], &[24, 0, 80, 0, 0, 0, 0, 0])?;
+                Ok(0)
+            }
+            SYS_BRK => {
+                let linux = self.linux_mut();
+                let (old, requested) = (linux.brk, a[0]);
+                if requested < linux.brk_start || requested > linux.mmap_top {
+                    return Ok(old);
+                }
+                linux.brk = requested;
+                // memory handed back and taken again must read as zero
+                if requested > old {
+                    self.zero_guest(old, requested - old)?;
+                }
+                Ok(requested)
+            }
+            SYS_MMAP => self.sys_mmap(a[0], a[1], a[3], a[4], a[5]),
+            SYS_MUNMAP | SYS_MPROTECT | SYS_MADVISE => Ok(0),
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code = Some(a[0] & 0xff);
+                Ok(0)
+            }
+            SYS_CLOCK_GETTIME => {
+                let (secs, nanos) = self.now(a[0]);
+                self.write_guest(a[1], &[secs.to_le_bytes(), nanos.to_le_bytes()].concat())?;
+                Ok(0)
+            }
+            SYS_GETTIMEOFDAY => {
+                let (secs, nanos) = self.now(CLOCK_REALTIME);
+                let micros = nanos / 1000;
+                self.write_guest(a[0], &[secs.to_le_bytes(), micros.to_le_bytes()].concat())?;
+                Ok(0)
+            }
+            SYS_UNAME => {
+                let mut buf = [0u8; 65 * 6];
+                for (i, field) in ["Linux", "risque", "6.1.0", "#1", "riscv64", ""]
+                    .iter()
+                    .enumerate()
+                {
+                    buf[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                self.write_guest(a[0], &buf)?;
+                Ok(0)
+            }
+            SYS_GETRANDOM => {
+                let mut buf = vec![0u8; a[1].min(MAX_IO) as usize];
+                rand::rng().fill_bytes(&mut buf);
+                self.write_guest(a[0], &buf)?;
+                Ok(buf.len() as u64)
+            }
+            SYS_PRLIMIT64 => {
+                if a[3] != 0 {
+                    let limit = if a[1] == RLIMIT_STACK {
+                        STACK_SIZE
+                    } else {
+                        RLIM_INFINITY
+                    };
+                    self.write_guest(a[3], &[limit.to_le_bytes(), limit.to_le_bytes()].concat())?;
+                }
+                Ok(0)
+            }
+            // a single-threaded process without signals
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK | SYS_GETPPID => Ok(0),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            _ => {
+                if self.trace {
+                    println!("unimplemented system call {}", nr);
+                }
+                Err(ENOSYS)
+            }
+        }
+    }
+
+    fn sys_read(&mut self, fd: u64, buf: u64, len: u64) -> Result<u64, u64> {
+        let mut bytes = vec![0u8; len.min(MAX_IO) as usize];
+        let n = if fd == 0 {
+            let mut n = 0;
+            while n < bytes.len() {
+                match self.console.read() {
+                    Some(byte) => bytes[n] = byte,
+                    None => break,
+                }
+                n += 1;
+            }
+            // block until the console has input
+            if n == 0 && !bytes.is_empty() {
+                return Err(ERESTARTSYS);
+            }
+            n
+        } else {
+            self.file(fd)?.read(&mut bytes).map_err(errno)?
+        };
+        self.write_guest(buf, &bytes[..n])?;
+        Ok(n as u64)
+    }
+
+    fn sys_write(&mut self, fd: u64, buf: u64, len: u64) -> Result<u64, u64> {
+        let bytes = self.read_guest(buf, len.min(MAX_IO))?;
+        if fd == 1 || fd == 2 {
+            self.console.write(&bytes);
+            return Ok(bytes.len() as u64);
+        }
+        let n = self.file(fd)?.write(&bytes).map_err(errno)?;
+        Ok(n as u64)
+    }
+
+    fn sys_openat(&mut self, dirfd: u64, path: u64, flags: u64, mode: u64) -> Result<u64, u64> {
+        let path = self.read_path(path)?;
+        let host = self.resolve(dirfd, &path)?;
+        let access = flags & O_ACCMODE;
+        let file = OpenOptions::new()
+            .read(access != O_WRONLY)
+            .write(access == O_WRONLY || access == O_RDWR)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .mode(mode as u32 & 0o777)
+            .open(host)
+            .map_err(errno)?;
+
+        let linux = self.linux_mut();
+        let fd = (3..).find(|fd| !linux.files.contains_key(fd)).unwrap();
+        linux.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn sys_mmap(&mut self, addr: u64, len: u64, flags: u64, fd: u64, off: u64) -> Result<u64, u64> {
+        if len == 0 {
+            return Err(EINVAL);
+        }
+        let len = len.checked_next_multiple_of(DRAM_PAGE_SIZE).ok_or(ENOMEM)?;
+        let addr = if flags & MAP_FIXED != 0 {
+            addr
+        } else {
+            let linux = self.linux_mut();
+            let addr = linux.mmap_top.checked_sub(len).ok_or(ENOMEM)?;
+            if addr < linux.brk {
+                return Err(ENOMEM);
+            }
+            linux.mmap_top = addr;
+            addr
+        };
+        self.zero_guest(addr, len).map_err(|_| ENOMEM)?;
+
+        if flags & MAP_ANONYMOUS == 0 {
+            let file = self.file(fd)?;
+            let mut bytes = Vec::new();
+            file.seek(SeekFrom::Start(off)).map_err(errno)?;
+            file.take(len).read_to_end(&mut bytes).map_err(errno)?;
+            self.write_guest(addr, &bytes)?;
+        }
+        Ok(addr)
+    }
+
+    fn fstat(&mut self, fd: u64) -> Result<[u8; 128], u64> {
+        if fd <= 2 {
+            return Ok(console_stat());
+        }
+        Ok(stat(&self.file(fd)?.metadata().map_err(errno)?))
+    }
+
+    /// Seconds and nanoseconds on `clock`; all but the real-time clock count
+    /// from program start.
+    fn now(&self, clock: u64) -> (u64, u64) {
+        let elapsed = if clock == CLOCK_REALTIME {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+        } else {
+            self.linux.as_ref().unwrap().started.elapsed()
+        };
+        (elapsed.as_secs(), elapsed.subsec_nanos() as u64)
+    }
+
+    fn linux_mut(&mut self) -> &mut Linux {
+        self.linux.as_mut().unwrap()
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut File, u64> {
+        if fd <= 2 {
+            return Err(ESPIPE);
+        }
+        self.linux_mut().files.get_mut(&fd).ok_or(EBADF)
+    }
+
+    /// Host path of the guest `path`. Only `AT_FDCWD` is accepted as a base,
+    /// and the working directory is always `/`.
+    fn resolve(&self, dirfd: u64, path: &str) -> Result<PathBuf, u64> {
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+        let root = &self.linux.as_ref().unwrap().root;
+        let mut host = root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => host.push(name),
+                // `..` stops at the root, as it does at a real `/`
+                Component::ParentDir if host != *root => {
+                    host.pop();
+                }
+                _ => {}
+            }
+        }
+
+        // a symlink may still point outside the root, and a dangling one
+        // would be followed wherever it leads by O_CREAT
+        let real = match host.canonicalize() {
+            Ok(real) => real,
+            Err(_) if host.symlink_metadata().is_ok() => return Err(EACCES),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let parent = host.parent().unwrap_or(root).canonicalize();
+                parent
+                    .map_err(errno)?
+                    .join(host.file_name().unwrap_or_default())
+            }
+            Err(e) => return Err(errno(e)),
+        };
+        if !real.starts_with(root) {
+            return Err(EACCES);
+        }
+        Ok(host)
+    }
+
+    fn read_guest(&self, addr: u64, len: u64) -> Result<Vec<u8>, u64> {
+        let mut buf = vec![0u8; len as usize];
+        self.bus.dram().read(addr, &mut buf).map_err(|_| EFAULT)?;
+        Ok(buf)
+    }
+
+    fn write_guest(&mut self, addr: u64, data: &[u8]) -> Result<(), u64> {
+        self.bus.write_dram(addr, data).map_err(|_| EFAULT)
+    }
+
+    fn zero_guest(&mut self, addr: u64, len: u64) -> Result<(), u64> {
+        if self.bus.dram().range(addr, len as usize).is_none() {
+            return Err(EFAULT);
+        }
+        self.write_guest(addr, &vec![0u8; len as usize])
+    }
+
+    fn read_path(&self, addr: u64) -> Result<String, u64> {
+        let mut path = Vec::new();
+        for i in 0..PATH_MAX {
+            let at = addr.checked_add(i).ok_or(EFAULT)?;
+            let mut byte = [0u8];
+            self.bus.dram().read(at, &mut byte).map_err(|_| EFAULT)?;
+            if byte[0] == 0 {
+                return String::from_utf8(path).map_err(|_| EINVAL);
+            }
+            path.push(byte[0]);
+        }
+        Err(ENAMETOOLONG)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::MachineConfig;
+    use crate::core::debug::StopReason;
+
+    const BASE: u64 = 0x10000;
+
+    /// A static executable with one segment holding the ELF and program
+    /// headers followed by `code`.
+    fn executable(code: &[u32], data: &[u8]) -> Vec<u8> {
+        let entry = BASE + 64 + 56;
+        let mut text: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        text.extend_from_slice(data);
+        let size = (64 + 56 + text.len()) as u64;
+
+        let mut elf = Vec::new();
+        elf.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+        elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        elf.extend_from_slice(&243u16.to_le_bytes()); // EM_RISCV
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&entry.to_le_bytes());
+        elf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        for half in [64u16, 56, 1, 64, 0, 0] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        // PT_LOAD, readable and executable
+        for word in [1u32, 5] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        for word in [0, BASE, BASE, size, size, 0x1000] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        elf.extend_from_slice(&text);
+        elf
+    }
+
+    #[test]
+    fn test_write_and_exit() {
+        let code = [
+            0x00100513, // li a0, 1
+            0x00000597, // auipc a1, 0
+            0x02058593, // addi a1, a1, 32
+            0x00300613, // li a2, 3
+            0x04000893, // li a7, 64 (write)
+            0x00000073, // ecall
+            0x00300513, // li a0, 3
+            0x05e00893, // li a7, 94 (exit_group)
+            0x00000073, // ecall
+        ];
+        let path = std::env::temp_dir().join("risque-linux-test.elf");
+        fs::write(&path, executable(&code, b"hi\n")).unwrap();
+
+        let config = MachineConfig::linux(&path, &["one"]).build().unwrap();
+        let mut cpu = Cpu::new(config, Vec::new()).unwrap();
+        let _ = fs::remove_file(&path);
+        cpu.trace = false;
+
+        // argc, then argv[0] and argv[1]
+        let sp = cpu.regs[SP];
+        assert_eq!(sp % 16, 0);
+        assert_eq!(cpu.load(sp, 64).unwrap(), 2);
+        let argv1 = cpu.load(sp + 16, 64).unwrap();
+        assert_eq!(cpu.read_path(argv1).unwrap(), "one");
+        assert_eq!(cpu.privilege, Privilege::User);
+
+        assert!(matches!(cpu.run_until(None, 100), StopReason::Exited(3)));
+        assert_eq!(cpu.console.output_since(0).1, b"hi\n");
+
+        // the write cannot be taken back, so it is not written twice
+        for _ in 0..3 {
+            assert_eq!(cpu.step_back(), StopReason::SteppedBack);
+        }
+        assert_eq!(cpu.step_back(), StopReason::DeviceAccess);
+        assert!(matches!(cpu.run_until(None, 100), StopReason::Exited(3)));
+        assert_eq!(cpu.console.output_since(0).1, b"hi\n");
+    }
+
+    #[test]
+    fn test_paths_stay_under_root() {
+        let root = std::env::temp_dir().canonicalize().unwrap();
+        let mut cpu = Cpu::new(MachineConfig::default(), Vec::new()).unwrap();
+        cpu.linux = Some(Linux {
+            root: root.clone(),
+            files: HashMap::new(),
+            brk_start: 0,
+            brk: 0,
+            mmap_top: 0,
+            started: Instant::now(),
+        });
+        assert_eq!(
+            cpu.resolve(AT_FDCWD, "/../../etc").unwrap(),
+            root.join("etc")
+        );
+        assert_eq!(cpu.resolve(AT_FDCWD, "a/./b/..").unwrap(), root.join("a"));
+    }
+
+    #[test]
+    fn test_symlinks_out_of_the_root_are_refused() {
+        let root = std::env::temp_dir().join(format!("risque-root-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("out")).unwrap();
+        let escape = std::env::temp_dir().join("risque-escape");
+        std::os::unix::fs::symlink(&escape, root.join("dangling")).unwrap();
+
+        let mut cpu = Cpu::new(MachineConfig::default(), Vec::new()).unwrap();
+        cpu.linux = Some(Linux {
+            root: root.clone(),
+            files: HashMap::new(),
+            brk_start: 0,
+            brk: 0,
+            mmap_top: 0,
+            started: Instant::now(),
+        });
+        let resolved = [
+            cpu.resolve(AT_FDCWD, "/out/passwd"),
+            cpu.resolve(AT_FDCWD, "/dangling"),
+            cpu.resolve(AT_FDCWD, "/missing/file"),
+            cpu.resolve(AT_FDCWD, "/new"),
+        ];
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(resolved[0], Err(EACCES));
+        assert_eq!(resolved[1], Err(EACCES));
+        assert_eq!(resolved[2], Err(ENOENT));
+        assert_eq!(resolved[3], Ok(root.join("new")));
+    }
+
+    #[test]
+    fn test_addresses_near_the_top_fail_cleanly() {
+        let mut cpu = Cpu::new(MachineConfig::default(), Vec::new()).unwrap();
+        cpu.linux = Some(Linux {
+            root: std::env::temp_dir(),
+            files: HashMap::new(),
+            brk_start: 0,
+            brk: 0,
+            mmap_top: 0,
+            started: Instant::now(),
+        });
+        let top = u64::MAX - 0xfff;
+        let flags = MAP_FIXED | MAP_ANONYMOUS;
+        assert_eq!(
+            cpu.dispatch(SYS_MMAP, [top, 0x2000, 0, flags, 0, 0]),
+            Err(ENOMEM)
+        );
+        assert_eq!(
+            cpu.dispatch(SYS_MMAP, [0, u64::MAX, 0, flags, 0, 0]),
+            Err(ENOMEM)
+        );
+        assert_eq!(
+            cpu.dispatch(SYS_WRITEV, [1, u64::MAX, 2, 0, 0, 0]),
+            Err(EFAULT)
+        );
+        assert_eq!(cpu.read_path(u64::MAX), Err(EFAULT));
+    }
+}
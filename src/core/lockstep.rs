@@ -0,0 +1,261 @@
+//! Lockstep checking against a reference commit log, from Spike's
+//! `--log-commits` or an earlier run of this emulator. Each instruction that
+//! retires is compared with the next reference line on its pc, encoding, the
+//! x and f registers it wrote and the memory it accessed. CSR writes are left
+//! out, as emulators disagree on side effects such as `mstatus.FS` turning
+//! dirty. Checking ends at the first difference.
+//!
+//! The reference may start earlier, as Spike's does in its boot ROM: lines
+//! before the first one at the pc the emulator starts from are skipped, as
+//! are lines that do not record a commit.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use super::{commit_log::CommitLog, cpu::Cpu};
+
+/// One commit log line, reduced to what is compared.
+#[derive(Debug, Default, PartialEq)]
+struct Retired {
+    pc: u64,
+    insn: u64,
+    // x and f registers written, as ("x5", value)
+    regs: Vec<(String, u64)>,
+    // (address, value stored), in access order
+    mem: Vec<(u64, Option<u64>)>,
+}
+
+fn hex(token: &str) -> Option<u64> {
+    u64::from_str_radix(token.strip_prefix("0x")?, 16).ok()
+}
+
+/// Parse `core   0: 3 0x... (0x...) x5  0x... mem 0x...`; the privilege
+/// level is optional, as older Spike versions leave it out.
+fn parse(line: &str) -> Option<Retired> {
+    let (core, rest) = line.split_once(':')?;
+    if !core.starts_with("core") {
+        return None;
+    }
+    let mut tokens = rest.split_whitespace().peekable();
+    if tokens.peek()?.len() == 1 {
+        tokens.next();
+    }
+    let mut retired = Retired {
+        pc: hex(tokens.next()?)?,
+        insn: hex(tokens.next()?.strip_prefix('(')?.strip_suffix(')')?)?,
+        ..Retired::default()
+    };
+    while let Some(key) = tokens.next() {
+        if key == "mem" {
+            let addr = hex(tokens.next()?)?;
+            let value = match tokens.peek() {
+                Some(token) if token.starts_with("0x") => hex(tokens.next()?),
+                _ => None,
+            };
+            retired.mem.push((addr, value));
+            continue;
+        }
+        let value = hex(tokens.next()?)?;
+        if key.starts_with('x') || key.starts_with('f') {
+            retired.regs.push((key.to_string(), value));
+        }
+    }
+    retired.regs.sort();
+    Some(retired)
+}
+
+/// How `actual` departs from `reference`, one line per difference.
+fn differences(reference: &Retired, actual: &Retired) -> Vec<String> {
+    let mut lines = Vec::new();
+    if reference.pc != actual.pc {
+        lines.push(format!(
+            "pc: expected 0x{:016x}, got 0x{:016x}",
+            reference.pc, actual.pc
+        ));
+    }
+    if reference.insn != actual.insn {
+        lines.push(format!(
+            "insn: expected 0x{:08x}, got 0x{:08x}",
+            reference.insn, actual.insn
+        ));
+    }
+    let value = |regs: &[(String, u64)], name: &str| {
+        regs.iter()
+            .find(|(reg, _)| reg == name)
+            .map_or("no write".to_string(), |(_, v)| format!("0x{:016x}", v))
+    };
+    let mut names: Vec<&String> = reference
+        .regs
+        .iter()
+        .chain(&actual.regs)
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        let (expected, got) = (value(&reference.regs, name), value(&actual.regs, name));
+        if expected != got {
+            lines.push(format!("{}: expected {}, got {}", name, expected, got));
+        }
+    }
+    if reference.mem != actual.mem {
+        let show = |mem: &[(u64, Option<u64>)]| {
+            mem.iter()
+                .map(|(addr, value)| match value {
+                    Some(value) => format!("store 0x{:x} to 0x{:016x}", value, addr),
+                    None => format!("load from 0x{:016x}", addr),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        lines.push(format!(
+            "mem: expected [{}], got [{}]",
+            show(&reference.mem),
+            show(&actual.mem)
+        ));
+    }
+    lines
+}
+
+pub struct Lockstep {
+    reference: Box<dyn BufRead + Send>,
+    // reference lines read so far
+    line: u64,
+    // instructions that matched so far
+    matched: u64,
+    // whether the reference has reached the pc the emulator started from
+    aligned: bool,
+    last: Option<String>,
+    /// Report of the first difference, once found.
+    pub(super) divergence: Option<String>,
+}
+
+impl Lockstep {
+    pub fn new(reference: Box<dyn BufRead + Send>) -> Self {
+        Self {
+            reference,
+            line: 0,
+            matched: 0,
+            aligned: false,
+            last: None,
+            divergence: None,
+        }
+    }
+
+    /// The next reference line recording a commit, with its text.
+    fn next_commit(&mut self) -> Option<(String, Retired)> {
+        let mut text = String::new();
+        loop {
+            text.clear();
+            match self.reference.read_line(&mut text) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => self.line += 1,
+            }
+            if let Some(retired) = parse(&text) {
+                return Some((text.trim_end().to_string(), retired));
+            }
+        }
+    }
+
+    /// Compare the commit log line of the instruction that just retired.
+    fn check(&mut self, line: &str, instret: u64) {
+        if self.divergence.is_some() {
+            return;
+        }
+        let Some(actual) = parse(line) else {
+            return;
+        };
+        let next = loop {
+            match self.next_commit() {
+                Some((_, reference)) if !self.aligned && reference.pc != actual.pc => continue,
+                next => break next,
+            }
+        };
+        self.aligned = true;
+
+        let (text, diffs) = match next {
+            Some((text, reference)) => (text, differences(&reference, &actual)),
+            None => (
+                "(end of trace)".to_string(),
+                vec!["the reference trace ends".to_string()],
+            ),
+        };
+        if diffs.is_empty() {
+            self.matched += 1;
+            self.last = Some(line.to_string());
+            return;
+        }
+
+        let mut report = format!(
+            "Diverged from the reference at instruction #{} (reference line {}), after {} matching.\n",
+            instret, self.line, self.matched
+        );
+        if let Some(last) = &self.last {
+            report += &format!("  last match: {}\n", last);
+        }
+        report += &format!("  reference:  {}\n  risque:     {}\n", text, line);
+        for diff in diffs {
+            report += &format!("  {}\n", diff);
+        }
+        self.divergence = Some(report);
+    }
+
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+}
+
+impl Cpu {
+    /// Check each instruction retired from now on against the trace at
+    /// `path`, starting a commit log if none is being taken.
+    pub fn start_lockstep(&mut self, path: &Path) -> Result<(), String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if self.commit_log.is_none() {
+            self.commit_log = Some(CommitLog::new(None)?);
+        }
+        self.lockstep = Some(Lockstep::new(Box::new(BufReader::new(file))));
+        Ok(())
+    }
+
+    /// Compare the instruction that just retired with the reference.
+    pub(super) fn check_lockstep(&mut self) {
+        if let (Some(lockstep), Some(line)) = (
+            &mut self.lockstep,
+            self.commit_log.as_ref().and_then(|log| log.last()),
+        ) {
+            lockstep.check(line, self.instret);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{config::MachineConfig, debug::StopReason};
+
+    #[test]
+    fn test_stops_at_first_divergence() {
+        let code = [
+            0x02a00513u32, // li a0, 42
+            0x00a00593,    // li a1, 10
+            0x00b50633,    // add a2, a0, a1
+        ];
+        // a boot ROM line to skip, then a wrong sum
+        let reference = "core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000\n\
+            core   0: 3 0x0000000000000000 (0x02a00513) x10 0x000000000000002a\n\
+            core   0: 3 0x0000000000000004 (0x00a00593) x11 0x000000000000000a\n\
+            core   0: exception trap_illegal_instruction, epc 0x0000000000000000\n\
+            core   0: 3 0x0000000000000008 (0x00b50633) x12 0x0000000000000035\n";
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &code);
+        cpu.start_commit_log(None).unwrap();
+        cpu.lockstep = Some(Lockstep::new(Box::new(reference.as_bytes())));
+
+        let StopReason::Diverged(report) = cpu.step_n(3) else {
+            panic!("no divergence found");
+        };
+        assert!(report.contains("instruction #3 (reference line 5), after 2 matching"));
+        assert!(report.contains("x12: expected 0x0000000000000035, got 0x0000000000000034"));
+        assert!(cpu.lockstep.is_none());
+    }
+}
@@ -20,12 +20,13 @@ fn mul() -> IsaDefine {
     )
 }
 
-/*
 fn mulh() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
-            // *cpu.wgpr(r.rd) = ((cpu.rgpr(r.rs1) as i64 as i128).wrapping_mul(cpu.rgpr(r.rs2) as u128) >> 64) as u64;
+            *cpu.wgpr(r.rd) = ((cpu.rgpr(r.rs1) as i64 as i128)
+                .wrapping_mul(cpu.rgpr(r.rs2) as i64 as i128)
+                >> 64) as u64;
             Ok(0)
         })),
         "mulh",
@@ -38,6 +39,9 @@ fn mulhsu() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
+            *cpu.wgpr(r.rd) = ((cpu.rgpr(r.rs1) as i64 as i128)
+                .wrapping_mul(cpu.rgpr(r.rs2) as i128)
+                >> 64) as u64;
             Ok(0)
         })),
         "mulhsu",
@@ -45,7 +49,6 @@ fn mulhsu() -> IsaDefine {
         InsnType::R,
     )
 }
- */
 
 fn mulhu() -> IsaDefine {
     IsaDefine::new(
@@ -65,7 +68,11 @@ fn div() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
-            *cpu.wgpr(r.rd) = (cpu.rgpr(r.rs1) as i64).wrapping_div(cpu.rgpr(r.rs2) as i64) as u64;
+            // x / 0 = -1; overflow wraps to the dividend
+            *cpu.wgpr(r.rd) = match cpu.rgpr(r.rs2) as i64 {
+                0 => u64::MAX,
+                d => (cpu.rgpr(r.rs1) as i64).wrapping_div(d) as u64,
+            };
             Ok(0)
         })),
         "div",
@@ -78,7 +85,10 @@ fn divu() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
-            *cpu.wgpr(r.rd) = cpu.rgpr(r.rs1).wrapping_div(cpu.rgpr(r.rs2));
+            *cpu.wgpr(r.rd) = cpu
+                .rgpr(r.rs1)
+                .checked_div(cpu.rgpr(r.rs2))
+                .unwrap_or(u64::MAX);
             Ok(0)
         })),
         "divu",
@@ -91,7 +101,11 @@ fn rem() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
-            *cpu.wgpr(r.rd) = (cpu.rgpr(r.rs1) as i64).wrapping_rem(cpu.rgpr(r.rs2) as i64) as u64;
+            // x % 0 = x; overflow leaves a zero remainder
+            *cpu.wgpr(r.rd) = match cpu.rgpr(r.rs2) as i64 {
+                0 => cpu.rgpr(r.rs1),
+                d => (cpu.rgpr(r.rs1) as i64).wrapping_rem(d) as u64,
+            };
             Ok(0)
         })),
         "rem",
@@ -104,7 +118,10 @@ fn remu() -> IsaDefine {
     IsaDefine::new(
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
-            *cpu.wgpr(r.rd) = cpu.rgpr(r.rs1).wrapping_rem(cpu.rgpr(r.rs2));
+            *cpu.wgpr(r.rd) = cpu
+                .rgpr(r.rs1)
+                .checked_rem(cpu.rgpr(r.rs2))
+                .unwrap_or(cpu.rgpr(r.rs1));
             Ok(0)
         })),
         "remu",
@@ -134,7 +151,10 @@ fn divw() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
             *cpu.wgpr(r.rd) = sext(
-                (cpu.rgpr(r.rs1) as i32).wrapping_div(cpu.rgpr(r.rs2) as i32) as u32 as u64,
+                match cpu.rgpr(r.rs2) as i32 {
+                    0 => u32::MAX,
+                    d => (cpu.rgpr(r.rs1) as i32).wrapping_div(d) as u32,
+                } as u64,
                 32,
             );
             Ok(0)
@@ -150,7 +170,9 @@ fn divuw() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
             *cpu.wgpr(r.rd) = sext(
-                (cpu.rgpr(r.rs1) as u32).wrapping_div(cpu.rgpr(r.rs2) as u32) as u64,
+                (cpu.rgpr(r.rs1) as u32)
+                    .checked_div(cpu.rgpr(r.rs2) as u32)
+                    .unwrap_or(u32::MAX) as u64,
                 32,
             );
             Ok(0)
@@ -166,7 +188,10 @@ fn remw() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
             *cpu.wgpr(r.rd) = sext(
-                (cpu.rgpr(r.rs1) as i32).wrapping_rem(cpu.rgpr(r.rs2) as i32) as u32 as u64,
+                match cpu.rgpr(r.rs2) as i32 {
+                    0 => cpu.rgpr(r.rs1) as u32,
+                    d => (cpu.rgpr(r.rs1) as i32).wrapping_rem(d) as u32,
+                } as u64,
                 32,
             );
             Ok(0)
@@ -182,7 +207,9 @@ fn remuw() -> IsaDefine {
         Arc::new(Box::new(|cpu, insn| {
             let r = vdepart!(insn, InsnType::R);
             *cpu.wgpr(r.rd) = sext(
-                (cpu.rgpr(r.rs1) as u32).wrapping_rem(cpu.rgpr(r.rs2) as u32) as u64,
+                (cpu.rgpr(r.rs1) as u32)
+                    .checked_rem(cpu.rgpr(r.rs2) as u32)
+                    .unwrap_or(cpu.rgpr(r.rs1) as u32) as u64,
                 32,
             );
             Ok(0)
@@ -194,10 +221,9 @@ fn remuw() -> IsaDefine {
 }
 
 pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
-    // TBD: add more instructions
     install(map, mul());
-    // install(map, mulh());
-    // install(map, mulhsu());
+    install(map, mulh());
+    install(map, mulhsu());
     install(map, mulhu());
     install(map, div());
     install(map, divu());
@@ -209,3 +235,49 @@ pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
     install(map, remw());
     install(map, remuw());
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{config::MachineConfig, cpu::Cpu};
+
+    const A0: usize = 10;
+    const A1: usize = 11;
+    const A2: usize = 12;
+
+    /// Execute `insn`, an `op a2, a0, a1`, and return a2.
+    fn op(insn: u32, a: u64, b: u64) -> u64 {
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &[]);
+        cpu.regs[A0] = a;
+        cpu.regs[A1] = b;
+        cpu.execute(insn).unwrap();
+        cpu.regs[A2]
+    }
+
+    #[test]
+    fn test_high_multiplies() {
+        let (mulh, mulhsu, mulhu) = (0x02b51633, 0x02b52633, 0x02b53633);
+        assert_eq!(op(mulh, -1i64 as u64, -1i64 as u64), 0);
+        assert_eq!(op(mulh, i64::MIN as u64, 2), u64::MAX);
+        // rs1 signed, rs2 unsigned
+        assert_eq!(op(mulhsu, -1i64 as u64, u64::MAX), u64::MAX);
+        assert_eq!(op(mulhsu, 2, u64::MAX), 1);
+        assert_eq!(op(mulhu, u64::MAX, u64::MAX), u64::MAX - 1);
+    }
+
+    #[test]
+    fn test_division_by_zero_and_overflow() {
+        let (div, divu, rem, remu) = (0x02b54633, 0x02b55633, 0x02b56633, 0x02b57633);
+        assert_eq!(op(div, 7, 0), u64::MAX);
+        assert_eq!(op(divu, 7, 0), u64::MAX);
+        assert_eq!(op(rem, 7, 0), 7);
+        assert_eq!(op(remu, 7, 0), 7);
+        assert_eq!(op(div, i64::MIN as u64, u64::MAX), i64::MIN as u64);
+        assert_eq!(op(rem, i64::MIN as u64, u64::MAX), 0);
+
+        // the word forms see only the low halves and sign-extend the result
+        let (divw, divuw, remw) = (0x02b5463b, 0x02b5563b, 0x02b5663b);
+        assert_eq!(op(divw, 0x1_0000_0007, 1 << 32), u64::MAX);
+        assert_eq!(op(divuw, 0xffff_fffe, 1), 0xffff_ffff_ffff_fffe);
+        assert_eq!(op(remw, 0x1_8000_0000, 0), 0xffff_ffff_8000_0000);
+    }
+}
@@ -0,0 +1,273 @@
+use super::{
+    cpu::Cpu,
+    csr::{self, SATP_MODE_SV39},
+    except::Exception,
+    trap::Privilege,
+};
+
+const PAGE_SHIFT: u64 = 12;
+const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+const PTE_SIZE: u64 = 8;
+const LEVELS: u64 = 3;
+
+// PTE bits
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: u64 = 10;
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+/// Kind of memory access being translated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault(addr),
+            Access::Load => Exception::LoadPageFault(addr),
+            Access::Store => Exception::StoreAMOPageFault(addr),
+        }
+    }
+
+    fn access_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAMOAccessFault(addr),
+        }
+    }
+}
+
+impl Cpu {
+    /// Privilege that loads and stores are checked against, honouring
+    /// `mstatus.MPRV`.
+    fn data_privilege(&self) -> Privilege {
+        let mstatus = self.csr.read(csr::MSTATUS);
+        if self.privilege == Privilege::Machine && mstatus & csr::MSTATUS_MPRV != 0 {
+            Privilege::from_bits(mstatus >> 11)
+        } else {
+            self.privilege
+        }
+    }
+
+    /// Translate `vaddr` to a physical address, walking the Sv39 page table
+    /// when paging applies. Accessed and dirty bits are set by the walk.
+    pub fn translate(&mut self, vaddr: u64, access: Access) -> Result<u64, Exception> {
+        let privilege = match access {
+            Access::Fetch => self.privilege,
+            Access::Load | Access::Store => self.data_privilege(),
+        };
+        let satp = self.csr.read(csr::SATP);
+        if privilege == Privilege::Machine || satp >> 60 != SATP_MODE_SV39 {
+            return Ok(vaddr);
+        }
+
+        // bits 63:39 must all equal bit 38
+        if ((vaddr as i64) << 25 >> 25) as u64 != vaddr {
+            return Err(access.page_fault(vaddr));
+        }
+
+        let mstatus = self.csr.read(csr::MSTATUS);
+        let mut table = (satp & PPN_MASK) << PAGE_SHIFT;
+        for level in (0..LEVELS).rev() {
+            let vpn = (vaddr >> (PAGE_SHIFT + 9 * level)) & 0x1ff;
+            let pte_addr = table + vpn * PTE_SIZE;
+            let pte = self
+                .bus
+                .load(pte_addr, 64)
+                .map_err(|_| access.access_fault(vaddr))?;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault(vaddr));
+            }
+            let ppn = (pte >> PTE_PPN_SHIFT) & PPN_MASK;
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+
+            let allowed = match access {
+                Access::Fetch => pte & PTE_X != 0,
+                Access::Load => {
+                    pte & PTE_R != 0 || (mstatus & csr::MSTATUS_MXR != 0 && pte & PTE_X != 0)
+                }
+                Access::Store => pte & PTE_W != 0,
+            };
+            let user_ok = match privilege {
+                Privilege::User => pte & PTE_U != 0,
+                // S-mode never executes user pages and reads them only with SUM
+                _ => {
+                    pte & PTE_U == 0 || (access != Access::Fetch && mstatus & csr::MSTATUS_SUM != 0)
+                }
+            };
+            // a superpage's unused PPN bits must be zero
+            let misaligned = ppn & ((1 << (9 * level)) - 1) != 0;
+            if !allowed || !user_ok || misaligned {
+                return Err(access.page_fault(vaddr));
+            }
+
+            let mut updated = pte | PTE_A;
+            if access == Access::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                self.history.record_store(pte_addr, 64, pte);
+                self.bus
+                    .store(pte_addr, 64, updated)
+                    .map_err(|_| access.access_fault(vaddr))?;
+            }
+
+            let offset_bits = PAGE_SHIFT + 9 * level;
+            let offset = vaddr & ((1 << offset_bits) - 1);
+            return Ok((ppn << PAGE_SHIFT) & !((1 << offset_bits) - 1) | offset);
+        }
+        Err(access.page_fault(vaddr))
+    }
+
+    /// Load through the MMU; an access that spans two pages is split up so
+    /// each half is translated.
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if let Some(log) = &mut self.commit_log {
+            log.load(addr);
+        }
+        let bytes = size / 8;
+        if crosses_page(addr, bytes) {
+            let mut value = 0;
+            for i in 0..bytes {
+                let paddr = self.translate(addr.wrapping_add(i), Access::Load)?;
+                value |= self.bus.load(paddr, 8)? << (8 * i);
+            }
+            return Ok(value);
+        }
+        let paddr = self.translate(addr, Access::Load)?;
+        self.bus.load(paddr, size)
+    }
+
+    /// Store through the MMU, recording the old contents for step-back.
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if let Some(log) = &mut self.commit_log {
+            log.store(addr, size, value);
+        }
+        let bytes = size / 8;
+        if crosses_page(addr, bytes) {
+            // translate both pages before writing anything
+            self.translate(addr, Access::Store)?;
+            self.translate(addr.wrapping_add(bytes - 1), Access::Store)?;
+            for i in 0..bytes {
+                let paddr = self.translate(addr.wrapping_add(i), Access::Store)?;
+                self.store_physical(paddr, 8, value >> (8 * i))?;
+            }
+            return Ok(());
+        }
+        let paddr = self.translate(addr, Access::Store)?;
+        self.store_physical(paddr, size, value)
+    }
+
+    fn store_physical(&mut self, paddr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if let Ok(old) = self.bus.peek(paddr, size) {
+            self.history.record_store(paddr, size, old);
+        }
+        self.bus.store(paddr, size, value)
+    }
+
+    /// Fetch the instruction at pc: 16 bits for a compressed instruction,
+    /// otherwise 32, with each half translated on its own.
+    pub fn fetch(&mut self) -> Result<u64, Exception> {
+        let low = self.fetch_half(self.pc)?;
+        if low & 3 != 3 {
+            return Ok(low);
+        }
+        let high = self.fetch_half(self.pc.wrapping_add(2))?;
+        Ok(high << 16 | low)
+    }
+
+    fn fetch_half(&mut self, addr: u64) -> Result<u64, Exception> {
+        let paddr = self.translate(addr, Access::Fetch)?;
+        self.bus
+            .load(paddr, 16)
+            .map_err(|_| Exception::InstructionAccessFault(addr))
+    }
+}
+
+fn crosses_page(addr: u64, bytes: u64) -> bool {
+    (addr % PAGE_SIZE) + bytes > PAGE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::MachineConfig;
+
+    const DRAM_BASE: u64 = 0x8000_0000;
+    const ROOT: u64 = DRAM_BASE + 0x1000;
+    const LEAF_TABLE: u64 = DRAM_BASE + 0x2000;
+    const MID_TABLE: u64 = DRAM_BASE + 0x3000;
+    const DATA: u64 = DRAM_BASE + 0x4000;
+
+    fn pte(paddr: u64, flags: u64) -> u64 {
+        (paddr >> PAGE_SHIFT) << PTE_PPN_SHIFT | flags | PTE_V
+    }
+
+    fn cpu() -> Cpu {
+        let config = MachineConfig::builder()
+            .dram(DRAM_BASE, 0x10_0000)
+            .reset_vector(DRAM_BASE)
+            .devices(&[])
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(config, Vec::new()).unwrap();
+        // VA 0x4000_0000 -> ROOT[1] -> MID_TABLE[0] -> LEAF_TABLE[0] -> DATA
+        cpu.bus.store(ROOT + 8, 64, pte(MID_TABLE, 0)).unwrap();
+        cpu.bus.store(MID_TABLE, 64, pte(LEAF_TABLE, 0)).unwrap();
+        cpu.bus
+            .store(LEAF_TABLE, 64, pte(DATA, PTE_R | PTE_U))
+            .unwrap();
+        cpu.csr
+            .write(csr::SATP, SATP_MODE_SV39 << 60 | ROOT >> PAGE_SHIFT);
+        cpu.privilege = Privilege::User;
+        cpu
+    }
+
+    #[test]
+    fn test_sv39_walk_sets_accessed_bit() {
+        let mut cpu = cpu();
+        cpu.bus.store(DATA + 8, 64, 0x1234).unwrap();
+        assert_eq!(cpu.load(0x4000_0008, 64).unwrap(), 0x1234);
+        assert_ne!(cpu.bus.load(LEAF_TABLE, 64).unwrap() & PTE_A, 0);
+
+        // read-only page
+        assert_eq!(
+            cpu.store(0x4000_0008, 64, 0),
+            Err(Exception::StoreAMOPageFault(0x4000_0008))
+        );
+        // unmapped page
+        assert_eq!(
+            cpu.load(0x4000_1000, 8),
+            Err(Exception::LoadPageFault(0x4000_1000))
+        );
+    }
+
+    #[test]
+    fn test_supervisor_needs_sum_for_user_pages() {
+        let mut cpu = cpu();
+        cpu.privilege = Privilege::Supervisor;
+        assert_eq!(
+            cpu.load(0x4000_0000, 8),
+            Err(Exception::LoadPageFault(0x4000_0000))
+        );
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_SUM);
+        assert!(cpu.load(0x4000_0000, 8).is_ok());
+        // M-mode is never translated
+        cpu.privilege = Privilege::Machine;
+        assert_eq!(cpu.translate(DATA, Access::Load).unwrap(), DATA);
+    }
+}
@@ -1,11 +1,47 @@
+mod a;
+mod boot;
 mod bus;
+mod c;
+mod call_stack;
+mod commit_log;
+mod config;
+mod console;
+mod coverage;
 mod cpu;
+mod csr;
+mod d;
+mod debug;
+pub mod device;
 mod dram;
+mod dwarf;
+mod elf;
 mod except;
+mod f;
+pub mod fdt;
+mod history;
+mod htif;
 mod i;
 mod isa;
 mod jit;
+mod linux;
+mod lockstep;
 mod m;
+mod mmu;
 pub mod param;
+mod privileged;
+mod profile;
+mod rars;
+mod snapshot;
+mod source;
+mod trap;
+mod zicsr;
+mod zifencei;
 
+pub use config::{
+    BootConfig, BootImage, DeviceConfig, Extension, LinuxProgram, MachineConfig, Personality,
+};
 pub use cpu::Cpu;
+pub use debug::StopReason;
+pub use except::Exception;
+pub use snapshot::Snapshot;
+pub use trap::Privilege;
@@ -1,7 +1,48 @@
-// pub const DRAM_BASE: u64 = 0x8000_0000;
-pub const DRAM_BASE: u64 = 0x0000_0000;
-pub const DRAM_SIZE: u64 = 1024 * 1024 * 128; // 128 MB
-pub const DRAM_END: u64 = DRAM_SIZE + DRAM_BASE - 1;
+// Defaults for MachineConfig; QEMU virt places DRAM at 0x8000_0000
+pub const DEFAULT_DRAM_BASE: u64 = 0x0000_0000;
+pub const DEFAULT_DRAM_SIZE: u64 = 1024 * 1024 * 128; // 128 MB
+pub const DRAM_PAGE_SIZE: u64 = 4096;
+
+// MachineConfig::virt()
+pub const VIRT_DRAM_BASE: u64 = 0x8000_0000;
+pub const VIRT_DRAM_SIZE: u64 = 1024 * 1024 * 256; // 256 MB
+
+// QEMU virt memory map
+pub const DEFAULT_CLINT_BASE: u64 = 0x0200_0000;
+pub const DEFAULT_PLIC_BASE: u64 = 0x0c00_0000;
+pub const DEFAULT_UART_BASE: u64 = 0x1000_0000;
+pub const DEFAULT_UART_IRQ: u32 = 10;
+pub const DEFAULT_VIRTIO_BASE: u64 = 0x1000_1000;
+pub const DEFAULT_VIRTIO_IRQ: u32 = 1;
+pub const DEFAULT_RNG_SEED: u64 = 0;
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+// mtime frequency in Hz when driven by the host clock
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+// Console output kept for clients that poll late
+pub const CONSOLE_CAPACITY: usize = 1 << 20;
+
+// Commit log lines kept for clients that poll late
+pub const COMMIT_LOG_CAPACITY: usize = 1 << 16;
+
+// Deepest call stack a backtrace unwinds
+pub const BACKTRACE_DEPTH: usize = 64;
+
+// Deepest call stack the shadow stack tracks
+pub const CALL_STACK_DEPTH: usize = 1024;
+
+// Mismatched returns kept for backtraces
+pub const CALL_STACK_MISMATCHES: usize = 16;
+
+// Upper bound on instructions executed by a single debugger command
+pub const STEP_BUDGET: u64 = 1_000_000;
+
+// Where files named in API requests are kept; requests cannot reach outside
+pub const API_FILES_DIR: &str = "/tmp/risque-temp/files";
+
+// Number of retired instructions that can be stepped back over
+pub const HISTORY_DEPTH: usize = 1 << 16;
 
 pub const ABINAME: [&str; 32] = [
     "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
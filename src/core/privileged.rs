@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::kit::insn::*;
+
+use super::{
+    csr,
+    except::Exception,
+    isa::{install, IsaDefine},
+    trap::Privilege,
+};
+
+fn mret() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            if cpu.privilege < Privilege::Machine {
+                return Err(Exception::IllegalInstruction(insn));
+            }
+            let mstatus = cpu.csr.read(csr::MSTATUS);
+            let mpp = Privilege::from_bits(mstatus >> 11);
+
+            let mut status = mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+            if mstatus & csr::MSTATUS_MPIE != 0 {
+                status |= csr::MSTATUS_MIE;
+            }
+            status |= csr::MSTATUS_MPIE;
+            if mpp != Privilege::Machine {
+                status &= !csr::MSTATUS_MPRV;
+            }
+            cpu.write_csr(csr::MSTATUS, status);
+
+            cpu.set_privilege(mpp);
+            cpu.pc = cpu.csr.read(csr::MEPC);
+            cpu.pcimm = 0;
+            Ok(0)
+        })),
+        "mret",
+        0x30200073,
+        InsnType::I,
+    )
+}
+
+fn sret() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let mstatus = cpu.csr.read(csr::MSTATUS);
+            // TSR traps sret in S-mode so M-mode can emulate it
+            if cpu.privilege < Privilege::Supervisor
+                || (cpu.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_TSR != 0)
+            {
+                return Err(Exception::IllegalInstruction(insn));
+            }
+            let spp = if mstatus & csr::MSTATUS_SPP != 0 {
+                Privilege::Supervisor
+            } else {
+                Privilege::User
+            };
+
+            let mut status = mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP | csr::MSTATUS_MPRV);
+            if mstatus & csr::MSTATUS_SPIE != 0 {
+                status |= csr::MSTATUS_SIE;
+            }
+            status |= csr::MSTATUS_SPIE;
+            cpu.write_csr(csr::MSTATUS, status);
+
+            cpu.set_privilege(spp);
+            cpu.pc = cpu.csr.read(csr::SEPC);
+            cpu.pcimm = 0;
+            Ok(0)
+        })),
+        "sret",
+        0x10200073,
+        InsnType::I,
+    )
+}
+
+fn wfi() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let timeout = cpu.csr.read(csr::MSTATUS) & csr::MSTATUS_TW != 0;
+            if cpu.privilege == Privilege::User
+                || (cpu.privilege == Privilege::Supervisor && timeout)
+            {
+                return Err(Exception::IllegalInstruction(insn));
+            }
+            cpu.waiting = true;
+            Ok(0)
+        })),
+        "wfi",
+        0x10500073,
+        InsnType::I,
+    )
+}
+
+fn sfence_vma() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let tvm = cpu.csr.read(csr::MSTATUS) & csr::MSTATUS_TVM != 0;
+            if cpu.privilege == Privilege::User || (cpu.privilege == Privilege::Supervisor && tvm) {
+                return Err(Exception::IllegalInstruction(insn));
+            }
+            // every access walks the page table, so there is no TLB to flush
+            Ok(0)
+        })),
+        "sfence.vma",
+        0x12000073,
+        InsnType::R,
+    )
+    .masked(0xfe007fff)
+}
+
+pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
+    install(map, mret());
+    install(map, sret());
+    install(map, wfi());
+    install(map, sfence_vma());
+}
@@ -0,0 +1,257 @@
+//! An instruction-level profile: how many instructions retired at each pc,
+//! and under which call stack. Stacks are read off the shadow call stack,
+//! so code that unwinds in ways it cannot follow skews the stacks, though
+//! not the counts.
+//!
+//! A profile exports as a flat report by function, an annotated disassembly
+//! of one function in the manner of `perf annotate`, or folded stacks for
+//! `flamegraph.pl` and compatible tools. Functions come from the symbols of
+//! the loaded executable; without them, addresses stand in for names.
+
+use std::collections::HashMap;
+
+use super::{
+    call_stack::CallStack,
+    cpu::Cpu,
+    debug::{is_call, is_return},
+};
+
+#[derive(Default)]
+pub struct Profile {
+    /// Cleared while the profile is stopped.
+    pub(super) running: bool,
+    // pc -> (instructions retired there, the instruction)
+    counts: HashMap<u64, (u64, u32)>,
+    // calls by target
+    calls: HashMap<u64, u64>,
+    // the current call stack: where its outermost function was entered or
+    // made its first call, then the target of each call not yet returned from
+    stack: Vec<u64>,
+    // retired instructions by call stack
+    stacks: HashMap<Vec<u64>, u64>,
+    // instructions retired under `stack` since it last changed
+    run: u64,
+}
+
+impl Profile {
+    /// Count `insn`, retired at `pc` with `next` to follow, once `calls`
+    /// has followed it.
+    pub(super) fn retire(&mut self, pc: u64, insn: u32, next: u64, calls: &CallStack) {
+        let count = self.counts.entry(pc).or_insert((0, insn));
+        *count = (count.0 + 1, insn);
+        self.run += 1;
+
+        if is_call(insn) {
+            *self.calls.entry(next).or_default() += 1;
+        }
+        if is_call(insn) || is_return(insn) {
+            self.flush();
+            self.stack = stack(calls, next);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.run > 0 {
+            *self.stacks.entry(self.stack.clone()).or_default() += self.run;
+            self.run = 0;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.values().map(|&(count, _)| count).sum()
+    }
+}
+
+/// The functions on `calls`, outermost first, as addresses in them: the
+/// outermost call site, or `pc` while no call is outstanding, then the
+/// target of each call.
+fn stack(calls: &CallStack, pc: u64) -> Vec<u64> {
+    let frames = calls.frames();
+    let root = frames.first().map_or(pc, |frame| frame.call_site);
+    std::iter::once(root)
+        .chain(frames.iter().map(|frame| frame.target))
+        .collect()
+}
+
+impl Cpu {
+    /// Profile the instructions retired from now on, discarding any earlier
+    /// profile.
+    pub fn start_profile(&mut self) {
+        self.profile = Some(Profile {
+            running: true,
+            stack: stack(&self.call_stack, self.pc),
+            ..Profile::default()
+        });
+    }
+
+    /// Stop counting, keeping the profile for export.
+    pub fn stop_profile(&mut self) {
+        if let Some(profile) = &mut self.profile {
+            profile.running = false;
+        }
+    }
+
+    fn taken_profile(&self) -> Result<&Profile, String> {
+        self.profile
+            .as_ref()
+            .ok_or_else(|| "no profile has been taken".to_string())
+    }
+
+    /// Name of the function `addr` lies in, or the address itself.
+    fn function_name(&self, addr: u64) -> String {
+        self.elf
+            .as_ref()
+            .and_then(|elf| elf.function_at(addr))
+            .map_or_else(|| format!("0x{:x}", addr), |symbol| symbol.name.clone())
+    }
+
+    /// Instructions retired and calls made per function, busiest first.
+    pub fn profile_flat(&self) -> Result<String, String> {
+        let profile = self.taken_profile()?;
+        let mut functions: HashMap<String, (u64, u64)> = HashMap::new();
+        for (&pc, &(count, _)) in &profile.counts {
+            functions.entry(self.function_name(pc)).or_default().0 += count;
+        }
+        for (&target, &calls) in &profile.calls {
+            functions.entry(self.function_name(target)).or_default().1 += calls;
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(&b.0)));
+
+        let total = profile.total();
+        let mut report = format!(
+            "{} instructions retired\n\n{:>7} {:>12} {:>10}  function\n",
+            total, "%", "instructions", "calls"
+        );
+        for (name, (count, calls)) in functions {
+            report += &format!(
+                "{:>7.2} {:>12} {:>10}  {}\n",
+                100.0 * count as f64 / total.max(1) as f64,
+                count,
+                calls,
+                name
+            );
+        }
+        Ok(report)
+    }
+
+    /// The disassembly of `function`, each instruction with its share of
+    /// the instructions retired in the function and its count.
+    pub fn profile_annotate(&self, function: &str) -> Result<String, String> {
+        let profile = self.taken_profile()?;
+        let elf = self.elf.as_ref().ok_or("no symbols are loaded")?;
+        let symbol = elf
+            .symbol(function)
+            .ok_or_else(|| format!("no symbol named {}", function))?;
+        // a label without a size runs up to the next symbol
+        let end = match symbol.size {
+            0 => elf
+                .symbols
+                .iter()
+                .map(|s| s.addr)
+                .filter(|&addr| addr > symbol.addr)
+                .min()
+                .unwrap_or(symbol.addr),
+            size => symbol.addr + size,
+        };
+
+        let total: u64 = (symbol.addr..end)
+            .filter_map(|pc| profile.counts.get(&pc))
+            .map(|&(count, _)| count)
+            .sum();
+        let mut report = format!("{} ({} instructions retired)\n", function, total);
+        let mut pc = symbol.addr;
+        while pc < end {
+            // instructions that never ran are read back from memory
+            let (count, insn) = match profile.counts.get(&pc) {
+                Some(&(count, insn)) => (count, insn),
+                None => match self.bus.peek(pc, 32).or_else(|_| self.bus.peek(pc, 16)) {
+                    Ok(insn) => (0, insn as u32),
+                    Err(_) => break,
+                },
+            };
+            let percent = match count {
+                0 => String::new(),
+                _ => format!("{:.2}", 100.0 * count as f64 / total as f64),
+            };
+            report += &format!(
+                "{:>7} {:>10} : {}\n",
+                percent,
+                count,
+                self.explain_at(pc, insn)
+            );
+            pc += if insn & 3 == 3 { 4 } else { 2 };
+        }
+        Ok(report)
+    }
+
+    /// Call stacks with the instructions retired under each, one per line,
+    /// outermost function first: `main;sort;swap 42`.
+    pub fn profile_folded(&self) -> Result<String, String> {
+        let profile = self.taken_profile()?;
+        let mut stacks: HashMap<String, u64> = HashMap::new();
+        let current = (!profile.stack.is_empty()).then_some((&profile.stack, &profile.run));
+        for (stack, &count) in profile.stacks.iter().chain(current) {
+            if count == 0 {
+                continue;
+            }
+            let names: Vec<String> = stack.iter().map(|&addr| self.function_name(addr)).collect();
+            *stacks.entry(names.join(";")).or_default() += count;
+        }
+        let mut stacks: Vec<_> = stacks.into_iter().collect();
+        stacks.sort();
+        Ok(stacks
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{
+        config::MachineConfig,
+        cpu::Cpu,
+        elf::{Elf, Symbol},
+    };
+
+    fn function(name: &str, addr: u64) -> Symbol {
+        Symbol {
+            name: name.into(),
+            addr,
+            size: 8,
+            func: true,
+        }
+    }
+
+    #[test]
+    fn test_stacks_follow_calls_and_returns() {
+        let code = [
+            0x008000ef, // main: jal ra, 8 (f)
+            0x0000006f, // j .
+            0x00000013, // f: nop
+            0x00008067, // ret
+        ];
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &code);
+        cpu.elf = Some(Elf {
+            entry: 0,
+            phdr: 0,
+            phnum: 0,
+            segments: Vec::new(),
+            symbols: vec![function("main", 0), function("f", 8)],
+            sections: Vec::new(),
+            lines: Default::default(),
+        });
+        cpu.start_profile();
+        cpu.step_n(5);
+
+        // j . ran twice, back in main
+        assert_eq!(cpu.profile_folded().unwrap(), "main 3\nmain;f 2\n");
+        let flat = cpu.profile_flat().unwrap();
+        assert!(flat.starts_with("5 instructions retired"));
+        assert!(flat.contains("  60.00            3          0  main\n"));
+        assert!(flat.contains("  40.00            2          1  f\n"));
+        let annotated = cpu.profile_annotate("main").unwrap();
+        assert!(annotated.contains("  33.33          1 : 000000000000: jal"));
+    }
+}
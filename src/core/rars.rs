@@ -0,0 +1,302 @@
+//! Environment calls of the RARS and Venus simulators, so that teaching
+//! material written for them runs unchanged. Both talk to the console.
+//!
+//! The two share one service table; Venus numbers its exit-with-code call
+//! 17 and passes everything one register later. Reads wait, as they do
+//! interactively, until the console has a whole line.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{
+    config::{Extension, MachineConfig, Personality},
+    cpu::Cpu,
+    except::Exception,
+    f::Float,
+};
+
+const ECALL: u32 = 0x00000073;
+
+const A0: usize = 10;
+const A1: usize = 11;
+const A7: usize = 17;
+const FA0: u32 = 10;
+
+// Service numbers, as RARS has them
+const PRINT_INT: u64 = 1;
+const PRINT_FLOAT: u64 = 2;
+const PRINT_DOUBLE: u64 = 3;
+const PRINT_STRING: u64 = 4;
+const READ_INT: u64 = 5;
+const READ_FLOAT: u64 = 6;
+const READ_DOUBLE: u64 = 7;
+const READ_STRING: u64 = 8;
+const SBRK: u64 = 9;
+const EXIT: u64 = 10;
+const PRINT_CHAR: u64 = 11;
+const READ_CHAR: u64 = 12;
+const TIME: u64 = 30;
+const SLEEP: u64 = 32;
+const PRINT_HEX: u64 = 34;
+const PRINT_BINARY: u64 = 35;
+const PRINT_UNSIGNED: u64 = 36;
+const RAND_SEED: u64 = 40;
+const RAND_INT: u64 = 41;
+const RAND_INT_RANGE: u64 = 42;
+const RAND_FLOAT: u64 = 43;
+const EXIT2: u64 = 93;
+
+const VENUS_EXIT2: u64 = 17;
+
+// the longest string PrintString writes; longer ones are cut off
+const MAX_STRING: u64 = 1 << 20;
+
+/// Simulator state the services keep between calls.
+#[derive(Clone)]
+pub struct Rars {
+    /// Next address `sbrk` hands out. The heap runs from halfway up DRAM to
+    /// the last quarter, which is left to the stack.
+    heap: u64,
+    rng: StdRng,
+}
+
+impl Rars {
+    pub fn new(config: &MachineConfig) -> Self {
+        Self {
+            heap: config.dram_base + config.dram_size / 2,
+            rng: StdRng::from_rng(&mut rand::rng()),
+        }
+    }
+
+    /// State restored from a snapshot file, which keeps the heap but not the
+    /// random number generator; it is seeded afresh.
+    pub(super) fn with_heap(heap: u64) -> Self {
+        Self {
+            heap,
+            rng: StdRng::from_rng(&mut rand::rng()),
+        }
+    }
+
+    pub(super) fn heap(&self) -> u64 {
+        self.heap
+    }
+}
+
+impl Cpu {
+    /// Serve the `ecall` at pc that raised `e`. Services that do not exist,
+    /// or need an extension the machine lacks, leave `e` to be taken.
+    pub(super) fn rars_ecall(&mut self, e: Exception) -> Result<(u32, u64), Exception> {
+        let (service, arg) = match self.config.personality {
+            Personality::Venus => match self.regs[A0] {
+                VENUS_EXIT2 => (EXIT2, A1),
+                service => (service, A1),
+            },
+            _ => (self.regs[A7], A0),
+        };
+        let (a, b) = (self.regs[arg], self.regs[arg + 1]);
+        let float = self.config.has(Extension::F);
+        let double = self.config.has(Extension::D);
+
+        match service {
+            PRINT_INT => self.print(&(a as i64).to_string()),
+            PRINT_FLOAT if float => {
+                self.print(&format!("{:?}", f32::unbox(self.fregs[FA0 as usize])))
+            }
+            PRINT_DOUBLE if double => {
+                self.print(&format!("{:?}", f64::unbox(self.fregs[FA0 as usize])))
+            }
+            PRINT_STRING => {
+                let mut bytes = Vec::new();
+                for i in 0..MAX_STRING {
+                    let addr = a.checked_add(i).ok_or(Exception::LoadAccessFault(a))?;
+                    match self.load(addr, 8)? as u8 {
+                        0 => break,
+                        byte => bytes.push(byte),
+                    }
+                }
+                self.console.write(&bytes);
+            }
+            PRINT_CHAR => self.console.write(&[a as u8]),
+            PRINT_HEX => self.print(&format!("0x{:016x}", a)),
+            PRINT_BINARY => self.print(&format!("{:064b}", a)),
+            PRINT_UNSIGNED => self.print(&a.to_string()),
+            READ_INT | READ_FLOAT | READ_DOUBLE | READ_STRING if !self.console.has_line() => {
+                return Ok((ECALL, self.pc));
+            }
+            READ_INT => {
+                let line = self.console.read_line().unwrap();
+                let value = String::from_utf8_lossy(&line).trim().parse::<i64>();
+                *self.wgpr(A0 as u32) = value.unwrap_or(0) as u64;
+            }
+            READ_FLOAT if float => {
+                let line = self.console.read_line().unwrap();
+                let value = String::from_utf8_lossy(&line).trim().parse::<f32>();
+                self.wfpr(FA0, value.unwrap_or(0.0).boxed());
+            }
+            READ_DOUBLE if double => {
+                let line = self.console.read_line().unwrap();
+                let value = String::from_utf8_lossy(&line).trim().parse::<f64>();
+                self.wfpr(FA0, value.unwrap_or(0.0).boxed());
+            }
+            READ_STRING => {
+                // at most b - 1 characters, then a terminating NUL
+                let line = self.console.read_line().unwrap();
+                let len = (b.saturating_sub(1) as usize).min(line.len());
+                for (i, &byte) in line[..len].iter().chain(&[0]).enumerate() {
+                    if (i as u64) < b {
+                        let addr = a
+                            .checked_add(i as u64)
+                            .ok_or(Exception::StoreAMOAccessFault(a))?;
+                        self.store(addr, 8, byte as u64)?;
+                    }
+                }
+            }
+            READ_CHAR => match self.console.read() {
+                Some(byte) => *self.wgpr(A0 as u32) = byte as u64,
+                None => return Ok((ECALL, self.pc)),
+            },
+            SBRK => {
+                let rars = self.rars.as_mut().unwrap();
+                let heap = rars.heap;
+                // word-aligned, and never into the stack at the top of DRAM
+                let end = heap.checked_add(a.next_multiple_of(4)).ok_or(e)?;
+                if (a as i64) < 0 || end > self.config.dram_end() - self.config.dram_size / 4 {
+                    return Err(e);
+                }
+                rars.heap = end;
+                *self.wgpr(A0 as u32) = heap;
+            }
+            EXIT => self.exit_code = Some(0),
+            EXIT2 => self.exit_code = Some(a & 0xff),
+            TIME => {
+                let millis = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+                *self.wgpr(A0 as u32) = millis & 0xffff_ffff;
+                *self.wgpr(A1 as u32) = millis >> 32;
+            }
+            // simulated time does not wait for the host
+            SLEEP => {}
+            RAND_SEED => self.rars.as_mut().unwrap().rng = StdRng::seed_from_u64(b),
+            RAND_INT => {
+                let value = self.rars.as_mut().unwrap().rng.random::<i32>();
+                *self.wgpr(A0 as u32) = value as i64 as u64;
+            }
+            RAND_INT_RANGE => {
+                if b as i32 <= 0 {
+                    return Err(e);
+                }
+                let value = self.rars.as_mut().unwrap().rng.random_range(0..b as i32);
+                *self.wgpr(A0 as u32) = value as u64;
+            }
+            RAND_FLOAT if float => {
+                let value = self.rars.as_mut().unwrap().rng.random::<f32>();
+                self.wfpr(FA0, value.boxed());
+            }
+            _ => return Err(e),
+        }
+        // console input and output, the heap and the random number
+        // generator are not in the undo history
+        if !matches!(service, EXIT | EXIT2 | TIME | SLEEP) {
+            self.history.record_side_effect();
+        }
+        Ok((ECALL, self.pc + 4))
+    }
+
+    fn print(&self, text: &str) {
+        self.console.write(text.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::debug::StopReason;
+
+    fn run(personality: Personality, code: &[u32], input: &[u8]) -> (StopReason, Vec<u8>) {
+        let config = MachineConfig::builder()
+            .personality(personality)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::with_code(config, code);
+        let reason = cpu.run_until(None, 20);
+        cpu.console.push_input(input);
+        let reason = match reason {
+            StopReason::Budget(_) => cpu.run_until(None, 20),
+            reason => reason,
+        };
+        (reason, cpu.console.output_since(0).1)
+    }
+
+    #[test]
+    fn test_rars_services() {
+        let code = [
+            0x02a00513, // li a0, 42
+            0x00100893, // li a7, 1 (PrintInt)
+            0x00000073, // ecall
+            0x00500893, // li a7, 5 (ReadInt), waits for input
+            0x00000073, // ecall
+            0x05d00893, // li a7, 93 (Exit2), with the value read
+            0x00000073, // ecall
+        ];
+        let (reason, output) = run(Personality::Rars, &code, b"7\n");
+        assert!(matches!(reason, StopReason::Exited(7)));
+        assert_eq!(output, b"42");
+    }
+
+    #[test]
+    fn test_services_are_not_run_twice() {
+        let config = MachineConfig::builder()
+            .personality(Personality::Rars)
+            .build()
+            .unwrap();
+        let code = [
+            0x02a00513, // li a0, 42
+            0x00100893, // li a7, 1 (PrintInt)
+            0x00000073, // ecall
+            0x0000006f, // j .
+        ];
+        let mut cpu = Cpu::with_code(config, &code);
+        cpu.step_n(3);
+        assert_eq!(cpu.step_back(), StopReason::DeviceAccess);
+        cpu.step_n(1);
+        assert_eq!(cpu.console.output_since(0).1, b"42");
+    }
+
+    #[test]
+    fn test_print_string_is_bounded() {
+        let code: [u32; 4] = [
+            0x00001537, // lui a0, 1
+            0x00400893, // li a7, 4 (PrintString)
+            0x00000073, // ecall
+            0x0000006f, // j .
+        ];
+        let config = MachineConfig::builder()
+            .personality(Personality::Rars)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::with_code(config, &code);
+        // no terminating NUL in sight
+        let text = vec![b'a'; 2 * MAX_STRING as usize];
+        cpu.bus.write_dram(0x1000, &text).unwrap();
+        cpu.step_n(3);
+        assert_eq!(cpu.console.output_since(0).1.len(), MAX_STRING as usize);
+    }
+
+    #[test]
+    fn test_venus_services() {
+        let code = [
+            0x00100513, // li a0, 1 (print_int)
+            0xffd00593, // li a1, -3
+            0x00000073, // ecall
+            0x01100513, // li a0, 17 (exit2)
+            0x00500593, // li a1, 5
+            0x00000073, // ecall
+        ];
+        let (reason, output) = run(Personality::Venus, &code, b"");
+        assert!(matches!(reason, StopReason::Exited(5)));
+        assert_eq!(output, b"-3");
+    }
+}
@@ -0,0 +1,444 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::{
+    call_stack::CallStack,
+    cpu::Cpu,
+    dram::Dram,
+    htif::Htif,
+    param::{DEFAULT_DRAM_BASE, DEFAULT_DRAM_SIZE, DRAM_PAGE_SIZE},
+    rars::Rars,
+    trap::Privilege,
+};
+
+const MAGIC: &[u8; 8] = b"RISQUESS";
+// Bumped whenever sections are added or change meaning; only snapshots of
+// the current version are read. 2 added MEM, 3 DEVS, 4 CSR, 5 PRIV, 6 FPR,
+// 7 RUN.
+const VERSION: u32 = 7;
+
+/// Complete architectural state of a machine at one point in execution.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub pc: u64,
+    pub regs: [u64; 32],
+    pub fregs: [u64; 32],
+    pub csrs: Vec<(u32, u64)>,
+    pub privilege: Privilege,
+    pub waiting: bool,
+    pub instret: u64,
+    pub dram: Dram,
+    pub devices: Vec<(String, Vec<u8>)>,
+    /// Set once the program has exited.
+    pub exit_code: Option<u64>,
+    pub htif: Option<Htif>,
+    /// Start of the program break, the break and the mapping limit of a
+    /// Linux process.
+    pub linux: Option<(u64, u64, u64)>,
+    pub rars: Option<Rars>,
+}
+
+impl Cpu {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            regs: self.regs,
+            fregs: self.fregs,
+            csrs: self.csr.dump(),
+            privilege: self.privilege,
+            waiting: self.waiting,
+            instret: self.instret,
+            dram: self.bus.dram().clone(),
+            devices: self.bus.snapshot_devices(),
+            exit_code: self.exit_code,
+            htif: self.htif.clone(),
+            linux: self.linux.as_ref().map(|linux| linux.layout()),
+            rars: self.rars.clone(),
+        }
+    }
+
+    /// Return the machine to `snapshot`. Undo history does not survive this,
+    /// nor do the host files a Linux process opened.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        if snapshot.dram.base() != self.config.dram_base
+            || snapshot.dram.size() != self.config.dram_size
+        {
+            return Err("snapshot DRAM layout does not match this machine".into());
+        }
+        if self.linux.is_some() != snapshot.linux.is_some()
+            || self.rars.is_some() != snapshot.rars.is_some()
+        {
+            return Err("snapshot personality does not match this machine".into());
+        }
+
+        self.bus.restore_devices(&snapshot.devices)?;
+        self.pc = snapshot.pc;
+        self.regs = snapshot.regs;
+        self.fregs = snapshot.fregs;
+        self.reservation = None;
+        self.csr.load(&snapshot.csrs);
+        self.privilege = snapshot.privilege;
+        self.waiting = snapshot.waiting;
+        self.instret = snapshot.instret;
+        self.bus.restore_dram(snapshot.dram.clone());
+        self.exit_code = snapshot.exit_code;
+        self.htif = snapshot.htif.clone();
+        if let (Some(linux), Some(layout)) = (&mut self.linux, snapshot.linux) {
+            linux.restore_layout(layout);
+        }
+        self.rars = snapshot.rars.clone();
+        self.history.clear();
+        // the calls that led to the snapshot were not saved with it
+        self.call_stack = CallStack::default();
+        self.call_stack.invalidate();
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    /// Write the snapshot to `path`.
+    ///
+    /// The file is a magic and version header followed by tagged sections,
+    /// each with its length.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+
+        let mut cpu = Vec::new();
+        cpu.extend_from_slice(&self.pc.to_le_bytes());
+        cpu.extend_from_slice(&self.instret.to_le_bytes());
+        for reg in self.regs {
+            cpu.extend_from_slice(&reg.to_le_bytes());
+        }
+        write_section(&mut w, b"CPU ", &cpu)?;
+
+        let mut fpr = Vec::new();
+        for reg in self.fregs {
+            fpr.extend_from_slice(&reg.to_le_bytes());
+        }
+        write_section(&mut w, b"FPR ", &fpr)?;
+
+        let mut csrs = Vec::new();
+        for &(addr, value) in &self.csrs {
+            csrs.extend_from_slice(&addr.to_le_bytes());
+            csrs.extend_from_slice(&value.to_le_bytes());
+        }
+        write_section(&mut w, b"CSR ", &csrs)?;
+        write_section(&mut w, b"PRIV", &[self.privilege as u8, self.waiting as u8])?;
+
+        let mut layout = Vec::new();
+        layout.extend_from_slice(&self.dram.base().to_le_bytes());
+        layout.extend_from_slice(&self.dram.size().to_le_bytes());
+        write_section(&mut w, b"MEM ", &layout)?;
+
+        // Only allocated pages are stored
+        let mut dram = Vec::new();
+        for (index, page) in self.dram.pages() {
+            dram.extend_from_slice(&index.to_le_bytes());
+            dram.extend_from_slice(page);
+        }
+        write_section(&mut w, b"DRAM", &dram)?;
+
+        let mut devices = Vec::new();
+        for (name, state) in &self.devices {
+            devices.extend_from_slice(&(name.len() as u64).to_le_bytes());
+            devices.extend_from_slice(name.as_bytes());
+            devices.extend_from_slice(&(state.len() as u64).to_le_bytes());
+            devices.extend_from_slice(state);
+        }
+        write_section(&mut w, b"DEVS", &devices)?;
+
+        // each part a presence flag, then its fields
+        let parts = [
+            self.exit_code.map(|code| vec![code]),
+            self.htif.as_ref().map(|htif| {
+                let fromhost = htif.fromhost.unwrap_or(0);
+                vec![htif.tohost, htif.fromhost.is_some() as u64, fromhost]
+            }),
+            self.linux.map(|(start, brk, top)| vec![start, brk, top]),
+            self.rars.as_ref().map(|rars| vec![rars.heap()]),
+        ];
+        let mut run = Vec::new();
+        for part in parts {
+            run.push(part.is_some() as u8);
+            for field in part.unwrap_or_default() {
+                run.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        write_section(&mut w, b"RUN ", &run)?;
+
+        w.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Snapshot> {
+        let file = File::open(path)?;
+        // bytes not yet read, so section lengths can be checked before
+        // anything is allocated for them
+        let mut remaining = file.metadata()?.len();
+        let mut r = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a risque snapshot"));
+        }
+        let version = read_u32(&mut r)?;
+        if version > VERSION {
+            return Err(invalid("snapshot was written by a newer version"));
+        }
+        if version < VERSION {
+            return Err(invalid("snapshot was written by an older version"));
+        }
+        remaining = remaining.saturating_sub(12);
+
+        let mut snapshot = Snapshot {
+            pc: 0,
+            regs: [0; 32],
+            fregs: [0; 32],
+            csrs: Vec::new(),
+            privilege: Privilege::Machine,
+            waiting: false,
+            instret: 0,
+            dram: Dram::new(DEFAULT_DRAM_BASE, DEFAULT_DRAM_SIZE, Vec::new())
+                .map_err(|e| invalid(&e))?,
+            devices: Vec::new(),
+            exit_code: None,
+            htif: None,
+            linux: None,
+            rars: None,
+        };
+        let mut pages = Vec::new();
+
+        let mut tag = [0u8; 4];
+        loop {
+            match r.read_exact(&mut tag) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let len = read_u64(&mut r)?;
+            remaining = remaining.saturating_sub(12);
+            if len > remaining {
+                return Err(invalid("section runs past the end of the file"));
+            }
+            remaining -= len;
+            let mut body = vec![0u8; len as usize];
+            r.read_exact(&mut body)?;
+            let mut body = body.as_slice();
+
+            match &tag {
+                b"CPU " => {
+                    snapshot.pc = read_u64(&mut body)?;
+                    snapshot.instret = read_u64(&mut body)?;
+                    for reg in snapshot.regs.iter_mut() {
+                        *reg = read_u64(&mut body)?;
+                    }
+                }
+                b"FPR " => {
+                    for reg in snapshot.fregs.iter_mut() {
+                        *reg = read_u64(&mut body)?;
+                    }
+                }
+                b"CSR " => {
+                    while !body.is_empty() {
+                        let addr = read_u32(&mut body)?;
+                        let value = read_u64(&mut body)?;
+                        snapshot.csrs.push((addr, value));
+                    }
+                }
+                b"PRIV" => {
+                    let mut state = [0u8; 2];
+                    body.read_exact(&mut state)?;
+                    snapshot.privilege = Privilege::from_bits(state[0] as u64);
+                    snapshot.waiting = state[1] != 0;
+                }
+                b"MEM " => {
+                    let base = read_u64(&mut body)?;
+                    let size = read_u64(&mut body)?;
+                    snapshot.dram = Dram::new(base, size, Vec::new()).map_err(|e| invalid(&e))?;
+                }
+                b"DRAM" => {
+                    while !body.is_empty() {
+                        let index = read_u64(&mut body)?;
+                        let mut page = vec![0u8; DRAM_PAGE_SIZE as usize];
+                        body.read_exact(&mut page)?;
+                        pages.push((index, page));
+                    }
+                }
+                b"DEVS" => {
+                    while !body.is_empty() {
+                        let name = read_bytes(&mut body)?;
+                        let name = String::from_utf8(name)
+                            .map_err(|_| invalid("device name is not UTF-8"))?;
+                        let state = read_bytes(&mut body)?;
+                        snapshot.devices.push((name, state));
+                    }
+                }
+                b"RUN " => {
+                    let mut part = |len: usize| -> io::Result<Option<Vec<u64>>> {
+                        let mut present = [0u8];
+                        body.read_exact(&mut present)?;
+                        if present[0] == 0 {
+                            return Ok(None);
+                        }
+                        (0..len)
+                            .map(|_| read_u64(&mut body))
+                            .collect::<io::Result<_>>()
+                            .map(Some)
+                    };
+                    snapshot.exit_code = part(1)?.map(|f| f[0]);
+                    snapshot.htif = part(3)?.map(|f| Htif {
+                        tohost: f[0],
+                        fromhost: (f[1] != 0).then_some(f[2]),
+                    });
+                    snapshot.linux = part(3)?.map(|f| (f[0], f[1], f[2]));
+                    snapshot.rars = part(1)?.map(|f| Rars::with_heap(f[0]));
+                }
+                // Unknown sections are skipped
+                _ => {}
+            }
+        }
+
+        for (index, page) in pages {
+            if index >= snapshot.dram.size() / DRAM_PAGE_SIZE {
+                return Err(invalid("page lies outside DRAM"));
+            }
+            snapshot.dram.write_page(index, &page);
+        }
+        Ok(snapshot)
+    }
+}
+
+fn write_section(w: &mut impl Write, tag: &[u8; 4], body: &[u8]) -> io::Result<()> {
+    w.write_all(tag)?;
+    w.write_all(&(body.len() as u64).to_le_bytes())?;
+    w.write_all(body)
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes(r: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)? as usize;
+    if len > r.len() {
+        return Err(invalid("truncated section"));
+    }
+    let (bytes, rest) = r.split_at(len);
+    *r = rest;
+    Ok(bytes.to_vec())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        config::{MachineConfig, Personality},
+        debug::StopReason,
+    };
+
+    const DRAM_BASE: u64 = 0x8000_0000;
+
+    #[test]
+    fn test_file_roundtrip() {
+        let config = MachineConfig::builder()
+            .dram(DRAM_BASE, 0x10_0000)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(config.clone(), vec![0x13, 0x00, 0x00, 0x00]).unwrap();
+        cpu.pc = DRAM_BASE + 0x40;
+        cpu.instret = 9;
+        cpu.store(DRAM_BASE + 0x1000, 64, 0xdead_beef).unwrap();
+
+        let path = std::env::temp_dir().join("risque-snapshot-test.bin");
+        cpu.snapshot().save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut other = Cpu::new(config, Vec::new()).unwrap();
+        other.restore(&loaded).unwrap();
+        assert_eq!(other.pc, DRAM_BASE + 0x40);
+        assert_eq!(other.instret, 9);
+        assert_eq!(other.regs, cpu.regs);
+        assert_eq!(other.load(DRAM_BASE, 32).unwrap(), 0x13);
+        assert_eq!(other.load(DRAM_BASE + 0x1000, 64).unwrap(), 0xdead_beef);
+    }
+
+    #[test]
+    fn test_restore_runs_again_after_exit() {
+        let config = MachineConfig::builder()
+            .personality(Personality::Rars)
+            .build()
+            .unwrap();
+        let code = [
+            0x01000513, // li a0, 16
+            0x00900893, // li a7, 9 (Sbrk)
+            0x00000073, // ecall
+            0x00a00893, // li a7, 10 (Exit)
+            0x00000073, // ecall
+        ];
+        let mut cpu = Cpu::with_code(config, &code);
+        let boot = cpu.snapshot();
+        let path = std::env::temp_dir().join("risque-snapshot-exit.bin");
+        boot.save(&path).unwrap();
+        let saved = Snapshot::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(cpu.run_until(None, 20), StopReason::Exited(0));
+        let heap = cpu.regs[10];
+        for snapshot in [&boot, &saved] {
+            cpu.restore(snapshot).unwrap();
+            assert_eq!(cpu.exit_code, None);
+            assert_eq!(cpu.run_until(None, 20), StopReason::Exited(0));
+            assert_eq!((cpu.instret, cpu.regs[10]), (5, heap));
+        }
+    }
+
+    #[test]
+    fn test_new_machines_keep_a_boot_snapshot() {
+        let mut cpu = Cpu::new(MachineConfig::default(), vec![0x13, 0, 0, 0]).unwrap();
+        let boot = cpu.boot_snapshot.clone().unwrap();
+        let regs = cpu.regs;
+        cpu.regs[5] = !regs[5];
+        cpu.store(0x1000, 64, 0xdead_beef).unwrap();
+
+        cpu.restore(&boot).unwrap();
+        assert_eq!(cpu.regs, regs);
+        assert_eq!(cpu.load(0x1000, 64).unwrap(), 0);
+        assert_eq!(cpu.load(0, 32).unwrap(), 0x13);
+    }
+
+    #[test]
+    fn test_bad_lengths_and_versions_are_refused() {
+        let path = std::env::temp_dir().join("risque-snapshot-bad.bin");
+        let header = |version: u32| [&MAGIC[..], &version.to_le_bytes()].concat();
+
+        // a section claiming more bytes than the file holds
+        let huge = [
+            header(VERSION),
+            b"DRAM".to_vec(),
+            u64::MAX.to_le_bytes().to_vec(),
+        ]
+        .concat();
+        std::fs::write(&path, huge).unwrap();
+        assert!(Snapshot::load(&path).is_err());
+
+        std::fs::write(&path, header(VERSION - 1)).unwrap();
+        assert!(Snapshot::load(&path).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
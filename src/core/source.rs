@@ -0,0 +1,346 @@
+//! Source-level debugging from the DWARF information of the loaded
+//! executable: where pc is in the source, stepping a line at a time, the
+//! variables in scope and the call stack, unwound with the call frame
+//! information in `.debug_frame` or `.eh_frame`.
+//!
+//! Memory is read at physical addresses, so stack contents are only right
+//! while translation is off or identity mapped, as for bare-metal programs.
+
+use std::sync::Arc;
+
+use super::{
+    cpu::Cpu,
+    debug::StopReason,
+    dwarf::{self, LineTable, Location},
+    param::{ABINAME, BACKTRACE_DEPTH},
+};
+
+const SP: usize = 2;
+
+/// Where in the source pc is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: u64,
+    /// The enclosing function, when a symbol covers pc.
+    pub function: Option<String>,
+}
+
+/// A variable in scope at pc.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local {
+    pub name: String,
+    pub parameter: bool,
+    pub type_name: String,
+    /// Where the variable lives: `a0`, `fs1`, `sp+24`, `frame-20`, ...
+    pub location: String,
+    /// The address of a variable kept in memory.
+    pub address: Option<u64>,
+    /// The raw value, when it could be read.
+    pub value: Option<u64>,
+}
+
+/// One frame of the call stack, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFrame {
+    pub pc: u64,
+    pub sp: u64,
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u64>,
+}
+
+/// `x` and `f` registers by DWARF number.
+fn register_name(reg: u16) -> String {
+    match reg {
+        0..=31 => ABINAME[reg as usize].to_string(),
+        32..=63 => format!("f{}", reg - 32),
+        _ => format!("r{}", reg),
+    }
+}
+
+impl Cpu {
+    fn line_table(&self) -> Result<Arc<LineTable>, String> {
+        let elf = self.elf.as_ref().ok_or("no symbols are loaded")?;
+        let table = elf.lines()?;
+        if table.rows.is_empty() {
+            return Err("the program has no line information; compile it with -g".into());
+        }
+        Ok(table)
+    }
+
+    /// The source line pc is on.
+    pub fn source_line(&self) -> Result<SourceLine, String> {
+        let table = self.line_table()?;
+        let row = table
+            .find(self.pc)
+            .ok_or_else(|| format!("no source for 0x{:x}", self.pc))?;
+        Ok(SourceLine {
+            file: row.file.clone(),
+            line: row.line,
+            function: self.symbol_at(self.pc),
+        })
+    }
+
+    pub(super) fn symbol_at(&self, addr: u64) -> Option<String> {
+        let elf = self.elf.as_ref()?;
+        elf.function_at(addr).map(|symbol| symbol.name.clone())
+    }
+
+    /// The address of symbol `name` in the loaded executable.
+    pub fn symbol_address(&self, name: &str) -> Option<u64> {
+        let elf = self.elf.as_ref()?;
+        elf.symbol(name).map(|symbol| symbol.addr)
+    }
+
+    /// Run until pc reaches the first statement of another source line,
+    /// stepping into calls that have line information and through those
+    /// that have none.
+    pub fn step_line(&mut self, budget: u64) -> Result<StopReason, String> {
+        let table = self.line_table()?;
+        let start = table.find(self.pc).map(|row| (row.file.clone(), row.line));
+        for _ in 0..budget {
+            if let Err(e) = self.step() {
+                return Ok(StopReason::Exception(e));
+            }
+            if let Some(reason) = self.halted() {
+                return Ok(reason);
+            }
+            let index = table.rows.partition_point(|row| row.addr < self.pc);
+            if let Some(row) = table.rows.get(index).filter(|row| row.addr == self.pc) {
+                if start.as_ref() != Some(&(row.file.clone(), row.line)) {
+                    return Ok(StopReason::Line(row.file.clone(), row.line));
+                }
+            }
+            if self.breakpoints.contains(&self.pc) {
+                return Ok(StopReason::Breakpoint(self.pc));
+            }
+        }
+        Ok(StopReason::Budget(budget))
+    }
+
+    /// The parameters and local variables in scope at pc, outer blocks
+    /// first.
+    pub fn locals(&self) -> Result<Vec<Local>, String> {
+        let elf = self.elf.as_ref().ok_or("no symbols are loaded")?;
+        let scope = dwarf::scope_at(elf, self.pc)?
+            .ok_or_else(|| format!("no debugging information for 0x{:x}", self.pc))?;
+        let reg = |reg: u16| match reg {
+            0..=31 => Some(self.regs[reg as usize]),
+            32..=63 => Some(self.fregs[reg as usize - 32]),
+            _ => None,
+        };
+        let frame_base = match scope.frame_base {
+            Location::Register(r) => reg(r),
+            Location::RegisterOffset(r, offset) => reg(r).map(|v| v.wrapping_add_signed(offset)),
+            Location::Cfa => match dwarf::frame_rule(elf, self.pc)? {
+                Some(rule) => reg(rule.cfa.0).map(|v| v.wrapping_add_signed(rule.cfa.1)),
+                None => None,
+            },
+            _ => None,
+        };
+
+        let mut locals = Vec::new();
+        for variable in scope.variables {
+            let (location, address) = match variable.location {
+                Location::Register(r) => (register_name(r), None),
+                Location::FrameOffset(offset) => (
+                    format!("frame{:+}", offset),
+                    frame_base.map(|base| base.wrapping_add_signed(offset)),
+                ),
+                Location::RegisterOffset(r, offset) => (
+                    format!("{}{:+}", register_name(r), offset),
+                    reg(r).map(|v| v.wrapping_add_signed(offset)),
+                ),
+                Location::Address(addr) => (format!("0x{:x}", addr), Some(addr)),
+                Location::Cfa | Location::Unknown => ("optimized out".into(), None),
+            };
+            let value = match (&variable.location, address, variable.size) {
+                (Location::Register(r), _, _) => reg(*r),
+                (_, Some(addr), Some(size @ (1 | 2 | 4 | 8))) => self.bus.peek(addr, size * 8).ok(),
+                _ => None,
+            };
+            locals.push(Local {
+                name: variable.name,
+                parameter: variable.parameter,
+                type_name: variable.type_name,
+                location,
+                address,
+                value,
+            });
+        }
+        Ok(locals)
+    }
+
+    /// The call stack, innermost frame first, as far as the call frame
+    /// information and the stack allow it to be unwound.
+    pub fn source_backtrace(&self) -> Result<Vec<SourceFrame>, String> {
+        let elf = self.elf.as_ref().ok_or("no symbols are loaded")?;
+        let table = elf.lines()?;
+        let (mut pc, mut regs) = (self.pc, self.regs);
+        let mut frames = Vec::new();
+        while frames.len() < BACKTRACE_DEPTH {
+            // a return address follows the call, which may end the function
+            let lookup = if frames.is_empty() { pc } else { pc - 1 };
+            let row = table.find(lookup);
+            frames.push(SourceFrame {
+                pc,
+                sp: regs[SP],
+                function: self.symbol_at(lookup),
+                file: row.map(|row| row.file.clone()),
+                line: row.map(|row| row.line),
+            });
+
+            let Some(rule) = dwarf::frame_rule(elf, lookup)? else {
+                break;
+            };
+            if rule.outermost || rule.cfa.0 > 31 || rule.ra > 31 {
+                break;
+            }
+            let cfa = regs[rule.cfa.0 as usize].wrapping_add_signed(rule.cfa.1);
+            let mut caller = regs;
+            for &(reg, offset) in rule.saved.iter().filter(|&&(reg, _)| reg < 32) {
+                match self.bus.peek(cfa.wrapping_add_signed(offset), 64) {
+                    Ok(value) => caller[reg as usize] = value,
+                    Err(_) => return Ok(frames),
+                }
+            }
+            caller[SP] = cfa;
+            let ra = caller[rule.ra as usize];
+            // an unchanged frame would repeat forever
+            if ra == 0 || (ra == pc && cfa == regs[SP]) {
+                break;
+            }
+            (pc, regs) = (ra, caller);
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{
+        config::MachineConfig,
+        cpu::Cpu,
+        debug::StopReason,
+        elf::{Elf, Section, Symbol},
+    };
+
+    /// `tests/data/add-g.elf`, the program described in the DWARF tests.
+    fn fixture() -> Cpu {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/add-g.elf");
+        let config = MachineConfig::virt().program(path).build().unwrap();
+        let mut cpu = Cpu::new(config, Vec::new()).unwrap();
+        cpu.trace = false;
+        cpu
+    }
+
+    fn step_line(cpu: &mut Cpu) -> u64 {
+        match cpu.step_line(100).unwrap() {
+            StopReason::Line(file, line) if file == "/src/fixture.c" => line,
+            reason => panic!("{}", reason),
+        }
+    }
+
+    #[test]
+    fn test_step_line_enters_calls() {
+        let mut cpu = fixture();
+        let line = cpu.source_line().unwrap();
+        assert_eq!((line.line, line.function.as_deref()), (10, Some("_start")));
+
+        assert_eq!(step_line(&mut cpu), 11);
+        assert_eq!(step_line(&mut cpu), 4);
+        assert_eq!(cpu.source_line().unwrap().function.as_deref(), Some("add"));
+        for line in [5, 6, 7, 11] {
+            assert_eq!(step_line(&mut cpu), line);
+        }
+        // the store to tohost ends the run before line 12
+        assert_eq!(cpu.step_line(100).unwrap(), StopReason::Exited(0));
+    }
+
+    #[test]
+    fn test_locals_read_registers_and_frame() {
+        let mut cpu = fixture();
+        while cpu.source_line().unwrap().line != 6 {
+            step_line(&mut cpu);
+        }
+        let locals: Vec<_> = cpu
+            .locals()
+            .unwrap()
+            .into_iter()
+            .map(|local| (local.name, local.location, local.address, local.value))
+            .collect();
+        let sp = 0x8001_0000;
+        assert_eq!(
+            locals,
+            [
+                ("a".to_string(), "a0".to_string(), None, Some(3)),
+                ("b".to_string(), "a1".to_string(), None, Some(4)),
+                (
+                    "sum".to_string(),
+                    "frame-24".to_string(),
+                    Some(sp - 24),
+                    Some(7)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backtrace_unwinds_saved_ra() {
+        let mut frame = vec![
+            12, 0, 0, 0, // CIE length
+            0xff, 0xff, 0xff, 0xff, // CIE id
+            1, 0, // version, no augmentation
+            1, 0x78, 1, // code align 1, data align -8, ra is x1
+            0x0c, 2, 0, // cfa = sp
+            28, 0, 0, 0, // FDE length
+            0, 0, 0, 0, // its CIE
+        ];
+        frame.extend(0x100u64.to_le_bytes());
+        frame.extend(0x10u64.to_le_bytes());
+        frame.extend([
+            0x44, // from 0x104
+            0x0e, 16, // cfa = sp + 16
+            0x81, 1, // ra saved at cfa - 8
+            0, 0, 0, // padding
+        ]);
+        let mut cpu = Cpu::new(MachineConfig::default(), vec![0; 0x200]).unwrap();
+        cpu.elf = Some(Elf {
+            entry: 0,
+            phdr: 0,
+            phnum: 0,
+            segments: Vec::new(),
+            symbols: vec![
+                Symbol {
+                    name: "main".into(),
+                    addr: 0,
+                    size: 0x100,
+                    func: true,
+                },
+                Symbol {
+                    name: "f".into(),
+                    addr: 0x100,
+                    size: 0x10,
+                    func: true,
+                },
+            ],
+            sections: vec![Section {
+                name: ".debug_frame".into(),
+                addr: 0,
+                data: frame,
+            }],
+            lines: Default::default(),
+        });
+        // f has stored the return address into main on its stack
+        cpu.pc = 0x108;
+        cpu.regs[2] = 0x1000;
+        cpu.bus.store(0x1008, 64, 0x20).unwrap();
+
+        let frames = cpu.source_backtrace().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].function.as_deref(), Some("f"));
+        assert_eq!((frames[1].pc, frames[1].sp), (0x20, 0x1010));
+        assert_eq!(frames[1].function.as_deref(), Some("main"));
+    }
+}
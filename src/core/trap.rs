@@ -0,0 +1,234 @@
+use super::{
+    cpu::Cpu,
+    csr::{self, MEIP, MSIP, MTIP, SEIP, SSIP, STIP},
+};
+
+/// Bit set in `xcause` for interrupts.
+pub const INTERRUPT: u64 = 1 << 63;
+
+// Interrupts in the order they are taken when several are pending
+const PRIORITY: [u64; 6] = [MEIP, MSIP, MTIP, SEIP, SSIP, STIP];
+
+/// Privilege level the hart is executing at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decode an `MPP`/`SPP` field; the reserved value 2 reads as User.
+    pub fn from_bits(bits: u64) -> Self {
+        match bits & 3 {
+            3 => Privilege::Machine,
+            1 => Privilege::Supervisor,
+            _ => Privilege::User,
+        }
+    }
+}
+
+impl Cpu {
+    /// The interrupt to take before the next instruction, as an `xcause` value.
+    ///
+    /// M-mode interrupts are enabled below M or with `mstatus.MIE` set in M;
+    /// delegated ones below S or with `mstatus.SIE` set in S, and never in M.
+    pub fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.csr.read(csr::MIP) & self.csr.read(csr::MIE);
+        if pending == 0 {
+            return None;
+        }
+        let mstatus = self.csr.read(csr::MSTATUS);
+        let mideleg = self.csr.read(csr::MIDELEG);
+
+        let m_enabled = self.privilege < Privilege::Machine || mstatus & csr::MSTATUS_MIE != 0;
+        let s_enabled = self.privilege < Privilege::Supervisor
+            || self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_SIE != 0;
+
+        let mut enabled = 0;
+        if m_enabled {
+            enabled |= pending & !mideleg;
+        }
+        if s_enabled {
+            enabled |= pending & mideleg;
+        }
+
+        // Machine-level interrupts outrank supervisor ones
+        let machine = enabled & !mideleg;
+        let candidates = if machine != 0 { machine } else { enabled };
+        PRIORITY
+            .iter()
+            .find(|&&bit| candidates & bit != 0)
+            .map(|bit| INTERRUPT | bit.trailing_zeros() as u64)
+    }
+
+    /// Whether a trap for `cause` is delegated to S-mode by
+    /// `mideleg`/`medeleg`. Traps taken in M-mode never are.
+    fn delegated(&self, cause: u64) -> bool {
+        let deleg = if cause & INTERRUPT != 0 {
+            self.csr.read(csr::MIDELEG)
+        } else {
+            self.csr.read(csr::MEDELEG)
+        };
+        self.privilege <= Privilege::Supervisor && deleg >> (cause & !INTERRUPT) & 1 != 0
+    }
+
+    /// Whether software has installed a handler that would receive `cause`.
+    /// Without one a trap would only loop, so the fault is reported instead.
+    pub fn has_handler(&self, cause: u64) -> bool {
+        let tvec = if self.delegated(cause) {
+            self.csr.read(csr::STVEC)
+        } else {
+            self.csr.read(csr::MTVEC)
+        };
+        tvec != 0
+    }
+
+    /// Enter the trap handler for `cause` with the current pc as the return
+    /// address, honouring `mideleg`/`medeleg` delegation to S-mode.
+    pub fn trap(&mut self, cause: u64, tval: u64) {
+        let mstatus = self.csr.read(csr::MSTATUS);
+
+        if self.delegated(cause) {
+            self.write_csr(csr::SEPC, self.pc);
+            self.write_csr(csr::SCAUSE, cause);
+            self.write_csr(csr::STVAL, tval);
+
+            let mut status = mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP);
+            if mstatus & csr::MSTATUS_SIE != 0 {
+                status |= csr::MSTATUS_SPIE;
+            }
+            if self.privilege == Privilege::Supervisor {
+                status |= csr::MSTATUS_SPP;
+            }
+            self.write_csr(csr::MSTATUS, status);
+
+            self.pc = vector(self.csr.read(csr::STVEC), cause);
+            self.set_privilege(Privilege::Supervisor);
+        } else {
+            self.write_csr(csr::MEPC, self.pc);
+            self.write_csr(csr::MCAUSE, cause);
+            self.write_csr(csr::MTVAL, tval);
+
+            let mut status = mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE | csr::MSTATUS_MPP);
+            if mstatus & csr::MSTATUS_MIE != 0 {
+                status |= csr::MSTATUS_MPIE;
+            }
+            status |= (self.privilege as u64) << 11;
+            self.write_csr(csr::MSTATUS, status);
+
+            self.pc = vector(self.csr.read(csr::MTVEC), cause);
+            self.set_privilege(Privilege::Machine);
+        }
+        self.waiting = false;
+        self.reservation = None;
+    }
+}
+
+/// Handler address for `cause` given an `xtvec` value.
+fn vector(tvec: u64, cause: u64) -> u64 {
+    let base = tvec & !3;
+    if tvec & 3 == 1 && cause & INTERRUPT != 0 {
+        base + 4 * (cause & !INTERRUPT)
+    } else {
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        config::{DeviceConfig, MachineConfig},
+        device::clint::ClockSource,
+        param::DEFAULT_CLINT_BASE,
+    };
+
+    fn cpu() -> Cpu {
+        let mut cpu = Cpu::new(MachineConfig::default(), Vec::new()).unwrap();
+        cpu.csr.write(csr::MTVEC, 0x1000);
+        cpu.csr.write(csr::STVEC, 0x2001);
+        cpu.csr.write(csr::MIE, MTIP | STIP | SEIP);
+        cpu.pc = 0x400;
+        cpu
+    }
+
+    #[test]
+    fn test_machine_interrupts_need_mie_in_m_mode() {
+        let mut cpu = cpu();
+        cpu.csr.lines = MTIP;
+        assert_eq!(cpu.pending_interrupt(), None);
+
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        let cause = cpu.pending_interrupt().unwrap();
+        assert_eq!(cause, INTERRUPT | 7);
+
+        cpu.trap(cause, 0);
+        assert_eq!(cpu.pc, 0x1000);
+        assert_eq!(cpu.csr.read(csr::MEPC), 0x400);
+        assert_eq!(
+            cpu.csr.read(csr::MSTATUS),
+            csr::MSTATUS_MPIE | csr::MSTATUS_MPP | csr::MSTATUS_XLEN
+        );
+        assert_eq!(cpu.pending_interrupt(), None);
+    }
+
+    #[test]
+    fn test_delegated_interrupt_vectors_to_s_mode() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MIDELEG, STIP | SEIP);
+        cpu.privilege = Privilege::User;
+        cpu.csr.lines = SEIP | STIP;
+
+        // external beats timer at the same level
+        let cause = cpu.pending_interrupt().unwrap();
+        assert_eq!(cause, INTERRUPT | 9);
+
+        cpu.trap(cause, 0);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.pc, 0x2000 + 4 * 9);
+        assert_eq!(cpu.csr.read(csr::SEPC), 0x400);
+        // SIE is clear in S-mode, so nothing further is taken
+        assert_eq!(cpu.pending_interrupt(), None);
+    }
+
+    #[test]
+    fn test_wfi_idles_until_timer_interrupt() {
+        let config = MachineConfig::builder()
+            .dram(0x8000_0000, 0x1000)
+            .reset_vector(0x8000_0000)
+            .devices(&[DeviceConfig::Clint {
+                base: DEFAULT_CLINT_BASE,
+                clock: ClockSource::Instret,
+            }])
+            .build()
+            .unwrap();
+        // wfi at the reset vector, a nop as the handler at 0x100
+        let mut code = vec![0u8; 0x104];
+        code[..4].copy_from_slice(&0x10500073u32.to_le_bytes());
+        code[0x100..].copy_from_slice(&0x13u32.to_le_bytes());
+        let mut cpu = Cpu::new(config, code).unwrap();
+
+        cpu.csr.write(csr::MTVEC, 0x8000_0100);
+        cpu.csr.write(csr::MIE, MTIP);
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        cpu.bus.store(DEFAULT_CLINT_BASE + 0x4000, 64, 5).unwrap();
+
+        cpu.step().unwrap();
+        assert!(cpu.waiting);
+        while cpu.waiting {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, 0x8000_0104);
+        assert_eq!(cpu.instret, 2);
+        assert_eq!(cpu.csr.read(csr::MEPC), 0x8000_0004);
+
+        // stepping back undoes the trap entry along with the handler's first instruction
+        cpu.step_back();
+        assert_eq!(cpu.pc, 0x8000_0004);
+        assert_eq!(
+            cpu.csr.read(csr::MSTATUS),
+            csr::MSTATUS_MIE | csr::MSTATUS_XLEN
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::kit::insn::*;
+use crate::vdepart;
+
+use super::{
+    cpu::Cpu,
+    csr,
+    except::Exception,
+    isa::{install, IsaDefine},
+    trap::Privilege,
+};
+
+impl Cpu {
+    /// Whether the current privilege level may access `addr`.
+    ///
+    /// Bits 9:8 of the address give the lowest privilege allowed and bits
+    /// 11:10 = 3 mark it read-only. Counters below M-mode are further gated
+    /// by `mcounteren`/`scounteren`, `satp` in S-mode by `mstatus.TVM`, and
+    /// the FP CSRs by `mstatus.FS`.
+    fn check_csr(&self, insn: u32, addr: u32, write: bool) -> Result<(), Exception> {
+        let illegal = Err(Exception::IllegalInstruction(insn));
+        if !self.csr.exists(addr)
+            || (self.privilege as u32) < (addr >> 8 & 3)
+            || (write && addr >> 10 == 3)
+        {
+            return illegal;
+        }
+
+        let mstatus = self.csr.read(csr::MSTATUS);
+        match addr {
+            csr::FFLAGS | csr::FRM | csr::FCSR if mstatus & csr::MSTATUS_FS == 0 => illegal,
+            csr::SATP
+                if self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_TVM != 0 =>
+            {
+                illegal
+            }
+            csr::CYCLE..=csr::HPMCOUNTER31 => {
+                let bit = 1 << (addr - csr::CYCLE);
+                let denied = (self.privilege < Privilege::Machine
+                    && self.csr.read(csr::MCOUNTEREN) & bit == 0)
+                    || (self.privilege == Privilege::User
+                        && self.csr.read(csr::SCOUNTEREN) & bit == 0);
+                if denied {
+                    illegal
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check, read and (when `write`) update the CSR named by `insn`, returning
+/// the old value.
+fn access(
+    cpu: &mut Cpu,
+    insn: u32,
+    write: bool,
+    update: impl FnOnce(u64) -> u64,
+) -> Result<u64, Exception> {
+    let addr = insn >> 20;
+    cpu.check_csr(insn, addr, write)?;
+    let old = cpu.read_csr(addr);
+    if write {
+        cpu.write_csr(addr, update(old));
+        if matches!(addr, csr::FFLAGS | csr::FRM | csr::FCSR) {
+            cpu.fp_dirty();
+        }
+    }
+    Ok(old)
+}
+
+fn csrrw() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            let src = cpu.rgpr(i.rs1);
+            *cpu.wgpr(i.rd) = access(cpu, insn, true, |_| src)?;
+            Ok(0)
+        })),
+        "csrrw",
+        0x1073,
+        InsnType::I,
+    )
+}
+
+fn csrrs() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            let src = cpu.rgpr(i.rs1);
+            *cpu.wgpr(i.rd) = access(cpu, insn, i.rs1 != 0, |t| t | src)?;
+            Ok(0)
+        })),
+        "csrrs",
+        0x2073,
+        InsnType::I,
+    )
+}
+
+fn csrrc() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            let src = cpu.rgpr(i.rs1);
+            *cpu.wgpr(i.rd) = access(cpu, insn, i.rs1 != 0, |t| t & !src)?;
+            Ok(0)
+        })),
+        "csrrc",
+        0x3073,
+        InsnType::I,
+    )
+}
+
+fn csrrwi() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            *cpu.wgpr(i.rd) = access(cpu, insn, true, |_| i.rs1 as u64)?;
+            Ok(0)
+        })),
+        "csrrwi",
+        0x5073,
+        InsnType::I,
+    )
+}
+
+fn csrrsi() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            *cpu.wgpr(i.rd) = access(cpu, insn, i.rs1 != 0, |t| t | i.rs1 as u64)?;
+            Ok(0)
+        })),
+        "csrrsi",
+        0x6073,
+        InsnType::I,
+    )
+}
+
+fn csrrci() -> IsaDefine {
+    IsaDefine::new(
+        Arc::new(Box::new(|cpu, insn| {
+            let i = vdepart!(insn, InsnType::I);
+            *cpu.wgpr(i.rd) = access(cpu, insn, i.rs1 != 0, |t| t & !(i.rs1 as u64))?;
+            Ok(0)
+        })),
+        "csrrci",
+        0x7073,
+        InsnType::I,
+    )
+}
+
+pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
+    install(map, csrrw());
+    install(map, csrrs());
+    install(map, csrrc());
+    install(map, csrrwi());
+    install(map, csrrsi());
+    install(map, csrrci());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::MachineConfig;
+
+    const A0: usize = 10;
+    const A2: usize = 12;
+
+    #[test]
+    fn test_reads_and_writes() {
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &[]);
+        cpu.write_csr(csr::MSCRATCH, 0xf0);
+        cpu.regs[A0] = 0x0f;
+
+        // csrrs a2, mscratch, x0 only reads
+        cpu.execute(0x34002673).unwrap();
+        assert_eq!(cpu.regs[A2], 0xf0);
+        assert_eq!(cpu.read_csr(csr::MSCRATCH), 0xf0);
+        // csrrw a2, mscratch, a0
+        cpu.execute(0x34051673).unwrap();
+        assert_eq!((cpu.regs[A2], cpu.read_csr(csr::MSCRATCH)), (0xf0, 0x0f));
+        // csrrci a2, mscratch, 3
+        cpu.execute(0x3401f673).unwrap();
+        assert_eq!((cpu.regs[A2], cpu.read_csr(csr::MSCRATCH)), (0x0f, 0x0c));
+    }
+
+    #[test]
+    fn test_access_checks() {
+        let mut cpu = Cpu::with_code(MachineConfig::default(), &[]);
+        // cycle is read-only: csrrs a2, cycle, x0 is fine, csrrw x0, cycle, a0 is not
+        assert!(cpu.execute(0xc0002673).is_ok());
+        assert_eq!(
+            cpu.execute(0xc0051073),
+            Err(Exception::IllegalInstruction(0xc0051073))
+        );
+
+        // mscratch is out of reach below M-mode
+        cpu.set_privilege(Privilege::User);
+        assert_eq!(
+            cpu.execute(0x34002673),
+            Err(Exception::IllegalInstruction(0x34002673))
+        );
+    }
+}
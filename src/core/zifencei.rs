@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::kit::insn::*;
+
+use super::isa::{install, IsaDefine};
+
+fn fence_i() -> IsaDefine {
+    IsaDefine::new(
+        // instructions are fetched from memory every time, so nothing is stale
+        Arc::new(Box::new(|_cpu, _insn| Ok(0))),
+        "fence.i",
+        0x100f,
+        InsnType::I,
+    )
+}
+
+pub fn register_ext(map: &mut HashMap<u32, Vec<IsaDefine>>) {
+    install(map, fence_i());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{
+        config::{Extension, MachineConfig},
+        cpu::Cpu,
+        except::Exception,
+    };
+
+    #[test]
+    fn test_fence_i_needs_the_extension() {
+        let config = |extensions: &[Extension]| {
+            MachineConfig::builder()
+                .extensions(extensions)
+                .build()
+                .unwrap()
+        };
+        let mut cpu = Cpu::with_code(config(&[Extension::I]), &[]);
+        assert_eq!(
+            cpu.execute(0x100f),
+            Err(Exception::IllegalInstruction(0x100f))
+        );
+        let mut cpu = Cpu::with_code(config(&[Extension::I, Extension::Zifencei]), &[]);
+        assert_eq!(cpu.execute(0x100f), Ok(4));
+    }
+}
@@ -1,6 +1,7 @@
 #[derive(Clone, PartialEq, Eq)]
 pub enum InsnType {
     R,
+    R4,
     I,
     S,
     B,
@@ -18,6 +19,17 @@ pub struct RType {
     pub funct7: u32,
 }
 
+#[derive(Debug)]
+pub struct R4Type {
+    pub opcode: u32,
+    pub rd: u32,
+    pub funct3: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub funct2: u32,
+    pub rs3: u32,
+}
+
 #[derive(Debug)]
 pub struct IType {
     pub opcode: u32,
@@ -71,6 +83,17 @@ macro_rules! vdepart {
             funct7: ($inst >> 25) & 0x7f,
         }
     }};
+    ($inst:expr, InsnType::R4) => {{
+        R4Type {
+            opcode:  $inst        & 0x7f,
+            rd:     ($inst >> 7)  & 0x1f,
+            funct3: ($inst >> 12) & 0x7,
+            rs1:    ($inst >> 15) & 0x1f,
+            rs2:    ($inst >> 20) & 0x1f,
+            funct2: ($inst >> 25) & 0x3,
+            rs3:    ($inst >> 27) & 0x1f,
+        }
+    }};
     ($inst:expr, InsnType::I) => {{
         IType {
             opcode:  $inst        & 0x7f,
@@ -6,4 +6,9 @@ pub mod platform;
 mod shell;
 
 pub use api::App;
-pub use core::Cpu;
+pub use core::device::{Device, PacketQueue};
+pub use core::{
+    BootConfig, BootImage, DeviceConfig, Exception, Extension, LinuxProgram, MachineConfig,
+    Personality, Privilege,
+};
+pub use core::{Cpu, StopReason};
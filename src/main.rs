@@ -1,6 +1,103 @@
-use risque::App;
+use std::{fs, path::Path, process};
+
+use risque::{App, Cpu, MachineConfig, StopReason};
+
+// Instructions a riscv-arch-test program may take to reach its HTIF exit
+const ARCH_TEST_BUDGET: u64 = 100_000_000;
+
+// Instructions a program may run in lockstep with its reference
+const LOCKSTEP_BUDGET: u64 = 100_000_000;
 
 #[tokio::main]
 async fn main() {
-    App::run().await
+    let args: Vec<String> = std::env::args().collect();
+
+    // `risque --signature <file> <elf>` runs a riscv-arch-test program and
+    // writes its signature, for use as a RISCOF target
+    if args.len() == 4 && args[1] == "--signature" {
+        process::exit(arch_test(&args[3], &args[2]));
+    }
+
+    // `risque --lockstep <trace> <elf>` runs a bare-metal program against a
+    // reference commit log, e.g. from `spike --log-commits`
+    if args.len() == 4 && args[1] == "--lockstep" {
+        process::exit(lockstep(&args[3], &args[2]));
+    }
+
+    // An optional machine description may be passed as the first argument
+    if let Err(e) = serve(args.get(1)).await {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Serve the API, for the machine described in the file at `path` if one
+/// is given.
+async fn serve(path: Option<&String>) -> Result<(), String> {
+    let config = match path {
+        Some(path) => MachineConfig::from_file(path)?,
+        None => MachineConfig::default(),
+    };
+    App::run(config).await
+}
+
+/// A virt machine running the bare-metal `program`.
+fn machine(program: &str) -> Result<Cpu, String> {
+    let config = MachineConfig::virt().program(program).build()?;
+    let mut cpu = Cpu::new(config, Vec::new())?;
+    cpu.trace = false;
+    Ok(cpu)
+}
+
+fn arch_test(program: &str, signature: &str) -> i32 {
+    let mut cpu = match machine(program) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("{}: {}", program, e);
+            return 1;
+        }
+    };
+
+    let reason = cpu.run_until(None, ARCH_TEST_BUDGET);
+    if !matches!(reason, StopReason::Exited(_)) {
+        eprintln!("{}: {}", program, reason);
+        return 1;
+    }
+    match cpu
+        .signature()
+        .and_then(|s| fs::write(signature, s).map_err(|e| e.to_string()))
+    {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", program, e);
+            1
+        }
+    }
+}
+
+fn lockstep(program: &str, reference: &str) -> i32 {
+    let mut cpu = match machine(program) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("{}: {}", program, e);
+            return 1;
+        }
+    };
+    if let Err(e) = cpu.start_lockstep(Path::new(reference)) {
+        eprintln!("{}", e);
+        return 1;
+    }
+
+    let reason = cpu.run_until(None, LOCKSTEP_BUDGET);
+    let matched = cpu.lockstep.as_ref().map_or(0, |l| l.matched());
+    match reason {
+        StopReason::Exited(_) => {
+            println!("{}: {} instructions match the reference.", program, matched);
+            0
+        }
+        reason => {
+            eprintln!("{}: {}", program, reason);
+            1
+        }
+    }
 }
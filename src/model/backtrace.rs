@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FrameResponse {
+    pub pc: u64,
+    pub symbol: Option<String>,
+    pub sp: u64,
+    pub return_address: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MismatchResponse {
+    /// Where the `ret` was.
+    pub pc: u64,
+    pub expected: Option<u64>,
+    pub actual: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BacktraceResponse {
+    /// Innermost frame first.
+    pub frames: Vec<FrameResponse>,
+    /// The latest returns that missed their caller, oldest first.
+    pub mismatches: Vec<MismatchResponse>,
+    /// Set once stepping back has emptied the call stack; outer frames may
+    /// be missing.
+    pub stale: bool,
+}
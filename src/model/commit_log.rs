@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitLogStartPayload {
+    /// A file under the server's API files directory.
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitLogPayload {
+    pub offset: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitLogResponse {
+    pub offset: u64,
+    pub lines: Vec<String>,
+}
+
+impl CommitLogResponse {
+    pub fn new(offset: u64, lines: Vec<String>) -> Self {
+        Self { offset, lines }
+    }
+}
@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConsoleOutputPayload {
+    pub offset: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConsoleOutputResponse {
+    pub offset: u64,
+    pub data: String,
+}
+
+impl ConsoleOutputResponse {
+    pub fn new(offset: u64, data: String) -> Self {
+        Self { offset, data }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConsoleInputPayload {
+    pub data: String,
+}
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FunctionCoverageResponse {
+    pub name: String,
+    pub file: String,
+    pub line: u64,
+    pub calls: u64,
+    pub lines: u64,
+    pub hit: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CoverageResponse {
+    /// The tracefile, in lcov's `.info` format.
+    pub lcov: String,
+    pub functions: Vec<FunctionCoverageResponse>,
+}
+
+impl CoverageResponse {
+    pub fn new(lcov: String, functions: Vec<FunctionCoverageResponse>) -> Self {
+        Self { lcov, functions }
+    }
+}
@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LockstepPayload {
+    /// A file under the server's API files directory.
+    pub path: String,
+}
@@ -17,3 +17,28 @@ impl MemoryValueResponse {
         Self { address, word }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MemoryUsageResponse {
+    pub size: u64,
+    pub resident: u64,
+}
+
+impl MemoryUsageResponse {
+    pub fn new(size: u64, resident: u64) -> Self {
+        Self { size, resident }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MemoryRegionResponse {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+}
+
+impl MemoryRegionResponse {
+    pub fn new(name: String, base: u64, size: u64) -> Self {
+        Self { name, base, size }
+    }
+}
@@ -1,10 +1,41 @@
+mod backtrace;
+mod commit_log;
+mod console;
+mod coverage;
 mod file;
+mod lockstep;
 mod memory;
+mod profile;
 mod register;
+mod snapshot;
+mod source;
 mod step;
 
+pub use backtrace::BacktraceResponse;
+pub use backtrace::FrameResponse;
+pub use backtrace::MismatchResponse;
+pub use commit_log::CommitLogPayload;
+pub use commit_log::CommitLogResponse;
+pub use commit_log::CommitLogStartPayload;
+pub use console::ConsoleInputPayload;
+pub use console::ConsoleOutputPayload;
+pub use console::ConsoleOutputResponse;
+pub use coverage::CoverageResponse;
+pub use coverage::FunctionCoverageResponse;
 pub use file::FileResponse;
+pub use lockstep::LockstepPayload;
 pub use memory::MemoryRangePayload;
+pub use memory::MemoryRegionResponse;
+pub use memory::MemoryUsageResponse;
 pub use memory::MemoryValueResponse;
+pub use profile::ProfilePayload;
 pub use register::RegisterValueResponse;
+pub use snapshot::SnapshotPayload;
+pub use source::LocalResponse;
+pub use source::SourceFrameResponse;
+pub use source::SourceLineResponse;
+pub use step::BreakpointsPayload;
+pub use step::GotoPayload;
+pub use step::RunUntilPayload;
+pub use step::StepCountPayload;
 pub use step::StepResponse;
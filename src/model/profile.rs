@@ -0,0 +1,9 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfilePayload {
+    /// `flat`, `annotate` or `folded`.
+    pub format: String,
+    /// The function to annotate.
+    pub function: Option<String>,
+}
@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotPayload {
+    pub name: String,
+    /// A file under the server's API files directory.
+    pub path: Option<String>,
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SourceLineResponse {
+    pub pc: u64,
+    pub file: String,
+    pub line: u64,
+    pub function: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocalResponse {
+    pub name: String,
+    pub parameter: bool,
+    #[serde(rename = "type")]
+    pub type_name: String,
+    /// A register such as `a0`, or an offset such as `sp+24` or `frame-20`.
+    pub location: String,
+    pub address: Option<u64>,
+    pub value: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SourceFrameResponse {
+    pub pc: u64,
+    pub sp: u64,
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u64>,
+}
@@ -12,3 +12,25 @@ impl StepResponse {
         Self { pc, insn, message }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StepCountPayload {
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunUntilPayload {
+    pub address: Option<u64>,
+    pub symbol: Option<String>,
+    pub budget: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BreakpointsPayload {
+    pub addresses: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GotoPayload {
+    pub instret: u64,
+}
@@ -5,7 +5,7 @@ use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use crate::model::FileResponse;
-use crate::Cpu;
+use crate::{Cpu, MachineConfig};
 
 pub fn compile(payload: Vec<FileResponse>) -> String {
     // Create temporary directory
@@ -80,7 +80,10 @@ pub fn decompile() -> String {
     let mut file = FsFile::open("/tmp/risque-temp/payload.bin").unwrap();
     let mut code = Vec::new();
     file.read_to_end(&mut code).unwrap();
-    let mut cpu = Cpu::new(code.clone());
+    let mut cpu = match Cpu::new(MachineConfig::default(), code.clone()) {
+        Ok(cpu) => cpu,
+        Err(e) => return e,
+    };
 
     let mut ret = String::new();
 
@@ -0,0 +1,123 @@
+//! Runs of the external conformance suites. Neither is part of the tree, so
+//! these are ignored by default; point the environment variables at local
+//! builds and run `cargo test --release --test conformance -- --ignored`.
+//! Two hand-assembled programs in `tests/data` keep the path from an ELF
+//! file on disk to an HTIF exit covered in every run.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glob::glob;
+use risque::{Cpu, MachineConfig, StopReason};
+
+const BUDGET: u64 = 10_000_000;
+
+// riscv-tests suites, physical-memory environment only
+const SUITES: [&str; 6] = ["rv64ui", "rv64um", "rv64ua", "rv64uf", "rv64ud", "rv64uc"];
+
+fn directory(var: &str) -> PathBuf {
+    PathBuf::from(env::var(var).unwrap_or_else(|_| panic!("{} is not set", var)))
+}
+
+fn run(program: &Path) -> (StopReason, Cpu) {
+    let config = MachineConfig::virt().program(program).build().unwrap();
+    let mut cpu = Cpu::new(config, Vec::new()).unwrap();
+    cpu.trace = false;
+    (cpu.run_until(None, BUDGET), cpu)
+}
+
+/// `rv64ui-p-add.elf`, in the manner of riscv-tests: adds 3 and 4 and
+/// writes 1 to `tohost` if it got 7, or fails test 1.
+///
+///     _start: auipc t0, 1         # tohost
+///             li a0, 3
+///             li a1, 4
+///             add a2, a0, a1
+///             li a3, 7
+///             li t1, 1
+///             beq a2, a3, pass
+///             li t1, 3
+///     pass:   sd t1, 0(t0)
+///             j .
+///
+/// `rv64um-p-muldiv.elf` runs 21 `TEST_RR_OP` cases over the M extension's
+/// doubleword operations, with expected values computed independently of
+/// the emulator. They cover division by zero, `MIN / -1`, and the signed
+/// and unsigned high products.
+/// Each case sets `gp` to its number, and a mismatch writes `gp << 1 | 1`
+/// to `tohost` as riscv-tests does.
+#[test]
+fn test_committed_programs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let (reason, cpu) = run(&dir.join("rv64ui-p-add.elf"));
+    assert!(matches!(reason, StopReason::Exited(0)), "{}", reason);
+    assert_eq!(cpu.instret, 8);
+
+    let (reason, _) = run(&dir.join("rv64um-p-muldiv.elf"));
+    assert!(matches!(reason, StopReason::Exited(0)), "{}", reason);
+}
+
+/// `RISQUE_RISCV_TESTS`: the `isa` directory of riscv-tests after `make`.
+#[test]
+#[ignore]
+fn test_riscv_tests() {
+    let dir = directory("RISQUE_RISCV_TESTS");
+    let mut failures = Vec::new();
+    let mut count = 0;
+
+    for suite in SUITES {
+        let pattern = dir.join(format!("{}-p-*", suite));
+        for path in glob(&pattern.to_string_lossy()).unwrap().flatten() {
+            if path.extension().is_some_and(|ext| ext == "dump") {
+                continue;
+            }
+            count += 1;
+            match run(&path).0 {
+                StopReason::Exited(0) => {}
+                reason => failures.push(format!("{}: {}", path.display(), reason)),
+            }
+        }
+    }
+
+    assert!(count > 0, "no tests found in {}", dir.display());
+    assert!(
+        failures.is_empty(),
+        "{} of {} failed:\n{}",
+        failures.len(),
+        count,
+        failures.join("\n")
+    );
+}
+
+/// `RISQUE_ARCH_TEST`: riscv-arch-test programs, each `<name>.elf` next to
+/// the `<name>.reference_output` it must reproduce.
+#[test]
+#[ignore]
+fn test_riscv_arch_test() {
+    let dir = directory("RISQUE_ARCH_TEST");
+    let mut failures = Vec::new();
+    let mut count = 0;
+
+    let pattern = dir.join("**/*.elf");
+    for path in glob(&pattern.to_string_lossy()).unwrap().flatten() {
+        let reference = fs::read_to_string(path.with_extension("reference_output"))
+            .unwrap_or_else(|e| panic!("{}: no reference output: {}", path.display(), e));
+        count += 1;
+        let (reason, cpu) = run(&path);
+        if !matches!(reason, StopReason::Exited(_)) {
+            failures.push(format!("{}: {}", path.display(), reason));
+        } else if cpu.signature().unwrap().trim() != reference.trim() {
+            failures.push(format!("{}: signature differs", path.display()));
+        }
+    }
+
+    assert!(count > 0, "no tests found in {}", dir.display());
+    assert!(
+        failures.is_empty(),
+        "{} of {} failed:\n{}",
+        failures.len(),
+        count,
+        failures.join("\n")
+    );
+}